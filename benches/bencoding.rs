@@ -0,0 +1,81 @@
+//! Compares the owned and the borrowed bencode parsers on a synthetic torrent
+//! with a multi-megabyte 'pieces' string.
+
+// The crate is a binary, so the module is included directly and most of it is unused here
+#![allow(dead_code, unused_imports)]
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+#[path = "../src"]
+mod src {
+    pub mod bencoding;
+}
+
+use src::bencoding::BeParser;
+
+/// Builds a multi-file torrent with 'piece_count' SHA-1 hashes and 'file_count' files
+fn synthetic_torrent(piece_count: usize, file_count: usize) -> Vec<u8> {
+    let put_str = |s: &[u8], dst: &mut Vec<u8>| {
+        dst.extend_from_slice(format!("{}:", s.len()).as_bytes());
+        dst.extend_from_slice(s);
+    };
+
+    let mut src = Vec::new();
+    src.push(b'd');
+    put_str(b"announce", &mut src);
+    put_str(b"http://tracker.example/announce", &mut src);
+    put_str(b"info", &mut src);
+    src.push(b'd');
+
+    put_str(b"files", &mut src);
+    src.push(b'l');
+    for i in 0..file_count {
+        src.push(b'd');
+        put_str(b"length", &mut src);
+        src.extend_from_slice(b"i1048576e");
+        put_str(b"path", &mut src);
+        src.push(b'l');
+        put_str(b"subdir", &mut src);
+        put_str(format!("file_{}.bin", i).as_bytes(), &mut src);
+        src.extend_from_slice(b"ee");
+    }
+    src.push(b'e');
+
+    put_str(b"name", &mut src);
+    put_str(b"bench", &mut src);
+    put_str(b"piece length", &mut src);
+    src.extend_from_slice(b"i262144e");
+
+    let pieces: Vec<u8> = (0..piece_count * 20).map(|i| i as u8).collect();
+    put_str(b"pieces", &mut src);
+    put_str(&pieces, &mut src);
+    src.extend_from_slice(b"ee");
+
+    // Make sure the whole input is actually parsed
+    src::bencoding::de::from_bytes::<serde::de::IgnoredAny>(&src).unwrap();
+
+    src
+}
+
+fn bench_parsers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_torrent");
+
+    // 1 MB, 4 MB and 16 MB of piece hashes
+    for piece_count in [50_000, 200_000, 800_000] {
+        let src = synthetic_torrent(piece_count, 1000);
+        group.throughput(Throughput::Bytes(src.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("owned", src.len()), &src, |b, src| {
+            b.iter(|| BeParser::parse_with(black_box(src)).unwrap())
+        });
+
+        group.bench_with_input(BenchmarkId::new("borrowed", src.len()), &src, |b, src| {
+            b.iter(|| BeParser::new(black_box(src)).parse_value_ref().unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_parsers);
+criterion_main!(benches);
@@ -1,15 +1,25 @@
-use std::collections::HashMap;
+use std::fmt;
 
 use thiserror::Error;
 
-use self::bevalue::{BeValue, Dict};
+use self::{
+    bevalue::{BeValue, Dict},
+    bevalue_ref::{BeValueRef, DictRef},
+};
 
 pub mod bevalue;
+pub mod bevalue_ref;
+pub mod de;
+pub mod encoder;
+pub mod ser;
+pub mod stream;
 
 pub struct BeParser<'s> {
     src: &'s [u8],
     pos: usize,
     recursion_depth: u32,
+    /// Non-canonical constructs, only collected in strict mode
+    violations: Option<Vec<NonCanonical>>,
 }
 
 const MAX_NESTING_DEPTH: u32 = 16;
@@ -20,17 +30,39 @@ impl<'s> BeParser<'s> {
             src,
             pos: 0,
             recursion_depth: 0,
+            violations: None,
         }
     }
 
+    /// Enables strict mode, which records every construct that a canonical encoder
+    /// would have encoded differently (see `violations`)
+    pub fn strict(mut self) -> Self {
+        self.violations = Some(Vec::new());
+        self
+    }
+
+    /// Non-canonical constructs encountered so far, always empty outside of strict mode
+    pub fn violations(&self) -> &[NonCanonical] {
+        self.violations.as_deref().unwrap_or_default()
+    }
+
     pub fn parse_with(src: &'s [u8]) -> Result<BeValue, BeDecodeErr> {
         let mut parser = Self::new(src);
         parser.parse_value()
     }
 
+    /// Parses a value, failing if it isn't encoded canonically
+    pub fn parse_strict_with(src: &'s [u8]) -> Result<BeValue, BeDecodeErr> {
+        let mut parser = Self::new(src).strict();
+        let value = parser.parse_value()?;
+        parser.check_canonical()?;
+
+        Ok(value)
+    }
+
     pub fn parse_value(&mut self) -> Result<BeValue, BeDecodeErr> {
         if self.recursion_depth > MAX_NESTING_DEPTH {
-            return Err(BeDecodeErr::MaxNestingDepthExceeded);
+            return Err(self.err(BeDecodeErrKind::MaxNestingDepthExceeded));
         }
 
         self.recursion_depth += 1;
@@ -40,8 +72,8 @@ impl<'s> BeParser<'s> {
             Some(b'l') => Ok(BeValue::List(self.parse_list()?)),
             Some(b'd') => Ok(BeValue::Dict(self.parse_dict()?)),
             Some(c) if c.is_ascii_digit() => Ok(BeValue::Str(self.parse_str()?)),
-            Some(i) => Err(BeDecodeErr::InvalidInitialByte(*i)),
-            None => Err(BeDecodeErr::UnexpectedEnd),
+            Some(i) => Err(self.err(BeDecodeErrKind::InvalidInitialByte(*i))),
+            None => Err(self.err(BeDecodeErrKind::UnexpectedEnd)),
         };
 
         self.recursion_depth -= 1;
@@ -51,6 +83,7 @@ impl<'s> BeParser<'s> {
 
     /// i<integer encoded in base ten ASCII>e
     fn parse_int(&mut self) -> Result<i64, BeDecodeErr> {
+        let start = self.pos;
         self.next().unwrap(); // Skip the "i"
 
         let minus_one = if self.peek() == Some(&b'-') {
@@ -60,37 +93,53 @@ impl<'s> BeParser<'s> {
             1
         };
 
-        let num = i64::try_from(self.parse_digits()?)?;
+        let num = self.parse_digits()?;
+        let num = i64::try_from(num).map_err(|e| self.err(e.into()))?;
 
         self.expect(b'e')?;
 
+        if minus_one == -1 && num == 0 {
+            self.non_canonical(NonCanonical::NegativeZero(start));
+        }
+
         Ok(minus_one * num)
     }
 
     fn parse_str(&mut self) -> Result<Vec<u8>, BeDecodeErr> {
+        Ok(self.parse_str_ref()?.to_vec())
+    }
+
+    /// <string length encoded in base ten ASCII>:<string data>
+    fn parse_str_ref(&mut self) -> Result<&'s [u8], BeDecodeErr> {
         let len = self.parse_digits()?;
 
         self.expect(b':')?;
 
-        let mut string = Vec::with_capacity(len as usize);
-        for _ in 0..len {
-            match self.next() {
-                Some(b) => string.push(*b),
-                None => return Err(BeDecodeErr::UnexpectedEnd),
-            }
-        }
+        let start = self.pos;
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| start.checked_add(len))
+            .filter(|end| *end <= self.src.len())
+            .ok_or_else(|| self.err(BeDecodeErrKind::UnexpectedEnd))?;
 
-        Ok(string)
+        self.pos = end;
+
+        Ok(&self.src[start..end])
     }
 
     fn parse_digits(&mut self) -> Result<u64, BeDecodeErr> {
+        let start = self.pos;
         let digits = self.take_while(|b| b.is_ascii_digit())?;
         // UNWRAP: safe becasue we are only accepting ASCII digits
         let digits = std::str::from_utf8(digits).unwrap();
 
         let num = digits
             .parse::<u64>()
-            .map_err(|_| BeDecodeErr::InvalidStrLen)?;
+            .map_err(|_| self.err_at(BeDecodeErrKind::InvalidStrLen, start))?;
+
+        if digits.len() > 1 && digits.starts_with('0') {
+            self.non_canonical(NonCanonical::LeadingZero(start));
+        }
 
         Ok(num)
     }
@@ -107,9 +156,12 @@ impl<'s> BeParser<'s> {
                         self.next().unwrap();
                         break;
                     }
-                    _ => list.push(self.parse_value()?),
+                    _ => {
+                        let val = self.parse_value().map_err(|e| e.in_index(list.len()))?;
+                        list.push(val);
+                    }
                 },
-                None => return Err(BeDecodeErr::UnexpectedEnd),
+                None => return Err(self.err(BeDecodeErrKind::UnexpectedEnd)),
             }
         }
 
@@ -121,7 +173,8 @@ impl<'s> BeParser<'s> {
         let start = self.pos;
         self.next().unwrap(); // Skip the "d"
 
-        let mut dict = HashMap::new();
+        let mut dict = Dict::default();
+        let mut prev_key = None;
 
         loop {
             match self.peek() {
@@ -131,39 +184,158 @@ impl<'s> BeParser<'s> {
                         break;
                     }
                     _ => {
-                        let key = String::from_utf8(self.parse_str()?)?;
-                        let val = self.parse_value()?;
-
-                        let already_in = dict.insert(key, val);
-                        if already_in.is_some() {
-                            return Err(BeDecodeErr::DuplicateDictKeys);
+                        let key_start = self.pos;
+                        let key = self.parse_str_ref()?;
+                        self.check_key_order(&mut prev_key, key, key_start);
+
+                        let val_start = self.pos;
+                        let val = self.parse_value().map_err(|e| e.in_key(key))?;
+                        if !dict.push(key.to_vec(), val, val_start) {
+                            return Err(self.err_at(BeDecodeErrKind::DuplicateDictKeys, key_start));
                         }
                     }
                 },
-                None => return Err(BeDecodeErr::UnexpectedEnd),
+                None => return Err(self.err(BeDecodeErrKind::UnexpectedEnd)),
+            }
+        }
+
+        dict.src_range = start..self.pos;
+        Ok(dict)
+    }
+
+    pub fn parse_value_ref(&mut self) -> Result<BeValueRef<'s>, BeDecodeErr> {
+        if self.recursion_depth > MAX_NESTING_DEPTH {
+            return Err(self.err(BeDecodeErrKind::MaxNestingDepthExceeded));
+        }
+
+        self.recursion_depth += 1;
+
+        let res = match self.peek() {
+            Some(b'i') => Ok(BeValueRef::Int(self.parse_int()?)),
+            Some(b'l') => Ok(BeValueRef::List(self.parse_list_ref()?)),
+            Some(b'd') => Ok(BeValueRef::Dict(self.parse_dict_ref()?)),
+            Some(c) if c.is_ascii_digit() => Ok(BeValueRef::Str(self.parse_str_ref()?)),
+            Some(i) => Err(self.err(BeDecodeErrKind::InvalidInitialByte(*i))),
+            None => Err(self.err(BeDecodeErrKind::UnexpectedEnd)),
+        };
+
+        self.recursion_depth -= 1;
+
+        res
+    }
+
+    fn parse_list_ref(&mut self) -> Result<Vec<BeValueRef<'s>>, BeDecodeErr> {
+        self.next().unwrap(); // Skip the "l"
+
+        let mut list = Vec::new();
+
+        loop {
+            match self.peek() {
+                Some(b'e') => {
+                    self.next().unwrap();
+                    break;
+                }
+                Some(_) => {
+                    let val = self.parse_value_ref().map_err(|e| e.in_index(list.len()))?;
+                    list.push(val);
+                }
+                None => return Err(self.err(BeDecodeErrKind::UnexpectedEnd)),
+            }
+        }
+
+        Ok(list)
+    }
+
+    fn parse_dict_ref(&mut self) -> Result<DictRef<'s>, BeDecodeErr> {
+        let start = self.pos;
+        self.next().unwrap(); // Skip the "d"
+
+        let mut dict = DictRef::default();
+        let mut prev_key = None;
+
+        loop {
+            match self.peek() {
+                Some(b'e') => {
+                    self.next().unwrap();
+                    break;
+                }
+                Some(_) => {
+                    let key_start = self.pos;
+                    let key = self.parse_str_ref()?;
+                    self.check_key_order(&mut prev_key, key, key_start);
+
+                    let val_start = self.pos;
+                    let val = self.parse_value_ref().map_err(|e| e.in_key(key))?;
+                    if !dict.push(key, val, val_start) {
+                        return Err(self.err_at(BeDecodeErrKind::DuplicateDictKeys, key_start));
+                    }
+                }
+                None => return Err(self.err(BeDecodeErrKind::UnexpectedEnd)),
             }
         }
 
-        let end = self.pos;
-        Ok(Dict::new(dict, start..end))
+        dict.src_range = start..self.pos;
+        Ok(dict)
+    }
+
+    /// Keys of a canonical dictionary are sorted as raw strings
+    fn check_key_order(&mut self, prev_key: &mut Option<&'s [u8]>, key: &'s [u8], pos: usize) {
+        if matches!(prev_key, Some(prev) if *prev >= key) {
+            self.non_canonical(NonCanonical::UnsortedKey(pos));
+        }
+
+        *prev_key = Some(key);
+    }
+
+    fn non_canonical(&mut self, violation: NonCanonical) {
+        if let Some(violations) = self.violations.as_mut() {
+            violations.push(violation);
+        }
+    }
+
+    /// Fails with every non-canonical construct encountered in strict mode
+    fn check_canonical(&mut self) -> Result<(), BeDecodeErr> {
+        match self.violations.as_mut() {
+            Some(violations) if !violations.is_empty() => {
+                let violations = std::mem::take(violations);
+                Err(self.err(BeDecodeErrKind::NonCanonical(violations)))
+            }
+            _ => Ok(()),
+        }
     }
 
-    fn take_while(&mut self, condition: fn(u8) -> bool) -> Result<&[u8], BeDecodeErr> {
+    fn take_while(&mut self, condition: fn(u8) -> bool) -> Result<&'s [u8], BeDecodeErr> {
+        let src: &'s [u8] = self.src;
         let start = self.pos;
 
         loop {
             match self.peek() {
                 Some(b) if condition(*b) => self.pos += 1,
-                Some(_) => return Ok(&self.src[start..self.pos]),
-                None => return Err(BeDecodeErr::UnexpectedEnd),
+                Some(_) => return Ok(&src[start..self.pos]),
+                None => return Err(self.err(BeDecodeErrKind::UnexpectedEnd)),
             }
         }
     }
 
     fn expect(&mut self, expected: u8) -> Result<(), BeDecodeErr> {
-        match self.next() {
-            Some(c) if *c == expected => Ok(()),
-            o => Err(BeDecodeErr::Unexpected(o.cloned())),
+        match self.peek() {
+            Some(c) if *c == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            o => Err(self.err(BeDecodeErrKind::Unexpected(o.cloned()))),
+        }
+    }
+
+    fn err(&self, kind: BeDecodeErrKind) -> BeDecodeErr {
+        self.err_at(kind, self.pos)
+    }
+
+    fn err_at(&self, kind: BeDecodeErrKind, pos: usize) -> BeDecodeErr {
+        BeDecodeErr {
+            kind,
+            pos,
+            path: KeyPath::default(),
         }
     }
 
@@ -177,9 +349,87 @@ impl<'s> BeParser<'s> {
         self.src.get(self.pos)
     }
 }
-// TODO: should include a span in the error messages
+/// Location of a value inside of nested dictionaries and lists, e.g. 'info.files[3].path'
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyPath {
+    /// Innermost segment first, the path is built while the error is propagated
+    segments: Vec<PathSegment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+impl KeyPath {
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Prepends a dictionary key
+    pub fn push_key(&mut self, key: &[u8]) {
+        let key = String::from_utf8_lossy(key).into_owned();
+        self.segments.push(PathSegment::Key(key));
+    }
+
+    /// Prepends a list index
+    pub fn push_index(&mut self, index: usize) {
+        self.segments.push(PathSegment::Index(index));
+    }
+}
+
+impl fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.segments.iter().rev().enumerate() {
+            match segment {
+                PathSegment::Key(k) if i == 0 => f.write_str(k)?,
+                PathSegment::Key(k) => write!(f, ".{}", k)?,
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct BeDecodeErr {
+    pub kind: BeDecodeErrKind,
+    /// Byte offset at which the error was encountered
+    pub pos: usize,
+    /// Path of the value which couldn't be decoded
+    pub path: KeyPath,
+}
+
+impl BeDecodeErr {
+    fn in_key(mut self, key: &[u8]) -> Self {
+        self.path.push_key(key);
+        self
+    }
+
+    fn in_index(mut self, index: usize) -> Self {
+        self.path.push_index(index);
+        self
+    }
+}
+
+impl std::error::Error for BeDecodeErr {}
+
+impl fmt::Display for BeDecodeErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind, self.pos)?;
+
+        if !self.path.is_empty() {
+            write!(f, " in '{}'", self.path)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Error, Debug)]
-pub enum BeDecodeErr {
+pub enum BeDecodeErrKind {
     #[error("The byte stream ended unexpectedly")]
     UnexpectedEnd,
     #[error("Initial element character should be either a digit, 'i', 'l' or 'd', got: {0}")]
@@ -190,17 +440,29 @@ pub enum BeDecodeErr {
     InvalidNum(#[from] std::num::TryFromIntError),
     #[error("Unexpected byte: {0:?}")]
     Unexpected(Option<u8>),
-    #[error("Dictionary key must be a valid UTF-8 string. {0}")]
-    InvalidDictKey(#[from] std::string::FromUtf8Error),
     #[error("Max nesting depth was exceeded")]
     MaxNestingDepthExceeded,
     #[error("Duplicate dict keys encountered")]
     DuplicateDictKeys,
+    #[error("Value isn't canonically encoded: {0:?}")]
+    NonCanonical(Vec<NonCanonical>),
+}
+
+/// A construct that a canonical encoder would have encoded differently,
+/// holds the byte offset of the construct
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonCanonical {
+    /// Integer or string length with a leading zero, e.g. 'i03e' or '03:abc'
+    LeadingZero(usize),
+    /// 'i-0e'
+    NegativeZero(usize),
+    /// Dictionary key that isn't sorted after the previous key
+    UnsortedKey(usize),
 }
 
 #[cfg(test)]
 mod test {
-    use super::{BeParser, BeValue};
+    use super::{BeDecodeErrKind, BeParser, BeValue, NonCanonical};
 
     #[test]
     fn test_correct_integer_decoding() {
@@ -251,4 +513,90 @@ mod test {
         BeParser::parse_with("5.5:10".as_bytes()).expect_err("");
         BeParser::parse_with("2ae".as_bytes()).expect_err("");
     }
+
+    #[test]
+    fn test_dict_decoding() {
+        let mut hash = vec![0xFF; 32];
+        hash[0] = 0xAB;
+
+        let mut src = b"d12:piece layersd32:".to_vec();
+        src.extend_from_slice(&hash);
+        src.extend_from_slice(b"4:abcde4:zeroi0e1:ai1ee");
+
+        let val = BeParser::new(&src).parse_value_ref().unwrap();
+        let dict = val.get_dict().unwrap();
+
+        let keys: Vec<&[u8]> = dict.iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, [&b"piece layers"[..], b"zero", b"a"]);
+
+        assert!(dict.contains("zero"));
+        assert_eq!(dict.expect("a").unwrap().get_u32().unwrap(), 1);
+
+        let layers = dict.expect(b"piece layers").unwrap().get_dict().unwrap();
+        assert_eq!(layers.expect(&hash).unwrap().get_str().unwrap(), b"abcd");
+
+        let mut dict = match BeParser::parse_with(&src).unwrap() {
+            BeValue::Dict(dict) => dict,
+            v => panic!("{:?}", v),
+        };
+
+        // Inserted entries are appended, existing ones are replaced in place
+        assert!(dict.insert("b", BeValue::Int(2)).is_none());
+        assert_eq!(dict.insert("zero", BeValue::Int(3)), Some(BeValue::Int(0)));
+        let keys: Vec<&[u8]> = dict.iter().map(|(k, _)| k.as_slice()).collect();
+        assert_eq!(keys, [&b"piece layers"[..], b"zero", b"a", b"b"]);
+        assert_eq!(dict.get_mut("b"), Some(&mut BeValue::Int(2)));
+        assert_eq!(dict.get_mut("zero"), Some(&mut BeValue::Int(3)));
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let src = b"d1:bi03e1:ai-0e1:c03:abce";
+
+        BeParser::parse_with(src).unwrap();
+
+        let mut parser = BeParser::new(src).strict();
+        parser.parse_value().unwrap();
+        assert_eq!(
+            parser.violations(),
+            [
+                NonCanonical::LeadingZero(5),
+                NonCanonical::UnsortedKey(8),
+                NonCanonical::NegativeZero(11),
+                NonCanonical::LeadingZero(18),
+            ]
+        );
+
+        match BeParser::parse_strict_with(b"li00ee").unwrap_err().kind {
+            BeDecodeErrKind::NonCanonical(v) => assert_eq!(v, [NonCanonical::LeadingZero(2)]),
+            e => panic!("{}", e),
+        }
+
+        BeParser::parse_strict_with(b"d1:ai0e1:bli-1e0:ee").unwrap();
+    }
+
+    #[test]
+    fn test_error_location() {
+        let src = b"d4:infod5:filesld6:lengthi1e4:pathl1:aeed6:lengthi2e4:pathl1:bi-e";
+        let err = BeParser::parse_with(src).unwrap_err();
+        assert!(matches!(err.kind, BeDecodeErrKind::InvalidStrLen));
+        assert_eq!(err.pos, 64);
+        assert_eq!(err.path.to_string(), "info.files[1].path[1]");
+
+        let err = BeParser::new(src).parse_value_ref().unwrap_err();
+        assert_eq!(err.pos, 64);
+        assert_eq!(err.path.to_string(), "info.files[1].path[1]");
+
+        let err = BeParser::parse_with(b"d1:ai1e1:ai2ee").unwrap_err();
+        assert!(matches!(err.kind, BeDecodeErrKind::DuplicateDictKeys));
+        assert_eq!(err.pos, 7);
+        assert!(err.path.is_empty());
+    }
+
+    #[test]
+    fn test_incorrect_dict_decoding() {
+        BeParser::parse_with("d1:ai1e1:ai2ee".as_bytes()).expect_err("");
+        BeParser::parse_with("di1ei2ee".as_bytes()).expect_err("");
+        BeParser::parse_with("d1:ai1e".as_bytes()).expect_err("");
+    }
 }
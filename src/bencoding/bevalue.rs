@@ -1,8 +1,15 @@
-use std::{collections::HashMap, fmt, num::TryFromIntError, ops::Range, string::FromUtf8Error};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    num::TryFromIntError,
+    ops::Range,
+    str::Utf8Error,
+};
 
 use thiserror::Error;
 
-use super::{BeDecodeErr, BeParser};
+use super::encoder::BeEncoder;
+use super::KeyPath;
 
 #[derive(PartialEq)]
 pub enum BeValue {
@@ -17,117 +24,157 @@ pub type BeStr = Vec<u8>;
 pub type BeInt = i64;
 pub type BeList = Vec<BeValue>;
 
+/// Dictionary with byte string keys, entries are kept in the order of the source.
+/// Keys can be looked up either as byte strings or as UTF-8 strings.
 #[derive(PartialEq, Default)]
 pub struct Dict {
-    vals: HashMap<String, BeValue>,
+    /// Keys, values and the byte offsets of the values
+    vals: Vec<(BeStr, BeValue, usize)>,
+    /// Positions of the entries in 'vals' by their keys
+    index: HashMap<BeStr, usize>,
     /// Used for computing the hash of a specific dict.
     pub src_range: Range<usize>,
 }
 
 impl Dict {
-    pub fn new(vals: HashMap<String, BeValue>, src_range: Range<usize>) -> Self {
-        Dict { vals, src_range }
+    pub fn new(vals: Vec<(BeStr, BeValue, usize)>, src_range: Range<usize>) -> Self {
+        let index = vals
+            .iter()
+            .enumerate()
+            .map(|(i, (k, _, _))| (k.clone(), i))
+            .collect();
+
+        Dict {
+            vals,
+            index,
+            src_range,
+        }
     }
 
-    /// Return a reference to a compulsory field
-    pub fn expect(&mut self, k: &str) -> ReponseParseResult<&mut BeValue> {
-        self.vals
-            .get_mut(k)
-            .ok_or_else(|| ResponseParseError::ValNotContained(k.to_string()))
+    /// Appends an entry of the source, returns false if the key is already present
+    pub(super) fn push(&mut self, k: BeStr, v: BeValue, pos: usize) -> bool {
+        match self.index.entry(k) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(e) => {
+                self.vals.push((e.key().clone(), v, pos));
+                e.insert(self.vals.len() - 1);
+                true
+            }
+        }
     }
 
-    pub fn contains(&self, k: &str) -> bool {
-        self.vals.contains_key(k)
+    /// Inserts a value, returning the previous one if the key was already present
+    pub fn insert(&mut self, k: impl Into<BeStr>, v: BeValue) -> Option<BeValue> {
+        let k = k.into();
+
+        match self.get_mut(&k) {
+            Some(old) => Some(std::mem::replace(old, v)),
+            None => {
+                // The value doesn't come from the source
+                let pos = self.src_range.start;
+                self.index.insert(k.clone(), self.vals.len());
+                self.vals.push((k, v, pos));
+                None
+            }
+        }
     }
 
-    /// Get a mutable reference to an optional field
-    pub fn get_mut(&mut self, k: &str) -> Option<&mut BeValue> {
-        self.vals.get_mut(k)
+    /// Iterates over the entries in the order of the source
+    pub fn iter(&self) -> impl Iterator<Item = (&BeStr, &BeValue)> {
+        self.vals.iter().map(|(k, v, _)| (k, v))
     }
 
-    /// Extracts an optional field into a specific type
-    pub fn try_get<T, F>(&mut self, k: &str, f: F) -> ReponseParseResult<Option<T>>
+    /// Get a mutable reference to an optional field
+    pub fn get_mut<K>(&mut self, k: &K) -> Option<&mut BeValue>
     where
-        F: FnOnce(&mut BeValue) -> ReponseParseResult<T>,
+        K: AsRef<[u8]> + ?Sized,
     {
-        let res = self.get_mut(k).map(f);
+        let i = *self.index.get(k.as_ref())?;
+        Some(&mut self.vals[i].1)
+    }
+}
 
-        res.transpose()
+impl BeValue {
+    /// Encodes the value into canonical bencode
+    pub fn to_bytes(&self) -> Vec<u8> {
+        BeEncoder::encode_with(self)
     }
+}
 
-    // TODO(commit): solve lifetime issue by using |BeVaule| -> &mut T in the previous method
-    /// Extracts an optional field into a specific type
-    pub fn try_get_ref_mut<T, F>(&mut self, k: &str, f: F) -> ReponseParseResult<Option<&mut T>>
-    where
-        F: FnOnce(&mut BeValue) -> ReponseParseResult<&mut T>,
-    {
-        let res = self.get_mut(k).map(f);
+pub type ReponseParseResult<T> = Result<T, ResponseParseError>;
 
-        res.transpose()
-    }
+/// Error while extracting values from a parsed structure
+#[derive(Debug)]
+pub struct ResponseParseError {
+    pub kind: ResponseParseErrKind,
+    /// Byte offset of the innermost value whose location is known
+    pub pos: Option<usize>,
+    /// Path of the value which couldn't be extracted
+    pub path: KeyPath,
 }
 
-impl BeValue {
-    pub fn from_bytes(src: &[u8]) -> Result<Self, BeDecodeErr> {
-        BeParser::parse_with(src)
+impl ResponseParseError {
+    /// Sets the location if it isn't already known
+    pub fn at(mut self, pos: usize) -> Self {
+        self.pos.get_or_insert(pos);
+        self
     }
 
-    pub fn get_dict(&mut self) -> ReponseParseResult<&mut Dict> {
-        match self {
-            BeValue::Dict(d) => Ok(d),
-            t => Err(ResponseParseError::InvalidType("dictionary", t.label())),
-        }
+    /// Adds the location of the dictionary entry that the error came from
+    pub fn in_key(mut self, key: &[u8], pos: usize) -> Self {
+        self.path.push_key(key);
+        self.at(pos)
     }
 
-    pub fn get_list(&mut self) -> ReponseParseResult<&mut BeList> {
-        match self {
-            BeValue::List(l) => Ok(l),
-            t => Err(ResponseParseError::InvalidType("dictionary", t.label())),
-        }
+    /// Adds the location of the list element that the error came from
+    pub fn in_index(mut self, index: usize) -> Self {
+        self.path.push_index(index);
+        self
     }
+}
 
-    pub fn get_str(&mut self) -> ReponseParseResult<BeStr> {
-        match self {
-            BeValue::Str(s) => Ok(s.clone()),
-            t => Err(ResponseParseError::InvalidType("string", t.label())),
-        }
-    }
+impl std::error::Error for ResponseParseError {}
 
-    pub fn get_str_utf8(&mut self) -> ReponseParseResult<String> {
-        match self {
-            BeValue::Str(s) => Ok(String::from_utf8(s.to_vec())?),
-            t => Err(ResponseParseError::InvalidType("string", t.label())),
+impl fmt::Display for ResponseParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+
+        if let Some(pos) = self.pos {
+            write!(f, " at byte {}", pos)?;
         }
-    }
 
-    pub fn get_u64(&mut self) -> ReponseParseResult<u64> {
-        match self {
-            BeValue::Int(i) => Ok(u64::try_from(*i)?),
-            t => Err(ResponseParseError::InvalidType("integer", t.label())),
+        if !self.path.is_empty() {
+            write!(f, " in '{}'", self.path)?;
         }
+
+        Ok(())
     }
+}
 
-    pub fn get_u32(&mut self) -> ReponseParseResult<u32> {
-        match self {
-            BeValue::Int(i) => Ok(u32::try_from(*i)?),
-            t => Err(ResponseParseError::InvalidType("integer", t.label())),
+impl From<ResponseParseErrKind> for ResponseParseError {
+    fn from(kind: ResponseParseErrKind) -> Self {
+        Self {
+            kind,
+            pos: None,
+            path: KeyPath::default(),
         }
     }
+}
 
-    fn label(&self) -> &'static str {
-        match self {
-            BeValue::Str(_) => "string",
-            BeValue::Int(_) => "integer",
-            BeValue::Dict(_) => "dictionary",
-            BeValue::List(_) => "list",
-        }
+impl From<TryFromIntError> for ResponseParseError {
+    fn from(e: TryFromIntError) -> Self {
+        ResponseParseErrKind::from(e).into()
     }
 }
 
-pub type ReponseParseResult<T> = Result<T, ResponseParseError>;
+impl From<Utf8Error> for ResponseParseError {
+    fn from(e: Utf8Error) -> Self {
+        ResponseParseErrKind::from(e).into()
+    }
+}
 
 #[derive(Error, Debug)]
-pub enum ResponseParseError {
+pub enum ResponseParseErrKind {
     #[error("Attempted to extract a value of type '{0}' when 'self' is '{1}'")]
     InvalidType(&'static str, &'static str),
     #[error("The value of key: '{0}' is not contained in this dictionary")]
@@ -135,7 +182,7 @@ pub enum ResponseParseError {
     #[error("Error while getting an unsigned int: '{0}'")]
     IntNotUnsigned(#[from] TryFromIntError),
     #[error("String isn't properly UTF-8 formatted: '{0}'")]
-    StrNotUtf8(#[from] FromUtf8Error),
+    StrNotUtf8(#[from] Utf8Error),
 }
 
 impl fmt::Debug for BeValue {
@@ -149,9 +196,9 @@ impl fmt::Debug for BeValue {
             BeValue::Dict(d) => f
                 .debug_map()
                 .entries(
-                    d.vals
-                        .iter()
-                        .filter(|(k, _)| *k != "pieces" && *k != "piece_hashes"),
+                    d.iter()
+                        .filter(|(k, _)| *k != b"pieces" && *k != b"piece_hashes")
+                        .map(|(k, v)| (String::from_utf8_lossy(k), v)),
                 )
                 .finish(),
             BeValue::List(l) => f.debug_list().entries(l.iter()).finish(),
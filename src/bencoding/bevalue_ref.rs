@@ -0,0 +1,284 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    ops::Range,
+};
+
+use super::bevalue::{
+    BeInt, BeValue, Dict, ReponseParseResult, ResponseParseErrKind, ResponseParseError,
+};
+
+/// A bencoded value that borrows strings from the source buffer instead of copying them
+#[derive(PartialEq)]
+pub enum BeValueRef<'a> {
+    Str(&'a [u8]),
+    Int(BeInt),
+    Dict(DictRef<'a>),
+    List(Vec<BeValueRef<'a>>),
+}
+
+/// Borrowed counterpart of Dict, entries are kept in the order of the source
+#[derive(PartialEq, Default)]
+pub struct DictRef<'a> {
+    /// Keys, values and the byte offsets of the values
+    vals: Vec<(&'a [u8], BeValueRef<'a>, usize)>,
+    /// Positions of the entries in 'vals' by their keys
+    index: HashMap<&'a [u8], usize>,
+    /// Used for computing the hash of a specific dict.
+    pub src_range: Range<usize>,
+}
+
+impl<'a> DictRef<'a> {
+    /// Appends an entry of the source, returns false if the key is already present
+    pub(super) fn push(&mut self, k: &'a [u8], v: BeValueRef<'a>, pos: usize) -> bool {
+        match self.index.entry(k) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(e) => {
+                e.insert(self.vals.len());
+                self.vals.push((k, v, pos));
+                true
+            }
+        }
+    }
+
+    /// Iterates over the entries in the order of the source
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &BeValueRef<'a>)> {
+        self.vals.iter().map(|(k, v, _)| (*k, v))
+    }
+
+    /// Return a reference to a compulsory field
+    pub fn expect<K>(&self, k: &K) -> ReponseParseResult<&BeValueRef<'a>>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        let k = k.as_ref();
+
+        self.get(k).ok_or_else(|| {
+            let key = String::from_utf8_lossy(k).to_string();
+            ResponseParseError::from(ResponseParseErrKind::ValNotContained(key))
+                .at(self.src_range.start)
+        })
+    }
+
+    /// Extracts a compulsory field into a specific type
+    pub fn expect_with<K, T, F>(&self, k: &K, f: F) -> ReponseParseResult<T>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        F: FnOnce(&BeValueRef<'a>) -> ReponseParseResult<T>,
+    {
+        self.expect(k)?;
+        // UNWRAP: checked by expect
+        self.try_get(k, f).map(Option::unwrap)
+    }
+
+    pub fn contains<K>(&self, k: &K) -> bool
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        self.get(k).is_some()
+    }
+
+    /// Get a reference to an optional field
+    pub fn get<K>(&self, k: &K) -> Option<&BeValueRef<'a>>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        self.get_entry(k).map(|(v, _)| v)
+    }
+
+    /// Extracts an optional field into a specific type
+    pub fn try_get<K, T, F>(&self, k: &K, f: F) -> ReponseParseResult<Option<T>>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        F: FnOnce(&BeValueRef<'a>) -> ReponseParseResult<T>,
+    {
+        let k = k.as_ref();
+
+        match self.get_entry(k) {
+            Some((v, pos)) => f(v).map(Some).map_err(|e| e.in_key(k, pos)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_entry<K>(&self, k: &K) -> Option<(&BeValueRef<'a>, usize)>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        let i = *self.index.get(k.as_ref())?;
+        let (_, v, pos) = &self.vals[i];
+        Some((v, *pos))
+    }
+}
+
+impl<'a> BeValueRef<'a> {
+    pub fn get_dict(&self) -> ReponseParseResult<&DictRef<'a>> {
+        match self {
+            BeValueRef::Dict(d) => Ok(d),
+            t => Err(ResponseParseErrKind::InvalidType("dictionary", t.label()).into()),
+        }
+    }
+
+    pub fn get_list(&self) -> ReponseParseResult<&[BeValueRef<'a>]> {
+        match self {
+            BeValueRef::List(l) => Ok(l),
+            t => Err(ResponseParseErrKind::InvalidType("list", t.label()).into()),
+        }
+    }
+
+    /// Extracts every element of a list into a specific type
+    pub fn map_list<T, F>(&self, mut f: F) -> ReponseParseResult<Vec<T>>
+    where
+        F: FnMut(&BeValueRef<'a>) -> ReponseParseResult<T>,
+    {
+        self.get_list()?
+            .iter()
+            .enumerate()
+            .map(|(i, v)| f(v).map_err(|e| e.in_index(i)))
+            .collect()
+    }
+
+    /// Returns the string borrowed from the source
+    pub fn get_str(&self) -> ReponseParseResult<&'a [u8]> {
+        match self {
+            BeValueRef::Str(s) => Ok(s),
+            t => Err(ResponseParseErrKind::InvalidType("string", t.label()).into()),
+        }
+    }
+
+    pub fn get_str_utf8(&self) -> ReponseParseResult<&'a str> {
+        match self {
+            BeValueRef::Str(s) => Ok(std::str::from_utf8(s)?),
+            t => Err(ResponseParseErrKind::InvalidType("string", t.label()).into()),
+        }
+    }
+
+    pub fn get_u64(&self) -> ReponseParseResult<u64> {
+        match self {
+            BeValueRef::Int(i) => Ok(u64::try_from(*i)?),
+            t => Err(ResponseParseErrKind::InvalidType("integer", t.label()).into()),
+        }
+    }
+
+    pub fn get_u32(&self) -> ReponseParseResult<u32> {
+        match self {
+            BeValueRef::Int(i) => Ok(u32::try_from(*i)?),
+            t => Err(ResponseParseErrKind::InvalidType("integer", t.label()).into()),
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            BeValueRef::Str(_) => "string",
+            BeValueRef::Int(_) => "integer",
+            BeValueRef::Dict(_) => "dictionary",
+            BeValueRef::List(_) => "list",
+        }
+    }
+}
+
+impl<'a> From<&BeValueRef<'a>> for BeValue {
+    fn from(value: &BeValueRef<'a>) -> Self {
+        match value {
+            BeValueRef::Str(s) => BeValue::Str(s.to_vec()),
+            BeValueRef::Int(i) => BeValue::Int(*i),
+            BeValueRef::Dict(d) => {
+                let vals = d
+                    .vals
+                    .iter()
+                    .map(|(k, v, pos)| (k.to_vec(), BeValue::from(v), *pos))
+                    .collect();
+
+                BeValue::Dict(Dict::new(vals, d.src_range.clone()))
+            }
+            BeValueRef::List(l) => BeValue::List(l.iter().map(BeValue::from).collect()),
+        }
+    }
+}
+
+impl<'a> fmt::Debug for BeValueRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BeValueRef::Str(s) => {
+                let lossy_string = String::from_utf8_lossy(s);
+                f.write_fmt(format_args!("{}", lossy_string))
+            }
+            BeValueRef::Int(i) => f.write_fmt(format_args!("{}", i)),
+            BeValueRef::Dict(d) => f
+                .debug_map()
+                .entries(
+                    d.iter()
+                        .filter(|(k, _)| *k != b"pieces" && *k != b"piece_hashes")
+                        .map(|(k, v)| (String::from_utf8_lossy(k), v)),
+                )
+                .finish(),
+            BeValueRef::List(l) => f.debug_list().entries(l.iter()).finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_bevalue_ref {
+    use super::super::BeParser;
+    use super::*;
+
+    #[test]
+    fn test_borrowed_strings() {
+        let src = b"d4:infod6:pieces4:\x01\x02\x03\x044:name4:teste4:listl1:ai-3eee";
+        let value = BeParser::new(src).parse_value_ref().unwrap();
+
+        let dict = value.get_dict().unwrap();
+        let info = dict.expect("info").unwrap().get_dict().unwrap();
+        let pieces = info.expect("pieces").unwrap().get_str().unwrap();
+
+        assert_eq!(pieces, &[1, 2, 3, 4]);
+        // The string points into the source buffer
+        assert!(src.as_ptr_range().contains(&pieces.as_ptr()));
+        assert_eq!(
+            &src[info.src_range.clone()],
+            b"d6:pieces4:\x01\x02\x03\x044:name4:teste"
+        );
+
+        assert_eq!(
+            info.expect_with("name", BeValueRef::get_str_utf8).unwrap(),
+            "test"
+        );
+        assert_eq!(dict.expect("list").unwrap().get_list().unwrap().len(), 2);
+        assert!(dict.contains("list") && !info.contains("list"));
+        dict.expect("list")
+            .unwrap()
+            .map_list(BeValueRef::get_str)
+            .unwrap_err();
+        assert!(dict
+            .try_get("missing", BeValueRef::get_u32)
+            .unwrap()
+            .is_none());
+        dict.expect("list").unwrap().get_u64().unwrap_err();
+    }
+
+    #[test]
+    fn test_matches_owned_parser() {
+        let src =
+            b"d8:announce3:url4:infod6:lengthi1024e4:name4:file12:piece lengthi512ee2:\xFF\xFEi1ee";
+
+        let borrowed = BeParser::new(src).parse_value_ref().unwrap();
+        let owned = BeParser::parse_with(src).unwrap();
+
+        assert_eq!(BeValue::from(&borrowed), owned);
+        assert_eq!(
+            borrowed.get_dict().unwrap().expect(b"\xFF\xFE").unwrap(),
+            &BeValueRef::Int(1)
+        );
+    }
+
+    #[test]
+    fn test_incorrect_decoding() {
+        BeParser::new(b"5:abc").parse_value_ref().unwrap_err();
+        BeParser::new(b"18446744073709551615:abc")
+            .parse_value_ref()
+            .unwrap_err();
+        BeParser::new(b"d1:ai1e1:ai2ee")
+            .parse_value_ref()
+            .unwrap_err();
+        BeParser::new(b"li1e").parse_value_ref().unwrap_err();
+    }
+}
@@ -0,0 +1,726 @@
+use std::collections::HashSet;
+
+use serde::de::{
+    self, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess,
+    Visitor,
+};
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use super::{BeDecodeErr, BeDecodeErrKind, BeParser, KeyPath, MAX_NESTING_DEPTH};
+
+/// Name of the newtype struct used for capturing raw bencoded values
+pub(super) const RAW_VALUE_TOKEN: &str = "$bencoding::RawValue";
+
+/// Deserializes an instance of T from bencoded bytes.
+/// Byte strings and raw values are borrowed from the source.
+pub fn from_bytes<'de, T>(src: &'de [u8]) -> Result<T, BeDeserializeErr>
+where
+    T: Deserialize<'de>,
+{
+    let mut deserializer = BeDeserializer::new(src);
+    let value = T::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+
+    Ok(value)
+}
+
+/// Like from_bytes, but fails if the input isn't canonically encoded
+pub fn from_bytes_strict<'de, T>(src: &'de [u8]) -> Result<T, BeDeserializeErr>
+where
+    T: Deserialize<'de>,
+{
+    let mut deserializer = BeDeserializer {
+        parser: BeParser::new(src).strict(),
+    };
+    let value = T::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    deserializer.parser.check_canonical()?;
+
+    Ok(value)
+}
+
+/// Serde data format built on top of the BeParser
+pub struct BeDeserializer<'de> {
+    parser: BeParser<'de>,
+}
+
+impl<'de> BeDeserializer<'de> {
+    pub fn new(src: &'de [u8]) -> Self {
+        Self {
+            parser: BeParser::new(src),
+        }
+    }
+
+    /// Checks that the whole input has been consumed
+    pub fn end(&self) -> Result<(), BeDeserializeErr> {
+        if self.parser.pos == self.parser.src.len() {
+            Ok(())
+        } else {
+            Err(BeDeserializeErrKind::TrailingBytes(self.parser.pos).into())
+        }
+    }
+
+    /// Returns the source bytes of the next value without interpreting them
+    fn skip_value(&mut self) -> Result<&'de [u8], BeDeserializeErr> {
+        let src: &'de [u8] = self.parser.src;
+
+        let start = self.parser.pos;
+        // The strings are only borrowed, large values like 'pieces' aren't copied
+        self.parser.parse_value_ref()?;
+
+        Ok(&src[start..self.parser.pos])
+    }
+
+    fn expect_start(&mut self, start: u8, expected: &'static str) -> Result<(), BeDeserializeErr> {
+        match self.parser.peek() {
+            Some(b) if *b == start => {
+                self.parser.next();
+                Ok(())
+            }
+            Some(_) => Err(BeDeserializeErrKind::InvalidType(expected, self.parser.pos).into()),
+            None => Err(self.parser.err(BeDecodeErrKind::UnexpectedEnd).into()),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), BeDeserializeErr> {
+        if self.parser.recursion_depth > MAX_NESTING_DEPTH {
+            return Err(self
+                .parser
+                .err(BeDecodeErrKind::MaxNestingDepthExceeded)
+                .into());
+        }
+
+        self.parser.recursion_depth += 1;
+        Ok(())
+    }
+
+    fn leave(&mut self) -> Result<(), BeDeserializeErr> {
+        self.parser.recursion_depth -= 1;
+        self.parser.expect(b'e')?;
+        Ok(())
+    }
+
+    fn is_str_next(&self) -> bool {
+        matches!(self.parser.peek(), Some(c) if c.is_ascii_digit())
+    }
+}
+
+impl<'de> Deserializer<'de> for &mut BeDeserializer<'de> {
+    type Error = BeDeserializeErr;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.parser.peek() {
+            Some(b'i') => visitor.visit_i64(self.parser.parse_int()?),
+            Some(b'l') => self.deserialize_seq(visitor),
+            Some(b'd') => self.deserialize_map(visitor),
+            Some(c) if c.is_ascii_digit() => {
+                visitor.visit_borrowed_bytes(self.parser.parse_str_ref()?)
+            }
+            Some(i) => Err(self
+                .parser
+                .err(BeDecodeErrKind::InvalidInitialByte(*i))
+                .into()),
+            None => Err(self.parser.err(BeDecodeErrKind::UnexpectedEnd).into()),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.parser.peek() != Some(&b'i') {
+            return Err(BeDeserializeErrKind::InvalidType("integer", self.parser.pos).into());
+        }
+
+        match self.parser.parse_int()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            _ => Err(BeDeserializeErrKind::InvalidType("boolean", self.parser.pos).into()),
+        }
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(BeDeserializeErrKind::Unsupported("f32").into())
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(BeDeserializeErrKind::Unsupported("f64").into())
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if !self.is_str_next() {
+            return Err(BeDeserializeErrKind::InvalidType("string", self.parser.pos).into());
+        }
+
+        let bytes = self.parser.parse_str_ref()?;
+        match std::str::from_utf8(bytes) {
+            Ok(s) => visitor.visit_borrowed_str(s),
+            // Let the visitor decide if it accepts arbitrary bytes
+            Err(_) => visitor.visit_borrowed_bytes(bytes),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if !self.is_str_next() {
+            return Err(BeDeserializeErrKind::InvalidType("string", self.parser.pos).into());
+        }
+
+        visitor.visit_borrowed_bytes(self.parser.parse_str_ref()?)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    /// Missing optional fields are handled by serde, a present value is always Some
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(BeDeserializeErrKind::Unsupported("unit").into())
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        if name == RAW_VALUE_TOKEN {
+            visitor.visit_borrowed_bytes(self.skip_value()?)
+        } else {
+            visitor.visit_newtype_struct(self)
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.expect_start(b'l', "list")?;
+        self.enter()?;
+
+        let value = visitor.visit_seq(ListAccess {
+            de: &mut *self,
+            index: 0,
+        })?;
+
+        self.leave()?;
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.expect_start(b'd', "dictionary")?;
+        self.enter()?;
+
+        let value = visitor.visit_map(DictAccess {
+            de: &mut *self,
+            last_key: None,
+            keys: HashSet::new(),
+        })?;
+
+        self.leave()?;
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    /// Unit variants are encoded as strings, other variants as a single-key dictionary
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        if self.is_str_next() {
+            let pos = self.parser.pos;
+            let variant = std::str::from_utf8(self.parser.parse_str_ref()?)
+                .map_err(|_| BeDeserializeErrKind::InvalidType("UTF-8 string", pos))?;
+
+            return visitor.visit_enum(variant.into_deserializer());
+        }
+
+        self.expect_start(b'd', "dictionary")?;
+        self.enter()?;
+
+        let value = visitor.visit_enum(VariantDictAccess { de: &mut *self })?;
+
+        self.leave()?;
+        Ok(value)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.skip_value()?;
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128
+    }
+}
+
+struct ListAccess<'a, 'de> {
+    de: &'a mut BeDeserializer<'de>,
+    /// Index of the next element, used for error paths
+    index: usize,
+}
+
+impl<'de, 'a> SeqAccess<'de> for ListAccess<'a, 'de> {
+    type Error = BeDeserializeErr;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.de.parser.peek() {
+            Some(b'e') => Ok(None),
+            Some(_) => {
+                let index = self.index;
+                self.index += 1;
+
+                seed.deserialize(&mut *self.de).map(Some).map_err(|mut e| {
+                    e.path.push_index(index);
+                    e
+                })
+            }
+            None => Err(self.de.parser.err(BeDecodeErrKind::UnexpectedEnd).into()),
+        }
+    }
+}
+
+struct DictAccess<'a, 'de> {
+    de: &'a mut BeDeserializer<'de>,
+    /// Used for error paths and for checking the key order in strict mode
+    last_key: Option<&'de [u8]>,
+    /// Duplicate keys are rejected like in BeParser, instead of keeping the last value
+    keys: HashSet<&'de [u8]>,
+}
+
+impl<'de, 'a> MapAccess<'de> for DictAccess<'a, 'de> {
+    type Error = BeDeserializeErr;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        match self.de.parser.peek() {
+            Some(b'e') => Ok(None),
+            Some(c) if c.is_ascii_digit() => {
+                let key_start = self.de.parser.pos;
+                let key = seed.deserialize(&mut *self.de)?;
+
+                // The key has already been validated, only strip the length prefix
+                let src: &'de [u8] = self.de.parser.src;
+                let raw = &src[key_start..self.de.parser.pos];
+                // UNWRAP: a string always contains the ':' delimiter
+                let delim = raw.iter().position(|b| *b == b':').unwrap();
+                let raw = &raw[delim + 1..];
+
+                if !self.keys.insert(raw) {
+                    let e = self
+                        .de
+                        .parser
+                        .err_at(BeDecodeErrKind::DuplicateDictKeys, key_start);
+                    return Err(e.into());
+                }
+                self.de
+                    .parser
+                    .check_key_order(&mut self.last_key, raw, key_start);
+
+                Ok(Some(key))
+            }
+            Some(_) => Err(BeDeserializeErrKind::InvalidType("string", self.de.parser.pos).into()),
+            None => Err(self.de.parser.err(BeDecodeErrKind::UnexpectedEnd).into()),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de).map_err(|mut e| {
+            if let Some(key) = self.last_key {
+                e.path.push_key(key);
+            }
+            e
+        })
+    }
+}
+
+struct VariantDictAccess<'a, 'de> {
+    de: &'a mut BeDeserializer<'de>,
+}
+
+impl<'de, 'a> EnumAccess<'de> for VariantDictAccess<'a, 'de> {
+    type Error = BeDeserializeErr;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self), Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(&mut *self.de)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a> VariantAccess<'de> for VariantDictAccess<'a, 'de> {
+    type Error = BeDeserializeErr;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        Err(BeDeserializeErrKind::InvalidType("string", self.de.parser.pos).into())
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Self::Error>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.de.deserialize_seq(visitor)
+    }
+
+    fn struct_variant<V>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.de.deserialize_map(visitor)
+    }
+}
+
+/// The raw bencoded bytes of a value, borrowed from the source.
+/// Can be used for computing the info hash of a torrent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawValue<'a>(&'a [u8]);
+
+impl<'a> RawValue<'a> {
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for RawValue<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RawValueVisitor<'a>(std::marker::PhantomData<RawValue<'a>>);
+
+        impl<'de: 'a, 'a> Visitor<'de> for RawValueVisitor<'a> {
+            type Value = RawValue<'a>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a raw bencoded value")
+            }
+
+            fn visit_borrowed_bytes<E: de::Error>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+                Ok(RawValue(v))
+            }
+        }
+
+        deserializer
+            .deserialize_newtype_struct(RAW_VALUE_TOKEN, RawValueVisitor(std::marker::PhantomData))
+    }
+}
+
+#[derive(Debug)]
+pub struct BeDeserializeErr {
+    pub kind: BeDeserializeErrKind,
+    /// Path of the value which couldn't be deserialized
+    pub path: KeyPath,
+}
+
+impl std::error::Error for BeDeserializeErr {}
+
+impl std::fmt::Display for BeDeserializeErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+
+        if !self.path.is_empty() {
+            write!(f, " in '{}'", self.path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<BeDeserializeErrKind> for BeDeserializeErr {
+    fn from(kind: BeDeserializeErrKind) -> Self {
+        Self {
+            kind,
+            path: KeyPath::default(),
+        }
+    }
+}
+
+impl From<BeDecodeErr> for BeDeserializeErr {
+    /// Paths are tracked by the deserializer, take over the path of the parser
+    fn from(mut e: BeDecodeErr) -> Self {
+        let path = std::mem::take(&mut e.path);
+
+        Self {
+            kind: BeDeserializeErrKind::Decode(e),
+            path,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BeDeserializeErrKind {
+    #[error("{0}")]
+    Decode(BeDecodeErr),
+    #[error("Expected a value of type '{0}' at byte {1}")]
+    InvalidType(&'static str, usize),
+    #[error("The type '{0}' can't be represented in bencode")]
+    Unsupported(&'static str),
+    #[error("Trailing bytes after the value at byte {0}")]
+    TrailingBytes(usize),
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl de::Error for BeDeserializeErr {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        BeDeserializeErrKind::Custom(msg.to_string()).into()
+    }
+}
+
+#[cfg(test)]
+mod test_de {
+    use std::collections::BTreeMap;
+
+    use serde::Deserialize;
+
+    use super::super::NonCanonical;
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Info<'a> {
+        name: &'a str,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        #[serde(with = "serde_bytes")]
+        pieces: Vec<u8>,
+        length: Option<u64>,
+        private: Option<bool>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Torrent<'a> {
+        announce: String,
+        #[serde(rename = "announce-list", default)]
+        announce_list: Vec<Vec<String>>,
+        #[serde(borrow)]
+        info: RawValue<'a>,
+    }
+
+    #[test]
+    fn test_primitives() {
+        assert_eq!(from_bytes::<i64>(b"i-12e").unwrap(), -12);
+        assert_eq!(from_bytes::<u8>(b"i255e").unwrap(), 255);
+        from_bytes::<u8>(b"i256e").unwrap_err();
+        from_bytes::<u32>(b"i-1e").unwrap_err();
+
+        assert!(from_bytes::<bool>(b"i1e").unwrap());
+        from_bytes::<bool>(b"i2e").unwrap_err();
+
+        assert_eq!(from_bytes::<&str>(b"4:spam").unwrap(), "spam");
+        assert_eq!(from_bytes::<String>(b"0:").unwrap(), "");
+        from_bytes::<String>(b"2:\xFF\xFE").unwrap_err();
+        assert_eq!(from_bytes::<&[u8]>(b"2:\xFF\xFE").unwrap(), b"\xFF\xFE");
+
+        from_bytes::<String>(b"i1e").unwrap_err();
+        from_bytes::<f64>(b"i1e").unwrap_err();
+    }
+
+    #[test]
+    fn test_collections() {
+        assert_eq!(
+            from_bytes::<Vec<u32>>(b"li1ei2ei3ee").unwrap(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            from_bytes::<(u32, &str)>(b"li1e3:abce").unwrap(),
+            (1, "abc")
+        );
+
+        let mut expected = BTreeMap::new();
+        expected.insert("a".to_string(), 1);
+        expected.insert("b".to_string(), 2);
+        assert_eq!(
+            from_bytes::<BTreeMap<String, u32>>(b"d1:ai1e1:bi2ee").unwrap(),
+            expected
+        );
+
+        from_bytes::<Vec<u32>>(b"li1ei2e").unwrap_err();
+        from_bytes::<BTreeMap<String, u32>>(b"di1ei2ee").unwrap_err();
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let err = from_bytes::<BTreeMap<&str, u32>>(b"d1:ai1e1:ai2ee").unwrap_err();
+        match err.kind {
+            BeDeserializeErrKind::Decode(BeDecodeErr {
+                kind: BeDecodeErrKind::DuplicateDictKeys,
+                pos,
+                ..
+            }) => assert_eq!(pos, 7),
+            e => panic!("{}", e),
+        }
+
+        // Ignored fields of structs are checked as well
+        let src = b"d7:comment1:x7:comment1:y4:name1:a12:piece lengthi1e6:pieces0:e";
+        from_bytes::<Info>(src).unwrap_err();
+        let src = b"d1:ald1:bi1e1:bi1eeee";
+        let err = from_bytes::<BTreeMap<&str, Vec<BTreeMap<&str, u32>>>>(src).unwrap_err();
+        assert_eq!(err.path.to_string(), "a[0]");
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        from_bytes::<u32>(b"i1ei2e").unwrap_err();
+        from_bytes::<(u32,)>(b"li1ei2ee").unwrap_err();
+    }
+
+    #[test]
+    fn test_error_path() {
+        let err = from_bytes::<BTreeMap<&str, Vec<u32>>>(b"d1:ali1ei2ee1:bli1e1:cee").unwrap_err();
+        assert!(matches!(err.kind, BeDeserializeErrKind::Custom(_)));
+        assert_eq!(err.path.to_string(), "b[1]");
+
+        let err = from_bytes::<Info>(b"d4:name4:test12:piece lengthi16e6:pieces5:abe").unwrap_err();
+        assert!(matches!(err.kind, BeDeserializeErrKind::Decode(_)));
+        assert_eq!(err.path.to_string(), "pieces");
+    }
+
+    #[test]
+    fn test_strict() {
+        let src = b"d12:piece lengthi016e4:name4:test6:pieces0:e";
+
+        assert_eq!(from_bytes::<Info>(src).unwrap().piece_length, 16);
+
+        match from_bytes_strict::<Info>(src).unwrap_err().kind {
+            BeDeserializeErrKind::Decode(BeDecodeErr {
+                kind: BeDecodeErrKind::NonCanonical(v),
+                ..
+            }) => assert_eq!(
+                v,
+                [NonCanonical::LeadingZero(17), NonCanonical::UnsortedKey(21)]
+            ),
+            e => panic!("{}", e),
+        }
+
+        let src = b"d4:name4:test6:pieces0:12:piece lengthi16ee";
+        from_bytes_strict::<Info>(src).unwrap_err();
+        let src = b"d4:name4:test12:piece lengthi16e6:pieces0:e";
+        from_bytes_strict::<Info>(src).unwrap();
+        from_bytes_strict::<BTreeMap<&str, u32>>(b"d1:ai1e1:bi-0ee").unwrap_err();
+    }
+
+    #[test]
+    fn test_struct() {
+        let src = b"d6:lengthi1024e4:name8:file.iso12:piece lengthi512e6:pieces2:\x01\x02e";
+
+        let info = from_bytes::<Info>(src).unwrap();
+        assert_eq!(
+            info,
+            Info {
+                name: "file.iso",
+                piece_length: 512,
+                pieces: vec![1, 2],
+                length: Some(1024),
+                private: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_raw_value() {
+        let info = b"d4:name1:a12:piece lengthi1e6:pieces0:7:privatei1ee";
+        let mut src = b"d8:announce3:url7:comment4:test4:info".to_vec();
+        src.extend_from_slice(info);
+        src.push(b'e');
+
+        let torrent = from_bytes::<Torrent>(&src).unwrap();
+        assert_eq!(torrent.announce, "url");
+        assert!(torrent.announce_list.is_empty());
+        assert_eq!(torrent.info.as_bytes(), info);
+
+        let parsed = from_bytes::<Info>(torrent.info.as_bytes()).unwrap();
+        assert_eq!(parsed.name, "a");
+        assert_eq!(parsed.private, Some(true));
+    }
+
+    #[test]
+    fn test_enum() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        #[serde(rename_all = "snake_case")]
+        enum Msg {
+            Ping,
+            Peers(Vec<u32>),
+            Error { code: u32 },
+        }
+
+        assert_eq!(from_bytes::<Msg>(b"4:ping").unwrap(), Msg::Ping);
+        assert_eq!(
+            from_bytes::<Msg>(b"d5:peersli1eee").unwrap(),
+            Msg::Peers(vec![1])
+        );
+        assert_eq!(
+            from_bytes::<Msg>(b"d5:errord4:codei7eee").unwrap(),
+            Msg::Error { code: 7 }
+        );
+        from_bytes::<Msg>(b"4:pong").unwrap_err();
+    }
+
+    #[test]
+    fn test_max_nesting() {
+        let src = [b"l".repeat(32), b"e".repeat(32)].concat();
+        from_bytes::<serde::de::IgnoredAny>(&src).unwrap_err();
+    }
+}
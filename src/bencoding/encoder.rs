@@ -0,0 +1,141 @@
+use std::io::Write;
+
+use super::bevalue::{BeStr, BeValue, Dict};
+
+/// Encodes BeValues into their canonical form:
+/// dictionary keys are sorted as raw byte strings and integers have no leading zeros
+pub struct BeEncoder {
+    dst: Vec<u8>,
+}
+
+impl BeEncoder {
+    pub fn new() -> Self {
+        Self { dst: Vec::new() }
+    }
+
+    pub fn encode_with(value: &BeValue) -> Vec<u8> {
+        let mut encoder = Self::new();
+        encoder.encode_value(value);
+        encoder.finish()
+    }
+
+    pub fn encode_value(&mut self, value: &BeValue) {
+        match value {
+            BeValue::Int(i) => self.encode_int(*i),
+            BeValue::Str(s) => self.encode_str(s),
+            BeValue::List(l) => self.encode_list(l),
+            BeValue::Dict(d) => self.encode_dict(d),
+        }
+    }
+
+    /// Returns the encoded bytes
+    pub fn finish(self) -> Vec<u8> {
+        self.dst
+    }
+
+    /// i<integer encoded in base ten ASCII>e
+    fn encode_int(&mut self, int: i64) {
+        // UNWRAP: writing to a Vec can't fail
+        write!(self.dst, "i{}e", int).unwrap();
+    }
+
+    /// <string length encoded in base ten ASCII>:<string data>
+    fn encode_str(&mut self, string: &[u8]) {
+        // UNWRAP: writing to a Vec can't fail
+        write!(self.dst, "{}:", string.len()).unwrap();
+        self.dst.extend_from_slice(string);
+    }
+
+    /// l<bencoded values>e
+    fn encode_list(&mut self, list: &[BeValue]) {
+        self.dst.push(b'l');
+
+        for v in list {
+            self.encode_value(v);
+        }
+
+        self.dst.push(b'e');
+    }
+
+    /// d<bencoded string><bencoded element>e
+    fn encode_dict(&mut self, dict: &Dict) {
+        self.dst.push(b'd');
+
+        let mut entries: Vec<(&BeStr, &BeValue)> = dict.iter().collect();
+        // Keys must be sorted as raw strings, not as UTF-8 characters
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+        for (k, v) in entries {
+            self.encode_str(k);
+            self.encode_value(v);
+        }
+
+        self.dst.push(b'e');
+    }
+}
+
+#[cfg(test)]
+mod test_encoder {
+    use super::super::BeParser;
+    use super::*;
+
+    fn roundtrip(src: &[u8]) {
+        let value = BeParser::parse_with(src).unwrap();
+        assert_eq!(BeEncoder::encode_with(&value), src);
+    }
+
+    #[test]
+    fn test_encode_int() {
+        assert_eq!(BeEncoder::encode_with(&BeValue::Int(12345)), b"i12345e");
+        assert_eq!(BeEncoder::encode_with(&BeValue::Int(0)), b"i0e");
+        assert_eq!(BeEncoder::encode_with(&BeValue::Int(-1)), b"i-1e");
+        assert_eq!(
+            BeEncoder::encode_with(&BeValue::Int(i64::MIN)),
+            b"i-9223372036854775808e"
+        );
+    }
+
+    #[test]
+    fn test_encode_str() {
+        assert_eq!(BeEncoder::encode_with(&BeValue::Str(vec![])), b"0:");
+        assert_eq!(
+            BeEncoder::encode_with(&BeValue::Str(b"spam".to_vec())),
+            b"4:spam"
+        );
+        assert_eq!(
+            BeEncoder::encode_with(&BeValue::Str(vec![0xFF, 0x00])),
+            b"2:\xFF\x00"
+        );
+    }
+
+    #[test]
+    fn test_encode_sorted_keys() {
+        let mut dict = Dict::default();
+        dict.insert("zebra", BeValue::Int(1));
+        dict.insert("apple", BeValue::Int(2));
+        dict.insert("Zulu", BeValue::Int(3));
+        dict.insert("appl", BeValue::Int(4));
+        dict.insert(vec![0xFF], BeValue::Int(5));
+
+        let dict = BeValue::Dict(dict);
+
+        assert_eq!(
+            BeEncoder::encode_with(&dict),
+            b"d4:Zului3e4:appli4e5:applei2e5:zebrai1e1:\xFFi5ee"
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(b"i42e");
+        roundtrip(b"i-42e");
+        roundtrip(b"0:");
+        roundtrip(b"le");
+        roundtrip(b"de");
+        roundtrip(b"l4:spami42ee");
+        roundtrip(b"d3:cow3:moo4:spam4:eggse");
+        roundtrip(b"d4:spaml1:a1:bee");
+        roundtrip(b"d8:announce3:url4:infod6:lengthi1024e4:name4:file12:piece lengthi512eee");
+        roundtrip(b"lllli1eeeee");
+    }
+}
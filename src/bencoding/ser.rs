@@ -0,0 +1,696 @@
+use serde::ser::{self, Impossible, Serialize};
+use thiserror::Error;
+
+use super::de::{RawValue, RAW_VALUE_TOKEN};
+use super::{
+    bevalue::{BeValue, Dict},
+    BeDecodeErr, BeParser,
+};
+
+/// Serializes a value into canonical bencode.
+/// Struct fields and map entries are sorted by their keys, None values are skipped.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, BeSerializeErr> {
+    // A None at the top level produces no output
+    Ok(to_value(value)?.map(|v| v.to_bytes()).unwrap_or_default())
+}
+
+/// Converts the value into a BeValue, which is written out by the BeEncoder.
+/// Returns None for None values.
+fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Option<BeValue>, BeSerializeErr> {
+    value.serialize(BeSerializer)
+}
+
+/// Enum variants are wrapped in a single-key dictionary
+fn wrap_variant(value: BeValue, variant: Option<&'static str>) -> BeValue {
+    match variant {
+        Some(variant) => {
+            let mut dict = Dict::default();
+            dict.insert(variant, value);
+            BeValue::Dict(dict)
+        }
+        None => value,
+    }
+}
+
+struct BeSerializer;
+
+impl BeSerializer {
+    fn int<T: TryInto<i64> + Copy + Into<i128>>(v: T) -> Result<Option<BeValue>, BeSerializeErr> {
+        let int = v
+            .try_into()
+            .map_err(|_| BeSerializeErr::IntOutOfRange(v.into()))?;
+
+        Ok(Some(BeValue::Int(int)))
+    }
+
+    fn str(v: &[u8]) -> Result<Option<BeValue>, BeSerializeErr> {
+        Ok(Some(BeValue::Str(v.to_vec())))
+    }
+}
+
+impl ser::Serializer for BeSerializer {
+    type Ok = Option<BeValue>;
+    type Error = BeSerializeErr;
+
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = ListSerializer;
+    type SerializeMap = DictSerializer;
+    type SerializeStruct = DictSerializer;
+    type SerializeStructVariant = DictSerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        Self::int(v as u8)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        Self::int(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        Self::int(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        Self::int(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        Self::int(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        Self::int(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
+        Self::int(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
+        Self::int(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        Self::int(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Self::Error> {
+        Err(BeSerializeErr::Unsupported("f32"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Self::Error> {
+        Err(BeSerializeErr::Unsupported("f64"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        Self::str(v.encode_utf8(&mut [0; 4]).as_bytes())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        Self::str(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        Self::str(v)
+    }
+
+    /// Bencode has no null value, lists and dictionaries skip the missing values
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Err(BeSerializeErr::Unsupported("unit"))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        Self::str(variant.as_bytes())
+    }
+
+    /// Raw values are decoded, so that they are re-encoded canonically along with the rest
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        match value.serialize(self)? {
+            Some(BeValue::Str(raw)) if name == RAW_VALUE_TOKEN => {
+                Ok(Some(BeParser::parse_with(&raw)?))
+            }
+            value => Ok(value),
+        }
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        let value = value.serialize(self)?;
+        Ok(value.map(|v| wrap_variant(v, Some(variant))))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Ok(ListSerializer::new(None))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Ok(ListSerializer::new(Some(variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(DictSerializer::new(None))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Ok(DictSerializer::new(None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Ok(DictSerializer::new(Some(variant)))
+    }
+}
+
+/// Collects the elements of sequences and tuples into a list
+struct ListSerializer {
+    list: Vec<BeValue>,
+    variant: Option<&'static str>,
+}
+
+impl ListSerializer {
+    fn new(variant: Option<&'static str>) -> Self {
+        Self {
+            list: Vec::new(),
+            variant,
+        }
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BeSerializeErr> {
+        if let Some(value) = to_value(value)? {
+            self.list.push(value);
+        }
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<Option<BeValue>, BeSerializeErr> {
+        Ok(Some(wrap_variant(BeValue::List(self.list), self.variant)))
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Option<BeValue>;
+    type Error = BeSerializeErr;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ListSerializer::end(self)
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Option<BeValue>;
+    type Error = BeSerializeErr;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ListSerializer::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Option<BeValue>;
+    type Error = BeSerializeErr;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ListSerializer::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer {
+    type Ok = Option<BeValue>;
+    type Error = BeSerializeErr;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        ListSerializer::end(self)
+    }
+}
+
+/// Collects the fields of structs and the entries of maps into a Dict,
+/// the BeEncoder sorts them by their keys
+struct DictSerializer {
+    dict: Dict,
+    variant: Option<&'static str>,
+    pending_key: Option<Vec<u8>>,
+}
+
+impl DictSerializer {
+    fn new(variant: Option<&'static str>) -> Self {
+        Self {
+            dict: Dict::default(),
+            variant,
+            pending_key: None,
+        }
+    }
+
+    fn entry<T: Serialize + ?Sized>(
+        &mut self,
+        key: Vec<u8>,
+        value: &T,
+    ) -> Result<(), BeSerializeErr> {
+        // None values are skipped
+        if let Some(value) = to_value(value)? {
+            if self.dict.insert(key, value).is_some() {
+                return Err(BeSerializeErr::DuplicateKeys);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn end(self) -> Result<Option<BeValue>, BeSerializeErr> {
+        Ok(Some(wrap_variant(BeValue::Dict(self.dict), self.variant)))
+    }
+}
+
+impl ser::SerializeMap for DictSerializer {
+    type Ok = Option<BeValue>;
+    type Error = BeSerializeErr;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Self::Error> {
+        self.pending_key = Some(key.serialize(KeySerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let key = self
+            .pending_key
+            .take()
+            .expect("Internal error: serialize_value called before serialize_key");
+
+        self.entry(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        DictSerializer::end(self)
+    }
+}
+
+impl ser::SerializeStruct for DictSerializer {
+    type Ok = Option<BeValue>;
+    type Error = BeSerializeErr;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.entry(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        DictSerializer::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for DictSerializer {
+    type Ok = Option<BeValue>;
+    type Error = BeSerializeErr;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.entry(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        DictSerializer::end(self)
+    }
+}
+
+/// Dictionary keys have to be byte strings
+struct KeySerializer;
+
+impl ser::Serializer for KeySerializer {
+    type Ok = Vec<u8>;
+    type Error = BeSerializeErr;
+
+    type SerializeSeq = Impossible<Vec<u8>, BeSerializeErr>;
+    type SerializeTuple = Impossible<Vec<u8>, BeSerializeErr>;
+    type SerializeTupleStruct = Impossible<Vec<u8>, BeSerializeErr>;
+    type SerializeTupleVariant = Impossible<Vec<u8>, BeSerializeErr>;
+    type SerializeMap = Impossible<Vec<u8>, BeSerializeErr>;
+    type SerializeStruct = Impossible<Vec<u8>, BeSerializeErr>;
+    type SerializeStructVariant = Impossible<Vec<u8>, BeSerializeErr>;
+
+    fn serialize_str(self, v: &str) -> Result<Vec<u8>, Self::Error> {
+        Ok(v.as_bytes().to_vec())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        Ok(v.to_vec())
+    }
+
+    fn serialize_char(self, v: char) -> Result<Vec<u8>, Self::Error> {
+        Ok(v.encode_utf8(&mut [0; 4]).as_bytes().to_vec())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Vec<u8>, Self::Error> {
+        Ok(variant.as_bytes().to_vec())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Vec<u8>, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_i8(self, _v: i8) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_i32(self, _v: i32) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_i64(self, _v: i64) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_u8(self, _v: u8) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_none(self) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_unit(self) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Vec<u8>, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(BeSerializeErr::InvalidKey)
+    }
+}
+
+impl<'a> Serialize for RawValue<'a> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer
+            .serialize_newtype_struct(RAW_VALUE_TOKEN, serde_bytes::Bytes::new(self.as_bytes()))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BeSerializeErr {
+    #[error("The type '{0}' can't be represented in bencode")]
+    Unsupported(&'static str),
+    #[error("Dictionary keys must be strings")]
+    InvalidKey,
+    #[error("Duplicate dict keys encountered")]
+    DuplicateKeys,
+    #[error("The integer '{0}' doesn't fit into an i64")]
+    IntOutOfRange(i128),
+    #[error("Invalid raw value: {0}")]
+    InvalidRawValue(#[from] BeDecodeErr),
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl ser::Error for BeSerializeErr {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        BeSerializeErr::Custom(msg.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test_ser {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::super::{de::from_bytes, BeParser};
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Info {
+        name: String,
+        #[serde(rename = "piece length")]
+        piece_length: u32,
+        #[serde(with = "serde_bytes")]
+        pieces: Vec<u8>,
+        length: Option<u64>,
+        private: Option<bool>,
+    }
+
+    #[test]
+    fn test_primitives() {
+        assert_eq!(to_bytes(&42u32).unwrap(), b"i42e");
+        assert_eq!(to_bytes(&-42i64).unwrap(), b"i-42e");
+        assert_eq!(to_bytes(&true).unwrap(), b"i1e");
+        assert_eq!(to_bytes("spam").unwrap(), b"4:spam");
+        assert_eq!(
+            to_bytes(serde_bytes::Bytes::new(b"\xFF\x00")).unwrap(),
+            b"2:\xFF\x00"
+        );
+        assert_eq!(to_bytes(&vec![1, 2]).unwrap(), b"li1ei2ee");
+        assert!(matches!(
+            to_bytes(&u64::MAX),
+            Err(BeSerializeErr::IntOutOfRange(_))
+        ));
+        to_bytes(&1.5f64).unwrap_err();
+        to_bytes(&()).unwrap_err();
+    }
+
+    #[test]
+    fn test_sorted_keys() {
+        let mut map = HashMap::new();
+        map.insert("zebra", 1);
+        map.insert("Zulu", 2);
+        map.insert("apple", 3);
+
+        assert_eq!(to_bytes(&map).unwrap(), b"d4:Zului2e5:applei3e5:zebrai1ee");
+
+        let mut map = HashMap::new();
+        map.insert(1, 1);
+        to_bytes(&map).unwrap_err();
+    }
+
+    #[test]
+    fn test_struct_roundtrip() {
+        let info = Info {
+            name: "file.iso".to_string(),
+            piece_length: 512,
+            pieces: vec![1, 2],
+            length: Some(1024),
+            private: None,
+        };
+
+        let encoded = to_bytes(&info).unwrap();
+        assert_eq!(
+            encoded,
+            &b"d6:lengthi1024e4:name8:file.iso12:piece lengthi512e6:pieces2:\x01\x02e"[..]
+        );
+
+        assert_eq!(from_bytes::<Info>(&encoded).unwrap(), info);
+        // The output is already in the canonical form
+        assert_eq!(BeParser::parse_with(&encoded).unwrap().to_bytes(), encoded);
+    }
+
+    #[test]
+    fn test_enum_roundtrip() {
+        #[derive(Debug, Serialize, Deserialize, PartialEq)]
+        #[serde(rename_all = "snake_case")]
+        enum Msg {
+            Ping,
+            Peers(Vec<u32>),
+            Pair(u32, u32),
+            Error { code: u32 },
+        }
+
+        for (msg, expected) in [
+            (Msg::Ping, &b"4:ping"[..]),
+            (Msg::Peers(vec![1]), b"d5:peersli1eee"),
+            (Msg::Pair(1, 2), b"d4:pairli1ei2eee"),
+            (Msg::Error { code: 7 }, b"d5:errord4:codei7eee"),
+        ] {
+            let encoded = to_bytes(&msg).unwrap();
+            assert_eq!(encoded, expected);
+            assert_eq!(from_bytes::<Msg>(&encoded).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_raw_value_roundtrip() {
+        #[derive(Serialize, Deserialize)]
+        struct Torrent<'a> {
+            announce: &'a str,
+            #[serde(borrow)]
+            info: RawValue<'a>,
+        }
+
+        let src = b"d8:announce3:url4:infod4:name1:aee";
+        let torrent = from_bytes::<Torrent>(src).unwrap();
+        assert_eq!(torrent.info.as_bytes(), b"d4:name1:ae");
+
+        assert_eq!(to_bytes(&torrent).unwrap(), src);
+    }
+}
@@ -0,0 +1,273 @@
+use bytes::BytesMut;
+use thiserror::Error;
+use tokio_util::codec::Decoder;
+
+use super::{BeDecodeErr, BeDecodeErrKind, KeyPath};
+
+/// Finds the end of a bencoded value in input that arrives in chunks.
+/// Already scanned bytes aren't scanned again when more input arrives.
+/// Only the framing is checked, the complete value should be parsed afterwards.
+#[derive(Debug, Default)]
+pub struct BeStreamDecoder {
+    /// Number of scanned bytes of the current value
+    pos: usize,
+    /// Number of open lists and dictionaries
+    depth: usize,
+    state: ScanState,
+}
+
+#[derive(Debug, Default)]
+enum ScanState {
+    /// Expecting the start of a value or the end of a list / dictionary
+    #[default]
+    Value,
+    /// i<integer encoded in base ten ASCII>e
+    Int,
+    /// <string length encoded in base ten ASCII>:
+    StrLen(u64),
+    /// Remaining bytes of the string data
+    Str(u64),
+}
+
+impl BeStreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Continues scanning the value at the start of src, which has to contain all of the
+    /// previously fed bytes of the value.
+    /// Returns the length of the value when it's complete and None if more bytes are needed.
+    pub fn feed(&mut self, src: &[u8]) -> Result<Option<usize>, BeDecodeErr> {
+        while self.pos < src.len() {
+            let b = src[self.pos];
+
+            let value_done = match self.state {
+                ScanState::Value => match b {
+                    b'i' => {
+                        self.state = ScanState::Int;
+                        false
+                    }
+                    b'l' | b'd' => {
+                        self.depth += 1;
+                        false
+                    }
+                    b'e' if self.depth > 0 => {
+                        self.depth -= 1;
+                        true
+                    }
+                    b'e' => return Err(self.err(BeDecodeErrKind::Unexpected(Some(b)))),
+                    b'0'..=b'9' => {
+                        self.state = ScanState::StrLen(u64::from(b - b'0'));
+                        false
+                    }
+                    b => return Err(self.err(BeDecodeErrKind::InvalidInitialByte(b))),
+                },
+                ScanState::Int => match b {
+                    b'e' => true,
+                    b'-' | b'0'..=b'9' => false,
+                    b => return Err(self.err(BeDecodeErrKind::Unexpected(Some(b)))),
+                },
+                ScanState::StrLen(len) => match b {
+                    b'0'..=b'9' => {
+                        let len = len
+                            .checked_mul(10)
+                            .and_then(|len| len.checked_add(u64::from(b - b'0')))
+                            .ok_or_else(|| self.err(BeDecodeErrKind::InvalidStrLen))?;
+
+                        self.state = ScanState::StrLen(len);
+                        false
+                    }
+                    b':' if len == 0 => true,
+                    b':' => {
+                        self.state = ScanState::Str(len);
+                        false
+                    }
+                    b => return Err(self.err(BeDecodeErrKind::Unexpected(Some(b)))),
+                },
+                ScanState::Str(remaining) => {
+                    // Skip the whole available part of the string at once
+                    let available = (src.len() - self.pos) as u64;
+                    let skip = remaining.min(available);
+
+                    // The position was already advanced past the first byte of the chunk
+                    self.pos += skip as usize - 1;
+
+                    if skip == remaining {
+                        true
+                    } else {
+                        self.state = ScanState::Str(remaining - skip);
+                        false
+                    }
+                }
+            };
+
+            self.pos += 1;
+
+            if value_done {
+                self.state = ScanState::Value;
+
+                if self.depth == 0 {
+                    let len = self.pos;
+                    *self = Self::default();
+
+                    return Ok(Some(len));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn err(&self, kind: BeDecodeErrKind) -> BeDecodeErr {
+        BeDecodeErr {
+            kind,
+            pos: self.pos,
+            path: KeyPath::default(),
+        }
+    }
+}
+
+/// 1 megabyte
+const MAXIMUM_VALUE_SIZE: usize = 1048576;
+
+/// Splits a stream into bencoded values. The values are returned in their encoded form,
+/// so that they can be deserialized into types that borrow from them.
+#[derive(Debug, Default)]
+pub struct BeCodec {
+    decoder: BeStreamDecoder,
+}
+
+#[derive(Error, Debug)]
+pub enum BeCodecErr {
+    #[error("IO error: '{0}'")]
+    Io(#[from] std::io::Error),
+    #[error("Bencode decoding error: '{0}'")]
+    Decode(#[from] BeDecodeErr),
+    #[error("Maximum value size exceeded")]
+    MaximumSizeExceeded,
+}
+
+impl Decoder for BeCodec {
+    type Item = BytesMut;
+    type Error = BeCodecErr;
+
+    fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<<Self as Decoder>::Item>, <Self as Decoder>::Error> {
+        match self.decoder.feed(src)? {
+            Some(len) => Ok(Some(src.split_to(len))),
+            None if src.len() >= MAXIMUM_VALUE_SIZE => Err(BeCodecErr::MaximumSizeExceeded),
+            None => Ok(None),
+        }
+    }
+
+    /// A value cut off by the end of the stream is an error
+    fn decode_eof(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<<Self as Decoder>::Item>, <Self as Decoder>::Error> {
+        match self.decode(src)? {
+            None if !src.is_empty() => Err(BeDecodeErr {
+                kind: BeDecodeErrKind::UnexpectedEnd,
+                pos: src.len(),
+                path: KeyPath::default(),
+            }
+            .into()),
+            value => Ok(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_stream {
+    use super::*;
+
+    const SRC: &[u8] = b"d8:announce3:url4:infod6:lengthi-1024e4:name4:file\
+        12:piece lengthi512e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+
+    #[test]
+    fn test_byte_by_byte() {
+        let mut decoder = BeStreamDecoder::new();
+
+        for end in 1..SRC.len() {
+            assert_eq!(decoder.feed(&SRC[..end]).unwrap(), None);
+        }
+
+        assert_eq!(decoder.feed(SRC).unwrap(), Some(SRC.len()));
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut src = SRC.to_vec();
+        src.extend_from_slice(b"i42e");
+
+        for chunk_len in [3, 7, 20, 1000] {
+            let mut decoder = BeStreamDecoder::new();
+            let mut end = 0;
+
+            let len = loop {
+                end = (end + chunk_len).min(src.len());
+
+                if let Some(len) = decoder.feed(&src[..end]).unwrap() {
+                    break len;
+                }
+            };
+
+            assert_eq!(len, SRC.len());
+            assert_eq!(decoder.feed(&src[len..]).unwrap(), Some(4));
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut decoder = BeStreamDecoder::new();
+        assert_eq!(decoder.feed(b"d1:").unwrap(), None);
+        let err = decoder.feed(b"d1:ax").unwrap_err();
+        assert!(matches!(
+            err.kind,
+            BeDecodeErrKind::InvalidInitialByte(b'x')
+        ));
+        assert_eq!(err.pos, 4);
+
+        BeStreamDecoder::new().feed(b"e").unwrap_err();
+        BeStreamDecoder::new().feed(b"i1x").unwrap_err();
+        BeStreamDecoder::new().feed(b"3x").unwrap_err();
+        BeStreamDecoder::new()
+            .feed(b"99999999999999999999999:")
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_codec() {
+        let mut codec = BeCodec::default();
+        let mut buf = BytesMut::new();
+
+        buf.extend_from_slice(&SRC[..10]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&SRC[10..]);
+        buf.extend_from_slice(b"li1e");
+        let value = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(&value[..], SRC);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(b"e");
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"li1ee");
+        assert!(buf.is_empty());
+        assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(b"i1");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        codec.decode_eof(&mut buf).unwrap_err();
+
+        let mut buf = BytesMut::from(&b"x"[..]);
+        BeCodec::default().decode(&mut buf).unwrap_err();
+        let mut buf = BytesMut::from(&b"l"[..]);
+        buf.resize(MAXIMUM_VALUE_SIZE, b'l');
+        assert!(matches!(
+            BeCodec::default().decode(&mut buf),
+            Err(BeCodecErr::MaximumSizeExceeded)
+        ));
+    }
+}
@@ -0,0 +1,444 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use thiserror::Error;
+
+use crate::dht;
+
+/// The torrent that is downloaded when no arguments are given
+const DEFAULT_TORRENT: &str = "debian-11.2.0-amd64-netinst.iso.torrent";
+/// Where the tracker server listens when no address is given
+const DEFAULT_TRACKER_ADDR: &str = "0.0.0.0:6969";
+const DEFAULT_DHT_PORT: u16 = 6881;
+/// Where the DHT routing table is kept between the runs
+const DEFAULT_DHT_STATE: &str = "learntorrent-dht.dat";
+
+pub const USAGE: &str = "\
+Usage:
+    learntorrent [<torrent file> | <magnet URI>] [options]
+    learntorrent create <file or directory> [options]
+    learntorrent scrape (<torrent file> | <magnet URI>)... [--proxy <url>]
+    learntorrent tracker [--http <address>] [--udp <address>]
+
+Options for downloading and 'scrape':
+        --proxy <url>            HTTP, HTTPS or SOCKS5 proxy for the HTTP trackers and web seeds
+
+Options for downloading:
+        --no-dht                 Don't look for peers in the DHT
+        --dht-port <port>        UDP port of the DHT node, 6881 by default
+        --dht-bootstrap <node>   'host:port' of a node to join the DHT through, can be repeated
+        --dht-state <path>       Where the DHT routing table is saved, 'learntorrent-dht.dat' by default
+
+Options for 'create':
+    -o, --output <path>          Where to write the torrent, '<name>.torrent' by default
+    -t, --tracker <url>          Tracker URL, can be repeated
+    -c, --comment <text>         Free-form comment
+    -p, --piece-length <bytes>   Power of two of at least 16384, picked automatically by default
+        --private                Peers should only be received from the trackers
+        --creation-date <secs>   Unix timestamp to store instead of the current time
+
+Options for 'tracker', both listen on 0.0.0.0:6969 if neither is given:
+        --http <address>         Serve HTTP announces and scrapes on this address
+        --udp <address>          Serve the UDP tracker protocol on this address";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Download a torrent file or a magnet link
+    Download(DownloadArgs),
+    /// Create a torrent file
+    Create(CreateArgs),
+    /// Ask the trackers about the swarms of torrent files or magnet links
+    Scrape(ScrapeArgs),
+    /// Run a tracker server
+    Tracker(TrackerArgs),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DownloadArgs {
+    pub source: String,
+    pub proxy: Option<String>,
+    pub dht: bool,
+    pub dht_port: u16,
+    /// The well-known routers if none are given
+    pub dht_bootstrap: Vec<String>,
+    pub dht_state: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScrapeArgs {
+    pub sources: Vec<String>,
+    pub proxy: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TrackerArgs {
+    pub http: Option<SocketAddr>,
+    pub udp: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CreateArgs {
+    pub path: PathBuf,
+    pub output: Option<PathBuf>,
+    pub trackers: Vec<String>,
+    pub comment: Option<String>,
+    pub piece_length: Option<u32>,
+    pub private: bool,
+    /// Unix timestamp, fixing it makes the torrent reproducible
+    pub creation_date: Option<u64>,
+}
+
+impl Command {
+    /// Parses the arguments without the program name
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> CliResult<Self> {
+        match args.next() {
+            Some(cmd) if cmd == "create" => Ok(Self::Create(CreateArgs::parse(args)?)),
+            Some(cmd) if cmd == "scrape" => Ok(Self::Scrape(ScrapeArgs::parse(args)?)),
+            Some(cmd) if cmd == "tracker" => Ok(Self::Tracker(TrackerArgs::parse(args)?)),
+            first => Ok(Self::Download(DownloadArgs::parse(
+                first.into_iter().chain(args),
+            )?)),
+        }
+    }
+}
+
+impl DownloadArgs {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> CliResult<Self> {
+        let mut source = None;
+        let mut proxy = None;
+        let mut dht = true;
+        let mut dht_port = DEFAULT_DHT_PORT;
+        let mut dht_bootstrap = Vec::new();
+        let mut dht_state = PathBuf::from(DEFAULT_DHT_STATE);
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| CliErr::MissingValue(arg.clone()));
+
+            match arg.as_str() {
+                "--proxy" => proxy = Some(value()?),
+                "--no-dht" => dht = false,
+                "--dht-port" => {
+                    let val = value()?;
+                    dht_port = val
+                        .parse()
+                        .map_err(|_| CliErr::InvalidValue(arg.clone(), val))?;
+                }
+                "--dht-bootstrap" => dht_bootstrap.push(value()?),
+                "--dht-state" => dht_state = value()?.into(),
+                a if a.starts_with('-') => return Err(CliErr::UnknownOption(arg)),
+                _ if source.is_none() => source = Some(arg),
+                _ => return Err(CliErr::UnexpectedArgument(arg)),
+            }
+        }
+
+        if dht_bootstrap.is_empty() {
+            dht_bootstrap = dht::DEFAULT_BOOTSTRAP.map(String::from).to_vec();
+        }
+
+        Ok(Self {
+            source: source.unwrap_or_else(|| DEFAULT_TORRENT.to_string()),
+            proxy,
+            dht,
+            dht_port,
+            dht_bootstrap,
+            dht_state,
+        })
+    }
+}
+
+impl ScrapeArgs {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> CliResult<Self> {
+        let mut sources = Vec::new();
+        let mut proxy = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--proxy" => proxy = Some(args.next().ok_or(CliErr::MissingValue(arg))?),
+                a if a.starts_with('-') => return Err(CliErr::UnknownOption(arg)),
+                _ => sources.push(arg),
+            }
+        }
+
+        if sources.is_empty() {
+            return Err(CliErr::MissingSource);
+        }
+
+        Ok(Self { sources, proxy })
+    }
+}
+
+impl TrackerArgs {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> CliResult<Self> {
+        let mut http = None;
+        let mut udp = None;
+
+        while let Some(arg) = args.next() {
+            let val = args.next().ok_or_else(|| CliErr::MissingValue(arg.clone()));
+
+            match arg.as_str() {
+                "--http" => http = Some(parse_addr(&arg, val?)?),
+                "--udp" => udp = Some(parse_addr(&arg, val?)?),
+                a if a.starts_with('-') => return Err(CliErr::UnknownOption(arg)),
+                _ => return Err(CliErr::UnexpectedArgument(arg)),
+            }
+        }
+
+        if http.is_none() && udp.is_none() {
+            // UNWRAP: the default address is valid
+            let addr = DEFAULT_TRACKER_ADDR.parse().unwrap();
+            http = Some(addr);
+            udp = Some(addr);
+        }
+
+        Ok(Self { http, udp })
+    }
+}
+
+fn parse_addr(option: &str, val: String) -> CliResult<SocketAddr> {
+    val.parse()
+        .map_err(|_| CliErr::InvalidValue(option.to_string(), val))
+}
+
+impl CreateArgs {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> CliResult<Self> {
+        let mut create_args = Self::default();
+        let mut path = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| CliErr::MissingValue(arg.clone()));
+
+            match arg.as_str() {
+                "-o" | "--output" => create_args.output = Some(value()?.into()),
+                "-t" | "--tracker" => create_args.trackers.push(value()?),
+                "-c" | "--comment" => create_args.comment = Some(value()?),
+                "-p" | "--piece-length" => {
+                    let val = value()?;
+                    let piece_length = val
+                        .parse()
+                        .map_err(|_| CliErr::InvalidValue(arg.clone(), val))?;
+                    create_args.piece_length = Some(piece_length);
+                }
+                "--private" => create_args.private = true,
+                "--creation-date" => {
+                    let val = value()?;
+                    let creation_date = val
+                        .parse()
+                        .map_err(|_| CliErr::InvalidValue(arg.clone(), val))?;
+                    create_args.creation_date = Some(creation_date);
+                }
+                a if a.starts_with('-') => return Err(CliErr::UnknownOption(arg)),
+                _ if path.is_none() => path = Some(PathBuf::from(arg)),
+                _ => return Err(CliErr::UnexpectedArgument(arg)),
+            }
+        }
+
+        create_args.path = path.ok_or(CliErr::MissingPath)?;
+        Ok(create_args)
+    }
+
+    /// The output path, '<name>.torrent' in the current directory by default
+    pub fn output_path(&self) -> PathBuf {
+        match &self.output {
+            Some(output) => output.clone(),
+            None => {
+                let name = self.path.file_name().unwrap_or(self.path.as_os_str());
+                let mut output = name.to_os_string();
+                output.push(".torrent");
+                output.into()
+            }
+        }
+    }
+}
+
+type CliResult<T> = Result<T, CliErr>;
+
+#[derive(Error, Debug)]
+pub enum CliErr {
+    #[error("The option '{0}' requires a value")]
+    MissingValue(String),
+    #[error("Invalid value for the option '{0}': '{1}'")]
+    InvalidValue(String, String),
+    #[error("Unknown option: '{0}'")]
+    UnknownOption(String),
+    #[error("Unexpected argument: '{0}'")]
+    UnexpectedArgument(String),
+    #[error("The path of the torrent content is missing")]
+    MissingPath,
+    #[error("No torrent file or magnet URI was given")]
+    MissingSource,
+}
+
+#[cfg(test)]
+mod test_cli {
+    use super::*;
+
+    fn parse(args: &[&str]) -> CliResult<Command> {
+        Command::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    fn download(source: &str, proxy: Option<&str>) -> Command {
+        Command::Download(DownloadArgs {
+            source: source.to_string(),
+            proxy: proxy.map(String::from),
+            dht: true,
+            dht_port: DEFAULT_DHT_PORT,
+            dht_bootstrap: dht::DEFAULT_BOOTSTRAP.map(String::from).to_vec(),
+            dht_state: PathBuf::from(DEFAULT_DHT_STATE),
+        })
+    }
+
+    #[test]
+    fn test_download() {
+        assert_eq!(parse(&[]).unwrap(), download(DEFAULT_TORRENT, None));
+        assert_eq!(
+            parse(&["magnet:?xt=urn:btih:abc"]).unwrap(),
+            download("magnet:?xt=urn:btih:abc", None)
+        );
+        assert_eq!(
+            parse(&["--proxy", "socks5://127.0.0.1:9050", "a.torrent"]).unwrap(),
+            download("a.torrent", Some("socks5://127.0.0.1:9050"))
+        );
+
+        let cmd = parse(&[
+            "a.torrent",
+            "--dht-port",
+            "6882",
+            "--dht-bootstrap",
+            "127.0.0.1:6881",
+            "--dht-state",
+            "dht.dat",
+        ])
+        .unwrap();
+        assert_eq!(
+            cmd,
+            Command::Download(DownloadArgs {
+                source: "a.torrent".to_string(),
+                proxy: None,
+                dht: true,
+                dht_port: 6882,
+                dht_bootstrap: vec!["127.0.0.1:6881".to_string()],
+                dht_state: PathBuf::from("dht.dat"),
+            })
+        );
+
+        match parse(&["a.torrent", "--no-dht"]).unwrap() {
+            Command::Download(args) => assert!(!args.dht),
+            cmd => panic!("{:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn test_create() {
+        let cmd = parse(&[
+            "create",
+            "dir",
+            "-t",
+            "udp://a/announce",
+            "--tracker",
+            "http://b/announce",
+            "-c",
+            "comment",
+            "-p",
+            "65536",
+            "--private",
+            "--creation-date",
+            "1234",
+        ])
+        .unwrap();
+
+        let args = CreateArgs {
+            path: "dir".into(),
+            output: None,
+            trackers: vec!["udp://a/announce".into(), "http://b/announce".into()],
+            comment: Some("comment".into()),
+            piece_length: Some(65536),
+            private: true,
+            creation_date: Some(1234),
+        };
+        assert_eq!(args.output_path(), PathBuf::from("dir.torrent"));
+        assert_eq!(cmd, Command::Create(args));
+
+        match parse(&["create", "-o", "out.torrent", "/data/file.iso"]).unwrap() {
+            Command::Create(args) => assert_eq!(args.output_path(), PathBuf::from("out.torrent")),
+            cmd => panic!("{:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn test_scrape() {
+        assert_eq!(
+            parse(&["scrape", "a.torrent", "magnet:?xt=urn:btih:abc"]).unwrap(),
+            Command::Scrape(ScrapeArgs {
+                sources: vec!["a.torrent".into(), "magnet:?xt=urn:btih:abc".into()],
+                proxy: None
+            })
+        );
+        assert_eq!(
+            parse(&["scrape", "a.torrent", "--proxy", "http://proxy:3128"]).unwrap(),
+            Command::Scrape(ScrapeArgs {
+                sources: vec!["a.torrent".into()],
+                proxy: Some("http://proxy:3128".into())
+            })
+        );
+    }
+
+    #[test]
+    fn test_tracker() {
+        let default = Some(SocketAddr::from(([0, 0, 0, 0], 6969)));
+        assert_eq!(
+            parse(&["tracker"]).unwrap(),
+            Command::Tracker(TrackerArgs {
+                http: default,
+                udp: default
+            })
+        );
+        assert_eq!(
+            parse(&["tracker", "--udp", "[::]:1337"]).unwrap(),
+            Command::Tracker(TrackerArgs {
+                http: None,
+                udp: Some("[::]:1337".parse().unwrap())
+            })
+        );
+        assert!(matches!(
+            parse(&["tracker", "--http", "localhost"]),
+            Err(CliErr::InvalidValue(..))
+        ));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(parse(&["create"]), Err(CliErr::MissingPath)));
+        assert!(matches!(parse(&["scrape"]), Err(CliErr::MissingSource)));
+        assert!(matches!(
+            parse(&["scrape", "--proxy", "http://proxy:3128"]),
+            Err(CliErr::MissingSource)
+        ));
+        assert!(matches!(
+            parse(&["a.torrent", "--proxy"]),
+            Err(CliErr::MissingValue(_))
+        ));
+        assert!(matches!(
+            parse(&["a.torrent", "--dht-port", "70000"]),
+            Err(CliErr::InvalidValue(..))
+        ));
+        assert!(matches!(
+            parse(&["a.torrent", "b.torrent"]),
+            Err(CliErr::UnexpectedArgument(_))
+        ));
+        assert!(matches!(
+            parse(&["create", "dir", "-t"]),
+            Err(CliErr::MissingValue(_))
+        ));
+        assert!(matches!(
+            parse(&["create", "dir", "-p", "big"]),
+            Err(CliErr::InvalidValue(..))
+        ));
+        assert!(matches!(
+            parse(&["create", "dir", "--verbose"]),
+            Err(CliErr::UnknownOption(_))
+        ));
+        assert!(matches!(
+            parse(&["create", "dir", "dir2"]),
+            Err(CliErr::UnexpectedArgument(_))
+        ));
+    }
+}
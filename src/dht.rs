@@ -0,0 +1,634 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::ErrorKind,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use thiserror::Error;
+use tokio::{fs, net::UdpSocket, sync::oneshot, time::Instant};
+
+use crate::bencoding::{
+    de::{self, BeDeserializeErr},
+    ser::{self, BeSerializeErr},
+};
+
+use self::{
+    krpc::{Msg, MsgBody, Query, Response, METHOD_UNKNOWN, PROTOCOL_ERROR},
+    routing::{distance, NodeId, NodeInfo, RoutingTable, K},
+    storage::{PeerStore, Tokens},
+};
+
+mod krpc;
+mod routing;
+mod storage;
+
+/// Nodes that know the DHT, used when the routing table is empty
+pub const DEFAULT_BOOTSTRAP: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Number of concurrent queries of a lookup
+const ALPHA: usize = 3;
+/// Upper bound for the number of queries of a single lookup
+const MAX_LOOKUP_QUERIES: usize = 100;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval of rotating the tokens, expiring the peers, refreshing the table and saving it
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// KRPC messages fit into a single packet
+const MAXIMUM_PACKET_SIZE: usize = 2048;
+
+pub struct DhtConfig {
+    /// Port 0 picks a free port
+    pub bind_addr: SocketAddr,
+    /// 'host:port' of the nodes that are asked about the DHT first
+    pub bootstrap: Vec<String>,
+    /// Where our ID and the routing table are kept between the runs
+    pub state_path: Option<PathBuf>,
+}
+
+/// A node of the Mainline DHT (BEP 5). The handle is cheap to clone,
+/// 'start' has to run for the queries to receive their responses.
+#[derive(Clone)]
+pub struct Dht {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    id: NodeId,
+    socket: UdpSocket,
+    bootstrap: Vec<String>,
+    state_path: Option<PathBuf>,
+    state: Mutex<DhtState>,
+}
+
+struct DhtState {
+    table: RoutingTable,
+    /// Our queries waiting for a response, by the transaction ID
+    pending: HashMap<Vec<u8>, PendingQuery>,
+    tokens: Tokens,
+    peers: PeerStore,
+    next_trans_id: u16,
+}
+
+struct PendingQuery {
+    addr: SocketAddr,
+    sender: oneshot::Sender<DhtResult<Response>>,
+}
+
+/// The part of the DHT state that is saved to the disk
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    id: ByteBuf,
+    /// Compact node infos
+    nodes: ByteBuf,
+}
+
+/// Outcome of an iterative lookup
+#[derive(Default)]
+struct Lookup {
+    /// Peers of the torrent, only for 'get_peers' lookups
+    peers: HashSet<SocketAddr>,
+    /// The nodes that responded, by the distance from the target, with their tokens
+    responded: BTreeMap<NodeId, (NodeInfo, Option<Vec<u8>>)>,
+}
+
+impl Dht {
+    /// Binds the socket, our ID and the nodes are loaded from the state file if it exists
+    pub async fn bind(config: DhtConfig) -> DhtResult<Self> {
+        let socket = UdpSocket::bind(config.bind_addr).await?;
+
+        let saved = match &config.state_path {
+            Some(path) => match fs::read(path).await {
+                Ok(src) => Some(de::from_bytes::<SavedState>(&src)?),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            },
+            None => None,
+        };
+
+        let (id, nodes) = match saved {
+            Some(saved) => {
+                let id = saved.id[..].try_into().map_err(|_| DhtErr::InvalidState)?;
+                (id, NodeInfo::decode_compact(&saved.nodes))
+            }
+            None => (rand::random(), Vec::new()),
+        };
+
+        let mut table = RoutingTable::new(id);
+        let now = Instant::now();
+        for node in nodes {
+            table.insert(node, now);
+        }
+
+        tracing::info!(
+            "DHT node listening on '{}', loaded '{}' nodes",
+            socket.local_addr()?,
+            table.len()
+        );
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                id,
+                socket,
+                bootstrap: config.bootstrap,
+                state_path: config.state_path,
+                state: Mutex::new(DhtState {
+                    table,
+                    pending: HashMap::new(),
+                    tokens: Tokens::new(),
+                    peers: PeerStore::default(),
+                    next_trans_id: rand::random(),
+                }),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> DhtResult<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// Receives the messages and maintains the routing table until an IO error occurs
+    pub async fn start(self) -> DhtResult<()> {
+        tokio::try_join!(self.receive(), self.maintain())?;
+
+        Ok(())
+    }
+
+    /// Finds the peers of the torrent
+    pub async fn get_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+        if self.lock().table.is_empty() {
+            self.bootstrap().await;
+        }
+
+        let lookup = self.lookup(*info_hash, true).await;
+        tracing::debug!("DHT lookup found '{}' peers", lookup.peers.len());
+
+        lookup.peers.into_iter().collect()
+    }
+
+    /// Asks the bootstrap nodes about the nodes close to us and looks up our own ID
+    pub async fn bootstrap(&self) {
+        let local_addr = match self.local_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                tracing::warn!("DHT bootstrap failed: '{}'", e);
+                return;
+            }
+        };
+
+        let mut addrs = Vec::new();
+        for host in &self.inner.bootstrap {
+            match tokio::net::lookup_host(host).await {
+                Ok(resolved) => {
+                    addrs.extend(resolved.filter(|a| a.is_ipv4() == local_addr.is_ipv4()))
+                }
+                Err(e) => tracing::debug!("Couldn't resolve the DHT node '{}': '{}'", host, e),
+            }
+        }
+
+        // The nodes that respond are added to the routing table
+        let queries = addrs.into_iter().map(|addr| {
+            self.query(
+                addr,
+                Query::FindNode {
+                    target: self.inner.id,
+                },
+            )
+        });
+        futures::future::join_all(queries).await;
+
+        self.lookup(self.inner.id, false).await;
+        tracing::info!(
+            "DHT bootstrapped, '{}' nodes known",
+            self.lock().table.len()
+        );
+    }
+
+    /// Saves our ID and the routing table to the state file
+    pub async fn save(&self) -> DhtResult<()> {
+        let path = match &self.inner.state_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let nodes = self.lock().table.nodes();
+        let state = SavedState {
+            id: ByteBuf::from(self.inner.id.to_vec()),
+            nodes: ByteBuf::from(NodeInfo::encode_compact(&nodes)),
+        };
+
+        fs::write(path, ser::to_bytes(&state)?).await?;
+
+        Ok(())
+    }
+
+    /// Iterative lookup of the nodes closest to the target, rounds of ALPHA queries
+    /// are sent until none of the remaining nodes is closer than the K closest responding nodes
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = self
+            .lock()
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|n| (distance(&target, &n.id), n))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut lookup = Lookup::default();
+
+        while queried.len() < MAX_LOOKUP_QUERIES {
+            let round: Vec<NodeInfo> = candidates
+                .values()
+                .filter(|n| !queried.contains(&n.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+
+            let closest_candidate = match round.first() {
+                Some(node) => distance(&target, &node.id),
+                None => break,
+            };
+            if let Some(kth) = lookup.responded.keys().nth(K - 1) {
+                if closest_candidate > *kth {
+                    break;
+                }
+            }
+
+            let queries = round.iter().map(|node| {
+                queried.insert(node.addr);
+
+                let query = match get_peers {
+                    true => Query::GetPeers { info_hash: target },
+                    false => Query::FindNode { target },
+                };
+                self.query(node.addr, query)
+            });
+            let responses = futures::future::join_all(queries).await;
+
+            for (node, response) in round.into_iter().zip(responses) {
+                let response = match response {
+                    Ok(r) => r,
+                    Err(e) => {
+                        tracing::trace!("DHT query to '{}' failed: '{}'", node.addr, e);
+                        continue;
+                    }
+                };
+
+                let node = NodeInfo {
+                    id: response.id,
+                    addr: node.addr,
+                };
+                lookup
+                    .responded
+                    .insert(distance(&target, &node.id), (node, response.token));
+                lookup.peers.extend(response.values);
+
+                for n in response.nodes {
+                    if n.id != self.inner.id && !queried.contains(&n.addr) {
+                        candidates.insert(distance(&target, &n.id), n);
+                    }
+                }
+            }
+        }
+
+        lookup
+    }
+
+    /// Sends the query and waits for the response
+    async fn query(&self, addr: SocketAddr, query: Query) -> DhtResult<Response> {
+        let (sender, recv) = oneshot::channel();
+
+        let trans_id = {
+            let mut state = self.lock();
+            let trans_id = state.next_trans_id.to_be_bytes().to_vec();
+            state.next_trans_id = state.next_trans_id.wrapping_add(1);
+            state
+                .pending
+                .insert(trans_id.clone(), PendingQuery { addr, sender });
+            trans_id
+        };
+
+        let msg = Msg {
+            trans_id: trans_id.clone(),
+            body: MsgBody::Query {
+                id: self.inner.id,
+                query,
+            },
+        };
+
+        if let Err(e) = self.inner.socket.send_to(&msg.encode(), addr).await {
+            self.lock().pending.remove(&trans_id);
+            return Err(e.into());
+        }
+
+        match tokio::time::timeout(QUERY_TIMEOUT, recv).await {
+            Ok(response) => response.expect(
+                "Internal error: a DHT query was removed from the pending queries without a response",
+            ),
+            Err(_) => {
+                let mut state = self.lock();
+                state.pending.remove(&trans_id);
+                state.table.failed(&addr);
+                Err(DhtErr::Timeout)
+            }
+        }
+    }
+
+    async fn receive(&self) -> DhtResult<()> {
+        let mut buf = vec![0; MAXIMUM_PACKET_SIZE];
+
+        loop {
+            let (read, remote) = match self.inner.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // Some platforms report ICMP errors of the previous packets
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let reply = match Msg::decode(&buf[..read]) {
+                Ok(msg) => self.on_msg(msg, remote),
+                Err(DhtErr::UnknownMethod(trans_id)) => Some(Msg {
+                    trans_id,
+                    body: MsgBody::Error {
+                        code: METHOD_UNKNOWN,
+                        message: String::from("Method Unknown"),
+                    },
+                }),
+                Err(e) => {
+                    tracing::trace!("Ignoring a DHT message from '{}': '{}'", remote, e);
+                    None
+                }
+            };
+
+            if let Some(reply) = reply {
+                // A failed response only affects a single node
+                if let Err(e) = self.inner.socket.send_to(&reply.encode(), remote).await {
+                    tracing::debug!("Couldn't respond to the DHT node '{}': '{}'", remote, e);
+                }
+            }
+        }
+    }
+
+    /// Returns the response to queries, responses and errors are passed to the pending queries
+    fn on_msg(&self, msg: Msg, remote: SocketAddr) -> Option<Msg> {
+        let mut state = self.lock();
+        let now = Instant::now();
+
+        let response = match msg.body {
+            MsgBody::Query { id, query } => {
+                state.table.insert(NodeInfo { id, addr: remote }, now);
+                Self::respond(&mut state, self.inner.id, query, remote, now)
+            }
+            MsgBody::Response(response) => {
+                if let Some(pending) = Self::take_pending(&mut state, &msg.trans_id, remote) {
+                    let id = response.id;
+                    state.table.insert(NodeInfo { id, addr: remote }, now);
+                    // The query could've timed out in the meantime
+                    let _ = pending.sender.send(Ok(response));
+                }
+                return None;
+            }
+            MsgBody::Error { code, message } => {
+                if let Some(pending) = Self::take_pending(&mut state, &msg.trans_id, remote) {
+                    let _ = pending
+                        .sender
+                        .send(Err(DhtErr::ErrorResponse(code, message)));
+                }
+                return None;
+            }
+        };
+
+        Some(Msg {
+            trans_id: msg.trans_id,
+            body: response,
+        })
+    }
+
+    /// Only the queried node can respond to a query
+    fn take_pending(
+        state: &mut DhtState,
+        trans_id: &[u8],
+        remote: SocketAddr,
+    ) -> Option<PendingQuery> {
+        match state.pending.get(trans_id) {
+            Some(pending) if pending.addr == remote => state.pending.remove(trans_id),
+            _ => None,
+        }
+    }
+
+    fn respond(
+        state: &mut DhtState,
+        own_id: NodeId,
+        query: Query,
+        remote: SocketAddr,
+        now: Instant,
+    ) -> MsgBody {
+        let mut response = Response {
+            id: own_id,
+            ..Response::default()
+        };
+
+        match query {
+            Query::Ping => (),
+            Query::FindNode { target } => response.nodes = state.table.closest(&target, K),
+            Query::GetPeers { info_hash } => {
+                response.values = state.peers.get(&info_hash);
+                response.nodes = state.table.closest(&info_hash, K);
+                response.token = Some(state.tokens.create(&remote.ip()));
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                if !state.tokens.verify(&token, &remote.ip()) {
+                    return MsgBody::Error {
+                        code: PROTOCOL_ERROR,
+                        message: String::from("Invalid token"),
+                    };
+                }
+
+                let port = if implied_port { remote.port() } else { port };
+                let peer = SocketAddr::new(remote.ip(), port);
+                state.peers.announce(info_hash, peer, now);
+            }
+        }
+
+        MsgBody::Response(response)
+    }
+
+    async fn maintain(&self) -> DhtResult<()> {
+        let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let table_empty = {
+                let mut state = self.lock();
+                state.tokens.rotate();
+                state.peers.expire(Instant::now());
+                state.table.is_empty()
+            };
+
+            // Looking up our own ID also replaces the nodes that stopped responding
+            match table_empty {
+                true => self.bootstrap().await,
+                false => drop(self.lookup(self.inner.id, false).await),
+            }
+
+            if let Err(e) = self.save().await {
+                tracing::warn!("Couldn't save the DHT state: '{}'", e);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DhtState> {
+        self.inner
+            .state
+            .lock()
+            .expect("Internal error: a DHT task panicked while holding the DHT state")
+    }
+}
+
+pub type DhtResult<T> = Result<T, DhtErr>;
+
+#[derive(Error, Debug)]
+pub enum DhtErr {
+    #[error("General IO error: '{0}'")]
+    Io(#[from] std::io::Error),
+    #[error("Error while decoding a message: '{0}'")]
+    Deserialize(#[from] BeDeserializeErr),
+    #[error("Error while encoding the state: '{0}'")]
+    Serialize(#[from] BeSerializeErr),
+    #[error("Received an invalid message")]
+    InvalidMessage,
+    #[error("Received a query with an unknown method")]
+    UnknownMethod(Vec<u8>),
+    #[error("The saved DHT state is invalid")]
+    InvalidState,
+    #[error("The node didn't respond in time")]
+    Timeout,
+    #[error("The node responded with an error: '{0}': '{1}'")]
+    ErrorResponse(i64, String),
+}
+
+#[cfg(test)]
+mod test_dht {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    async fn spawn_node(bootstrap: Vec<String>, state_path: Option<PathBuf>) -> Dht {
+        let dht = Dht::bind(DhtConfig {
+            bind_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            bootstrap,
+            state_path,
+        })
+        .await
+        .unwrap();
+
+        tokio::spawn(dht.clone().start());
+        dht
+    }
+
+    /// Finds the peers of the torrent and tells the closest nodes that we're one of them.
+    /// The client doesn't accept incoming connections yet, so only the tests announce.
+    async fn announce(dht: &Dht, info_hash: &[u8; 20], port: u16) -> Vec<SocketAddr> {
+        let lookup = dht.lookup(*info_hash, true).await;
+
+        let announces = lookup
+            .responded
+            .values()
+            .filter_map(|(node, token)| Some((node.addr, token.clone()?)))
+            .take(K)
+            .map(|(addr, token)| {
+                let query = Query::AnnouncePeer {
+                    info_hash: *info_hash,
+                    port,
+                    implied_port: false,
+                    token,
+                };
+
+                dht.query(addr, query)
+            });
+
+        let announced = futures::future::join_all(announces)
+            .await
+            .into_iter()
+            .filter(Result::is_ok)
+            .count();
+        assert!(announced > 0);
+
+        lookup.peers.into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn test_swarm() {
+        let first = spawn_node(vec![], None).await;
+        let bootstrap = vec![first.local_addr().unwrap().to_string()];
+
+        let mut nodes = vec![first];
+        for _ in 0..7 {
+            let node = spawn_node(bootstrap.clone(), None).await;
+            node.bootstrap().await;
+            nodes.push(node);
+        }
+
+        // Every node is known by at least the bootstrap node
+        assert_eq!(nodes[0].lock().table.len(), 7);
+        assert!(nodes[1..].iter().all(|n| !n.lock().table.is_empty()));
+
+        let info_hash = [7; 20];
+        assert!(announce(&nodes[3], &info_hash, 6881).await.is_empty());
+        assert!(nodes[5].get_peers(&[8; 20]).await.is_empty());
+
+        let peers = nodes[7].get_peers(&info_hash).await;
+        assert_eq!(peers, [SocketAddr::from((Ipv4Addr::LOCALHOST, 6881))]);
+
+        // Tokens are bound to the IP of the node
+        let addr = nodes[1].local_addr().unwrap();
+        let query = Query::AnnouncePeer {
+            info_hash,
+            port: 1,
+            implied_port: true,
+            token: b"invalid".to_vec(),
+        };
+        assert!(matches!(
+            nodes[2].query(addr, query).await,
+            Err(DhtErr::ErrorResponse(PROTOCOL_ERROR, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("learntorrent-dht-{}", rand::random::<u64>()));
+
+        let first = spawn_node(vec![], None).await;
+        let bootstrap = vec![first.local_addr().unwrap().to_string()];
+
+        let node = spawn_node(bootstrap, Some(path.clone())).await;
+        node.bootstrap().await;
+        node.save().await.unwrap();
+
+        let loaded = spawn_node(vec![], Some(path.clone())).await;
+        assert_eq!(loaded.inner.id, node.inner.id);
+        assert_eq!(
+            loaded.lock().table.nodes(),
+            [NodeInfo {
+                id: first.inner.id,
+                addr: first.local_addr().unwrap()
+            }]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
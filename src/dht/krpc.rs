@@ -0,0 +1,330 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use super::{
+    routing::{NodeId, NodeInfo},
+    DhtErr, DhtResult,
+};
+use crate::{
+    bencoding::{de, ser},
+    tracker_manager::{decode_compact_peers, encode_compact_peer, COMPACT_V4_LEN},
+};
+
+/// Error codes of the error messages
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+
+/// A KRPC message, a bencoded dictionary sent in a single UDP packet (BEP 5)
+#[derive(Debug, PartialEq)]
+pub struct Msg {
+    /// Chosen by the querying node and echoed back in the response
+    pub trans_id: Vec<u8>,
+    pub body: MsgBody,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MsgBody {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        /// The port of the UDP packet should be used instead of 'port'
+        implied_port: bool,
+        /// Received in the response to 'get_peers'
+        token: Vec<u8>,
+    },
+}
+
+/// The responses of all queries share the same fields, most of them are optional
+#[derive(Debug, Default, PartialEq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    /// Peers of the torrent, only in 'get_peers' responses
+    pub values: Vec<SocketAddr>,
+    /// Only in 'get_peers' responses
+    pub token: Option<Vec<u8>>,
+}
+
+impl Msg {
+    pub fn decode(src: &[u8]) -> DhtResult<Self> {
+        let raw: RawMsg = de::from_bytes(src)?;
+        let trans_id = raw.t.into_vec();
+
+        let body = match raw.y.as_str() {
+            "q" => {
+                let args = raw.a.ok_or(DhtErr::InvalidMessage)?;
+                let query = match raw.q.as_deref().ok_or(DhtErr::InvalidMessage)? {
+                    "ping" => Query::Ping,
+                    "find_node" => Query::FindNode {
+                        target: id(args.target.as_ref())?,
+                    },
+                    "get_peers" => Query::GetPeers {
+                        info_hash: id(args.info_hash.as_ref())?,
+                    },
+                    "announce_peer" => Query::AnnouncePeer {
+                        info_hash: id(args.info_hash.as_ref())?,
+                        port: args.port.ok_or(DhtErr::InvalidMessage)?,
+                        implied_port: args.implied_port.unwrap_or(0) != 0,
+                        token: args.token.ok_or(DhtErr::InvalidMessage)?.into_vec(),
+                    },
+                    _ => return Err(DhtErr::UnknownMethod(trans_id)),
+                };
+
+                MsgBody::Query {
+                    id: id(Some(&args.id))?,
+                    query,
+                }
+            }
+            "r" => {
+                let r = raw.r.ok_or(DhtErr::InvalidMessage)?;
+                let nodes = r.nodes.as_ref().map(|n| NodeInfo::decode_compact(n));
+                let values = r.values.unwrap_or_default();
+
+                MsgBody::Response(Response {
+                    id: id(Some(&r.id))?,
+                    nodes: nodes.unwrap_or_default(),
+                    values: values
+                        .iter()
+                        .flat_map(|v| decode_compact_peers(v, COMPACT_V4_LEN))
+                        .collect(),
+                    token: r.token.map(ByteBuf::into_vec),
+                })
+            }
+            "e" => {
+                let (code, message) = raw.e.ok_or(DhtErr::InvalidMessage)?;
+                MsgBody::Error { code, message }
+            }
+            _ => return Err(DhtErr::InvalidMessage),
+        };
+
+        Ok(Self { trans_id, body })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut raw = RawMsg {
+            t: ByteBuf::from(self.trans_id.clone()),
+            ..RawMsg::default()
+        };
+
+        match &self.body {
+            MsgBody::Query { id, query } => {
+                let mut args = RawArgs {
+                    id: ByteBuf::from(id.to_vec()),
+                    ..RawArgs::default()
+                };
+
+                let method = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode { target } => {
+                        args.target = Some(ByteBuf::from(target.to_vec()));
+                        "find_node"
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        args.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+                        args.port = Some(*port);
+                        args.implied_port = Some(*implied_port as u8);
+                        args.token = Some(ByteBuf::from(token.clone()));
+                        "announce_peer"
+                    }
+                };
+
+                raw.y = String::from("q");
+                raw.q = Some(method.to_string());
+                raw.a = Some(args);
+            }
+            MsgBody::Response(r) => {
+                let values = r
+                    .values
+                    .iter()
+                    .filter(|addr| addr.is_ipv4())
+                    .map(|addr| {
+                        let mut buf = Vec::with_capacity(COMPACT_V4_LEN);
+                        encode_compact_peer(addr, &mut buf);
+                        ByteBuf::from(buf)
+                    })
+                    .collect();
+
+                raw.y = String::from("r");
+                raw.r = Some(RawResponse {
+                    id: ByteBuf::from(r.id.to_vec()),
+                    nodes: (!r.nodes.is_empty())
+                        .then(|| ByteBuf::from(NodeInfo::encode_compact(&r.nodes))),
+                    values: (!r.values.is_empty()).then_some(values),
+                    token: r.token.clone().map(ByteBuf::from),
+                });
+            }
+            MsgBody::Error { code, message } => {
+                raw.y = String::from("e");
+                raw.e = Some((*code, message.clone()));
+            }
+        }
+
+        // UNWRAP: the message only contains serializable types
+        ser::to_bytes(&raw).unwrap()
+    }
+}
+
+fn id(src: Option<&ByteBuf>) -> DhtResult<NodeId> {
+    src.and_then(|id| id.as_slice().try_into().ok())
+        .ok_or(DhtErr::InvalidMessage)
+}
+
+/// The bencoded form of all message types, other keys (e.g. the client version) are ignored
+#[derive(Serialize, Deserialize, Default)]
+struct RawMsg {
+    /// Transaction ID
+    t: ByteBuf,
+    /// Message type: 'q', 'r' or 'e'
+    y: String,
+    /// Query method
+    q: Option<String>,
+    /// Query arguments
+    a: Option<RawArgs>,
+    /// Response values
+    r: Option<RawResponse>,
+    /// Error code and message
+    e: Option<(i64, String)>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct RawArgs {
+    id: ByteBuf,
+    target: Option<ByteBuf>,
+    info_hash: Option<ByteBuf>,
+    port: Option<u16>,
+    implied_port: Option<u8>,
+    token: Option<ByteBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawResponse {
+    id: ByteBuf,
+    /// Compact node infos
+    nodes: Option<ByteBuf>,
+    /// Compact peer addresses
+    values: Option<Vec<ByteBuf>>,
+    token: Option<ByteBuf>,
+}
+
+#[cfg(test)]
+mod test_krpc {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    // Examples from BEP 5
+    #[test]
+    fn test_queries() {
+        let ping = Msg {
+            trans_id: b"aa".to_vec(),
+            body: MsgBody::Query {
+                id: *b"abcdefghij0123456789",
+                query: Query::Ping,
+            },
+        };
+        let encoded = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(ping.encode(), encoded);
+        assert_eq!(Msg::decode(encoded).unwrap(), ping);
+
+        let announce = Msg {
+            trans_id: b"aa".to_vec(),
+            body: MsgBody::Query {
+                id: *b"abcdefghij0123456789",
+                query: Query::AnnouncePeer {
+                    info_hash: *b"mnopqrstuvwxyz123456",
+                    port: 6881,
+                    implied_port: true,
+                    token: b"aoeusnth".to_vec(),
+                },
+            },
+        };
+        let encoded = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:\
+            mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+        assert_eq!(announce.encode(), encoded);
+        assert_eq!(Msg::decode(encoded).unwrap(), announce);
+
+        let find_node = Msg {
+            trans_id: b"aa".to_vec(),
+            body: MsgBody::Query {
+                id: [1; 20],
+                query: Query::FindNode { target: [2; 20] },
+            },
+        };
+        assert_eq!(Msg::decode(&find_node.encode()).unwrap(), find_node);
+
+        let unknown = b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe";
+        assert!(matches!(
+            Msg::decode(unknown),
+            Err(DhtErr::UnknownMethod(t)) if t == b"aa"
+        ));
+    }
+
+    #[test]
+    fn test_responses() {
+        let encoded = b"d1:rd2:id20:mnopqrstuvwxyz1234565:token8:aoeusnth\
+            6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+        let msg = Msg::decode(encoded).unwrap();
+        assert_eq!(
+            msg.body,
+            MsgBody::Response(Response {
+                id: *b"mnopqrstuvwxyz123456",
+                nodes: vec![],
+                values: vec![
+                    SocketAddr::from((Ipv4Addr::new(97, 120, 106, 101), 11893)),
+                    SocketAddr::from((Ipv4Addr::new(105, 100, 104, 116), 28269)),
+                ],
+                token: Some(b"aoeusnth".to_vec()),
+            })
+        );
+        assert_eq!(msg.encode(), encoded);
+
+        let nodes = Msg {
+            trans_id: vec![0, 1],
+            body: MsgBody::Response(Response {
+                id: [1; 20],
+                nodes: vec![NodeInfo {
+                    id: [2; 20],
+                    addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 6881)),
+                }],
+                ..Response::default()
+            }),
+        };
+        assert_eq!(Msg::decode(&nodes.encode()).unwrap(), nodes);
+
+        let encoded = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let msg = Msg::decode(encoded).unwrap();
+        assert_eq!(
+            msg.body,
+            MsgBody::Error {
+                code: 201,
+                message: String::from("A Generic Error Ocurred")
+            }
+        );
+        assert_eq!(msg.encode(), encoded);
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::tracker_manager::{decode_compact_peers, encode_compact_peer, COMPACT_V4_LEN};
+
+/// Node IDs and info hashes share the same 160-bit space
+pub type NodeId = [u8; 20];
+
+/// Maximum number of nodes in a bucket, also the number of nodes returned by queries
+pub const K: usize = 8;
+/// Length of a compact node info, the node ID followed by the compact IPv4 address
+pub const COMPACT_NODE_LEN: usize = 20 + COMPACT_V4_LEN;
+/// Nodes that didn't respond to this many queries in a row can be replaced
+const MAX_FAILURES: u8 = 2;
+/// Nodes that weren't heard from for this long can be replaced (BEP 5)
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// XOR distance between two IDs, comparable as a big-endian number
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut dist = [0; 20];
+    for (d, (a, b)) in dist.iter_mut().zip(a.iter().zip(b)) {
+        *d = a ^ b;
+    }
+    dist
+}
+
+/// Number of leading bits the IDs have in common
+fn common_prefix_len(a: &NodeId, b: &NodeId) -> usize {
+    let dist = distance(a, b);
+
+    match dist.iter().position(|byte| *byte != 0) {
+        Some(pos) => pos * 8 + dist[pos].leading_zeros() as usize,
+        None => 160,
+    }
+}
+
+/// ID and address of a DHT node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl NodeInfo {
+    /// Only IPv4 nodes can be encoded, IPv6 nodes (BEP 32) are skipped
+    pub fn encode_compact(nodes: &[NodeInfo]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+
+        for node in nodes.iter().filter(|n| n.addr.is_ipv4()) {
+            buf.extend_from_slice(&node.id);
+            encode_compact_peer(&node.addr, &mut buf);
+        }
+
+        buf
+    }
+
+    /// Trailing bytes are ignored
+    pub fn decode_compact(src: &[u8]) -> Vec<NodeInfo> {
+        src.chunks_exact(COMPACT_NODE_LEN)
+            .flat_map(|chunk| {
+                let (id, addr) = chunk.split_at(20);
+                // UNWRAP: the chunk has the exact length
+                let id = id.try_into().unwrap();
+
+                decode_compact_peers(addr, COMPACT_V4_LEN)
+                    .into_iter()
+                    .map(move |addr| NodeInfo { id, addr })
+            })
+            .collect()
+    }
+}
+
+struct Node {
+    info: NodeInfo,
+    last_seen: Instant,
+    /// Consecutive unanswered queries
+    failures: u8,
+}
+
+impl Node {
+    fn is_replaceable(&self, now: Instant) -> bool {
+        self.failures >= MAX_FAILURES || now.duration_since(self.last_seen) >= QUESTIONABLE_AFTER
+    }
+}
+
+/// The known nodes, grouped into buckets by the distance from our ID (BEP 5).
+/// The n-th bucket holds the nodes that share exactly n leading bits with our ID,
+/// the last one holds all of the closer nodes and is split when it fills up.
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new()],
+        }
+    }
+
+    /// Adds the node or marks it as seen. Full buckets only accept a new node
+    /// if one of their nodes stopped responding. Returns whether the node is in the table.
+    pub fn insert(&mut self, info: NodeInfo, now: Instant) -> bool {
+        // Our own ID and martian addresses don't belong to the table
+        if info.id == self.own_id || !is_valid_addr(&info.addr) {
+            return false;
+        }
+
+        loop {
+            let index = self.bucket_index(&info.id);
+            let last = index == self.buckets.len() - 1;
+            let bucket = &mut self.buckets[index];
+
+            if let Some(node) = bucket.iter_mut().find(|n| n.info.id == info.id) {
+                node.info.addr = info.addr;
+                node.last_seen = now;
+                node.failures = 0;
+                return true;
+            }
+
+            let node = Node {
+                info,
+                last_seen: now,
+                failures: 0,
+            };
+
+            if bucket.len() < K {
+                bucket.push(node);
+                return true;
+            }
+
+            if let Some(pos) = bucket.iter().position(|n| n.is_replaceable(now)) {
+                bucket[pos] = node;
+                return true;
+            }
+
+            if !last || self.buckets.len() == 160 {
+                return false;
+            }
+
+            self.split_last();
+        }
+    }
+
+    /// Moves the nodes of the last bucket that are closer to our ID into a new bucket
+    fn split_last(&mut self) {
+        let index = self.buckets.len() - 1;
+        let own_id = self.own_id;
+
+        // UNWRAP: there's always at least one bucket
+        let (farther, closer) = self
+            .buckets
+            .pop()
+            .unwrap()
+            .into_iter()
+            .partition(|n| common_prefix_len(&own_id, &n.info.id) == index);
+
+        self.buckets.push(farther);
+        self.buckets.push(closer);
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        common_prefix_len(&self.own_id, id).min(self.buckets.len() - 1)
+    }
+
+    /// Called when the node didn't respond to a query
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        for node in self.buckets.iter_mut().flatten() {
+            if node.info.addr == *addr {
+                node.failures = node.failures.saturating_add(1);
+            }
+        }
+    }
+
+    /// The nodes closest to the target, nodes that stopped responding are skipped
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|n| n.failures < MAX_FAILURES)
+            .map(|n| n.info)
+            .collect();
+
+        nodes.sort_by_key(|n| distance(target, &n.id));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// All nodes, e.g. for saving the table
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets.iter().flatten().map(|n| n.info).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(Vec::is_empty)
+    }
+}
+
+fn is_valid_addr(addr: &SocketAddr) -> bool {
+    let unspecified = match addr.ip() {
+        IpAddr::V4(ip) => ip.is_unspecified() || ip.is_broadcast(),
+        IpAddr::V6(ip) => ip.is_unspecified(),
+    };
+
+    !unspecified && addr.port() != 0
+}
+
+#[cfg(test)]
+mod test_routing {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn node(first_byte: u8, last_byte: u8) -> NodeInfo {
+        let mut id = [0; 20];
+        id[0] = first_byte;
+        id[19] = last_byte;
+
+        NodeInfo {
+            id,
+            addr: SocketAddr::from((
+                Ipv4Addr::LOCALHOST,
+                u16::from_be_bytes([first_byte, last_byte]),
+            )),
+        }
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(common_prefix_len(&[0; 20], &[0; 20]), 160);
+        assert_eq!(common_prefix_len(&[0; 20], &node(0x80, 0).id), 0);
+        assert_eq!(common_prefix_len(&[0; 20], &node(0x01, 0).id), 7);
+        assert_eq!(common_prefix_len(&[0; 20], &node(0, 1).id), 159);
+
+        assert!(distance(&[0; 20], &node(0, 1).id) < distance(&[0; 20], &node(1, 0).id));
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut table = RoutingTable::new([0; 20]);
+        let now = Instant::now();
+
+        // The far half of the space only fits K nodes
+        for i in 0..20 {
+            table.insert(node(0x80, i), now);
+        }
+        assert_eq!(table.len(), K);
+
+        // The last bucket was split until the closer nodes got a bucket of their own
+        for i in 0..20 {
+            table.insert(node(0x01, i), now);
+        }
+        assert_eq!(table.len(), 2 * K);
+        assert!(table.insert(node(0x40, 0), now));
+        assert!(!table.insert(
+            NodeInfo {
+                id: [0; 20],
+                ..node(0, 1)
+            },
+            now
+        ));
+
+        // Unresponsive nodes are replaced
+        let far = node(0x80, 0);
+        table.failed(&far.addr);
+        table.failed(&far.addr);
+        assert!(table.insert(node(0x80, 100), now));
+        assert!(!table.nodes().contains(&far));
+
+        let closest = table.closest(&node(0x01, 3).id, 3);
+        assert_eq!(closest[0], node(0x01, 3));
+        assert_eq!(closest.len(), 3);
+    }
+
+    #[test]
+    fn test_compact() {
+        let nodes = [node(1, 1), node(2, 2)];
+        let encoded = NodeInfo::encode_compact(&nodes);
+
+        assert_eq!(encoded.len(), 2 * COMPACT_NODE_LEN);
+        assert_eq!(NodeInfo::decode_compact(&encoded), nodes);
+    }
+}
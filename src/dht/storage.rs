@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use rand::seq::IteratorRandom;
+use sha1::{Digest, Sha1};
+use tokio::time::Instant;
+
+/// Length of the tokens we hand out
+const TOKEN_LEN: usize = 8;
+/// Announced peers are forgotten after this long unless they announce again
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Maximum number of stored peers per torrent
+const MAX_PEERS_PER_TORRENT: usize = 1000;
+/// Maximum number of peers in a 'get_peers' response, so that it fits into a UDP packet
+pub const MAX_VALUES: usize = 50;
+
+/// Tokens are derived from a secret and the IP of the querying node.
+/// The secret is rotated every few minutes and tokens from the previous secret
+/// are still accepted, so a token is valid for at least one rotation interval (BEP 5).
+pub struct Tokens {
+    secret: [u8; 20],
+    prev_secret: [u8; 20],
+}
+
+impl Tokens {
+    pub fn new() -> Self {
+        let secret = rand::random();
+        Self {
+            secret,
+            prev_secret: secret,
+        }
+    }
+
+    pub fn rotate(&mut self) {
+        self.prev_secret = self.secret;
+        self.secret = rand::random();
+    }
+
+    pub fn create(&self, ip: &IpAddr) -> Vec<u8> {
+        Self::token(&self.secret, ip)
+    }
+
+    pub fn verify(&self, token: &[u8], ip: &IpAddr) -> bool {
+        token == Self::token(&self.secret, ip) || token == Self::token(&self.prev_secret, ip)
+    }
+
+    fn token(secret: &[u8; 20], ip: &IpAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+
+        hasher.finalize()[..TOKEN_LEN].to_vec()
+    }
+}
+
+/// The peers that announced themselves to us through 'announce_peer'
+#[derive(Default)]
+pub struct PeerStore {
+    torrents: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+}
+
+impl PeerStore {
+    pub fn announce(&mut self, info_hash: [u8; 20], addr: SocketAddr, now: Instant) {
+        let peers = self.torrents.entry(info_hash).or_default();
+
+        if peers.len() < MAX_PEERS_PER_TORRENT || peers.contains_key(&addr) {
+            peers.insert(addr, now);
+        }
+    }
+
+    /// A random selection of the stored peers of the torrent
+    pub fn get(&self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+        self.torrents.get(info_hash).map_or_else(Vec::new, |peers| {
+            peers
+                .keys()
+                .copied()
+                .choose_multiple(&mut rand::thread_rng(), MAX_VALUES)
+        })
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        for peers in self.torrents.values_mut() {
+            peers.retain(|_, announced| now.duration_since(*announced) < PEER_TTL);
+        }
+
+        self.torrents.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test_storage {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        let mut tokens = Tokens::new();
+        let ip = IpAddr::from(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::from(Ipv4Addr::new(10, 0, 0, 2));
+
+        let token = tokens.create(&ip);
+        assert_eq!(token.len(), TOKEN_LEN);
+        assert!(tokens.verify(&token, &ip));
+        assert!(!tokens.verify(&token, &other));
+        assert!(tokens.verify(&token, &ip.to_canonical()));
+
+        tokens.rotate();
+        assert!(tokens.verify(&token, &ip));
+        tokens.rotate();
+        assert!(!tokens.verify(&token, &ip));
+    }
+
+    #[test]
+    fn test_peers() {
+        let mut store = PeerStore::default();
+        let now = Instant::now();
+        let info_hash = [1; 20];
+
+        for port in 1..=100 {
+            store.announce(
+                info_hash,
+                SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+                now,
+            );
+        }
+        assert_eq!(store.get(&info_hash).len(), MAX_VALUES);
+        assert!(store.get(&[2; 20]).is_empty());
+
+        store.expire(now + PEER_TTL / 2);
+        assert_eq!(store.get(&info_hash).len(), MAX_VALUES);
+        store.expire(now + PEER_TTL);
+        assert!(store.get(&info_hash).is_empty());
+    }
+}
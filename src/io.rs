@@ -1,4 +1,4 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{path::Path, sync::Arc};
 
 use async_trait::async_trait;
 use bytes::BytesMut;
@@ -37,28 +37,36 @@ impl Io {
         piece_recv: mpsc::Receiver<IoMsg>,
         metainfo: Arc<Metainfo>,
     ) -> Result<Self, IoErr> {
-        let files = match &metainfo.file_entries {
-            TorrentFileEntries::Single(fe) => vec![File::create(&fe.name).await?],
+        let files = Self::create_files(Path::new(""), &metainfo.file_entries).await?;
+
+        Ok(Self {
+            piece_recv,
+            piece_saver: PieceSaver::new(files).await?,
+            metainfo,
+        })
+    }
+
+    /// Creates the files and their parent directories inside of the root directory
+    async fn create_files(root: &Path, entries: &TorrentFileEntries) -> Result<Vec<File>, IoErr> {
+        match entries {
+            TorrentFileEntries::Single(fe) => {
+                Ok(vec![File::create(root.join(fe.rel_path())).await?])
+            }
             TorrentFileEntries::Multi(mf) => {
                 let mut files = Vec::new();
-                let base_path = PathBuf::from(&mf.dir_name);
-                // TODO: support for multiple subdirectories (needs change in metainfo aswell)
-                fs::create_dir_all(&base_path).await?;
+                let base_path = root.join(&mf.dir_name);
+
+                for fe in entries.as_slice() {
+                    let path = base_path.join(fe.rel_path());
+                    // UNWRAP: the path always contains at least the base directory
+                    fs::create_dir_all(path.parent().unwrap()).await?;
 
-                for fe in metainfo.file_entries.as_slice() {
-                    let path = base_path.join(&fe.name);
                     files.push(File::create(path).await?);
                 }
 
-                files
+                Ok(files)
             }
-        };
-
-        Ok(Self {
-            piece_recv,
-            piece_saver: PieceSaver::new(files).await?,
-            metainfo,
-        })
+        }
     }
 
     pub async fn start(mut self) -> Result<(), IoErr> {
@@ -109,14 +117,14 @@ impl Io {
                     .file_entries
                     .iter()
                     .enumerate()
-                    .find(|(_, fe)| fe.start <= piece_start && fe.end >= piece_start)
+                    .find(|(_, fe)| fe.len > 0 && fe.start <= piece_start && fe.end >= piece_start)
                     .expect("Internal error: I/O received an invalid piece");
 
                 let (end_i, _) = fe
                     .file_entries
                     .iter()
                     .enumerate()
-                    .find(|(_, fe)| fe.start <= piece_end && fe.end >= piece_end)
+                    .find(|(_, fe)| fe.len > 0 && fe.start <= piece_end && fe.end >= piece_end)
                     .expect("Internal error: I/O received an invalid piece");
 
                 // Non-overlapping piece
@@ -127,7 +135,7 @@ impl Io {
                         file_index: start_i,
                     };
 
-                    return vec![piece];
+                    vec![piece]
                 } else if start_i < end_i {
                     Self::split_overlapping(fe, piece, start_i, end_i, piece_start, piece_end)
                 } else {
@@ -162,6 +170,9 @@ impl Io {
 
         for fe_index in start_i..=end_i {
             let fe = &fe.file_entries[fe_index];
+            if fe.len == 0 {
+                continue;
+            }
 
             let end = fe.end.min(piece_end);
             let len = (end as usize - current_pos) + 1;
@@ -201,7 +212,9 @@ pub struct IoPiece {
 
 #[async_trait]
 pub trait PieceSave {
-    async fn new(files: Vec<File>) -> Result<PieceSaver, IoErr>;
+    async fn new(files: Vec<File>) -> Result<Self, IoErr>
+    where
+        Self: Sized;
     async fn on_piece_msg(&mut self, piece: IoPiece) -> Result<(), IoErr>;
 }
 
@@ -230,9 +243,9 @@ mod test_piece_split {
 
     #[rustfmt::skip]
     fn create_metainfo_short(piece_length: u32) -> Metainfo {
-        let fe_0 = FileEntry { name: "".to_string(), start: 0, end: 127, len: 128 };
-        let fe_1 = FileEntry { name: "".to_string(), start: 128, end: 191, len: 64 };
-        let fe_2 = FileEntry { name: "".to_string(), start: 192, end: 223, len: 32 };
+        let fe_0 = FileEntry { path: vec![], orig_path: vec![], start: 0, end: 127, len: 128 };
+        let fe_1 = FileEntry { path: vec![], orig_path: vec![], start: 128, end: 191, len: 64 };
+        let fe_2 = FileEntry { path: vec![], orig_path: vec![], start: 192, end: 223, len: 32 };
 
         let file_entries = vec![fe_0, fe_1, fe_2];
 
@@ -241,10 +254,10 @@ mod test_piece_split {
 
     #[rustfmt::skip]
     fn create_metainfo_long(piece_length: u32) -> Metainfo {
-        let fe_0 = FileEntry { name: "".to_string(), start: 0, end: 256, len: 257 };
-        let fe_1 = FileEntry { name: "".to_string(), start: 257, end: 384, len: 128 };
-        let fe_2 = FileEntry { name: "".to_string(), start: 385, end: 448, len: 64 };
-        let fe_3 = FileEntry { name: "".to_string(), start: 449, end: 704, len: 256 };
+        let fe_0 = FileEntry { path: vec![], orig_path: vec![], start: 0, end: 256, len: 257 };
+        let fe_1 = FileEntry { path: vec![], orig_path: vec![], start: 257, end: 384, len: 128 };
+        let fe_2 = FileEntry { path: vec![], orig_path: vec![], start: 385, end: 448, len: 64 };
+        let fe_3 = FileEntry { path: vec![], orig_path: vec![], start: 449, end: 704, len: 256 };
 
 
         let file_entries = vec![fe_0, fe_1, fe_2, fe_3];
@@ -257,6 +270,7 @@ mod test_piece_split {
         let total_length = file_entries.iter().map(|fe| fe.len).sum();
         let multi_file = MultiFile {
             dir_name: "".to_string(),
+            orig_dir_name: "".to_string(),
             file_entries,
         };
 
@@ -264,11 +278,15 @@ mod test_piece_split {
 
         Metainfo {
             trackers: vec![],
+            web_seeds: vec![],
             info_hash: Box::new([0; 20]),
+            info_hash_v2: None,
             piece_length,
             total_length,
             file_entries,
             piece_hashes: vec![],
+            merkle_pieces: vec![],
+            private: false,
         }
     }
 
@@ -313,19 +331,19 @@ mod test_piece_split {
         let file_entries = create_metainfo_short(128);
 
         let mut bytes = BytesMut::new();
-        bytes.extend_from_slice(&vec![0; 64]);
-        bytes.extend_from_slice(&vec![1; 32]);
+        bytes.extend_from_slice(&[0; 64]);
+        bytes.extend_from_slice(&[1; 32]);
 
         let cbr = CompletedBlockRequest { offset: 0, size: 96, bytes };
         let piece = ValidatedPiece { pid: 1, blocks: vec![cbr] };
 
         let pieces = Io::split_piece(&file_entries, piece);
 
-        let bytes_1 = BytesMut::from_iter(vec![0u8; 64].iter());
+        let bytes_1 = BytesMut::from_iter([0u8; 64].iter());
         let cb_1 = CompletedBlockRequest { offset: 0, size: 64, bytes: bytes_1 };
         let expected_1 = IoPiece { offset: 0, blocks: vec![cb_1], file_index: 1 };
 
-        let bytes_2 = BytesMut::from_iter(vec![1u8; 32].iter());
+        let bytes_2 = BytesMut::from_iter([1u8; 32].iter());
         let cb_2 = CompletedBlockRequest { offset: 0, size: 32, bytes: bytes_2 };
         let expected_2 = IoPiece { offset: 0, blocks: vec![cb_2], file_index: 2 };
 
@@ -338,57 +356,125 @@ mod test_piece_split {
         let file_entries = create_metainfo_short(96);
 
         let mut bytes = BytesMut::new();
-        bytes.extend_from_slice(&vec![0; 32]);
-        bytes.extend_from_slice(&vec![1; 64]);
+        bytes.extend_from_slice(&[0; 32]);
+        bytes.extend_from_slice(&[1; 64]);
 
         let cbr = CompletedBlockRequest { offset: 0, size: 96, bytes };
         let piece = ValidatedPiece { pid: 1, blocks: vec![cbr] };
 
         let pieces = Io::split_piece(&file_entries, piece);
 
-        let bytes_1 = BytesMut::from_iter(vec![0u8; 32].iter());
+        let bytes_1 = BytesMut::from_iter([0u8; 32].iter());
         let cb_1 = CompletedBlockRequest { offset: 0, size: 32, bytes: bytes_1 };
         let expected_1 = IoPiece { offset: 96, blocks: vec![cb_1], file_index: 0 };
 
-        let bytes_2 = BytesMut::from_iter(vec![1u8; 64].iter());
+        let bytes_2 = BytesMut::from_iter([1u8; 64].iter());
         let cb_2 = CompletedBlockRequest { offset: 0, size: 64, bytes: bytes_2 };
         let expected_2 = IoPiece { offset: 0, blocks: vec![cb_2], file_index: 1 };
 
         assert_eq!(&[expected_1, expected_2], pieces.as_slice());
     }
 
+    #[rustfmt::skip]
+    #[test]
+    fn test_split_zero_length_files() {
+        let fe_0 = FileEntry { path: vec![], orig_path: vec![], start: 0, end: 0, len: 0 };
+        let fe_1 = FileEntry { path: vec![], orig_path: vec![], start: 0, end: 15, len: 16 };
+        let fe_2 = FileEntry { path: vec![], orig_path: vec![], start: 16, end: 15, len: 0 };
+        let fe_3 = FileEntry { path: vec![], orig_path: vec![], start: 16, end: 31, len: 16 };
+        let file_entries = create_mock_metainfo(32, vec![fe_0, fe_1, fe_2, fe_3]);
+
+        let mut bytes = BytesMut::new();
+        bytes.extend_from_slice(&[1; 16]);
+        bytes.extend_from_slice(&[3; 16]);
+
+        let cbr = CompletedBlockRequest { offset: 0, size: 32, bytes };
+        let piece = ValidatedPiece { pid: 0, blocks: vec![cbr] };
+
+        let pieces = Io::split_piece(&file_entries, piece);
+
+        let bytes_1 = BytesMut::from_iter([1u8; 16].iter());
+        let cb_1 = CompletedBlockRequest { offset: 0, size: 16, bytes: bytes_1 };
+        let expected_1 = IoPiece { offset: 0, blocks: vec![cb_1], file_index: 1 };
+
+        let bytes_3 = BytesMut::from_iter([3u8; 16].iter());
+        let cb_3 = CompletedBlockRequest { offset: 0, size: 16, bytes: bytes_3 };
+        let expected_3 = IoPiece { offset: 0, blocks: vec![cb_3], file_index: 3 };
+
+        assert_eq!(&[expected_1, expected_3], pieces.as_slice());
+    }
+
     #[rustfmt::skip]
     #[test]
     fn test_split_overlapping_two() {
         let file_entries = create_metainfo_long(256);
 
         let mut bytes = BytesMut::new();
-        bytes.extend_from_slice(&vec![0; 1]);
-        bytes.extend_from_slice(&vec![1; 128]);
-        bytes.extend_from_slice(&vec![2; 64]);
-        bytes.extend_from_slice(&vec![3; 63]);
+        bytes.extend_from_slice(&[0; 1]);
+        bytes.extend_from_slice(&[1; 128]);
+        bytes.extend_from_slice(&[2; 64]);
+        bytes.extend_from_slice(&[3; 63]);
 
         let cbr = CompletedBlockRequest { offset: 0, size: 256, bytes };
         let piece = ValidatedPiece { pid: 1, blocks: vec![cbr] };
 
         let pieces = Io::split_piece(&file_entries, piece);
 
-        let bytes_0 = BytesMut::from_iter(vec![0u8; 1].iter());
+        let bytes_0 = BytesMut::from_iter([0u8; 1].iter());
         let cb_0 = CompletedBlockRequest { offset: 0, size: 1, bytes: bytes_0 };
         let expected_0 = IoPiece { offset: 256, blocks: vec![cb_0], file_index: 0};
 
-        let bytes_1 = BytesMut::from_iter(vec![1u8; 128].iter());
+        let bytes_1 = BytesMut::from_iter([1u8; 128].iter());
         let cb_1 = CompletedBlockRequest { offset: 0, size: 128, bytes: bytes_1 };
         let expected_1 = IoPiece { offset: 0, blocks: vec![cb_1], file_index: 1};
 
-        let bytes_2 = BytesMut::from_iter(vec![2u8; 64].iter());
+        let bytes_2 = BytesMut::from_iter([2u8; 64].iter());
         let cb_2 = CompletedBlockRequest { offset: 0, size: 64, bytes: bytes_2 };
         let expected_2 = IoPiece { offset: 0, blocks: vec![cb_2], file_index: 2};
 
-        let bytes_3 = BytesMut::from_iter(vec![3u8; 63].iter());
+        let bytes_3 = BytesMut::from_iter([3u8; 63].iter());
         let cb_3 = CompletedBlockRequest { offset: 0, size: 63, bytes: bytes_3 };
         let expected_3 = IoPiece { offset: 0, blocks: vec![cb_3], file_index: 3};
 
         assert_eq!(&[expected_0, expected_1, expected_2, expected_3], pieces.as_slice());
     }
 }
+
+#[cfg(test)]
+mod test_create_files {
+    use crate::metainfo::{FileEntry, MultiFile};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_nested_directories() {
+        let root = std::env::temp_dir().join(format!("learntorrent-{}", rand::random::<u64>()));
+
+        let entry = |path: &[&str]| FileEntry {
+            path: path.iter().map(|c| c.to_string()).collect(),
+            orig_path: vec![],
+            len: 0,
+            start: 0,
+            end: 0,
+        };
+
+        let entries = TorrentFileEntries::Multi(MultiFile {
+            dir_name: "torrent".to_string(),
+            orig_dir_name: "torrent".to_string(),
+            file_entries: vec![
+                entry(&["a.txt"]),
+                entry(&["sub", "b.txt"]),
+                entry(&["sub", "deeper", "c.txt"]),
+            ],
+        });
+
+        let files = Io::create_files(&root, &entries).await.unwrap();
+        assert_eq!(files.len(), 3);
+
+        assert!(root.join("torrent/a.txt").is_file());
+        assert!(root.join("torrent/sub/b.txt").is_file());
+        assert!(root.join("torrent/sub/deeper/c.txt").is_file());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
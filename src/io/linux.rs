@@ -41,7 +41,7 @@ impl PieceSave for PieceSaver {
         )
         .offset(piece.offset as i64)
         .build()
-        .user_data(piece.offset as u64);
+        .user_data(piece.offset);
 
         // INVESTIGATE: replace io_uring with an ordinary pwritev syscall since we are only
         // submitting 1 operation at a time
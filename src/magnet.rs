@@ -0,0 +1,190 @@
+use std::net::SocketAddr;
+
+use thiserror::Error;
+
+/// Parsed magnet URI (BEP 9):
+/// magnet:?xt=urn:btih:<info-hash>&dn=<name>&tr=<tracker-url>&x.pe=<peer-address>
+#[derive(Debug, PartialEq)]
+pub struct MagnetLink {
+    /// Hash of the info dictionary
+    pub info_hash: [u8; 20],
+    /// Suggested name, only used for displaying before the metadata is downloaded
+    pub display_name: Option<String>,
+    /// Tracker URLs
+    pub trackers: Vec<String>,
+    /// Addresses of peers that can be contacted directly
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> MagnetResult<Self> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or(MagnetErr::InvalidScheme)?;
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, val) = param.split_once('=').unwrap_or((param, ""));
+
+            match key {
+                // There can be multiple topics (e.g. a v2 'urn:btmh'), use the first v1 one
+                "xt" if info_hash.is_none() => {
+                    if let Some(hash) = val.strip_prefix("urn:btih:") {
+                        info_hash = Some(Self::parse_info_hash(hash)?);
+                    }
+                }
+                // Names are often form-encoded, with '+' instead of spaces
+                "dn" => display_name = Some(Self::decode(&val.replace('+', " "))?),
+                "tr" => trackers.push(Self::decode(val)?),
+                "x.pe" => match Self::decode(val)?.parse() {
+                    Ok(peer) => peers.push(peer),
+                    Err(_) => tracing::debug!("Ignoring an unsupported peer address: '{}'", val),
+                },
+                _ => tracing::debug!("Ignoring magnet URI parameter: '{}'", key),
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetErr::MissingInfoHash)?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+
+    /// The info-hash is either hex encoded (40 characters) or base32 encoded (32 characters)
+    fn parse_info_hash(hash: &str) -> MagnetResult<[u8; 20]> {
+        let decoded = match hash.len() {
+            40 => hex_decode(hash),
+            32 => base32_decode(hash),
+            _ => None,
+        };
+
+        let mut info_hash = [0; 20];
+        match decoded {
+            Some(d) if d.len() == 20 => info_hash.copy_from_slice(&d),
+            _ => return Err(MagnetErr::InvalidInfoHash(hash.to_string())),
+        }
+
+        Ok(info_hash)
+    }
+
+    fn decode(val: &str) -> MagnetResult<String> {
+        urlencoding::decode(val)
+            .map(|v| v.into_owned())
+            .map_err(|_| MagnetErr::InvalidEncoding(val.to_string()))
+    }
+}
+
+fn hex_decode(src: &str) -> Option<Vec<u8>> {
+    src.as_bytes()
+        .chunks(2)
+        .map(|c| {
+            let hi = (c[0] as char).to_digit(16)?;
+            let lo = (c[1] as char).to_digit(16)?;
+            Some((hi * 16 + lo) as u8)
+        })
+        .collect()
+}
+
+/// RFC 4648 base32 without padding
+fn base32_decode(src: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(src.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in src.bytes() {
+        let val = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | u32::from(val);
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+type MagnetResult<T> = Result<T, MagnetErr>;
+
+#[derive(Error, Debug)]
+pub enum MagnetErr {
+    #[error("Magnet URIs have to start with 'magnet:?'")]
+    InvalidScheme,
+    #[error("The magnet URI doesn't contain a BitTorrent info-hash ('xt=urn:btih:')")]
+    MissingInfoHash,
+    #[error("Invalid info-hash: '{0}'")]
+    InvalidInfoHash(String),
+    #[error("Invalid percent-encoding: '{0}'")]
+    InvalidEncoding(String),
+}
+
+#[cfg(test)]
+mod test_magnet {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    const HASH: [u8; 20] = [
+        0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf1, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef,
+        0x12, 0x34, 0x56, 0x78, 0x9a,
+    ];
+
+    #[test]
+    fn test_parse() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:123456789abcdef123456789ABCDEF123456789a\
+            &dn=debian+11%2E2&tr=udp%3A%2F%2Ftracker.example%3A1337%2Fannounce\
+            &tr=http://tracker.example/announce&x.pe=10.0.0.1:6881&x.pe=[::1]:6881&so=0",
+        )
+        .unwrap();
+
+        assert_eq!(
+            magnet,
+            MagnetLink {
+                info_hash: HASH,
+                display_name: Some("debian 11.2".to_string()),
+                trackers: vec![
+                    "udp://tracker.example:1337/announce".to_string(),
+                    "http://tracker.example/announce".to_string(),
+                ],
+                peers: vec![
+                    SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 6881)),
+                    SocketAddr::from((Ipv6Addr::LOCALHOST, 6881)),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_base32() {
+        let magnet =
+            MagnetLink::parse("magnet:?xt=urn:btih:CI2FM6E2XTPPCI2FM6E2XTPPCI2FM6E2").unwrap();
+        assert_eq!(magnet.info_hash, HASH);
+        assert_eq!(magnet.display_name, None);
+        assert!(magnet.trackers.is_empty());
+    }
+
+    #[test]
+    fn test_invalid() {
+        MagnetLink::parse("http://example.com").unwrap_err();
+        MagnetLink::parse("magnet:?dn=name").unwrap_err();
+        MagnetLink::parse("magnet:?xt=urn:btih:1234").unwrap_err();
+        MagnetLink::parse("magnet:?xt=urn:btih:x23456789abcdef123456789abcdef123456789a")
+            .unwrap_err();
+        MagnetLink::parse("magnet:?xt=urn:btih:CI2FM6E2XTPPCI2FM6E2XTPPCI2FM6E1").unwrap_err();
+        MagnetLink::parse("magnet:?xt=urn:btih:123456789abcdef123456789abcdef123456789a&tr=%FF%FE")
+            .unwrap_err();
+    }
+}
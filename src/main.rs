@@ -1,4 +1,9 @@
-use std::{env, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use eyre::{Result, WrapErr};
 use tokio::{
@@ -7,16 +12,30 @@ use tokio::{
 };
 
 use crate::{
-    bencoding::bevalue::BeValue, io::Io, metainfo::Metainfo, piece_keeper::PieceKeeper,
+    bencoding::BeParser,
+    cli::{Command, CreateArgs, DownloadArgs, ScrapeArgs, TrackerArgs},
+    dht::{Dht, DhtConfig},
+    io::Io,
+    magnet::MagnetLink,
+    metainfo::{Metainfo, MetainfoBuilder},
+    p2p::MetadataFetcher,
+    piece_keeper::PieceKeeper,
+    stats::TransferStats,
     tracker_manager::TrackerManager,
+    tracker_server::TrackerServer,
 };
 
 mod bencoding;
+mod cli;
+mod dht;
 mod io;
+mod magnet;
 mod metainfo;
 mod p2p;
 mod piece_keeper;
+mod stats;
 mod tracker_manager;
+mod tracker_server;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -24,19 +43,37 @@ async fn main() -> Result<()> {
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
-    let metadata_path = env::args()
-        .nth(1)
-        .unwrap_or("debian-11.2.0-amd64-netinst.iso.torrent".to_string());
+    let command = match Command::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    let file_contents = fs::read(metadata_path)
-        .await
-        .wrap_err("Failed to read the torrent metadata file")?;
+    match command {
+        Command::Download(args) => download(args).await,
+        Command::Create(args) => create_torrent(args).await,
+        Command::Scrape(args) => scrape(args).await,
+        Command::Tracker(args) => run_tracker(args).await,
+    }
+}
+
+async fn download(args: DownloadArgs) -> Result<()> {
+    let client_id = TrackerManager::gen_client_id();
+    let http_client = tracker_manager::http_client(args.proxy.as_deref())
+        .wrap_err("Failed to initialize the HTTP client")?;
 
-    let contents =
-        BeValue::from_bytes(&file_contents).wrap_err("Failed to parse the torrent metadata")?;
+    let dht = match args.dht {
+        true => start_dht(&args).await,
+        false => None,
+    };
 
-    let metainfo = Metainfo::from_src_be(&file_contents, contents)
-        .wrap_err("Failed to create the metainfo struct")?;
+    let metainfo = if args.source.starts_with("magnet:") {
+        metainfo_from_magnet(&args.source, &client_id, &http_client, dht.as_ref()).await?
+    } else {
+        metainfo_from_file(&args.source).await?
+    };
 
     tracing::debug!("Torrent metainfo parsed: {:?}", metainfo);
     let metainfo = Arc::new(metainfo);
@@ -44,6 +81,7 @@ async fn main() -> Result<()> {
     let (appstate_sender, appstate_recv) = watch::channel(AppState::Running);
     let (io_sender, io_recv) = mpsc::channel(128);
     let (pm_sender, pm_recv) = flume::bounded(128);
+    let stats = Arc::new(TransferStats::new(metainfo.total_length));
 
     let io = Io::new(io_recv, Arc::clone(&metainfo))
         .await
@@ -54,11 +92,17 @@ async fn main() -> Result<()> {
         pm_recv.clone(),
         io_sender.clone(),
         metainfo.piece_count(),
+        Arc::clone(&stats),
     );
     let tracker = TrackerManager::new(
         appstate_recv.clone(),
+        pm.torrentstate_recv(),
         pm_sender.clone(),
         Arc::clone(&metainfo),
+        client_id,
+        stats,
+        http_client,
+        dht.clone(),
     );
 
     drop(appstate_recv);
@@ -85,9 +129,206 @@ async fn main() -> Result<()> {
     io_task.await??;
     tracker_task.await??;
 
+    if let Some(dht) = dht {
+        if let Err(e) = dht.save().await {
+            tracing::warn!("Couldn't save the DHT state: '{}'", e);
+        }
+    }
+
     Ok(())
 }
 
+/// The download works without the DHT, so failing to start it isn't fatal
+async fn start_dht(args: &DownloadArgs) -> Option<Dht> {
+    let config = DhtConfig {
+        bind_addr: SocketAddr::from(([0, 0, 0, 0], args.dht_port)),
+        bootstrap: args.dht_bootstrap.clone(),
+        state_path: Some(args.dht_state.clone()),
+    };
+
+    let dht = match Dht::bind(config).await {
+        Ok(dht) => dht,
+        Err(e) => {
+            tracing::warn!("Couldn't start the DHT node: '{}'", e);
+            return None;
+        }
+    };
+
+    let node = dht.clone();
+    tokio::spawn(async move {
+        if let Err(e) = node.start().await {
+            tracing::warn!("The DHT node failed: '{}'", e);
+        }
+    });
+
+    Some(dht)
+}
+
+async fn create_torrent(args: CreateArgs) -> Result<()> {
+    let output = args.output_path();
+
+    let mut builder = MetainfoBuilder::new(&args.path).private(args.private);
+    for tracker in args.trackers {
+        builder = builder.tracker(tracker);
+    }
+    if let Some(comment) = args.comment {
+        builder = builder.comment(comment);
+    }
+    if let Some(piece_length) = args.piece_length {
+        builder = builder.piece_length(piece_length);
+    }
+    if let Some(creation_date) = args.creation_date {
+        builder = builder.creation_date(creation_date);
+    }
+
+    // Hashing is CPU-bound and uses blocking file IO
+    let torrent = tokio::task::spawn_blocking(move || builder.build())
+        .await?
+        .wrap_err("Failed to create the torrent")?;
+
+    // Other clients compute the info hash from the encoding, so it has to be canonical
+    BeParser::parse_strict_with(&torrent)
+        .wrap_err("The created torrent isn't canonically encoded")?;
+
+    fs::write(&output, torrent)
+        .await
+        .wrap_err("Failed to write the torrent file")?;
+
+    tracing::info!("Created '{}'", output.display());
+
+    Ok(())
+}
+
+async fn scrape(args: ScrapeArgs) -> Result<()> {
+    let http_client = tracker_manager::http_client(args.proxy.as_deref())
+        .wrap_err("Failed to initialize the HTTP client")?;
+
+    let mut torrents = Vec::new();
+    for source in args.sources {
+        let (info_hash, trackers) = if source.starts_with("magnet:") {
+            let magnet = MagnetLink::parse(&source).wrap_err("Failed to parse the magnet URI")?;
+            (magnet.info_hash, magnet.trackers)
+        } else {
+            let metainfo = metainfo_from_file(&source).await?;
+            (*metainfo.info_hash, metainfo.trackers.concat())
+        };
+
+        torrents.push((source, info_hash, trackers));
+    }
+
+    // Every tracker is scraped only once for all of its torrents
+    let mut tracker_hashes: BTreeMap<&str, Vec<[u8; 20]>> = BTreeMap::new();
+    for (_, info_hash, trackers) in &torrents {
+        for url in trackers {
+            let info_hashes = tracker_hashes.entry(url).or_default();
+            if !info_hashes.contains(info_hash) {
+                info_hashes.push(*info_hash);
+            }
+        }
+    }
+
+    let http_client = &http_client;
+    let scrapes = tracker_hashes.iter().map(|(url, info_hashes)| async move {
+        (
+            *url,
+            tracker_manager::scrape(url, info_hashes, http_client).await,
+        )
+    });
+    let results: HashMap<_, _> = futures::future::join_all(scrapes)
+        .await
+        .into_iter()
+        .collect();
+
+    for (source, info_hash, trackers) in &torrents {
+        println!("{}", source);
+
+        if trackers.is_empty() {
+            println!("    no trackers");
+        }
+
+        for url in trackers {
+            match &results[url.as_str()] {
+                Ok(swarms) => match swarms.get(info_hash) {
+                    Some(s) => println!(
+                        "    {}: {} seeders, {} leechers, {} completed",
+                        url, s.seeders, s.leechers, s.completed
+                    ),
+                    None => println!("    {}: unknown torrent", url),
+                },
+                Err(e) => println!("    {}: {}", url, e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_tracker(args: TrackerArgs) -> Result<()> {
+    let server = TrackerServer::bind(args.http, args.udp)
+        .await
+        .wrap_err("Failed to bind the tracker sockets")?;
+
+    if let Some(addr) = server.http_addr() {
+        tracing::info!("Serving HTTP announces on http://{}/announce", addr);
+    }
+    if let Some(addr) = server.udp_addr() {
+        tracing::info!("Serving UDP announces on udp://{}/announce", addr);
+    }
+
+    tokio::select! {
+        res = server.start() => res.wrap_err("The tracker server failed"),
+        res = tokio::signal::ctrl_c() => res.wrap_err("Unable to listen for shutdown signal"),
+    }
+}
+
+async fn metainfo_from_file(path: &str) -> Result<Metainfo> {
+    let file_contents = fs::read(path)
+        .await
+        .wrap_err("Failed to read the torrent metadata file")?;
+
+    Metainfo::from_src(&file_contents).wrap_err("Failed to create the metainfo struct")
+}
+
+/// Downloads the info dictionary from the peers of the torrent
+async fn metainfo_from_magnet(
+    uri: &str,
+    client_id: &[u8; 20],
+    http_client: &reqwest::Client,
+    dht: Option<&Dht>,
+) -> Result<Metainfo> {
+    let magnet = MagnetLink::parse(uri).wrap_err("Failed to parse the magnet URI")?;
+
+    tracing::info!(
+        "Fetching the metadata of '{}'",
+        magnet
+            .display_name
+            .as_deref()
+            .unwrap_or("<unnamed torrent>")
+    );
+
+    let mut peers = magnet.peers.clone();
+    peers.extend(
+        tracker_manager::find_peers(&magnet.trackers, &magnet.info_hash, client_id, http_client)
+            .await,
+    );
+    if let Some(dht) = dht {
+        peers.extend(dht.get_peers(&magnet.info_hash).await);
+    }
+
+    let info = MetadataFetcher::new(magnet.info_hash, client_id)
+        .fetch(peers)
+        .await
+        .wrap_err("Failed to download the torrent metadata")?;
+
+    // All of the trackers of a magnet link are equal, so they form a single tier
+    let tiers = match magnet.trackers.is_empty() {
+        true => vec![],
+        false => vec![magnet.trackers],
+    };
+
+    Metainfo::from_info_src(&info, tiers).wrap_err("Failed to create the metainfo struct")
+}
+
 #[derive(Debug)]
 pub enum AppState {
     Running,
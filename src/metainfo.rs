@@ -1,16 +1,40 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::Deserialize;
 use sha1::{Digest, Sha1};
 use thiserror::Error;
 
 use crate::{
-    bencoding::bevalue::{BeValue, Dict, ResponseParseError},
+    bencoding::{
+        bevalue::{ReponseParseResult, ResponseParseError},
+        bevalue_ref::{BeValueRef, DictRef},
+        de::{BeDeserializeErr, BeDeserializer, RawValue},
+        BeDecodeErr, BeParser,
+    },
     piece_keeper::PieceId,
 };
 
+use self::{
+    merkle::{Sha256Hash, MERKLE_BLOCK_LEN},
+    sanitize::{check_collisions, sanitize_name, sanitize_path},
+};
+
+pub use self::{builder::MetainfoBuilder, merkle::MerklePiece};
+
+mod builder;
+mod merkle;
+mod sanitize;
+
 pub struct Metainfo {
-    /// Tracker URLs
-    pub trackers: Vec<String>,
-    /// Hash of the info dictionary
+    /// Tracker URLs grouped into tiers, the tiers are tried in order (BEP 12)
+    pub trackers: Vec<Vec<String>>,
+    /// HTTP(S) URLs of servers hosting the torrent content (BEP 19)
+    pub web_seeds: Vec<String>,
+    /// Hash of the info dictionary used in handshakes and announces.
+    /// SHA1 for v1 and hybrid torrents, truncated SHA-256 for v2-only torrents.
     pub info_hash: Box<[u8; 20]>,
+    /// SHA-256 hash of the info dictionary of v2 and hybrid torrents
+    pub info_hash_v2: Option<Box<Sha256Hash>>,
     /// Number of bytes of 1 piece
     pub piece_length: u32,
     /// Total length of the file in bytes
@@ -19,30 +43,118 @@ pub struct Metainfo {
     pub file_entries: TorrentFileEntries,
     /// SHA1 hashes of the individual pieces
     pub piece_hashes: Vec<[u8; 20]>,
+    /// Merkle roots of the individual pieces, used instead of the SHA1 hashes if present
+    pub merkle_pieces: Vec<MerklePiece>,
+    /// Peers should only be received from the trackers, PEX and DHT are disabled (BEP 27)
+    pub private: bool,
+}
+
+/// Hashes of the piece layers of v2 torrents, keyed by the 'pieces root' of the file
+type PieceLayers<'a> = HashMap<&'a [u8], &'a [u8]>;
+
+/// Top level of a torrent file. The info dictionary is kept in its source form,
+/// which is needed for computing the info hash.
+#[derive(Deserialize)]
+struct TorrentFile<'a> {
+    #[serde(borrow)]
+    info: RawValue<'a>,
+    announce: Option<String>,
+    #[serde(rename = "announce-list")]
+    announce_list: Option<Vec<Vec<String>>>,
+    #[serde(rename = "url-list")]
+    url_list: Option<UrlList>,
+    #[serde(rename = "piece layers", borrow)]
+    piece_layers: Option<PieceLayers<'a>>,
+}
+
+/// url-list = single URL or list of URLs
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UrlList {
+    Single(String),
+    Multi(Vec<String>),
 }
 
 impl Metainfo {
-    pub fn from_src_be(src: &[u8], mut be: BeValue) -> MiResult<Self> {
-        let torrent = be.get_dict()?;
+    pub fn from_src(src: &[u8]) -> MiResult<Self> {
+        // Trailing bytes after the torrent are ignored
+        let torrent = TorrentFile::deserialize(&mut BeDeserializer::new(src))?;
+
+        let trackers = Self::parse_trackers(torrent.announce, torrent.announce_list);
+        let web_seeds = Self::parse_web_seeds(torrent.url_list);
+
+        // Extraction errors are located in 'info', at offsets of the whole file
+        let info_src = torrent.info.as_bytes();
+        let info_pos = info_src.as_ptr() as usize - src.as_ptr() as usize;
+        let piece_layers = torrent.piece_layers.as_ref();
+        let mut mi = Self::parse_info(info_src, trackers, piece_layers).map_err(|e| match e {
+            MiErr::BeError(mut e) => {
+                e.pos = e.pos.map(|pos| pos + info_pos);
+                MiErr::BeError(e.in_key(b"info", info_pos))
+            }
+            e => e,
+        })?;
+
+        mi.web_seeds = web_seeds;
+        Ok(mi)
+    }
 
-        let trackers = Self::parse_trackers(torrent)?;
+    /// Creates the metainfo from a bare info dictionary, e.g. one downloaded from peers.
+    /// The piece layers of v2 torrents aren't a part of it, so only the v1 part
+    /// of hybrid torrents and v2 torrents with single-piece files can be used.
+    pub fn from_info_src(src: &[u8], trackers: Vec<Vec<String>>) -> MiResult<Self> {
+        Self::parse_info(src, trackers, None)
+    }
+
+    /// The info hash is computed from the source of the info dictionary
+    fn parse_info(
+        info_src: &[u8],
+        trackers: Vec<Vec<String>>,
+        piece_layers: Option<&PieceLayers>,
+    ) -> MiResult<Self> {
+        // Non-canonical info dicts are accepted, but clients that re-encode them
+        // compute a different info hash
+        let mut parser = BeParser::new(info_src).strict();
+        let info = parser.parse_value_ref()?;
+        if !parser.violations().is_empty() {
+            tracing::warn!(
+                "The info dict isn't canonically encoded: {:?}",
+                parser.violations()
+            );
+        }
+        let info = info.get_dict().map_err(|e| e.at(0))?;
 
-        let info = torrent.expect("info")?.get_dict()?;
+        let piece_length = info.expect_with("piece length", BeValueRef::get_u32)?;
+        let private = info.try_get("private", BeValueRef::get_u64)? == Some(1);
 
-        let piece_length = info.expect("piece length")?.get_u32()?;
-        let info_hash = {
-            let info_slice = &src[info.src_range.clone()];
-            Self::sha1(info_slice)
+        let v2 = match info.try_get("meta version", BeValueRef::get_u64)? {
+            None => false,
+            Some(2) => true,
+            Some(version) => return Err(MiErr::UnsupportedVersion(version)),
         };
 
-        let file_entries = Self::parse_files(info)?;
+        // Hybrid torrents contain both the v1 and the v2 hashes
+        let piece_hashes = match v2 {
+            true => info.try_get("pieces", BeValueRef::get_str)?,
+            false => Some(info.expect_with("pieces", BeValueRef::get_str)?),
+        };
+        let has_v1 = piece_hashes.is_some();
+
+        let (file_entries, merkle_pieces) = match (v2, piece_layers) {
+            (true, Some(layers)) => Self::parse_file_tree(info, piece_length, layers)?,
+            (true, None) if !has_v1 => {
+                Self::parse_file_tree(info, piece_length, &PieceLayers::new())?
+            }
+            _ => (Self::parse_files(info)?, Vec::new()),
+        };
+        check_collisions(file_entries.as_slice().iter().map(|f| f.path.as_slice()))?;
+
         let total_length = match &file_entries {
             TorrentFileEntries::Single(f) => f.len,
             TorrentFileEntries::Multi(mf) => mf.file_entries.iter().map(|f| f.len).sum(),
         };
 
-        let piece_hashes = info.expect("pieces")?.get_str()?;
-
+        let piece_hashes = piece_hashes.unwrap_or_default();
         let len = piece_hashes.len();
         if len % 20 != 0 {
             return Err(MiErr::InvalidHashesLen(len));
@@ -58,17 +170,31 @@ impl Metainfo {
             })
             .collect();
 
+        let info_hash_v2 = v2.then(|| Box::new(merkle::sha256(info_src)));
+        let info_hash = match &info_hash_v2 {
+            Some(v2_hash) if !has_v1 => {
+                let mut truncated = [0; 20];
+                truncated.copy_from_slice(&v2_hash[..20]);
+                Box::new(truncated)
+            }
+            _ => Self::sha1(info_src),
+        };
+
         let mi = Metainfo {
             trackers,
+            web_seeds: Vec::new(),
             info_hash,
+            info_hash_v2,
             piece_length,
             total_length,
             file_entries,
             piece_hashes,
+            merkle_pieces,
+            private,
         };
 
         // Validate hash count
-        if mi.piece_hashes.len() != mi.piece_count() as usize {
+        if has_v1 && mi.piece_hashes.len() != mi.piece_count() as usize {
             return Err(MiErr::InvalidHashesLen(len));
         }
 
@@ -76,102 +202,262 @@ impl Metainfo {
     }
 
     // announce      = single URL
-    // announce-list = list of lists URLs
-    // url-list      = list of URLs
-    fn parse_trackers(torrent: &mut Dict) -> Result<Vec<String>, MiErr> {
-        let mut trackers = torrent
-            .try_get("announce", BeValue::get_str_utf8)?
-            .and_then(|a| Some(vec![a]))
-            .unwrap_or(vec![]);
-
-        let announce_list = torrent.try_get_ref_mut("announce-list", BeValue::get_list)?;
-        if let Some(announce_list) = announce_list {
-            for l in announce_list {
-                let url_list = l.get_list()?;
-
-                for url in url_list {
-                    trackers.push(url.get_str_utf8()?);
-                }
-            }
-        }
+    // announce-list = list of tiers, which are lists of URLs (BEP 12)
+    fn parse_trackers(
+        announce: Option<String>,
+        announce_list: Option<Vec<Vec<String>>>,
+    ) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = announce_list
+            .unwrap_or_default()
+            .into_iter()
+            .map(|tier| tier.into_iter().filter(|url| !url.is_empty()).collect())
+            .filter(|tier: &Vec<String>| !tier.is_empty())
+            .collect();
 
-        // TODO: some torrents do not use 'url-list' for tracker URLs, but rather for file URLs
-        let url_list = torrent.try_get_ref_mut("url-list", BeValue::get_list)?;
-        if let Some(url_list) = url_list {
-            for url in url_list {
-                let url = url.get_str_utf8()?;
-                trackers.push(url);
-            }
+        // 'announce' is only a fallback for clients that don't support 'announce-list'
+        match (tiers.is_empty(), announce) {
+            (true, Some(announce)) if !announce.is_empty() => vec![vec![announce]],
+            _ => tiers,
         }
+    }
+
+    fn parse_web_seeds(url_list: Option<UrlList>) -> Vec<String> {
+        let web_seeds = match url_list {
+            Some(UrlList::Single(url)) => vec![url],
+            Some(UrlList::Multi(urls)) => urls,
+            None => Vec::new(),
+        };
 
-        Ok(trackers)
+        // Empty strings are sometimes used in place of an empty list
+        web_seeds
+            .into_iter()
+            .filter(|url| !url.is_empty())
+            .collect()
     }
 
-    fn parse_files(info: &mut Dict) -> Result<TorrentFileEntries, MiErr> {
+    fn parse_files(info: &DictRef) -> Result<TorrentFileEntries, MiErr> {
         match info.contains("files") {
             // Multi-file
             true => {
-                let dir_name = info.expect("name")?.get_str_utf8()?;
-                let file_list = info.expect("files")?.get_list()?;
+                let orig_dir_name = info.expect_with("name", Self::get_string)?;
+                let dir_name = sanitize_name(orig_dir_name.clone())?;
+                let file_list = info.expect_with("files", |files| {
+                    files.map_list(|file| {
+                        let file = file.get_dict()?;
 
-                let mut file_offset = 0;
+                        let len = file.expect_with("length", BeValueRef::get_u64)?;
 
-                let mut file_entries = Vec::new();
-                for file in file_list {
-                    let file = file.get_dict()?;
+                        let path =
+                            file.expect_with("path", |path| path.map_list(Self::get_string))?;
 
-                    let len = file.expect("length")?.get_u64()?;
+                        Ok((path, len))
+                    })
+                })?;
 
-                    let path = file.expect("path")?.get_list()?;
-                    if path.len() > 1 {
-                        unimplemented!("Multi-part file paths are unimplemented");
-                    }
+                let mut file_offset: u64 = 0;
 
-                    let path = match path.get_mut(0) {
-                        Some(p) => p.get_str_utf8()?,
-                        None => return Err(MiErr::InvalidFilePath),
-                    };
+                let mut file_entries = Vec::new();
+                for (orig_path, len) in file_list {
+                    let path = sanitize_path(orig_path.clone())?;
+                    let end = file_offset.checked_add(len).ok_or(MiErr::LengthOverflow)?;
 
                     let file_entry = FileEntry {
-                        name: path,
+                        path,
+                        orig_path,
                         len,
                         start: file_offset,
-                        end: file_offset + len - 1,
+                        // Zero-length files don't contain any bytes, the end is irrelevant
+                        end: end.saturating_sub(1),
                     };
                     file_entries.push(file_entry);
 
-                    file_offset += len;
+                    file_offset = end;
                 }
 
                 Ok(TorrentFileEntries::Multi(MultiFile {
                     dir_name,
+                    orig_dir_name,
                     file_entries,
                 }))
             }
             // Single-file
             false => {
-                let name = info.expect("name")?.get_str_utf8()?;
-                let len = info.expect("length")?.get_u64()?;
+                let orig_name = info.expect_with("name", Self::get_string)?;
+                let name = sanitize_name(orig_name.clone())?;
+                let len = info.expect_with("length", BeValueRef::get_u64)?;
 
-                return Ok(TorrentFileEntries::Single(FileEntry {
-                    name,
+                Ok(TorrentFileEntries::Single(FileEntry {
+                    path: vec![name],
+                    orig_path: vec![orig_name],
                     len,
                     start: 0,
                     end: len,
-                }));
+                }))
+            }
+        }
+    }
+
+    /// Parses the 'file tree' of v2 torrents, files are aligned to piece boundaries.
+    /// The piece layers are verified against the 'pieces root' of each file.
+    fn parse_file_tree(
+        info: &DictRef,
+        piece_length: u32,
+        piece_layers: &PieceLayers,
+    ) -> MiResult<(TorrentFileEntries, Vec<MerklePiece>)> {
+        if !piece_length.is_power_of_two() || (piece_length as usize) < MERKLE_BLOCK_LEN {
+            return Err(MiErr::InvalidPieceLength(piece_length));
+        }
+
+        let orig_name = info.expect_with("name", Self::get_string)?;
+        let name = sanitize_name(orig_name.clone())?;
+        let tree_files = info.expect_with("file tree", |tree| {
+            let mut files = Vec::new();
+            Self::walk_file_tree(tree, &mut Vec::new(), &mut files)?;
+            Ok(files)
+        })?;
+
+        let single_file = tree_files.len() == 1 && tree_files[0].path.len() == 1;
+        let blocks_per_piece = piece_length as usize / MERKLE_BLOCK_LEN;
+
+        let mut file_offset: u64 = 0;
+        let mut file_entries = Vec::new();
+        let mut merkle_pieces = Vec::new();
+
+        for TreeFile {
+            path: orig_path,
+            len,
+            pieces_root,
+        } in tree_files
+        {
+            let path = sanitize_path(orig_path.clone())?;
+            let end = file_offset.checked_add(len).ok_or(MiErr::LengthOverflow)?;
+            let piece_count = len.div_ceil(u64::from(piece_length));
+
+            if len > 0 {
+                let invalid_root = || MiErr::InvalidPiecesRoot(path.join("/"));
+                let root: Sha256Hash = pieces_root
+                    .and_then(|r| r.try_into().ok())
+                    .ok_or_else(invalid_root)?;
+
+                if piece_count == 1 {
+                    let block_count = (len as usize).div_ceil(MERKLE_BLOCK_LEN);
+
+                    merkle_pieces.push(MerklePiece {
+                        root,
+                        len: len as u32,
+                        leaf_count: block_count.next_power_of_two() as u32,
+                    });
+                } else {
+                    let layer = piece_layers
+                        .get(&root[..])
+                        .filter(|l| l.len() as u64 == piece_count * 32)
+                        .ok_or_else(|| MiErr::InvalidPieceLayer(path.join("/")))?;
+
+                    let hashes: Vec<Sha256Hash> = layer
+                        .chunks(32)
+                        .map(|h| {
+                            let mut a = [0; 32];
+                            a.copy_from_slice(h);
+                            a
+                        })
+                        .collect();
+
+                    let width = hashes.len().next_power_of_two();
+                    if merkle::merkle_root(&hashes, width, merkle::pad_hash(blocks_per_piece))
+                        != Some(root)
+                    {
+                        return Err(MiErr::InvalidPieceLayer(path.join("/")));
+                    }
+
+                    for (i, hash) in hashes.into_iter().enumerate() {
+                        let remaining = len - i as u64 * u64::from(piece_length);
+
+                        merkle_pieces.push(MerklePiece {
+                            root: hash,
+                            len: remaining.min(u64::from(piece_length)) as u32,
+                            leaf_count: blocks_per_piece as u32,
+                        });
+                    }
+                }
             }
+
+            file_entries.push(FileEntry {
+                path,
+                orig_path,
+                len,
+                start: file_offset,
+                // Zero-length files don't contain any bytes, the end is irrelevant
+                end: end.saturating_sub(1),
+            });
+
+            // The next file starts at a piece boundary
+            file_offset = piece_count
+                .checked_mul(u64::from(piece_length))
+                .and_then(|aligned_len| file_offset.checked_add(aligned_len))
+                .ok_or(MiErr::LengthOverflow)?;
+        }
+
+        let file_entries = match single_file {
+            // UNWRAP: checked by single_file
+            true => TorrentFileEntries::Single(file_entries.pop().unwrap()),
+            false => TorrentFileEntries::Multi(MultiFile {
+                dir_name: name,
+                orig_dir_name: orig_name,
+                file_entries,
+            }),
+        };
+
+        Ok((file_entries, merkle_pieces))
+    }
+
+    /// Directories are dictionaries keyed by the path components,
+    /// files are dictionaries with a single empty key
+    fn walk_file_tree<'a>(
+        node: &BeValueRef<'a>,
+        path: &mut Vec<String>,
+        files: &mut Vec<TreeFile<'a>>,
+    ) -> ReponseParseResult<()> {
+        let dir = node.get_dict()?;
+
+        for (name, _) in dir.iter() {
+            dir.expect_with(name, |child| {
+                if name.is_empty() {
+                    let file = child.get_dict()?;
+
+                    files.push(TreeFile {
+                        path: path.clone(),
+                        len: file.expect_with("length", BeValueRef::get_u64)?,
+                        pieces_root: file.try_get("pieces root", BeValueRef::get_str)?,
+                    });
+                } else {
+                    path.push(std::str::from_utf8(name)?.to_string());
+                    Self::walk_file_tree(child, path, files)?;
+                    path.pop();
+                }
+
+                Ok(())
+            })?;
         }
+
+        Ok(())
     }
 
     /// The number of pieces is 0-indexed !
     pub fn piece_count(&self) -> u32 {
+        // The files of v2 torrents are aligned, so the pieces can't be computed from the length
+        if !self.merkle_pieces.is_empty() {
+            return self.merkle_pieces.len() as u32;
+        }
+
         (self.total_length as f64 / self.piece_length as f64).ceil() as u32
     }
 
     /// Returns the piece size for a specific piece
     pub fn get_piece_size(&self, piece: PieceId) -> u32 {
-        if piece != self.piece_count() - 1 {
-            self.piece_length as u32
+        if let Some(mp) = self.merkle_pieces.get(piece as usize) {
+            mp.len
+        } else if piece != self.piece_count() - 1 {
+            self.piece_length
         } else {
             let prev_pieces_len = (self.piece_count() - 1) * self.piece_length;
             self.total_length as u32 - prev_pieces_len
@@ -185,15 +471,23 @@ impl Metainfo {
         let gen_arr = hasher.finalize();
         Box::new(gen_arr.into())
     }
+
+    /// File names outlive the source, so they are copied
+    fn get_string(value: &BeValueRef) -> ReponseParseResult<String> {
+        value.get_str_utf8().map(str::to_string)
+    }
 }
 
 impl std::fmt::Debug for Metainfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Metainfo")
             .field("announce", &self.trackers)
+            .field("web_seeds", &self.web_seeds)
             .field("info_hash", &self.info_hash)
+            .field("info_hash_v2", &self.info_hash_v2)
             .field("piece_length", &self.piece_length)
             .field("files", &self.file_entries)
+            .field("private", &self.private)
             .field("piece_hashes", &format_args!("<piece hashes>"))
             .finish()
     }
@@ -205,6 +499,13 @@ pub enum TorrentFileEntries {
     Multi(MultiFile),
 }
 
+impl FileEntry {
+    /// Path of the file relative to the download directory
+    pub fn rel_path(&self) -> PathBuf {
+        self.path.iter().collect()
+    }
+}
+
 impl TorrentFileEntries {
     pub fn as_slice(&self) -> &[FileEntry] {
         match self {
@@ -217,12 +518,25 @@ impl TorrentFileEntries {
 #[derive(Debug)]
 pub struct MultiFile {
     pub dir_name: String,
+    /// The name before sanitizing, used in the web seed URLs
+    pub orig_dir_name: String,
     pub file_entries: Vec<FileEntry>,
 }
 
+/// File from the 'file tree' of a v2 torrent
+struct TreeFile<'a> {
+    path: Vec<String>,
+    len: u64,
+    /// Merkle root of the file, absent for empty files
+    pieces_root: Option<&'a [u8]>,
+}
+
 #[derive(Debug)]
 pub struct FileEntry {
-    pub name: String,
+    /// Path components, the last one is the filename
+    pub path: Vec<String>,
+    /// The path components before sanitizing, used in the web seed URLs
+    pub orig_path: Vec<String>,
     pub len: u64,
     pub start: u64,
     pub end: u64,
@@ -234,8 +548,423 @@ type MiResult<T> = Result<T, MiErr>;
 pub enum MiErr {
     #[error("{0}")]
     BeError(#[from] ResponseParseError),
+    #[error("Invalid bencode: {0}")]
+    DecodeError(#[from] BeDecodeErr),
+    #[error("Invalid torrent file: {0}")]
+    DeserializeError(#[from] BeDeserializeErr),
     #[error("Hashes length '{0}' should be a multiple of 20")]
     InvalidHashesLen(usize),
-    #[error("The 'info' dict contains an invalid file path")]
-    InvalidFilePath,
+    #[error("The 'info' dict contains an invalid file path '{0}': {1}")]
+    InvalidFilePath(String, &'static str),
+    #[error("The total length of the files overflows")]
+    LengthOverflow,
+    #[error("Unsupported 'meta version': '{0}'")]
+    UnsupportedVersion(u64),
+    #[error("Piece length '{0}' of a v2 torrent has to be a power of two of at least 16 KiB")]
+    InvalidPieceLength(u32),
+    #[error("The file '{0}' has an invalid 'pieces root'")]
+    InvalidPiecesRoot(String),
+    #[error("The piece layer of the file '{0}' is missing or doesn't match its 'pieces root'")]
+    InvalidPieceLayer(String),
+}
+
+#[cfg(test)]
+mod test_metainfo {
+    use crate::bencoding::bevalue::{BeValue, Dict};
+
+    use super::*;
+
+    /// Builds a multi-file torrent with a piece length of 16 bytes
+    fn multi_file_torrent(name: &str, files: &[(&[&str], i64)]) -> Vec<u8> {
+        let total_len = files
+            .iter()
+            .map(|(_, len)| *len)
+            .fold(0, i64::saturating_add);
+        // The hashes aren't validated for lengths that can't be parsed
+        let piece_count = (total_len.clamp(0, 1 << 20) as usize).div_ceil(16);
+
+        let files = files
+            .iter()
+            .map(|(path, len)| {
+                let path = path.iter().map(|c| BeValue::Str(c.as_bytes().to_vec()));
+
+                let mut file = Dict::default();
+                file.insert("length", BeValue::Int(*len));
+                file.insert("path", BeValue::List(path.collect()));
+                BeValue::Dict(file)
+            })
+            .collect();
+
+        let mut info = Dict::default();
+        info.insert("files", BeValue::List(files));
+        info.insert("name", BeValue::Str(name.as_bytes().to_vec()));
+        info.insert("piece length", BeValue::Int(16));
+        info.insert("pieces", BeValue::Str(vec![0; piece_count * 20]));
+
+        let mut torrent = Dict::default();
+        torrent.insert("info", BeValue::Dict(info));
+        BeValue::Dict(torrent).to_bytes()
+    }
+
+    fn parse(src: &[u8]) -> MiResult<Metainfo> {
+        Metainfo::from_src(src)
+    }
+
+    const V2_PIECE_LEN: usize = 32768;
+
+    /// Builds the info dict of a v2 torrent with a piece length of 32 KiB,
+    /// returns it with the piece layers
+    fn v2_info(files: &[(&[&str], &[u8])]) -> (Dict, Dict) {
+        let blocks_per_piece = V2_PIECE_LEN / MERKLE_BLOCK_LEN;
+
+        let mut tree = Dict::default();
+        let mut piece_layers = Dict::default();
+
+        for (path, data) in files {
+            let mut file = Dict::default();
+            file.insert("length", BeValue::Int(data.len() as i64));
+
+            let piece_hashes: Vec<Sha256Hash> = data
+                .chunks(V2_PIECE_LEN)
+                .map(|piece| {
+                    let leaves: Vec<_> =
+                        piece.chunks(MERKLE_BLOCK_LEN).map(merkle::sha256).collect();
+                    let width = match data.len() > V2_PIECE_LEN {
+                        true => blocks_per_piece,
+                        false => leaves.len().next_power_of_two(),
+                    };
+                    merkle::merkle_root(&leaves, width, [0; 32]).unwrap()
+                })
+                .collect();
+
+            if data.len() > V2_PIECE_LEN {
+                let width = piece_hashes.len().next_power_of_two();
+                let pad = merkle::pad_hash(blocks_per_piece);
+                let root = merkle::merkle_root(&piece_hashes, width, pad).unwrap();
+
+                piece_layers.insert(root.to_vec(), BeValue::Str(piece_hashes.concat()));
+                file.insert("pieces root", BeValue::Str(root.to_vec()));
+            } else if let Some(root) = piece_hashes.first() {
+                file.insert("pieces root", BeValue::Str(root.to_vec()));
+            }
+
+            let mut node = Dict::default();
+            node.insert("", BeValue::Dict(file));
+            insert_tree_node(&mut tree, path, node);
+        }
+
+        let mut info = Dict::default();
+        info.insert("file tree", BeValue::Dict(tree));
+        info.insert("meta version", BeValue::Int(2));
+        info.insert("name", BeValue::Str(b"dir".to_vec()));
+        info.insert("piece length", BeValue::Int(V2_PIECE_LEN as i64));
+
+        (info, piece_layers)
+    }
+
+    fn insert_tree_node(tree: &mut Dict, path: &[&str], node: Dict) {
+        match path {
+            [name] => {
+                tree.insert(*name, BeValue::Dict(node));
+            }
+            [dir, rest @ ..] => {
+                if tree.get_mut(*dir).is_none() {
+                    tree.insert(*dir, BeValue::Dict(Dict::default()));
+                }
+
+                let subtree = match tree.get_mut(*dir) {
+                    Some(BeValue::Dict(subtree)) => subtree,
+                    _ => unreachable!(),
+                };
+                insert_tree_node(subtree, rest, node);
+            }
+            [] => unreachable!(),
+        }
+    }
+
+    /// The info dict is passed already encoded, so that its hashes can be computed
+    fn v2_torrent(info_src: &[u8], piece_layers: Dict) -> Vec<u8> {
+        let mut src = b"d4:info".to_vec();
+        src.extend_from_slice(info_src);
+        src.extend_from_slice(b"12:piece layers");
+        src.extend_from_slice(&BeValue::Dict(piece_layers).to_bytes());
+        src.push(b'e');
+        src
+    }
+
+    #[test]
+    fn test_error_location() {
+        let src = b"d4:infod5:filesld6:lengthi1e4:pathl1:aeed6:length1:24:pathl1:beee\
+            4:name3:dir12:piece lengthi16e6:pieces0:ee";
+        match Metainfo::from_src(src).unwrap_err() {
+            MiErr::BeError(e) => {
+                assert_eq!(e.path.to_string(), "info.files[1].length");
+                assert_eq!(e.pos, Some(49));
+            }
+            e => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn test_nested_paths() {
+        let src = b"d4:infod5:filesld6:lengthi10e4:pathl1:a5:b.txteed6:lengthi6e4:pathl1:c\
+            eee4:name3:dir12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let mi = Metainfo::from_src(src).unwrap();
+
+        let entries = mi.file_entries.as_slice();
+        assert_eq!(entries[0].path, ["a", "b.txt"]);
+        assert_eq!(entries[0].rel_path(), PathBuf::from("a/b.txt"));
+        assert_eq!((entries[1].start, entries[1].end), (10, 15));
+        assert_eq!(mi.total_length, 16);
+
+        let src = b"d4:infod5:filesld6:lengthi10e4:pathleee\
+            4:name3:dir12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(
+            Metainfo::from_src(src),
+            Err(MiErr::InvalidFilePath(..))
+        ));
+    }
+
+    #[test]
+    fn test_from_info_src() {
+        let info = b"d6:lengthi20e4:name4:file12:piece lengthi16e6:pieces40:\
+            aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe";
+        let mut src = b"d4:info".to_vec();
+        src.extend_from_slice(info);
+        src.push(b'e');
+
+        let trackers = vec![vec!["udp://tracker.example:1337/announce".to_string()]];
+        let mi = Metainfo::from_info_src(info, trackers.clone()).unwrap();
+
+        assert_eq!(mi.info_hash, parse(&src).unwrap().info_hash);
+        assert_eq!(mi.trackers, trackers);
+        assert_eq!(mi.piece_count(), 2);
+
+        assert!(matches!(
+            Metainfo::from_info_src(b"d4:name", vec![]),
+            Err(MiErr::DecodeError(_))
+        ));
+        assert!(matches!(
+            Metainfo::from_info_src(b"d4:name4:filee", vec![]),
+            Err(MiErr::BeError(_))
+        ));
+    }
+
+    #[test]
+    fn test_tracker_tiers() {
+        let info = "4:infod6:lengthi20e4:name4:file12:piece lengthi16e6:pieces40:\
+            aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe";
+
+        // 'announce' is ignored if there is an 'announce-list', empty tiers are skipped
+        let src = format!("d8:announce1:a13:announce-listll1:b1:cel0:el1:dee{}e", info);
+        assert_eq!(
+            parse(src.as_bytes()).unwrap().trackers,
+            [vec!["b", "c"], vec!["d"]]
+        );
+
+        let src = format!("d8:announce1:a13:announce-listllee{}e", info);
+        assert_eq!(parse(src.as_bytes()).unwrap().trackers, [["a"]]);
+
+        let src = format!("d{}e", info);
+        assert!(parse(src.as_bytes()).unwrap().trackers.is_empty());
+    }
+
+    #[test]
+    fn test_web_seeds() {
+        let info = "4:infod6:lengthi20e4:name4:file12:piece lengthi16e6:pieces40:\
+            aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe";
+
+        let src = format!(
+            "d8:announce19:http://t.example/an{}8:url-listl17:http://a.example/0:ee",
+            info
+        );
+        let mi = parse(src.as_bytes()).unwrap();
+        assert_eq!(mi.trackers, [["http://t.example/an"]]);
+        assert_eq!(mi.web_seeds, ["http://a.example/"]);
+
+        let src = format!("d{}8:url-list17:http://b.example/e", info);
+        assert_eq!(
+            parse(src.as_bytes()).unwrap().web_seeds,
+            ["http://b.example/"]
+        );
+
+        let src = format!("d{}8:url-list0:e", info);
+        assert!(parse(src.as_bytes()).unwrap().web_seeds.is_empty());
+    }
+
+    #[test]
+    fn test_v2() {
+        let a: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        let b = vec![1; 100];
+        let files: [(&[&str], &[u8]); 3] = [(&["a"], &a), (&["c"], &[]), (&["dir", "b"], &b)];
+
+        let (info, piece_layers) = v2_info(&files);
+        let info_src = BeValue::Dict(info).to_bytes();
+        let mi = parse(&v2_torrent(&info_src, piece_layers)).unwrap();
+
+        let v2_hash = merkle::sha256(&info_src);
+        assert_eq!(mi.info_hash_v2.as_deref(), Some(&v2_hash));
+        assert_eq!(mi.info_hash[..], v2_hash[..20]);
+        assert_eq!(mi.total_length, 40100);
+
+        // Files are aligned to piece boundaries
+        let entries = mi.file_entries.as_slice();
+        assert_eq!(entries[0].path, ["a"]);
+        assert_eq!((entries[0].start, entries[0].end), (0, 39999));
+        assert_eq!(entries[1].path, ["c"]);
+        assert_eq!(entries[2].path, ["dir", "b"]);
+        assert_eq!((entries[2].start, entries[2].end), (65536, 65635));
+
+        assert_eq!(mi.piece_count(), 3);
+        assert_eq!(mi.get_piece_size(1), 40000 - 32768);
+        assert_eq!(mi.get_piece_size(2), 100);
+
+        assert!(mi.merkle_pieces[0].verify(&a[..V2_PIECE_LEN]));
+        assert!(mi.merkle_pieces[1].verify(&a[V2_PIECE_LEN..]));
+        assert!(mi.merkle_pieces[2].verify(&b));
+        assert!(!mi.merkle_pieces[2].verify(&a[..100]));
+    }
+
+    #[test]
+    fn test_hybrid() {
+        let a = vec![2; 40000];
+        let (mut info, piece_layers) = v2_info(&[(&["a"], &a)]);
+        info.insert("length", BeValue::Int(40000));
+        info.insert("pieces", BeValue::Str(vec![0; 40]));
+        let info_src = BeValue::Dict(info).to_bytes();
+
+        // v1 hash on the wire, v2 verification
+        let mi = parse(&v2_torrent(&info_src, piece_layers)).unwrap();
+        assert_eq!(mi.info_hash, Metainfo::sha1(&info_src));
+        assert_eq!(mi.info_hash_v2.as_deref(), Some(&merkle::sha256(&info_src)));
+        assert_eq!(mi.merkle_pieces.len(), 2);
+        assert!(matches!(mi.file_entries, TorrentFileEntries::Single(_)));
+
+        // Without the piece layers, only the v1 part can be used
+        let mi = Metainfo::from_info_src(&info_src, vec![]).unwrap();
+        assert!(mi.merkle_pieces.is_empty());
+        assert_eq!(mi.piece_hashes.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_v2() {
+        let a = vec![3; 40000];
+        let invalid = |info: Dict, piece_layers: Dict| {
+            parse(&v2_torrent(&BeValue::Dict(info).to_bytes(), piece_layers))
+        };
+
+        let (mut info, piece_layers) = v2_info(&[(&["a"], &a)]);
+        info.insert("piece length", BeValue::Int(20000));
+        assert!(matches!(
+            invalid(info, piece_layers),
+            Err(MiErr::InvalidPieceLength(20000))
+        ));
+
+        let (mut info, piece_layers) = v2_info(&[(&["a"], &a)]);
+        info.insert("meta version", BeValue::Int(3));
+        assert!(matches!(
+            invalid(info, piece_layers),
+            Err(MiErr::UnsupportedVersion(3))
+        ));
+
+        let (info, _) = v2_info(&[(&["a"], &a)]);
+        assert!(matches!(
+            invalid(info, Dict::default()),
+            Err(MiErr::InvalidPieceLayer(_))
+        ));
+
+        // The piece layer doesn't match the root
+        let (info, _) = v2_info(&[(&["a"], &a)]);
+        let (_, other_layers) = v2_info(&[(&["a"], &a[1..])]);
+        assert!(matches!(
+            invalid(info, other_layers),
+            Err(MiErr::InvalidPieceLayer(_))
+        ));
+
+        let (info, piece_layers) = v2_info(&[(&["a"], &[1]), (&["..", "b"], &[2])]);
+        assert!(matches!(
+            invalid(info, piece_layers),
+            Err(MiErr::InvalidFilePath(..))
+        ));
+
+        // Distinct keys of the file tree that are the same after sanitizing
+        let (info, piece_layers) = v2_info(&[(&["a?"], &[1]), (&["a_"], &[2])]);
+        assert!(matches!(
+            invalid(info, piece_layers),
+            Err(MiErr::InvalidFilePath(..))
+        ));
+
+        let src = b"d4:infod9:file treed1:ad0:d6:lengthi1eeee12:meta versioni2e\
+            4:name1:a12:piece lengthi16384eee";
+        assert!(matches!(parse(src), Err(MiErr::InvalidPiecesRoot(_))));
+
+        let src = b"d4:infod9:file treed1:ad0:d6:lengthi-1eeee12:meta versioni2e\
+            4:name1:a12:piece lengthi16384eee";
+        match parse(src) {
+            Err(MiErr::BeError(e)) => assert_eq!(e.path.to_string(), "info.file tree.a..length"),
+            _ => panic!("Expected an extraction error"),
+        }
+    }
+
+    #[test]
+    fn test_hostile_torrents() {
+        let invalid_path = |name: &str, path: &[&str]| {
+            let src = multi_file_torrent(name, &[(&["ok"], 16), (path, 16)]);
+            matches!(parse(&src), Err(MiErr::InvalidFilePath(..)))
+        };
+
+        assert!(invalid_path("dir", &["..", "..", ".bashrc"]));
+        assert!(invalid_path("dir", &["sub", "..", "..", "x"]));
+        assert!(invalid_path("dir", &["/etc", "passwd"]));
+        assert!(invalid_path("dir", &["sub/../../x"]));
+        assert!(invalid_path("dir", &["..\\x"]));
+        assert!(invalid_path("dir", &[]));
+        assert!(invalid_path("dir", &["", "."]));
+        assert!(invalid_path("..", &["x"]));
+        assert!(invalid_path("/tmp", &["x"]));
+        assert!(invalid_path("", &["x"]));
+        // Different paths that are the same after sanitizing
+        assert!(invalid_path("dir", &["ok "]));
+        assert!(invalid_path("dir", &["ok."]));
+        assert!(invalid_path("dir", &["...", "ok"]));
+        assert!(invalid_path("dir", &["ok", "x"]));
+        assert!(invalid_path("dir", &["ok"]));
+
+        let collide = |files: &[(&[&str], i64)]| {
+            let src = multi_file_torrent("dir", files);
+            matches!(parse(&src), Err(MiErr::InvalidFilePath(..)))
+        };
+        assert!(collide(&[(&["a?"], 16), (&["a_"], 16)]));
+        assert!(collide(&[(&["b."], 16), (&["b "], 16), (&["b"], 16)]));
+        assert!(collide(&[(&["CON"], 16), (&["_CON"], 16)]));
+        assert!(collide(&[
+            (&["..."], 16),
+            (&["x"], 16),
+            (&["...", "x"], 16)
+        ]));
+
+        // Lengths that overflow the offsets
+        let src = multi_file_torrent(
+            "dir",
+            &[(&["a"], i64::MAX), (&["b"], i64::MAX), (&["c"], 2)],
+        );
+        assert!(matches!(parse(&src), Err(MiErr::LengthOverflow)));
+
+        // Cosmetic problems are rewritten
+        let src = multi_file_torrent("dir:", &[(&["", "con", "a?"], 0), (&["b "], 20)]);
+        let mi = parse(&src).unwrap();
+        match &mi.file_entries {
+            TorrentFileEntries::Multi(mf) => {
+                assert_eq!(mf.dir_name, "dir_");
+                assert_eq!(mf.orig_dir_name, "dir:");
+            }
+            TorrentFileEntries::Single(_) => panic!("Expected a multi-file torrent"),
+        }
+
+        let entries = mi.file_entries.as_slice();
+        assert_eq!(entries[0].path, ["_con", "a_"]);
+        assert_eq!(entries[0].orig_path, ["", "con", "a?"]);
+        assert_eq!(entries[1].path, ["b"]);
+        assert_eq!((entries[1].start, entries[1].end), (0, 19));
+    }
 }
@@ -0,0 +1,478 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::bencoding::ser::{self, BeSerializeErr};
+
+/// Creates .torrent files from a local file or directory
+pub struct MetainfoBuilder {
+    path: PathBuf,
+    trackers: Vec<String>,
+    comment: Option<String>,
+    private: bool,
+    /// Picked based on the total length if not set
+    piece_length: Option<u32>,
+    /// Unix timestamp, the current time if not set
+    creation_date: Option<u64>,
+}
+
+impl MetainfoBuilder {
+    /// Smallest piece length that peers will request in whole blocks
+    const MIN_PIECE_LENGTH: u32 = 16384;
+    const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+    /// The automatic piece length aims for roughly this number of pieces
+    const TARGET_PIECE_COUNT: u64 = 1500;
+
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            trackers: Vec::new(),
+            comment: None,
+            private: false,
+            piece_length: None,
+            creation_date: None,
+        }
+    }
+
+    /// Adds a tracker URL, the first one is used as the 'announce' URL
+    pub fn tracker<S: Into<String>>(mut self, url: S) -> Self {
+        self.trackers.push(url.into());
+        self
+    }
+
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Private torrents should only get peers from their trackers (BEP 27)
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Has to be a power of two of at least 16 KiB
+    pub fn piece_length(mut self, piece_length: u32) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Unix timestamp, a fixed one makes the output reproducible
+    pub fn creation_date(mut self, timestamp: u64) -> Self {
+        self.creation_date = Some(timestamp);
+        self
+    }
+
+    /// Hashes the files and returns the canonically bencoded torrent.
+    /// Blocks on file IO.
+    pub fn build(self) -> BuildResult<Vec<u8>> {
+        let name = file_name(&self.path)?;
+        let files = Self::collect_files(&self.path)?;
+
+        let total_length: u64 = files.iter().map(|f| f.len).sum();
+        if total_length == 0 {
+            return Err(BuildErr::Empty);
+        }
+
+        let piece_length = match self.piece_length {
+            Some(pl) if pl.is_power_of_two() && pl >= Self::MIN_PIECE_LENGTH => pl,
+            Some(pl) => return Err(BuildErr::InvalidPieceLength(pl)),
+            None => Self::pick_piece_length(total_length),
+        };
+
+        tracing::info!(
+            "Hashing '{}' bytes in pieces of '{}' bytes",
+            total_length,
+            piece_length
+        );
+
+        let pieces = hash_pieces(&files, total_length, piece_length)?;
+
+        let single_file = files.len() == 1 && files[0].path.is_empty();
+        let info = InfoDict {
+            length: single_file.then_some(total_length),
+            files: (!single_file).then(|| {
+                files
+                    .into_iter()
+                    .map(|f| FileDict {
+                        length: f.len,
+                        path: f.path,
+                    })
+                    .collect()
+            }),
+            name,
+            piece_length,
+            pieces,
+            private: self.private.then_some(1),
+        };
+
+        let creation_date = self.creation_date.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+
+        let torrent = TorrentDict {
+            announce: self.trackers.first().cloned(),
+            announce_list: (self.trackers.len() > 1)
+                .then(|| self.trackers.iter().map(|t| vec![t.clone()]).collect()),
+            comment: self.comment,
+            created_by: concat!("learntorrent ", env!("CARGO_PKG_VERSION")),
+            creation_date,
+            info,
+        };
+
+        Ok(ser::to_bytes(&torrent)?)
+    }
+
+    /// Powers of two between 16 KiB and 16 MiB
+    fn pick_piece_length(total_length: u64) -> u32 {
+        let target = (total_length / Self::TARGET_PIECE_COUNT).next_power_of_two();
+        target.clamp(
+            u64::from(Self::MIN_PIECE_LENGTH),
+            u64::from(Self::MAX_PIECE_LENGTH),
+        ) as u32
+    }
+
+    /// Collects the files in a deterministic (sorted) order.
+    /// The paths are relative to the root, the path of a single file is empty.
+    fn collect_files(root: &Path) -> BuildResult<Vec<SourceFile>> {
+        let mut files = Vec::new();
+
+        if root.is_dir() {
+            Self::walk_dir(root, &mut Vec::new(), &mut files)?;
+        } else {
+            files.push(SourceFile {
+                abs_path: root.to_path_buf(),
+                path: Vec::new(),
+                len: root.metadata()?.len(),
+            });
+        }
+
+        Ok(files)
+    }
+
+    fn walk_dir(
+        dir: &Path,
+        path: &mut Vec<String>,
+        files: &mut Vec<SourceFile>,
+    ) -> BuildResult<()> {
+        let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let abs_path = entry.path();
+            path.push(file_name(&abs_path)?);
+
+            // Follows symlinks
+            let metadata = std::fs::metadata(&abs_path)?;
+            if metadata.is_dir() {
+                Self::walk_dir(&abs_path, path, files)?;
+            } else {
+                files.push(SourceFile {
+                    abs_path,
+                    path: path.clone(),
+                    len: metadata.len(),
+                });
+            }
+
+            path.pop();
+        }
+
+        Ok(())
+    }
+}
+
+fn file_name(path: &Path) -> BuildResult<String> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string())
+        .ok_or_else(|| BuildErr::InvalidFileName(path.to_path_buf()))
+}
+
+/// Hashes the pieces on all available cores, every thread hashes a contiguous range of pieces
+fn hash_pieces(files: &[SourceFile], total_length: u64, piece_length: u32) -> BuildResult<Vec<u8>> {
+    let piece_count = total_length.div_ceil(u64::from(piece_length));
+    let threads = thread::available_parallelism().map_or(1, |n| n.get() as u64);
+    let pieces_per_thread = piece_count.div_ceil(threads);
+
+    let ranges: Vec<(u64, u64)> = (0..piece_count)
+        .step_by(pieces_per_thread as usize)
+        .map(|first| (first, (first + pieces_per_thread).min(piece_count)))
+        .collect();
+
+    let hashes = thread::scope(|s| {
+        let handles: Vec<_> = ranges
+            .into_iter()
+            .map(|(first, end)| {
+                s.spawn(move || {
+                    let start = first * u64::from(piece_length);
+                    let end = (end * u64::from(piece_length)).min(total_length);
+                    hash_range(files, start, end, piece_length)
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("Internal error: a hashing thread panicked"))
+            .collect::<BuildResult<Vec<_>>>()
+    })?;
+
+    Ok(hashes.concat())
+}
+
+/// Hashes the pieces in the byte range of the concatenated files, start must be at a piece boundary
+fn hash_range(
+    files: &[SourceFile],
+    start: u64,
+    end: u64,
+    piece_length: u32,
+) -> BuildResult<Vec<u8>> {
+    let mut hashes = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length as usize);
+
+    let mut file_start = 0;
+    for file in files {
+        let file_end = file_start + file.len;
+
+        if file_end > start && file_start < end {
+            let read_start = start.max(file_start);
+            let read_end = end.min(file_end);
+
+            let mut f = File::open(&file.abs_path)?;
+            f.seek(SeekFrom::Start(read_start - file_start))?;
+            let mut f = f.take(read_end - read_start);
+
+            loop {
+                let missing = piece_length as usize - piece.len();
+                let read = (&mut f).take(missing as u64).read_to_end(&mut piece)?;
+
+                if piece.len() == piece_length as usize {
+                    hashes.extend_from_slice(&Sha1::digest(&piece));
+                    piece.clear();
+                }
+
+                if read == 0 {
+                    break;
+                }
+            }
+
+            if f.limit() > 0 {
+                return Err(BuildErr::FileChanged(file.abs_path.clone()));
+            }
+        }
+
+        file_start = file_end;
+    }
+
+    // The last piece of the torrent can be shorter
+    if !piece.is_empty() {
+        hashes.extend_from_slice(&Sha1::digest(&piece));
+    }
+
+    Ok(hashes)
+}
+
+struct SourceFile {
+    abs_path: PathBuf,
+    /// Path components relative to the root directory
+    path: Vec<String>,
+    len: u64,
+}
+
+#[derive(Serialize)]
+struct TorrentDict {
+    announce: Option<String>,
+    #[serde(rename = "announce-list")]
+    announce_list: Option<Vec<Vec<String>>>,
+    comment: Option<String>,
+    #[serde(rename = "created by")]
+    created_by: &'static str,
+    #[serde(rename = "creation date")]
+    creation_date: u64,
+    info: InfoDict,
+}
+
+#[derive(Serialize)]
+struct InfoDict {
+    /// Single-file mode
+    length: Option<u64>,
+    /// Multi-file mode
+    files: Option<Vec<FileDict>>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: u32,
+    #[serde(with = "serde_bytes")]
+    pieces: Vec<u8>,
+    private: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct FileDict {
+    length: u64,
+    path: Vec<String>,
+}
+
+type BuildResult<T> = Result<T, BuildErr>;
+
+#[derive(Error, Debug)]
+pub enum BuildErr {
+    #[error("IO error: '{0}'")]
+    Io(#[from] std::io::Error),
+    #[error("The file name of '{0}' isn't valid UTF-8")]
+    InvalidFileName(PathBuf),
+    #[error("The torrent doesn't contain any data")]
+    Empty,
+    #[error("Piece length '{0}' has to be a power of two of at least 16 KiB")]
+    InvalidPieceLength(u32),
+    #[error("The file '{0}' changed while it was being hashed")]
+    FileChanged(PathBuf),
+    #[error("Bencode encoding error: '{0}'")]
+    Encode(#[from] BeSerializeErr),
+}
+
+#[cfg(test)]
+mod test_builder {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        bencoding::{bevalue_ref::BeValueRef, BeParser},
+        metainfo::{Metainfo, TorrentFileEntries},
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("learntorrent-{}-{}", name, rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn parse(src: &[u8]) -> Metainfo {
+        Metainfo::from_src(src).unwrap()
+    }
+
+    #[test]
+    fn test_single_file() {
+        let dir = temp_dir("single");
+        let path = dir.join("file.bin");
+        let data: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        let src = MetainfoBuilder::new(&path)
+            .tracker("udp://tracker.example:1337/announce")
+            .tracker("http://tracker.example/announce")
+            .comment("test")
+            .private(true)
+            .piece_length(16384)
+            .creation_date(1234)
+            .build()
+            .unwrap();
+
+        // The encoding is canonical
+        assert_eq!(BeParser::parse_with(&src).unwrap().to_bytes(), src);
+
+        let mi = parse(&src);
+        assert_eq!(mi.total_length, 40000);
+        assert!(mi.private);
+        assert_eq!(mi.piece_hashes.len(), 3);
+        assert_eq!(mi.piece_hashes[2][..], Sha1::digest(&data[32768..])[..]);
+        assert_eq!(
+            mi.trackers,
+            [
+                ["udp://tracker.example:1337/announce"],
+                ["http://tracker.example/announce"]
+            ]
+        );
+        assert!(matches!(mi.file_entries, TorrentFileEntries::Single(_)));
+
+        let be = BeParser::new(&src).parse_value_ref().unwrap();
+        let torrent = be.get_dict().unwrap();
+        assert_eq!(
+            torrent.expect("creation date").unwrap(),
+            &BeValueRef::Int(1234)
+        );
+        assert_eq!(
+            torrent.expect("comment").unwrap(),
+            &BeValueRef::Str(b"test")
+        );
+        let info = torrent.expect("info").unwrap().get_dict().unwrap();
+        assert_eq!(info.expect("private").unwrap(), &BeValueRef::Int(1));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_directory() {
+        let dir = temp_dir("dir");
+        let root = dir.join("content");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("b.txt"), vec![1; 20000]).unwrap();
+        fs::write(root.join("a.txt"), vec![2; 100]).unwrap();
+        fs::write(root.join("sub").join("c.txt"), vec![3; 50000]).unwrap();
+        fs::write(root.join("empty"), []).unwrap();
+
+        let src = MetainfoBuilder::new(&root).build().unwrap();
+        let mi = parse(&src);
+
+        let entries = mi.file_entries.as_slice();
+        let paths: Vec<_> = entries.iter().map(|e| e.path.join("/")).collect();
+        assert_eq!(paths, ["a.txt", "b.txt", "empty", "sub/c.txt"]);
+        assert_eq!(mi.total_length, 70100);
+        assert_eq!(mi.piece_length, 16384);
+        assert!(mi.trackers.is_empty());
+
+        // Pieces span files
+        let mut data = vec![2; 100];
+        data.extend_from_slice(&[1; 20000]);
+        data.extend_from_slice(&[3; 50000]);
+        for (i, piece) in data.chunks(16384).enumerate() {
+            assert_eq!(mi.piece_hashes[i][..], Sha1::digest(piece)[..]);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid() {
+        let dir = temp_dir("invalid");
+        fs::write(dir.join("empty"), []).unwrap();
+
+        assert!(matches!(
+            MetainfoBuilder::new(&dir).build(),
+            Err(BuildErr::Empty)
+        ));
+        assert!(matches!(
+            MetainfoBuilder::new(dir.join("missing")).build(),
+            Err(BuildErr::Io(_))
+        ));
+
+        fs::write(dir.join("file"), [1]).unwrap();
+        assert!(matches!(
+            MetainfoBuilder::new(&dir).piece_length(20000).build(),
+            Err(BuildErr::InvalidPieceLength(20000))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_pick_piece_length() {
+        assert_eq!(MetainfoBuilder::pick_piece_length(1), 16384);
+        assert_eq!(MetainfoBuilder::pick_piece_length(1 << 30), 1 << 20);
+        assert_eq!(
+            MetainfoBuilder::pick_piece_length(1 << 50),
+            16 * 1024 * 1024
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use sha2::{Digest, Sha256};
+
+/// Leaves of the merkle trees are hashes of 16 KiB blocks (BEP 52)
+pub const MERKLE_BLOCK_LEN: usize = 16384;
+
+pub type Sha256Hash = [u8; 32];
+
+/// The expected root of the merkle subtree covering a single piece of a v2 torrent
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerklePiece {
+    pub root: Sha256Hash,
+    /// Number of bytes of the piece that belong to the file
+    pub len: u32,
+    /// Width of the subtree, the leaves past the end of the file are zeroed
+    pub leaf_count: u32,
+}
+
+impl MerklePiece {
+    pub fn verify(&self, data: &[u8]) -> bool {
+        if data.len() != self.len as usize {
+            return false;
+        }
+
+        let leaves: Vec<Sha256Hash> = data.chunks(MERKLE_BLOCK_LEN).map(sha256).collect();
+        merkle_root(&leaves, self.leaf_count as usize, [0; 32]) == Some(self.root)
+    }
+}
+
+pub fn sha256(src: &[u8]) -> Sha256Hash {
+    Sha256::digest(src).into()
+}
+
+/// Computes the root of a tree with 'width' leaves, the missing leaves are set to 'pad'.
+/// Returns None if the width isn't a power of two or the hashes don't fit.
+pub fn merkle_root(hashes: &[Sha256Hash], width: usize, pad: Sha256Hash) -> Option<Sha256Hash> {
+    if !width.is_power_of_two() || hashes.len() > width {
+        return None;
+    }
+
+    let mut layer = hashes.to_vec();
+    layer.resize(width, pad);
+
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(pair[0]);
+                hasher.update(pair[1]);
+                hasher.finalize().into()
+            })
+            .collect();
+    }
+
+    Some(layer[0])
+}
+
+/// Root of a subtree with 'width' zeroed leaves, used for padding the piece layer
+pub fn pad_hash(width: usize) -> Sha256Hash {
+    // UNWRAP: there are no hashes, so the width is the only condition
+    merkle_root(&[], width, [0; 32]).unwrap()
+}
+
+#[cfg(test)]
+mod test_merkle {
+    use super::*;
+
+    fn node(left: Sha256Hash, right: Sha256Hash) -> Sha256Hash {
+        let mut src = left.to_vec();
+        src.extend_from_slice(&right);
+        sha256(&src)
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let a = sha256(b"a");
+        let b = sha256(b"b");
+        let c = sha256(b"c");
+
+        assert_eq!(merkle_root(&[a], 1, [0; 32]), Some(a));
+        assert_eq!(merkle_root(&[a, b], 2, [0; 32]), Some(node(a, b)));
+        assert_eq!(
+            merkle_root(&[a, b, c], 4, [0; 32]),
+            Some(node(node(a, b), node(c, [0; 32])))
+        );
+        assert_eq!(merkle_root(&[a, b, c], 3, [0; 32]), None);
+        assert_eq!(merkle_root(&[a, b, c], 2, [0; 32]), None);
+
+        assert_eq!(pad_hash(2), node([0; 32], [0; 32]));
+    }
+
+    #[test]
+    fn test_verify_piece() {
+        let data = vec![7u8; MERKLE_BLOCK_LEN + 100];
+        let leaves = [
+            sha256(&data[..MERKLE_BLOCK_LEN]),
+            sha256(&data[MERKLE_BLOCK_LEN..]),
+        ];
+
+        let piece = MerklePiece {
+            root: node(node(leaves[0], leaves[1]), pad_hash(2)),
+            len: data.len() as u32,
+            leaf_count: 4,
+        };
+
+        assert!(piece.verify(&data));
+        assert!(!piece.verify(&data[1..]));
+
+        let mut corrupted = data.clone();
+        corrupted[5] = 0;
+        assert!(!piece.verify(&corrupted));
+    }
+}
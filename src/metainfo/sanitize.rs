@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+
+use super::MiErr;
+
+/// Maximum length of a single path component in bytes, common for most filesystems
+const MAX_COMPONENT_LEN: usize = 255;
+
+/// Names that can't be used for files on Windows, regardless of the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Validates the path of a file entry.
+/// Components that could escape the download directory are rejected,
+/// empty and '.' components are removed and the rest is rewritten to be a valid filename.
+pub fn sanitize_path(path: Vec<String>) -> Result<Vec<String>, MiErr> {
+    let mut sanitized = Vec::with_capacity(path.len());
+
+    for component in &path {
+        if let Some(c) = sanitize_component(component).map_err(|reason| invalid(&path, reason))? {
+            sanitized.push(c);
+        }
+    }
+
+    if sanitized.is_empty() {
+        return Err(invalid(&path, "the path is empty"));
+    }
+
+    Ok(sanitized)
+}
+
+/// Validates a name that has to be a single path component (the torrent or directory name)
+pub fn sanitize_name(name: String) -> Result<String, MiErr> {
+    match sanitize_component(&name) {
+        Ok(Some(n)) => Ok(n),
+        Ok(None) => Err(invalid(&[name], "the name is empty")),
+        Err(reason) => Err(invalid(&[name], reason)),
+    }
+}
+
+/// Rewriting isn't one-to-one, so the sanitized paths are checked for files
+/// that would overwrite each other or that would also have to be directories
+pub fn check_collisions<'a, I>(paths: I) -> Result<(), MiErr>
+where
+    I: IntoIterator<Item = &'a [String]>,
+{
+    let mut files = HashSet::new();
+    let mut dirs = HashSet::new();
+
+    for path in paths {
+        if dirs.contains(path) || !files.insert(path) {
+            return Err(invalid(path, "the path collides with another file"));
+        }
+
+        for end in 1..path.len() {
+            let dir = &path[..end];
+            if files.contains(dir) {
+                return Err(invalid(
+                    path,
+                    "a parent directory collides with another file",
+                ));
+            }
+            dirs.insert(dir);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns None if the component should be skipped
+fn sanitize_component(component: &str) -> Result<Option<String>, &'static str> {
+    match component {
+        "" | "." => return Ok(None),
+        ".." => return Err("parent directory components aren't allowed"),
+        _ => (),
+    }
+
+    if component.contains(['/', '\\']) {
+        return Err("components can't contain path separators");
+    }
+
+    if component.contains('\0') {
+        return Err("components can't contain NUL characters");
+    }
+
+    // Characters that are invalid on Windows (including drive letters) and control characters
+    let mut c: String = component
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows silently strips trailing dots and spaces
+    let trimmed_len = c.trim_end_matches(['.', ' ']).len();
+    c.truncate(trimmed_len);
+    if c.is_empty() {
+        return Ok(None);
+    }
+
+    let stem = c.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        c.insert(0, '_');
+    }
+
+    if c.len() > MAX_COMPONENT_LEN {
+        c = truncate_component(&c);
+    }
+
+    Ok(Some(c))
+}
+
+/// Shortens the component to MAX_COMPONENT_LEN bytes, keeping a short extension
+fn truncate_component(component: &str) -> String {
+    const MAX_EXTENSION_LEN: usize = 16;
+
+    let (stem, ext) = match component.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() < MAX_EXTENSION_LEN => (stem, ext),
+        _ => (component, ""),
+    };
+
+    let mut stem_len = if ext.is_empty() {
+        MAX_COMPONENT_LEN
+    } else {
+        MAX_COMPONENT_LEN - ext.len() - 1
+    };
+
+    while !stem.is_char_boundary(stem_len) {
+        stem_len -= 1;
+    }
+
+    if ext.is_empty() {
+        stem[..stem_len].to_string()
+    } else {
+        format!("{}.{}", &stem[..stem_len], ext)
+    }
+}
+
+fn invalid<S: AsRef<str>>(path: &[S], reason: &'static str) -> MiErr {
+    let path: Vec<&str> = path.iter().map(|c| c.as_ref()).collect();
+    MiErr::InvalidFilePath(path.join("/"), reason)
+}
+
+#[cfg(test)]
+mod test_sanitize {
+    use super::*;
+
+    fn path(components: &[&str]) -> Vec<String> {
+        components.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn test_valid_paths() {
+        let p = path(&["dir", "sub dir", "file.txt"]);
+        assert_eq!(sanitize_path(p.clone()).unwrap(), p);
+
+        let p = path(&["", "dir", ".", "file"]);
+        assert_eq!(sanitize_path(p).unwrap(), ["dir", "file"]);
+    }
+
+    #[test]
+    fn test_rejected_paths() {
+        sanitize_path(path(&[".."])).unwrap_err();
+        sanitize_path(path(&["dir", "..", "..", "etc", "passwd"])).unwrap_err();
+        sanitize_path(path(&["/etc/passwd"])).unwrap_err();
+        sanitize_path(path(&["dir/../../file"])).unwrap_err();
+        sanitize_path(path(&["..\\..\\file"])).unwrap_err();
+        sanitize_path(path(&["file\0.txt"])).unwrap_err();
+        sanitize_path(path(&[])).unwrap_err();
+        sanitize_path(path(&["", ".", "  "])).unwrap_err();
+
+        sanitize_name("..".to_string()).unwrap_err();
+        sanitize_name("/".to_string()).unwrap_err();
+        sanitize_name("".to_string()).unwrap_err();
+    }
+
+    #[test]
+    fn test_rewritten_paths() {
+        let p = path(&[
+            "C:",
+            "a<b>c?.txt",
+            "con",
+            "Lpt1.tar.gz",
+            "x\u{7}y",
+            "dots...",
+        ]);
+        assert_eq!(
+            sanitize_path(p).unwrap(),
+            ["C_", "a_b_c_.txt", "_con", "_Lpt1.tar.gz", "x_y", "dots"]
+        );
+
+        assert_eq!(sanitize_name("console".to_string()).unwrap(), "console");
+    }
+
+    #[test]
+    fn test_collisions() {
+        let collide = |paths: &[&[&str]]| {
+            let paths: Vec<Vec<String>> = paths
+                .iter()
+                .map(|p| sanitize_path(path(p)).unwrap())
+                .collect();
+            check_collisions(paths.iter().map(Vec::as_slice)).is_err()
+        };
+
+        assert!(!collide(&[&["a"], &["b"], &["dir", "a"], &["dir", "b"]]));
+        assert!(collide(&[&["a?"], &["a_"]]));
+        assert!(collide(&[&["b."], &["b "]]));
+        assert!(collide(&[&["b"], &["dir"], &["b."]]));
+        assert!(collide(&[&["CON"], &["_CON"]]));
+        assert!(collide(&[&["...", "x"], &["x"]]));
+        // A file and a directory with the same name
+        assert!(collide(&[&["a"], &["a", "b"]]));
+        assert!(collide(&[&["a", "b", "c"], &["a", "b"]]));
+        assert!(collide(&[&["a:", "b"], &["a_"]]));
+    }
+
+    #[test]
+    fn test_overlong_names() {
+        let long = "a".repeat(300);
+        let c = &sanitize_path(vec![long.clone()]).unwrap()[0];
+        assert_eq!(c.len(), MAX_COMPONENT_LEN);
+
+        let c = &sanitize_path(vec![format!("{}.mkv", long)]).unwrap()[0];
+        assert_eq!(c.len(), MAX_COMPONENT_LEN);
+        assert!(c.ends_with(".mkv"));
+
+        // Multi-byte characters can't be split
+        let c = &sanitize_path(vec!["ř".repeat(200)]).unwrap()[0];
+        assert_eq!(c.len(), 254);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{net::SocketAddrV4, sync::Arc, time::Duration};
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Duration};
 
 use bytes::BytesMut;
 use flume::{Receiver, Sender};
@@ -8,31 +8,71 @@ use thiserror::Error;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    sync::{oneshot, watch},
+    sync::{mpsc, oneshot, watch},
 };
 use tokio_util::codec::Framed;
 
 use crate::{
+    bencoding::{
+        de::{self, BeDeserializeErr},
+        ser::{self, BeSerializeErr},
+    },
     metainfo::Metainfo,
     p2p::piece_tracker::PieceTracker,
     piece_keeper::{PieceMsg, PmMsg, TaskId, TaskRegMsg, TorrentState},
+    stats::TransferStats,
     AppState,
 };
 
-use self::message::{Message, MessageCodec, MessageDecodeErr, MessageEncodeErr};
+use self::{
+    extension::{ExtHandshake, HANDSHAKE_ID, UT_PEX_ID},
+    message::{Message, MessageCodec, MessageDecodeErr, MessageEncodeErr},
+    pex::{PexMsg, PexState, PEX_INTERVAL},
+};
 
+mod extension;
 mod message;
+mod metadata;
+mod pex;
 mod piece_tracker;
+mod web_seed;
 
-pub use piece_tracker::{CompletedBlockRequest, ValidatedPiece, BLOCK_LEN};
+pub use metadata::MetadataFetcher;
+pub use piece_tracker::{CompletedBlockRequest, ValidatedPiece};
+pub use web_seed::WebSeed;
 
 type MsgStream = Framed<TcpStream, MessageCodec>;
 
+/// Address of a peer and its ID, if the source of the address knows it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerAddr {
+    pub socket_addr: SocketAddr,
+    pub peer_id: Option<[u8; 20]>,
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(socket_addr: SocketAddr) -> Self {
+        Self {
+            socket_addr,
+            peer_id: None,
+        }
+    }
+}
+
+/// Notifications from the peer tasks to the Tracker Manager
+#[derive(Debug)]
+pub enum SwarmMsg {
+    /// The handshake with the peer at the address succeeded
+    Connected(TaskId, SocketAddr),
+    /// Peers received through peer exchange
+    Pex(Vec<PeerAddr>),
+}
+
 pub struct Peer {
     /// ID of the task assigned by Piece Manager
     id: TaskId,
-    /// IPv4 address of the peer
-    socket_addr: SocketAddrV4,
+    /// Address of the peer, the peer ID is checked against the handshake if it's known
+    peer_addr: PeerAddr,
     /// The handshake shared for all peer connections
     handshake: Arc<Handshake>,
     /// Sender for communicating with Piece Manager
@@ -43,9 +83,15 @@ pub struct Peer {
     torrentstate_recv: watch::Receiver<TorrentState>,
     /// Receiver for AppState notification
     appstate_recv: watch::Receiver<AppState>,
+    /// Sender for communicating with the Tracker Manager
+    swarm_sender: mpsc::Sender<SwarmMsg>,
+    /// Addresses of all connected peers, published by the Tracker Manager
+    connected_recv: watch::Receiver<HashSet<SocketAddr>>,
 
     /// Torrent metainfo
     metainfo: Arc<Metainfo>,
+    /// Counters reported to the trackers
+    stats: Arc<TransferStats>,
 
     // Is this task choked by the peer ?
     am_choked: bool,
@@ -54,41 +100,59 @@ pub struct Peer {
 
     /// Holds information about the progress of the currently downloaded piece
     piece_tracker: Option<PieceTracker>,
+
+    /// The ID the peer wants to receive ut_pex messages under, None if it doesn't support PEX
+    peer_ut_pex: Option<u8>,
+    /// The peers sent to the peer through PEX
+    pex_state: PexState,
 }
 
 impl Peer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: TaskId,
-        socket_addr: SocketAddrV4,
+        peer_addr: PeerAddr,
         handshake: Arc<Handshake>,
         pm_sender: Sender<PmMsg>,
         piece_recv: Receiver<PieceMsg>,
         torrentstate_recv: watch::Receiver<TorrentState>,
         appstate_recv: watch::Receiver<AppState>,
+        swarm_sender: mpsc::Sender<SwarmMsg>,
+        connected_recv: watch::Receiver<HashSet<SocketAddr>>,
         metainfo: Arc<Metainfo>,
+        stats: Arc<TransferStats>,
     ) -> Self {
         Self {
             id,
-            socket_addr,
+            peer_addr,
             handshake,
             pm_sender,
             piece_recv,
             torrentstate_recv,
             appstate_recv,
+            swarm_sender,
+            connected_recv,
             metainfo,
+            stats,
             am_choked: true,
             am_interested: false,
             piece_tracker: None,
+            peer_ut_pex: None,
+            pex_state: PexState::default(),
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn create(
         id: TaskId,
-        socket_addr: SocketAddrV4,
+        peer_addr: PeerAddr,
         handshake: Arc<Handshake>,
         metainfo: Arc<Metainfo>,
+        stats: Arc<TransferStats>,
         pm_sender: Sender<PmMsg>,
         appstate_recv: watch::Receiver<AppState>,
+        swarm_sender: mpsc::Sender<SwarmMsg>,
+        connected_recv: watch::Receiver<HashSet<SocketAddr>>,
     ) -> Peer {
         // TODO: this will have to be a broadcast channel for the 'Have' messages...
         let (reg_sender, reg_recv) = oneshot::channel::<TaskRegMsg>();
@@ -106,13 +170,16 @@ impl Peer {
 
         Peer::new(
             id,
-            socket_addr,
+            peer_addr,
             handshake,
             pm_sender,
             task_reg_msg.piece_recv,
             task_reg_msg.torrentstate_recv,
             appstate_recv,
+            swarm_sender,
+            connected_recv,
             metainfo,
+            stats,
         )
     }
 
@@ -146,6 +213,8 @@ impl Peer {
         const TIMEOUT: u64 = 30;
 
         let mut msg_stream = self.setup_connection().await?;
+        // The first tick is immediate, before the peer could send its extension handshake
+        let mut pex_interval = tokio::time::interval(Duration::from_secs(PEX_INTERVAL));
 
         let mut first_message = true;
         loop {
@@ -166,10 +235,13 @@ impl Peer {
                         return Ok(());
                     }
                 }
+                _ = pex_interval.tick() => self.send_pex(&mut msg_stream).await?,
                 msg = tokio::time::timeout(Duration::from_secs(TIMEOUT), msg_stream.next()) => {
                     let m = msg.map_err(|_| PeerErr::Timeout)?.ok_or(PeerErr::Terminated)??;
+                    // Some clients send the extension handshake before the bitfield
+                    let extended = matches!(m, Message::Extended { .. });
                     self.on_msg_received(m, first_message).await?;
-                    first_message = false;
+                    first_message = first_message && extended;
                 }
             }
         }
@@ -187,12 +259,71 @@ impl Peer {
                 begin,
                 block,
             } => self.on_block_receive_msg(index, begin, block).await?,
+            Message::Extended { id, payload } => self.on_extended_msg(id, &payload).await?,
             m => tracing::warn!("Unimplmeneted message received: {:?}", m),
         }
 
         Ok(())
     }
 
+    /// Only PEX is used after the metadata is known
+    async fn on_extended_msg(&mut self, id: u8, payload: &[u8]) -> PeerResult<()> {
+        match id {
+            HANDSHAKE_ID => {
+                let ext_handshake: ExtHandshake = de::from_bytes(payload)?;
+                // Peers of private torrents can't be shared (BEP 27)
+                if !self.metainfo.private {
+                    self.peer_ut_pex = ext_handshake.ut_pex();
+                }
+            }
+            UT_PEX_ID if !self.metainfo.private => {
+                let pex_msg: PexMsg = de::from_bytes(payload)?;
+                let peers: Vec<PeerAddr> = pex_msg
+                    .added_peers()
+                    .into_iter()
+                    .map(PeerAddr::from)
+                    .collect();
+
+                tracing::debug!("Peer '{}' sent '{}' PEX peers", self.id, peers.len());
+
+                if !peers.is_empty() {
+                    self.swarm_sender
+                        .send(SwarmMsg::Pex(peers))
+                        .await
+                        .expect("Internal error: a peer task couldn't send PEX peers to the Tracker Manager");
+                }
+            }
+            id => tracing::trace!("Peer '{}' extended message '{}'", self.id, id),
+        }
+
+        Ok(())
+    }
+
+    /// Sends the changes of the connected peers if the peer supports PEX
+    async fn send_pex(&mut self, msg_stream: &mut MsgStream) -> PeerResult<()> {
+        let peer_ut_pex = match self.peer_ut_pex {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        // The borrow can't be held across the send
+        let pex_msg = self
+            .pex_state
+            .next_msg(&self.connected_recv.borrow(), self.peer_addr.socket_addr);
+
+        if let Some(pex_msg) = pex_msg {
+            let payload = BytesMut::from(&ser::to_bytes(&pex_msg)?[..]);
+            msg_stream
+                .send(Message::Extended {
+                    id: peer_ut_pex,
+                    payload,
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+
     fn on_unchoke_msg(&mut self) {
         tracing::debug!("Peer '{}' unchoked", self.id);
         self.am_choked = false;
@@ -285,6 +416,8 @@ impl Peer {
     ) -> PeerResult<()> {
         tracing::trace!("Task '{}' received a block", self.id);
 
+        self.stats.add_downloaded(block.len() as u64);
+
         if let Some(pt) = &mut self.piece_tracker {
             if index != pt.pid {
                 return Err(PeerErr::InvalidPieceReceived);
@@ -370,40 +503,75 @@ impl Peer {
         Ok(())
     }
 
-    // TODO: make TCP connection and handshake cancellable
     /// Set up a TCP connection, exchange and validate handshakes
     async fn setup_connection(&mut self) -> PeerResult<MsgStream> {
-        let mut stream = tokio::time::timeout(
-            std::time::Duration::from_secs(30),
-            TcpStream::connect(self.socket_addr),
-        )
-        .await
-        .map_err(|_| PeerErr::Timeout)??;
-
-        tracing::debug!(
-            "Successfull connection: '{:?}'. Sending a handshake.",
-            self.socket_addr
-        );
-
-        stream.write_all(&self.handshake.inner).await?;
+        let (mut msg_stream, peer_handshake) =
+            connect(self.peer_addr.socket_addr, &self.handshake).await?;
 
-        let mut peer_handshake = Handshake::new_empty();
-        tokio::time::timeout(
-            std::time::Duration::from_secs(30),
-            stream.read_exact(&mut peer_handshake.inner),
-        )
-        .await
-        .map_err(|_| PeerErr::Timeout)??;
-
-        let _peer_id = peer_handshake.validate(&peer_handshake)?;
-        let msg_stream = MsgStream::new(stream, MessageCodec);
+        // The peer ID has to match the one sent by the tracker (BEP 3)
+        if let Some(peer_id) = &self.peer_addr.peer_id {
+            if self.handshake.validate(&peer_handshake)? != peer_id {
+                return Err(PeerErr::InvalidHandshake);
+            }
+        }
 
-        tracing::debug!("Handshake with '{:?}' complete", self.socket_addr);
+        self.swarm_sender
+            .send(SwarmMsg::Connected(self.id, self.peer_addr.socket_addr))
+            .await
+            .expect("Internal error: a peer task couldn't send a 'connected' message to the Tracker Manager");
+
+        // Peers of private torrents can only come from the trackers (BEP 27)
+        if peer_handshake.supports_extensions() && !self.metainfo.private {
+            let payload = BytesMut::from(&ser::to_bytes(&ExtHandshake::pex())?[..]);
+            msg_stream
+                .send(Message::Extended {
+                    id: HANDSHAKE_ID,
+                    payload,
+                })
+                .await?;
+        }
 
         Ok(msg_stream)
     }
 }
 
+// TODO: make TCP connection and handshake cancellable
+/// Set up a TCP connection, exchange and validate handshakes.
+/// Returns the handshake of the peer.
+async fn connect(
+    socket_addr: SocketAddr,
+    handshake: &Handshake,
+) -> PeerResult<(MsgStream, Handshake)> {
+    let mut stream = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        TcpStream::connect(socket_addr),
+    )
+    .await
+    .map_err(|_| PeerErr::Timeout)??;
+
+    tracing::debug!(
+        "Successfull connection: '{:?}'. Sending a handshake.",
+        socket_addr
+    );
+
+    stream.write_all(&handshake.inner).await?;
+
+    let mut peer_handshake = Handshake::new_empty();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        stream.read_exact(&mut peer_handshake.inner),
+    )
+    .await
+    .map_err(|_| PeerErr::Timeout)??;
+
+    let _peer_id = handshake.validate(&peer_handshake)?;
+    let msg_stream = MsgStream::new(stream, MessageCodec);
+
+    tracing::debug!("Handshake with '{:?}' complete", socket_addr);
+
+    Ok((msg_stream, peer_handshake))
+}
+
 #[derive(Error, Debug)]
 pub enum PeerErr {
     #[error("TCP conenction error: '{0}'")]
@@ -428,6 +596,10 @@ pub enum PeerErr {
     InvalidMessage(#[from] MessageDecodeErr),
     #[error("Message encoding error: '{0}'")]
     MessageEncodeErr(#[from] MessageEncodeErr),
+    #[error("Invalid extension message: '{0}'")]
+    InvalidExtensionMsg(#[from] BeDeserializeErr),
+    #[error("Extension message encoding error: '{0}'")]
+    ExtensionEncodeErr(#[from] BeSerializeErr),
 }
 
 type PeerResult<T> = Result<T, PeerErr>;
@@ -438,14 +610,18 @@ pub struct Handshake {
 
 impl Handshake {
     const HANDSHAKE_LEN: usize = 68;
+    /// Reserved bit signalling support for the extension protocol (BEP 10)
+    const EXTENSION_PROTOCOL_BIT: (usize, u8) = (25, 0x10);
 
-    pub fn new(client_id: &[u8; 20], metainfo: &Metainfo) -> Self {
+    pub fn new(client_id: &[u8; 20], info_hash: &[u8; 20]) -> Self {
         let mut handshake = vec![0; Self::HANDSHAKE_LEN];
         handshake[0] = 0x13;
         handshake[1..20].copy_from_slice("BitTorrent protocol".as_bytes());
-        // Extensions, currently all 0
+        // Extensions
         handshake[20..28].fill(0);
-        handshake[28..48].copy_from_slice(&*metainfo.info_hash);
+        let (byte, mask) = Self::EXTENSION_PROTOCOL_BIT;
+        handshake[byte] |= mask;
+        handshake[28..48].copy_from_slice(info_hash);
         handshake[48..68].copy_from_slice(client_id);
 
         Handshake { inner: handshake }
@@ -457,12 +633,111 @@ impl Handshake {
         }
     }
 
-    /// Check if info hash matches, then returns the peer id
+    /// Check if the protocol and info hash match, then returns the peer id.
+    /// The reserved bytes can differ.
     pub fn validate<'p>(&self, peer: &'p Self) -> Result<&'p [u8], PeerErr> {
-        if self.inner[0..48] != peer.inner[0..48] {
+        if self.inner[0..20] != peer.inner[0..20] || self.inner[28..48] != peer.inner[28..48] {
             return Err(PeerErr::InvalidHandshake);
         }
 
         Ok(&peer.inner[48..68])
     }
+
+    pub fn supports_extensions(&self) -> bool {
+        let (byte, mask) = Self::EXTENSION_PROTOCOL_BIT;
+        self.inner[byte] & mask != 0
+    }
+}
+
+#[cfg(test)]
+mod test_handshake {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let ours = Handshake::new(&[1; 20], &[2; 20]);
+        assert!(ours.supports_extensions());
+
+        let mut theirs = Handshake::new(&[3; 20], &[2; 20]);
+        theirs.inner[20..28].fill(0);
+        assert!(!theirs.supports_extensions());
+        assert_eq!(ours.validate(&theirs).unwrap(), [3; 20]);
+
+        let other_torrent = Handshake::new(&[3; 20], &[4; 20]);
+        ours.validate(&other_torrent).unwrap_err();
+
+        let mut other_protocol = Handshake::new(&[3; 20], &[2; 20]);
+        other_protocol.inner[0] = 0x12;
+        ours.validate(&other_protocol).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod test_pex {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::metainfo::{FileEntry, TorrentFileEntries};
+
+    fn peer(private: bool) -> Peer {
+        let metainfo = Metainfo {
+            trackers: vec![],
+            web_seeds: vec![],
+            info_hash: Box::new([0; 20]),
+            info_hash_v2: None,
+            piece_length: 16384,
+            total_length: 16384,
+            file_entries: TorrentFileEntries::Single(FileEntry {
+                path: vec!["a".to_string()],
+                orig_path: vec!["a".to_string()],
+                len: 16384,
+                start: 0,
+                end: 16384,
+            }),
+            piece_hashes: vec![[0; 20]],
+            merkle_pieces: vec![],
+            private,
+        };
+
+        let (pm_sender, _) = flume::unbounded();
+        let (_, piece_recv) = flume::unbounded();
+        let (_, torrentstate_recv) = watch::channel(TorrentState::InProgress);
+        let (_, appstate_recv) = watch::channel(AppState::Running);
+        let (swarm_sender, _) = mpsc::channel(1);
+        let (_, connected_recv) = watch::channel(HashSet::new());
+
+        Peer::new(
+            0,
+            PeerAddr::from(SocketAddr::from((Ipv4Addr::LOCALHOST, 6881))),
+            Arc::new(Handshake::new(&[1; 20], &[0; 20])),
+            pm_sender,
+            piece_recv,
+            torrentstate_recv,
+            appstate_recv,
+            swarm_sender,
+            connected_recv,
+            Arc::new(metainfo),
+            Arc::new(TransferStats::new(16384)),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_private_torrent() {
+        let payload = ser::to_bytes(&ExtHandshake::pex()).unwrap();
+
+        let mut public = peer(false);
+        public
+            .on_extended_msg(HANDSHAKE_ID, &payload)
+            .await
+            .unwrap();
+        assert_eq!(public.peer_ut_pex, Some(UT_PEX_ID));
+
+        // Nothing is ever sent to the peers of private torrents
+        let mut private = peer(true);
+        private
+            .on_extended_msg(HANDSHAKE_ID, &payload)
+            .await
+            .unwrap();
+        assert_eq!(private.peer_ut_pex, None);
+    }
 }
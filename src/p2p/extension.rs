@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+/// Extended message ID of the extension handshake, the IDs of the other messages are assigned in it
+pub const HANDSHAKE_ID: u8 = 0;
+/// The ID under which we want to receive ut_metadata messages
+pub const UT_METADATA_ID: u8 = 1;
+/// The ID under which we want to receive ut_pex messages
+pub const UT_PEX_ID: u8 = 2;
+
+/// The first extended message, announces the supported extensions (BEP 10)
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ExtHandshake {
+    /// Supported extensions
+    #[serde(default)]
+    pub m: ExtMessageIds,
+    /// Size of the info dictionary in bytes (BEP 9)
+    pub metadata_size: Option<u64>,
+}
+
+/// Extended message IDs, an ID of 0 means that the extension is disabled
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ExtMessageIds {
+    pub ut_metadata: Option<u8>,
+    pub ut_pex: Option<u8>,
+}
+
+impl ExtHandshake {
+    /// The handshake of the metadata downloads
+    pub fn new() -> Self {
+        Self {
+            m: ExtMessageIds {
+                ut_metadata: Some(UT_METADATA_ID),
+                ut_pex: None,
+            },
+            metadata_size: None,
+        }
+    }
+
+    /// The handshake of the peer sessions, which only exchange peers
+    pub fn pex() -> Self {
+        Self {
+            m: ExtMessageIds {
+                ut_metadata: None,
+                ut_pex: Some(UT_PEX_ID),
+            },
+            metadata_size: None,
+        }
+    }
+
+    /// Returns the ID the peer wants to receive ut_metadata messages under
+    pub fn ut_metadata(&self) -> Option<u8> {
+        self.m.ut_metadata.filter(|id| *id != 0)
+    }
+
+    /// Returns the ID the peer wants to receive ut_pex messages under
+    pub fn ut_pex(&self) -> Option<u8> {
+        self.m.ut_pex.filter(|id| *id != 0)
+    }
+}
+
+#[cfg(test)]
+mod test_extension {
+    use super::*;
+    use crate::bencoding::{de, ser};
+
+    #[test]
+    fn test_handshake() {
+        let encoded = ser::to_bytes(&ExtHandshake::new()).unwrap();
+        assert_eq!(encoded, b"d1:md11:ut_metadatai1eee");
+        let encoded = ser::to_bytes(&ExtHandshake::pex()).unwrap();
+        assert_eq!(encoded, b"d1:md6:ut_pexi2eee");
+
+        let src = b"d1:md11:LT_metadatai1e6:ut_pexi2e11:ut_metadatai3ee13:metadata_sizei31235e\
+            1:v14:uTorrent 3.5.5e";
+        let hs: ExtHandshake = de::from_bytes(src).unwrap();
+        assert_eq!(hs.ut_metadata(), Some(3));
+        assert_eq!(hs.ut_pex(), Some(2));
+        assert_eq!(hs.metadata_size, Some(31235));
+
+        let hs: ExtHandshake = de::from_bytes(b"d1:md11:ut_metadatai0eee").unwrap();
+        assert_eq!(hs.ut_metadata(), None);
+        assert_eq!(hs.ut_pex(), None);
+
+        let hs: ExtHandshake = de::from_bytes(b"de").unwrap();
+        assert_eq!(hs, ExtHandshake::default());
+    }
+}
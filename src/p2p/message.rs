@@ -31,6 +31,8 @@ pub enum Message {
     },
     /// cancel: <len=0013><id=8>\<index>\<begin>\<length>
     Cancel { index: u32, begin: u32, len: u32 },
+    /// extended: <len=0002+X><id=20>\<extended message ID>\<payload> (BEP 10)
+    Extended { id: u8, payload: BytesMut },
 }
 
 /// 1 megabyte
@@ -143,6 +145,12 @@ impl Decoder for MessageCodec {
                     }
                     // Port
                     (3, 9) => Err(MessageDecodeErr::PortUnimplemented),
+                    // Extended
+                    (len, 20) if len > 1 => {
+                        let id = src.get_u8();
+                        let payload = src.split_to(len - 2);
+                        Ok(Some(Message::Extended { id, payload }))
+                    }
                     (len, id) => Err(MessageDecodeErr::InvalidMessage(len as u32, id)),
                 }
             }
@@ -218,6 +226,13 @@ impl Encoder<Message> for MessageCodec {
                 put_u32(begin, dst);
                 put_u32(len, dst);
             }
+            Message::Extended { id, payload } => {
+                let len = 2 + payload.len();
+                put_u32(len as u32, dst);
+                put_u8(20, dst);
+                put_u8(id, dst);
+                dst.extend_from_slice(&payload);
+            }
         };
 
         Ok(())
@@ -248,7 +263,7 @@ mod test_message {
         let not_interested = Message::NotInterested;
         let have = Message::Have(162534);
 
-        let bitfield = vec![1u8, 6, 2, 5, 3, 4];
+        let bitfield = [1u8, 6, 2, 5, 3, 4];
         let bitfield = Message::Bitfield(BytesMut::from(&bitfield[..]));
 
         let request = Message::Request {
@@ -257,7 +272,7 @@ mod test_message {
             len: 789,
         };
 
-        let block = vec![1u8, 2, 3, 4, 5];
+        let block = [1u8, 2, 3, 4, 5];
         let piece = Message::Piece {
             index: 123,
             begin: 456,
@@ -270,6 +285,11 @@ mod test_message {
             len: 789,
         };
 
+        let extended = Message::Extended {
+            id: 0,
+            payload: BytesMut::from(&b"d1:md11:ut_metadatai1eee"[..]),
+        };
+
         for m in [
             keep_alive,
             choke,
@@ -281,6 +301,7 @@ mod test_message {
             request,
             piece,
             cancel,
+            extended,
         ] {
             roundtrip(m);
         }
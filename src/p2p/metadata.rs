@@ -0,0 +1,348 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::BytesMut;
+use futures::{sink::SinkExt, stream::FuturesUnordered};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use super::{
+    connect,
+    extension::{ExtHandshake, HANDSHAKE_ID, UT_METADATA_ID},
+    message::Message,
+    Handshake, MsgStream, PeerErr,
+};
+use crate::bencoding::{
+    de::{self, BeDeserializeErr},
+    ser::{self, BeSerializeErr},
+    stream::BeStreamDecoder,
+    BeDecodeErr,
+};
+
+/// Metadata is requested in pieces of 16 KiB
+const METADATA_PIECE_LEN: usize = 16384;
+/// Bigger info dictionaries are most likely malicious
+const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Downloads the info dictionary of a torrent from peers with the ut_metadata extension (BEP 9)
+pub struct MetadataFetcher {
+    info_hash: [u8; 20],
+    /// The handshake shared for all peer connections
+    handshake: Arc<Handshake>,
+}
+
+impl MetadataFetcher {
+    const MAX_ACTIVE_PEERS: usize = 10;
+    /// Time limit for downloading the whole metadata from a single peer
+    const PEER_TIMEOUT: u64 = 60;
+
+    pub fn new(info_hash: [u8; 20], client_id: &[u8; 20]) -> Self {
+        Self {
+            info_hash,
+            handshake: Arc::new(Handshake::new(client_id, &info_hash)),
+        }
+    }
+
+    /// Contacts the peers until one of them sends metadata matching the info hash,
+    /// returns the bencoded info dictionary
+    pub async fn fetch(&self, peers: Vec<SocketAddr>) -> MetadataResult<Vec<u8>> {
+        let mut peers = peers.into_iter();
+        let mut active = FuturesUnordered::new();
+
+        loop {
+            while active.len() < Self::MAX_ACTIVE_PEERS {
+                match peers.next() {
+                    Some(socket_addr) => active.push(async move {
+                        let res = tokio::time::timeout(
+                            Duration::from_secs(Self::PEER_TIMEOUT),
+                            self.fetch_from(socket_addr),
+                        )
+                        .await;
+
+                        (socket_addr, res.unwrap_or(Err(PeerErr::Timeout.into())))
+                    }),
+                    None => break,
+                }
+            }
+
+            match active.next().await {
+                Some((_, Ok(metadata))) => return Ok(metadata),
+                Some((socket_addr, Err(e))) => tracing::debug!(
+                    "Couldn't get the metadata from '{:?}': '{}'",
+                    socket_addr,
+                    e
+                ),
+                None => return Err(MetadataErr::NoPeersLeft),
+            }
+        }
+    }
+
+    async fn fetch_from(&self, socket_addr: SocketAddr) -> MetadataResult<Vec<u8>> {
+        let (mut msg_stream, peer_handshake) = connect(socket_addr, &self.handshake).await?;
+
+        if !peer_handshake.supports_extensions() {
+            return Err(MetadataErr::ExtensionsUnsupported);
+        }
+
+        let payload = ser::to_bytes(&ExtHandshake::new())?;
+        send_extended(&mut msg_stream, HANDSHAKE_ID, payload).await?;
+
+        let mut download = None;
+
+        loop {
+            let msg = msg_stream
+                .next()
+                .await
+                .ok_or(PeerErr::Terminated)?
+                .map_err(PeerErr::from)?;
+
+            match msg {
+                Message::Extended {
+                    id: HANDSHAKE_ID,
+                    payload,
+                } => {
+                    let ext_handshake: ExtHandshake = de::from_bytes(&payload)?;
+
+                    let peer_ut_metadata = ext_handshake
+                        .ut_metadata()
+                        .ok_or(MetadataErr::MetadataUnsupported)?;
+                    let size = ext_handshake
+                        .metadata_size
+                        .ok_or(MetadataErr::InvalidSize(0))?;
+
+                    let dl = MetadataDownload::new(self.info_hash, size)?;
+
+                    for piece in 0..dl.piece_count() {
+                        let payload = ser::to_bytes(&MetadataMsg::request(piece))?;
+                        send_extended(&mut msg_stream, peer_ut_metadata, payload).await?;
+                    }
+
+                    download = Some(dl);
+                }
+                Message::Extended {
+                    id: UT_METADATA_ID,
+                    payload,
+                } => {
+                    let dl = download.as_mut().ok_or(MetadataErr::UnexpectedMessage)?;
+
+                    if let Some(metadata) = dl.on_msg(&payload)? {
+                        tracing::info!("Received the metadata from '{:?}'", socket_addr);
+                        return Ok(metadata);
+                    }
+                }
+                // Other messages aren't important before the metadata is known
+                _ => (),
+            }
+        }
+    }
+}
+
+async fn send_extended(msg_stream: &mut MsgStream, id: u8, payload: Vec<u8>) -> MetadataResult<()> {
+    let payload = BytesMut::from(&payload[..]);
+    msg_stream
+        .send(Message::Extended { id, payload })
+        .await
+        .map_err(PeerErr::from)?;
+
+    Ok(())
+}
+
+/// Assembles the metadata pieces received from a single peer
+struct MetadataDownload {
+    info_hash: [u8; 20],
+    metadata: Vec<u8>,
+    received: Vec<bool>,
+}
+
+impl MetadataDownload {
+    fn new(info_hash: [u8; 20], size: u64) -> MetadataResult<Self> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(MetadataErr::InvalidSize(size));
+        }
+
+        let size = size as usize;
+        let piece_count = size.div_ceil(METADATA_PIECE_LEN);
+
+        Ok(Self {
+            info_hash,
+            metadata: vec![0; size],
+            received: vec![false; piece_count],
+        })
+    }
+
+    fn piece_count(&self) -> u32 {
+        self.received.len() as u32
+    }
+
+    /// Processes a ut_metadata message, returns the metadata once all pieces are received
+    fn on_msg(&mut self, payload: &[u8]) -> MetadataResult<Option<Vec<u8>>> {
+        // The dictionary of 'data' messages is followed by the piece
+        let dict_len = BeStreamDecoder::new()
+            .feed(payload)?
+            .ok_or(MetadataErr::UnexpectedMessage)?;
+        // Malformed messages are rejected, another peer can send the piece
+        let msg: MetadataMsg = de::from_bytes_strict(&payload[..dict_len])?;
+        let data = &payload[dict_len..];
+
+        match msg.msg_type {
+            MetadataMsg::DATA => (),
+            MetadataMsg::REJECT => return Err(MetadataErr::Rejected(msg.piece)),
+            // We don't have the metadata, requests can't be answered
+            _ => return Ok(None),
+        }
+
+        let piece = msg.piece as usize;
+        if piece >= self.received.len() {
+            return Err(MetadataErr::InvalidPiece(msg.piece));
+        }
+
+        let start = piece * METADATA_PIECE_LEN;
+        let end = (start + METADATA_PIECE_LEN).min(self.metadata.len());
+        if data.len() != end - start {
+            return Err(MetadataErr::InvalidPiece(msg.piece));
+        }
+
+        self.metadata[start..end].copy_from_slice(data);
+        self.received[piece] = true;
+
+        if !self.received.iter().all(|r| *r) {
+            return Ok(None);
+        }
+
+        let mut hasher = Sha1::new();
+        hasher.update(&self.metadata);
+        if hasher.finalize()[..] != self.info_hash {
+            return Err(MetadataErr::HashMismatch);
+        }
+
+        Ok(Some(std::mem::take(&mut self.metadata)))
+    }
+}
+
+/// ut_metadata message: {'msg_type': <type>, 'piece': <index>, 'total_size': <size>}
+#[derive(Serialize, Deserialize)]
+struct MetadataMsg {
+    msg_type: u8,
+    piece: u32,
+    /// Only present in 'data' messages
+    total_size: Option<u64>,
+}
+
+impl MetadataMsg {
+    const REQUEST: u8 = 0;
+    const DATA: u8 = 1;
+    const REJECT: u8 = 2;
+
+    fn request(piece: u32) -> Self {
+        Self {
+            msg_type: Self::REQUEST,
+            piece,
+            total_size: None,
+        }
+    }
+}
+
+type MetadataResult<T> = Result<T, MetadataErr>;
+
+#[derive(Error, Debug)]
+pub enum MetadataErr {
+    #[error("{0}")]
+    Peer(#[from] PeerErr),
+    #[error("The peer doesn't support the extension protocol")]
+    ExtensionsUnsupported,
+    #[error("The peer doesn't support the 'ut_metadata' extension")]
+    MetadataUnsupported,
+    #[error("Invalid metadata size: '{0}'")]
+    InvalidSize(u64),
+    #[error("Received an unexpected 'ut_metadata' message")]
+    UnexpectedMessage,
+    #[error("The peer rejected the request for metadata piece '{0}'")]
+    Rejected(u32),
+    #[error("Received an invalid metadata piece '{0}'")]
+    InvalidPiece(u32),
+    #[error("The metadata doesn't match the info hash")]
+    HashMismatch,
+    #[error("None of the peers sent the metadata")]
+    NoPeersLeft,
+
+    #[error("Invalid extension message: '{0}'")]
+    Decode(#[from] BeDecodeErr),
+    #[error("Invalid extension message: '{0}'")]
+    Deserialize(#[from] BeDeserializeErr),
+    #[error("Extension message encoding error: '{0}'")]
+    Serialize(#[from] BeSerializeErr),
+}
+
+#[cfg(test)]
+mod test_metadata {
+    use super::*;
+
+    fn data_msg(piece: u32, data: &[u8]) -> Vec<u8> {
+        let mut msg = format!("d8:msg_typei1e5:piecei{}e10:total_sizei20000ee", piece).into_bytes();
+        msg.extend_from_slice(data);
+        msg
+    }
+
+    fn metadata() -> (Vec<u8>, [u8; 20]) {
+        let metadata: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let mut hasher = Sha1::new();
+        hasher.update(&metadata);
+
+        (metadata, hasher.finalize().into())
+    }
+
+    #[test]
+    fn test_request() {
+        let msg = ser::to_bytes(&MetadataMsg::request(3)).unwrap();
+        assert_eq!(msg, b"d8:msg_typei0e5:piecei3ee");
+    }
+
+    #[test]
+    fn test_download() {
+        let (metadata, info_hash) = metadata();
+
+        let mut dl = MetadataDownload::new(info_hash, metadata.len() as u64).unwrap();
+        assert_eq!(dl.piece_count(), 2);
+
+        // Pieces can arrive in any order
+        let res = dl.on_msg(&data_msg(1, &metadata[16384..])).unwrap();
+        assert_eq!(res, None);
+        let res = dl.on_msg(&data_msg(0, &metadata[..16384])).unwrap();
+        assert_eq!(res, Some(metadata));
+    }
+
+    #[test]
+    fn test_invalid_download() {
+        let (metadata, info_hash) = metadata();
+
+        assert!(MetadataDownload::new(info_hash, 0).is_err());
+        assert!(MetadataDownload::new(info_hash, MAX_METADATA_SIZE + 1).is_err());
+
+        let mut dl = MetadataDownload::new(info_hash, metadata.len() as u64).unwrap();
+        assert!(matches!(
+            dl.on_msg(&data_msg(2, &metadata[..100])),
+            Err(MetadataErr::InvalidPiece(2))
+        ));
+        assert!(matches!(
+            dl.on_msg(&data_msg(1, &metadata[..100])),
+            Err(MetadataErr::InvalidPiece(1))
+        ));
+        assert!(matches!(
+            dl.on_msg(b"d8:msg_typei2e5:piecei0ee"),
+            Err(MetadataErr::Rejected(0))
+        ));
+        dl.on_msg(b"d8:msg_typei1e5:piecei0e").unwrap_err();
+        assert!(matches!(
+            dl.on_msg(b"d8:msg_typei2e5:piecei00ee"),
+            Err(MetadataErr::Deserialize(_))
+        ));
+
+        let mut dl = MetadataDownload::new([0; 20], metadata.len() as u64).unwrap();
+        dl.on_msg(&data_msg(0, &metadata[..16384])).unwrap();
+        assert!(matches!(
+            dl.on_msg(&data_msg(1, &metadata[16384..])),
+            Err(MetadataErr::HashMismatch)
+        ));
+    }
+}
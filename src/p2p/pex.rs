@@ -0,0 +1,169 @@
+use std::{collections::HashSet, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::tracker_manager::{
+    decode_compact_peers, encode_compact_peer, COMPACT_V4_LEN, COMPACT_V6_LEN,
+};
+
+/// PEX messages can't be sent more often than once a minute (BEP 11)
+pub const PEX_INTERVAL: u64 = 60;
+/// Maximum number of added and dropped peers in a single message
+const MAX_PEERS: usize = 50;
+/// The peer accepts incoming connections, we know because we connected to it
+const FLAG_REACHABLE: u8 = 0x10;
+
+/// The changes of the connected peers since the previous message (BEP 11)
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct PexMsg {
+    /// Compact IPv4 addresses
+    added: Option<ByteBuf>,
+    /// One byte of flags for every added IPv4 peer
+    #[serde(rename = "added.f")]
+    added_flags: Option<ByteBuf>,
+    dropped: Option<ByteBuf>,
+    /// Compact IPv6 addresses
+    added6: Option<ByteBuf>,
+    #[serde(rename = "added6.f")]
+    added6_flags: Option<ByteBuf>,
+    dropped6: Option<ByteBuf>,
+}
+
+impl PexMsg {
+    /// The added peers of both IP versions, at most MAX_PEERS of them
+    pub fn added_peers(&self) -> Vec<SocketAddr> {
+        let v4 = self.added.as_ref().map_or(&[][..], |a| &a[..]);
+        let v6 = self.added6.as_ref().map_or(&[][..], |a| &a[..]);
+
+        let mut peers = decode_compact_peers(v4, COMPACT_V4_LEN);
+        peers.extend(decode_compact_peers(v6, COMPACT_V6_LEN));
+        peers.truncate(MAX_PEERS);
+        peers
+    }
+}
+
+/// Remembers the peers that were sent to a single peer
+#[derive(Default)]
+pub struct PexState {
+    sent: HashSet<SocketAddr>,
+}
+
+impl PexState {
+    /// Creates the next message from the currently connected peers, except the receiving peer.
+    /// Returns None if nothing changed.
+    pub fn next_msg(
+        &mut self,
+        connected: &HashSet<SocketAddr>,
+        receiver: SocketAddr,
+    ) -> Option<PexMsg> {
+        let added: Vec<SocketAddr> = connected
+            .difference(&self.sent)
+            .filter(|addr| **addr != receiver)
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .difference(connected)
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        // The peers that didn't fit into this message are sent in the next one
+        self.sent.extend(&added);
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+
+        let mut msg = PexMsg::default();
+        for addr in &added {
+            let (peers, flags) = match addr {
+                SocketAddr::V4(_) => (&mut msg.added, &mut msg.added_flags),
+                SocketAddr::V6(_) => (&mut msg.added6, &mut msg.added6_flags),
+            };
+
+            encode_compact_peer(addr, peers.get_or_insert_with(ByteBuf::new));
+            flags.get_or_insert_with(ByteBuf::new).push(FLAG_REACHABLE);
+        }
+        for addr in &dropped {
+            let peers = match addr {
+                SocketAddr::V4(_) => &mut msg.dropped,
+                SocketAddr::V6(_) => &mut msg.dropped6,
+            };
+
+            encode_compact_peer(addr, peers.get_or_insert_with(ByteBuf::new));
+        }
+
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod test_pex {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::bencoding::{de, ser};
+
+    fn v4(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    #[test]
+    fn test_next_msg() {
+        let mut state = PexState::default();
+        let receiver = v4(1);
+
+        let connected = HashSet::from([receiver, v4(2)]);
+        let msg = state.next_msg(&connected, receiver).unwrap();
+        assert_eq!(
+            ser::to_bytes(&msg).unwrap(),
+            b"d5:added6:\x7f\x00\x00\x01\x00\x027:added.f1:\x10e"
+        );
+        assert_eq!(msg.added_peers(), [v4(2)]);
+
+        assert_eq!(state.next_msg(&connected, receiver), None);
+
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 3));
+        let connected = HashSet::from([receiver, v6]);
+        let msg = state.next_msg(&connected, receiver).unwrap();
+        assert_eq!(msg.added_peers(), [v6]);
+        assert_eq!(
+            msg.dropped,
+            Some(ByteBuf::from(b"\x7f\x00\x00\x01\x00\x02".to_vec()))
+        );
+
+        // Only MAX_PEERS peers fit into a message
+        let connected: HashSet<_> = (10..110).map(v4).collect();
+        let msg = state.next_msg(&connected, receiver).unwrap();
+        assert_eq!(msg.added_peers().len(), MAX_PEERS);
+        assert_eq!(msg.dropped6.as_ref().map(|d| d.len()), Some(COMPACT_V6_LEN));
+        let msg = state.next_msg(&connected, receiver).unwrap();
+        assert_eq!(msg.added_peers().len(), MAX_PEERS);
+        assert_eq!(msg.dropped6, None);
+        assert_eq!(state.next_msg(&connected, receiver), None);
+    }
+
+    #[test]
+    fn test_decode() {
+        let src = b"d5:added12:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe27:added.f2:\x10\x00\
+            6:added60:7:dropped0:8:dropped60:e";
+        let msg: PexMsg = de::from_bytes(src).unwrap();
+
+        assert_eq!(
+            msg.added_peers(),
+            [
+                SocketAddr::from(([10, 0, 0, 1], 6881)),
+                SocketAddr::from(([10, 0, 0, 2], 6882))
+            ]
+        );
+
+        let msg: PexMsg = de::from_bytes(b"de").unwrap();
+        assert!(msg.added_peers().is_empty());
+    }
+}
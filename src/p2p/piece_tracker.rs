@@ -38,6 +38,24 @@ impl PieceTracker {
         }
     }
 
+    /// Creates a tracker of a piece that was downloaded at once, e.g. from a web seed.
+    /// The data is split into blocks, so that it can be handled like a piece received from a peer.
+    pub fn from_data(piece_id: PieceId, mut data: BytesMut) -> Self {
+        let mut pt = Self::new(piece_id, data.len() as u32);
+
+        while !data.is_empty() {
+            let len = data.len().min(BLOCK_LEN as usize);
+            let block = data.split_to(len);
+
+            pt.completed_requests
+                .push(CompletedBlockRequest::new(pt.offset, len as u32, block));
+            pt.offset += len as u32;
+        }
+
+        pt.remaining_bytes = 0;
+        pt
+    }
+
     /// Calculates the offset and size of the next block
     pub fn next_pending_request(&mut self) -> Option<PendingBlockRequest> {
         let old_offset = self.offset;
@@ -93,31 +111,41 @@ impl PieceTracker {
         Ok(self.remaining_bytes == 0)
     }
 
-    /// Check the SHA1 sum of the piece and sort the blocks
+    /// Check the hash of the piece and sort the blocks.
+    /// v2 pieces are verified against their merkle root, v1 pieces against their SHA1 sum.
     pub fn validate_piece(
         mut self,
         metainfo: &Metainfo,
     ) -> Result<Option<ValidatedPiece>, PeerErr> {
-        let piece_hash = {
-            self.completed_requests
-                .sort_by(|a, b| a.offset.cmp(&b.offset));
-
-            // INVESTIGATE: spawn_blocking
-            let mut hasher = Sha1::new();
-            for b in &self.completed_requests {
-                hasher.update(&b.bytes);
+        self.completed_requests.sort_by_key(|b| b.offset);
+
+        // INVESTIGATE: spawn_blocking
+        let valid = match metainfo.merkle_pieces.get(self.pid as usize) {
+            Some(merkle_piece) => {
+                let mut piece = Vec::with_capacity(self.piece_size as usize);
+                for b in &self.completed_requests {
+                    piece.extend_from_slice(&b.bytes);
+                }
+
+                merkle_piece.verify(&piece)
             }
+            None => {
+                let mut hasher = Sha1::new();
+                for b in &self.completed_requests {
+                    hasher.update(&b.bytes);
+                }
 
-            hasher.finalize()
-        };
+                // The length of the metainfo hash string must have been validated,
+                // so it should contain all valid pieces
+                let expected_hash = metainfo.piece_hashes.get(self.pid as usize).expect(
+                    "Internal error: a peer task received an invalid piece ID from the Piece Keeper",
+                );
 
-        // The length of the metainfo hash string must have been validated,
-        // so it should contain all valid pieces
-        let expected_hash = metainfo.piece_hashes.get(self.pid as usize).expect(
-            "Internal error: a peer task received an invalid piece ID from the Piece Keeper",
-        );
+                expected_hash == hasher.finalize().as_slice()
+            }
+        };
 
-        if expected_hash == piece_hash.as_slice() {
+        if valid {
             Ok(Some(ValidatedPiece {
                 pid: self.pid,
                 blocks: self.completed_requests,
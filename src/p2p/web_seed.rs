@@ -0,0 +1,524 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::BytesMut;
+use flume::{Receiver, Sender};
+use reqwest::{
+    header::{ACCEPT_ENCODING, RANGE},
+    Client, StatusCode,
+};
+use thiserror::Error;
+use tokio::sync::{oneshot, watch};
+
+use super::{piece_tracker::PieceTracker, PeerErr};
+use crate::{
+    metainfo::{Metainfo, TorrentFileEntries},
+    piece_keeper::{PieceId, PieceMsg, PmMsg, TaskId, TaskRegMsg, TorrentState},
+    stats::TransferStats,
+    AppState,
+};
+
+/// Downloads pieces from an HTTP server hosting the torrent content (BEP 19).
+/// Registers with the Piece Keeper like a peer that has all of the pieces.
+pub struct WebSeed {
+    /// ID of the task assigned by Piece Manager
+    id: TaskId,
+    /// URL from the 'url-list' of the torrent
+    url: String,
+    /// HTTP client shared by all web seeds
+    client: Client,
+    /// Sender for communicating with Piece Manager
+    pm_sender: Sender<PmMsg>,
+    /// Receiver for communcating with Piece Manager
+    piece_recv: Receiver<PieceMsg>,
+    /// Receiver for broadcast notifications from the Piece Manager
+    torrentstate_recv: watch::Receiver<TorrentState>,
+    /// Receiver for AppState notification
+    appstate_recv: watch::Receiver<AppState>,
+
+    /// Torrent metainfo
+    metainfo: Arc<Metainfo>,
+    /// Counters reported to the trackers
+    stats: Arc<TransferStats>,
+}
+
+impl WebSeed {
+    /// How long to wait before asking for a piece again when none were available
+    const RETRY_INTERVAL: u64 = 5;
+    /// Number of retries of a piece after transient errors, before the web seed is given up
+    const MAX_RETRIES: u32 = 3;
+
+    pub async fn create(
+        id: TaskId,
+        url: String,
+        client: Client,
+        metainfo: Arc<Metainfo>,
+        stats: Arc<TransferStats>,
+        pm_sender: Sender<PmMsg>,
+        appstate_recv: watch::Receiver<AppState>,
+    ) -> Self {
+        let (reg_sender, reg_recv) = oneshot::channel::<TaskRegMsg>();
+
+        pm_sender
+            .send_async(PmMsg::Register(id, reg_sender))
+            .await
+            .expect(
+            "Internal error: a web seed task couldn't send a 'register' message to the Piece Keeper",
+        );
+
+        let task_reg_msg = reg_recv.await.expect(
+            "Internal error: a web seed task couldn't receive a 'TaskRegMsg' from the Piece Keeper",
+        );
+
+        Self {
+            id,
+            url,
+            client,
+            pm_sender,
+            piece_recv: task_reg_msg.piece_recv,
+            torrentstate_recv: task_reg_msg.torrentstate_recv,
+            appstate_recv,
+            metainfo,
+            stats,
+        }
+    }
+
+    pub async fn start(mut self) -> WebSeedResult<()> {
+        let res = self.process().await;
+
+        self.pm_sender
+            .send_async(PmMsg::Deregister(self.id))
+            .await
+            .expect(
+            "Internal error: a web seed task couldn't send a 'deregister' message to the Piece Keeper",
+        );
+
+        res
+    }
+
+    async fn process(&mut self) -> WebSeedResult<()> {
+        // The server has the whole content
+        let bitfield = full_bitfield(self.metainfo.piece_count());
+        self.pm_sender
+            .send_async(PmMsg::Bitfield(self.id, bitfield))
+            .await
+            .expect("Internal error: a web seed task couldn't send a 'bitfield' message to the Piece Keeper");
+
+        loop {
+            self.pm_sender
+                .send_async(PmMsg::Pick(self.id))
+                .await
+                .expect("Internal error: a web seed task couldn't send a 'piece pick' message to the Piece Keeper");
+            let piece_msg = self.piece_recv.recv_async().await
+                .expect("Internal error: a web seed task couldn't receive a 'piece pick' message from the Piece Keeper");
+
+            let pid = match piece_msg {
+                PieceMsg::Piece(pid) => pid,
+                // Other tasks are downloading the remaining pieces, but they might fail
+                PieceMsg::NoneAvailable => {
+                    tokio::select! {
+                        _ = stopped(&mut self.appstate_recv, &mut self.torrentstate_recv) => return Ok(()),
+                        _ = tokio::time::sleep(Duration::from_secs(Self::RETRY_INTERVAL)) => continue,
+                    }
+                }
+            };
+
+            tracing::debug!("Web seed '{}' picked piece '{}'", self.id, pid);
+
+            let res = tokio::select! {
+                _ = stopped(&mut self.appstate_recv, &mut self.torrentstate_recv) => return Ok(()),
+                res = download_with_retries(&self.client, &self.url, &self.metainfo, pid) => res,
+            };
+
+            let valid = match res {
+                Ok(data) => {
+                    self.stats.add_downloaded(data.len() as u64);
+                    PieceTracker::from_data(pid, data).validate_piece(&self.metainfo)?
+                }
+                Err(e) => {
+                    self.send_piece_failed(pid).await;
+                    return Err(e);
+                }
+            };
+
+            match valid {
+                Some(vp) => {
+                    tracing::debug!("Piece '{}' finished", pid);
+
+                    self.pm_sender.send_async(PmMsg::PieceFinished(vp)).await
+                        .expect("Internal error: a web seed task couldn't send a 'piece finished' message to the Piece Keeper");
+                }
+                // The content on the server is most likely different, so don't try again
+                None => {
+                    self.send_piece_failed(pid).await;
+                    return Err(WebSeedErr::InvalidPiece(pid));
+                }
+            }
+        }
+    }
+
+    /// The delay doubles after every failure
+    fn backoff(failures: u32) -> Duration {
+        let exp = failures.saturating_sub(1).min(Self::MAX_RETRIES);
+        Duration::from_secs(Self::RETRY_INTERVAL << exp)
+    }
+
+    async fn send_piece_failed(&self, pid: PieceId) {
+        self.pm_sender
+            .send_async(PmMsg::PieceFailed(pid))
+            .await
+            .expect("Internal error: a web seed task couldn't send a 'piece retry' message to the Piece Keeper");
+    }
+}
+
+/// Resolves once the app is exiting or all of the pieces have been downloaded
+async fn stopped(
+    appstate_recv: &mut watch::Receiver<AppState>,
+    torrentstate_recv: &mut watch::Receiver<TorrentState>,
+) {
+    loop {
+        tokio::select! {
+            Ok(_) = appstate_recv.changed() => {
+                if let AppState::Exit = *appstate_recv.borrow() {
+                    return;
+                }
+            }
+            Ok(_) = torrentstate_recv.changed() => {
+                if let TorrentState::Complete = *torrentstate_recv.borrow() {
+                    return;
+                }
+            }
+            else => std::future::pending::<()>().await,
+        }
+    }
+}
+
+/// Timeout of a single range request
+const REQUEST_TIMEOUT: u64 = 60;
+
+/// Timeouts and server errors are often temporary, so the piece is tried again
+async fn download_with_retries(
+    client: &Client,
+    url: &str,
+    metainfo: &Metainfo,
+    pid: PieceId,
+) -> WebSeedResult<BytesMut> {
+    let mut failures = 0;
+
+    loop {
+        match download_piece(client, url, metainfo, pid).await {
+            Err(e) if e.is_transient() && failures < WebSeed::MAX_RETRIES => {
+                failures += 1;
+
+                let backoff = WebSeed::backoff(failures);
+                tracing::debug!(
+                    "Web seed '{}' failed: '{}', retrying in '{:?}'",
+                    url,
+                    e,
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Fetches the piece with a range request for every file it overlaps
+async fn download_piece(
+    client: &Client,
+    url: &str,
+    metainfo: &Metainfo,
+    pid: PieceId,
+) -> WebSeedResult<BytesMut> {
+    let mut piece = BytesMut::with_capacity(metainfo.get_piece_size(pid) as usize);
+
+    for range in piece_ranges(metainfo, pid) {
+        let file_url = file_url(url, &metainfo.file_entries, range.file_index);
+        let last = range.offset + range.len - 1;
+
+        let mut response = client
+            .get(&file_url)
+            .header(RANGE, format!("bytes={}-{}", range.offset, last))
+            // The range has to refer to the file itself, not to its compressed form
+            .header(ACCEPT_ENCODING, "identity")
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT))
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => (),
+            // The server ignored the range, it would send the whole file for every piece
+            StatusCode::OK => return Err(WebSeedErr::RangesUnsupported(file_url)),
+            status => return Err(WebSeedErr::UnexpectedStatus(file_url, status)),
+        }
+
+        if let Some(len) = response.content_length() {
+            if len != range.len {
+                return Err(WebSeedErr::InvalidLength(file_url, len));
+            }
+        }
+
+        // The body is read in chunks, so a misbehaving server can't make us buffer more than the range
+        let mut received = 0;
+        while let Some(chunk) = response.chunk().await? {
+            received += chunk.len() as u64;
+            if received > range.len {
+                return Err(WebSeedErr::InvalidLength(file_url, received));
+            }
+
+            piece.extend_from_slice(&chunk);
+        }
+
+        if received != range.len {
+            return Err(WebSeedErr::InvalidLength(file_url, received));
+        }
+    }
+
+    Ok(piece)
+}
+
+/// Part of a piece that is stored in a single file
+#[derive(Debug, PartialEq)]
+struct FileRange {
+    /// Index into the file entries
+    file_index: usize,
+    /// Offset of the first byte in the file
+    offset: u64,
+    len: u64,
+}
+
+/// Maps the bytes of a piece to the files they belong to
+fn piece_ranges(metainfo: &Metainfo, pid: PieceId) -> Vec<FileRange> {
+    let piece_start = pid as u64 * metainfo.piece_length as u64;
+    let piece_end = piece_start + metainfo.get_piece_size(pid) as u64;
+
+    metainfo
+        .file_entries
+        .as_slice()
+        .iter()
+        .enumerate()
+        .filter_map(|(file_index, fe)| {
+            let start = fe.start.max(piece_start);
+            let end = (fe.start + fe.len).min(piece_end);
+
+            (start < end).then(|| FileRange {
+                file_index,
+                offset: start - fe.start,
+                len: end - start,
+            })
+        })
+        .collect()
+}
+
+/// The URL of a single-file torrent points to the file itself, or to its directory
+/// if it ends with a slash. The URL of a multi-file torrent points to the directory
+/// containing the torrent's root directory.
+/// The server uses the names from the torrent, not the sanitized ones.
+fn file_url(url: &str, file_entries: &TorrentFileEntries, file_index: usize) -> String {
+    match file_entries {
+        TorrentFileEntries::Single(fe) if url.ends_with('/') => {
+            format!("{}{}", url, urlencoding::encode(&fe.orig_path[0]))
+        }
+        TorrentFileEntries::Single(_) => url.to_string(),
+        TorrentFileEntries::Multi(mf) => {
+            let mut file_url = url.trim_end_matches('/').to_string();
+
+            let fe = &mf.file_entries[file_index];
+            for component in std::iter::once(&mf.orig_dir_name).chain(&fe.orig_path) {
+                file_url.push('/');
+                file_url.push_str(&urlencoding::encode(component));
+            }
+
+            file_url
+        }
+    }
+}
+
+/// Bitfield with all of the pieces available, the spare bits are zero
+fn full_bitfield(piece_count: u32) -> BytesMut {
+    let len = (piece_count as usize).div_ceil(8);
+    let mut bitfield = BytesMut::from(&vec![0xFF; len][..]);
+
+    let spare_bits = len as u32 * 8 - piece_count;
+    if let Some(last) = bitfield.last_mut() {
+        *last <<= spare_bits;
+    }
+
+    bitfield
+}
+
+type WebSeedResult<T> = Result<T, WebSeedErr>;
+
+#[derive(Error, Debug)]
+pub enum WebSeedErr {
+    #[error("HTTP error: '{0}'")]
+    Http(#[from] reqwest::Error),
+    #[error("Unexpected HTTP status for '{0}': '{1}'")]
+    UnexpectedStatus(String, StatusCode),
+    #[error("The server doesn't support range requests for '{0}'")]
+    RangesUnsupported(String),
+    #[error("The server sent an invalid amount of bytes for '{0}': '{1}'")]
+    InvalidLength(String, u64),
+    #[error("The server sent invalid data for piece '{0}'")]
+    InvalidPiece(PieceId),
+    #[error("{0}")]
+    Peer(#[from] PeerErr),
+}
+
+impl WebSeedErr {
+    /// Errors that can go away when the request is repeated
+    fn is_transient(&self) -> bool {
+        match self {
+            WebSeedErr::Http(_) => true,
+            WebSeedErr::UnexpectedStatus(_, status) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_web_seed {
+    use super::*;
+    use crate::metainfo::{FileEntry, MultiFile};
+
+    fn metainfo(piece_length: u32, file_entries: TorrentFileEntries) -> Metainfo {
+        let total_length = file_entries.as_slice().iter().map(|fe| fe.len).sum();
+
+        Metainfo {
+            trackers: vec![],
+            web_seeds: vec![],
+            info_hash: Box::new([0; 20]),
+            info_hash_v2: None,
+            piece_length,
+            total_length,
+            file_entries,
+            piece_hashes: vec![],
+            merkle_pieces: vec![],
+            private: false,
+        }
+    }
+
+    fn multi_file(files: &[(&[&str], u64)]) -> TorrentFileEntries {
+        let mut start = 0;
+        let file_entries = files
+            .iter()
+            .map(|(path, len)| {
+                let path: Vec<String> = path.iter().map(|c| c.to_string()).collect();
+                let fe = FileEntry {
+                    path: path.clone(),
+                    orig_path: path,
+                    len: *len,
+                    start,
+                    end: (start + len).saturating_sub(1),
+                };
+                start += len;
+                fe
+            })
+            .collect();
+
+        TorrentFileEntries::Multi(MultiFile {
+            dir_name: "dir".to_string(),
+            orig_dir_name: "dir".to_string(),
+            file_entries,
+        })
+    }
+
+    #[test]
+    fn test_file_url() {
+        let single = TorrentFileEntries::Single(FileEntry {
+            path: vec!["a b.iso".to_string()],
+            orig_path: vec!["a b.iso".to_string()],
+            len: 10,
+            start: 0,
+            end: 10,
+        });
+        assert_eq!(
+            file_url("http://a.example/files/", &single, 0),
+            "http://a.example/files/a%20b.iso"
+        );
+        assert_eq!(
+            file_url("http://a.example/x.iso", &single, 0),
+            "http://a.example/x.iso"
+        );
+
+        let multi = multi_file(&[(&["a"], 10), (&["sub", "b#1"], 10)]);
+        assert_eq!(
+            file_url("http://a.example/files", &multi, 1),
+            "http://a.example/files/dir/sub/b%231"
+        );
+        assert_eq!(
+            file_url("http://a.example/files/", &multi, 0),
+            "http://a.example/files/dir/a"
+        );
+
+        // The server doesn't know about the sanitized names
+        let mut sanitized = multi_file(&[(&["a_"], 10)]);
+        if let TorrentFileEntries::Multi(mf) = &mut sanitized {
+            mf.orig_dir_name = "dir:".to_string();
+            mf.file_entries[0].orig_path = vec!["a?".to_string()];
+        }
+        assert_eq!(
+            file_url("http://a.example", &sanitized, 0),
+            "http://a.example/dir%3A/a%3F"
+        );
+    }
+
+    #[test]
+    fn test_piece_ranges() {
+        let mi = metainfo(16, multi_file(&[(&["a"], 10), (&["b"], 0), (&["c"], 30)]));
+
+        assert_eq!(
+            piece_ranges(&mi, 0),
+            [
+                FileRange {
+                    file_index: 0,
+                    offset: 0,
+                    len: 10
+                },
+                FileRange {
+                    file_index: 2,
+                    offset: 0,
+                    len: 6
+                }
+            ]
+        );
+        assert_eq!(
+            piece_ranges(&mi, 2),
+            [FileRange {
+                file_index: 2,
+                offset: 22,
+                len: 8
+            }]
+        );
+    }
+
+    #[test]
+    fn test_retries() {
+        assert_eq!(WebSeed::backoff(1), Duration::from_secs(5));
+        assert_eq!(WebSeed::backoff(2), Duration::from_secs(10));
+        assert_eq!(WebSeed::backoff(3), Duration::from_secs(20));
+        assert_eq!(WebSeed::backoff(u32::MAX), Duration::from_secs(40));
+
+        let url = "http://a.example/x.iso".to_string();
+        assert!(
+            WebSeedErr::UnexpectedStatus(url.clone(), StatusCode::SERVICE_UNAVAILABLE)
+                .is_transient()
+        );
+        assert!(
+            WebSeedErr::UnexpectedStatus(url.clone(), StatusCode::TOO_MANY_REQUESTS).is_transient()
+        );
+        assert!(!WebSeedErr::UnexpectedStatus(url.clone(), StatusCode::NOT_FOUND).is_transient());
+        assert!(!WebSeedErr::RangesUnsupported(url.clone()).is_transient());
+        assert!(!WebSeedErr::InvalidLength(url, 10).is_transient());
+        assert!(!WebSeedErr::InvalidPiece(0).is_transient());
+    }
+
+    #[test]
+    fn test_full_bitfield() {
+        assert_eq!(full_bitfield(8)[..], [0xFF]);
+        assert_eq!(full_bitfield(11)[..], [0xFF, 0b1110_0000]);
+        assert!(full_bitfield(0).is_empty());
+    }
+}
@@ -1,11 +1,14 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+};
 
 use bytes::BytesMut;
 use flume::{Receiver, Sender};
 use rand::Rng;
 use tokio::sync::{mpsc, oneshot::Sender as OSender, watch};
 
-use crate::{io::IoMsg, p2p::ValidatedPiece, AppState};
+use crate::{io::IoMsg, p2p::ValidatedPiece, stats::TransferStats, AppState};
 
 use self::{bitfield::BitField, piece_list::PieceList};
 
@@ -41,6 +44,8 @@ pub struct PieceKeeper {
     notify_recv: watch::Receiver<TorrentState>,
     /// AppStates notification
     appstate_recv: watch::Receiver<AppState>,
+    /// The amount of remaining bytes is updated for the trackers
+    stats: Arc<TransferStats>,
     should_exit: bool,
 }
 
@@ -50,6 +55,7 @@ impl PieceKeeper {
         pm_recv: Receiver<PmMsg>,
         io_sender: mpsc::Sender<IoMsg>,
         num_pieces: u32,
+        stats: Arc<TransferStats>,
     ) -> Self {
         let (notify_sender, notify_recv) = watch::channel(TorrentState::InProgress);
 
@@ -66,10 +72,16 @@ impl PieceKeeper {
             notify_sender,
             notify_recv,
             appstate_recv,
+            stats,
             should_exit: false,
         }
     }
 
+    /// For tasks that aren't registered, but want to know when the torrent is complete
+    pub fn torrentstate_recv(&self) -> watch::Receiver<TorrentState> {
+        self.notify_recv.clone()
+    }
+
     pub async fn start(mut self) {
         loop {
             // Wait for the peer tasks to deregister
@@ -199,6 +211,9 @@ impl PieceKeeper {
 
         self.finished_pieces.insert(piece_msg.pid);
 
+        let piece_size: u64 = piece_msg.blocks.iter().map(|b| u64::from(b.size)).sum();
+        self.stats.piece_finished(piece_size);
+
         self.missing_pieces -= 1;
         // TODO(UI): move this somewhere else
         let ratio = if self.missing_pieces == 0 {
@@ -55,7 +55,7 @@ mod test_super {
 
     #[test]
     fn test_bitfield() {
-        let bf = vec![0b0000_1000, 0b1001_0001];
+        let bf = [0b0000_1000, 0b1001_0001];
         let bf = BytesMut::from(&bf[..]);
         let mut bf = BitField::new(bf);
 
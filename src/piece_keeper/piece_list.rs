@@ -126,12 +126,10 @@ impl PieceList {
 
             match (head_middle_continuous, middle_tail_continuous) {
                 (true, true) => {
-                    if head_middle_continuous && middle_tail_continuous {
-                        // Join all 3 blocks together
-                        self.blocks[i - 1] = (head_start, tail_end);
-                        self.blocks.remove(i + 1);
-                        self.blocks.remove(i);
-                    }
+                    // Join all 3 blocks together
+                    self.blocks[i - 1] = (head_start, tail_end);
+                    self.blocks.remove(i + 1);
+                    self.blocks.remove(i);
                 }
                 (true, false) => {
                     // Join previous and current blocks
@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Byte counters of the torrent, shared by the tasks and reported to the trackers
+#[derive(Debug)]
+pub struct TransferStats {
+    /// Nothing is uploaded yet, the peer tasks don't serve requests
+    uploaded: AtomicU64,
+    /// Payload bytes received from peers and web seeds, including pieces that failed validation
+    downloaded: AtomicU64,
+    /// Bytes of the pieces that haven't been downloaded and verified yet
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(total_length: u64) -> Self {
+        Self {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(total_length),
+        }
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Called by the Piece Keeper for every verified piece
+    pub fn piece_finished(&self, piece_size: u64) {
+        // The closure never returns None, so the update can't fail
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(piece_size))
+            });
+    }
+
+    pub fn snapshot(&self) -> Transfer {
+        Transfer {
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left: self.left.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The counters at a point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Transfer {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+#[cfg(test)]
+mod test_stats {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let stats = TransferStats::new(100);
+
+        stats.add_downloaded(40);
+        stats.add_downloaded(40);
+        stats.piece_finished(40);
+
+        assert_eq!(
+            stats.snapshot(),
+            Transfer {
+                uploaded: 0,
+                downloaded: 80,
+                left: 60
+            }
+        );
+
+        stats.piece_finished(80);
+        assert_eq!(stats.snapshot().left, 0);
+    }
+}
@@ -1,31 +1,39 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    net::{Ipv4Addr, SocketAddrV4},
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     time::Duration,
 };
 
 use thiserror::Error;
 use tokio::{
-    sync::{mpsc, watch},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        watch,
+    },
     task::JoinHandle,
+    time::Instant,
 };
 
 use crate::{
-    bencoding::{bevalue::ResponseParseError, BeDecodeErr},
+    bencoding::{de::BeDeserializeErr, stream::BeCodecErr},
+    dht::Dht,
     metainfo::Metainfo,
-    p2p::{Handshake, Peer},
-    piece_keeper::{PmMsg, TaskId},
+    p2p::{Handshake, Peer, PeerAddr, SwarmMsg, WebSeed},
+    piece_keeper::{PmMsg, TaskId, TorrentState},
+    stats::{Transfer, TransferStats},
     AppState,
 };
 
 use self::{
     http::HttpTracker,
+    tiers::TrackerTiers,
     udp::{TrackerMsgDecodeErr, UdpTracker},
 };
 
 mod http;
-mod udp;
+mod tiers;
+pub mod udp;
 
 /// The job of this object is to perdiocially resend the announce "request"
 /// and manage an adequate number of peers (30 right now)
@@ -34,15 +42,29 @@ pub struct TrackerManager {
     metainfo: Arc<Metainfo>,
     /// Our ID, unique for every run
     client_id: Arc<[u8; 20]>,
+    /// Counters reported to the trackers, passed to the tasks
+    stats: Arc<TransferStats>,
+    /// HTTP client for the HTTP trackers and the web seeds
+    client: reqwest::Client,
+    /// Another source of peers, unused for private torrents
+    dht: Option<Dht>,
 
     /// ID of the next peer task
     next_id: TaskId,
     /// Map for keeping track of active peer tasks
     active_peers: HashMap<TaskId, JoinHandle<()>>,
+    /// Map for keeping track of active web seed tasks
+    web_seeds: HashMap<TaskId, JoinHandle<()>>,
     /// Queue of uncontacted available peers
-    queued_peers: VecDeque<SocketAddrV4>,
-    // All received peers from all trackers, used for filtering duplicates
-    all_peers: HashSet<Ipv4Addr>,
+    queued_peers: VecDeque<PeerAddr>,
+    // All received peers from all trackers and PEX, used for filtering duplicates
+    all_peers: HashSet<SocketAddr>,
+    /// Addresses of the peers that completed the handshake
+    connected_peers: HashMap<TaskId, SocketAddr>,
+    /// Publishes the connected peers to the peer tasks for PEX
+    connected_sender: watch::Sender<HashSet<SocketAddr>>,
+    /// Passed to the peer tasks, keeping it also means that sending can't fail
+    connected_recv: watch::Receiver<HashSet<SocketAddr>>,
 
     // TODO: torrent_complete
     /// Received a notification from PieceManager that all pieces have been downloaded,
@@ -52,60 +74,83 @@ pub struct TrackerManager {
     /// PieceManagerMsg sender, passed to the tasks
     pm_sender: flume::Sender<PmMsg>,
 
+    /// Torrent completion notification from the Piece Keeper, passed to the tracker task
+    torrentstate_recv: watch::Receiver<TorrentState>,
     /// AppState notifications
     appstate_recv: watch::Receiver<AppState>,
     should_exit: bool,
 }
 
 impl TrackerManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         appstate_recv: watch::Receiver<AppState>,
+        torrentstate_recv: watch::Receiver<TorrentState>,
         pm_sender: flume::Sender<PmMsg>,
         metainfo: Arc<Metainfo>,
+        client_id: Arc<[u8; 20]>,
+        stats: Arc<TransferStats>,
+        client: reqwest::Client,
+        dht: Option<Dht>,
     ) -> Self {
-        let client_id = Self::gen_client_id();
+        let (connected_sender, connected_recv) = watch::channel(HashSet::new());
 
         Self {
             metainfo,
             client_id,
+            stats,
+            client,
+            dht,
             next_id: 0,
             active_peers: HashMap::new(),
+            web_seeds: HashMap::new(),
             queued_peers: VecDeque::new(),
             all_peers: HashSet::new(),
+            connected_peers: HashMap::new(),
+            connected_sender,
+            connected_recv,
             pm_sender,
+            torrentstate_recv,
             appstate_recv,
             should_exit: false,
         }
     }
 
     pub async fn start(mut self) -> TrResult<()> {
-        let total_trackers = self.metainfo.trackers.len();
-        // FIXME: trackers len can be 0
-        let (tracker_sender, mut tracker_recv) = mpsc::channel::<Vec<SocketAddrV4>>(total_trackers);
-        let tracker_tasks = self.spawn_trackers(&self.metainfo.trackers, tracker_sender);
+        let (tracker_sender, mut tracker_recv) = mpsc::channel::<Vec<PeerAddr>>(1);
+        let (peers_wanted_sender, peers_wanted_recv) = mpsc::channel::<()>(1);
+        let dht_task = self.spawn_dht(tracker_sender.clone());
+        let tracker_task = self.spawn_tracker(tracker_sender, peers_wanted_recv);
 
         let (completion_sender, mut completion_recv) =
             mpsc::channel::<TaskId>(Self::MAX_ACTIVE_TASKS);
+        let (swarm_sender, mut swarm_recv) = mpsc::channel::<SwarmMsg>(Self::MAX_ACTIVE_TASKS);
+        self.spawn_web_seeds(completion_sender.clone()).await;
 
-        let handshake = Arc::new(Handshake::new(&self.client_id, &self.metainfo));
+        let handshake = Arc::new(Handshake::new(&self.client_id, &self.metainfo.info_hash));
 
         loop {
-            if self.should_exit && self.active_peers.is_empty() {
-                for t in tracker_tasks {
-                    let res = t.await;
-
-                    if let Err(e) = res {
+            if self.should_exit && self.active_peers.is_empty() && self.web_seeds.is_empty() {
+                // Torrents with only web seeds don't have to contain any trackers
+                for t in [tracker_task, dht_task].into_iter().flatten() {
+                    if let Err(e) = t.await {
                         tracing::warn!("{}", e);
                     }
                 }
 
-                tracing::info!("Exiting - all peer tasks completed, all tracker tasks completed");
+                tracing::info!(
+                    "Exiting - all peer and web seed tasks completed, all tracker tasks completed"
+                );
 
                 return Ok(());
             }
 
-            self.queue_peer_tasks(completion_sender.clone(), handshake.clone())
-                .await?;
+            self.queue_peer_tasks(
+                completion_sender.clone(),
+                swarm_sender.clone(),
+                handshake.clone(),
+            )
+            .await?;
 
             tokio::select! {
                 Some(new_peers) = tracker_recv.recv() => {
@@ -113,22 +158,37 @@ impl TrackerManager {
                         .expect("Internal error: Tracker Manager couldn't receive the \
                             completion notification from the tracker tasks"); */
 
-                    for peer in new_peers {
-                        if self.all_peers.insert(*peer.ip()) {
-                            self.queued_peers.push_back(peer);
-                        }
-                    }
-
-                    tracing::info!("Unique peers: '{}'", &self.all_peers.len());
+                    self.queue_peers(new_peers);
                 }
+                // We have a copy of the swarm sender, so recv() can't return None
+                Some(swarm_msg) = swarm_recv.recv() => match swarm_msg {
+                    SwarmMsg::Connected(task_id, socket_addr) => {
+                        self.connected_peers.insert(task_id, socket_addr);
+                        self.publish_connected_peers();
+                    }
+                    SwarmMsg::Pex(new_peers) => self.queue_peers(new_peers),
+                },
                 // We have a copy of the completion sender, so recv() can't return None
                 Some(task_id) = completion_recv.recv() => {
                     tracing::debug!("Task '{}' completion message", task_id);
 
                     self.active_peers.remove(&task_id);
+                    self.web_seeds.remove(&task_id);
+                    if self.connected_peers.remove(&task_id).is_some() {
+                        self.publish_connected_peers();
+                    }
 
-                    self.queue_peer_tasks(completion_sender.clone(), Arc::clone(&handshake))
+                    self.queue_peer_tasks(
+                        completion_sender.clone(),
+                        swarm_sender.clone(),
+                        Arc::clone(&handshake),
+                    )
                     .await?;
+
+                    // Ask for an early announce, the request is dropped if one is already pending
+                    if self.queued_peers.is_empty() && !self.should_exit {
+                        let _ = peers_wanted_sender.try_send(());
+                    }
                 }
                 appstate_notif = self.appstate_recv.changed() => {
                     appstate_notif
@@ -145,41 +205,147 @@ impl TrackerManager {
         }
     }
 
-    fn spawn_trackers(
+    /// Queues the peers that weren't received before, from the trackers or through PEX
+    fn queue_peers(&mut self, new_peers: Vec<PeerAddr>) {
+        for peer in new_peers {
+            if self.all_peers.insert(peer.socket_addr) {
+                self.queued_peers.push_back(peer);
+            }
+        }
+
+        tracing::info!("Unique peers: '{}'", &self.all_peers.len());
+    }
+
+    fn publish_connected_peers(&self) {
+        let connected = self.connected_peers.values().copied().collect();
+
+        // We hold a receiver, so sending can't fail
+        let _ = self.connected_sender.send(connected);
+    }
+
+    fn spawn_tracker(
         &self,
-        trackers: &[String],
-        tracker_sender: mpsc::Sender<Vec<SocketAddrV4>>,
-    ) -> Vec<JoinHandle<()>> {
-        let mut tasks = vec![];
+        tracker_sender: mpsc::Sender<Vec<PeerAddr>>,
+        peers_wanted_recv: mpsc::Receiver<()>,
+    ) -> Option<JoinHandle<()>> {
+        let tiers = TrackerTiers::new(&self.metainfo.trackers);
+        if tiers.is_empty() {
+            return None;
+        }
+
+        let tracker_task = TrackerTask::new(
+            tiers,
+            self.client.clone(),
+            self.metainfo.clone(),
+            self.client_id.clone(),
+            self.stats.clone(),
+            tracker_sender,
+            peers_wanted_recv,
+            self.torrentstate_recv.clone(),
+            self.appstate_recv.clone(),
+        );
+
+        Some(tokio::spawn(async move {
+            let res = TrackerTask::start(tracker_task).await;
+
+            if let Err(e) = res {
+                tracing::debug!("Tracker task error: '{}'", e);
+            }
+        }))
+    }
+
+    /// Periodically looks up the peers of the torrent in the DHT, the peers are handled
+    /// like the peers from the trackers. We don't announce to the DHT, because the client
+    /// doesn't accept incoming connections yet.
+    fn spawn_dht(&self, tracker_sender: mpsc::Sender<Vec<PeerAddr>>) -> Option<JoinHandle<()>> {
+        let dht = self.dht.clone()?;
+        if self.metainfo.private {
+            return None;
+        }
+
+        let info_hash = *self.metainfo.info_hash;
+        let mut appstate_recv = self.appstate_recv.clone();
+
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(Self::DHT_INTERVAL));
+
+            loop {
+                let lookup = async {
+                    interval.tick().await;
+                    dht.get_peers(&info_hash).await
+                };
+
+                tokio::select! {
+                    peers = lookup => {
+                        let peers = peers.into_iter().map(PeerAddr::from).collect();
+                        // Waiting for the Tracker Manager could block the exit,
+                        // the next lookup finds the dropped peers again
+                        match tracker_sender.try_send(peers) {
+                            Ok(()) => (),
+                            Err(TrySendError::Full(_)) => {
+                                tracing::debug!("The Tracker Manager is busy, dropping the DHT peers");
+                            }
+                            Err(TrySendError::Closed(_)) => return,
+                        }
+                    }
+                    _ = appstate_recv.changed() => {
+                        if let AppState::Exit = *appstate_recv.borrow() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }))
+    }
 
-        for url in trackers {
-            let tracker_task = TrackerTask::new(
+    /// Web seed tasks are tracked along with the peer tasks, but don't count towards the limit
+    async fn spawn_web_seeds(&mut self, completion_sender: mpsc::Sender<TaskId>) {
+        for url in &self.metainfo.web_seeds {
+            let task_id = self.next_id;
+            self.next_id += 1;
+
+            tracing::debug!(
+                "Created a web seed task for '{}' with id of '{}'",
+                url,
+                task_id
+            );
+
+            let web_seed = WebSeed::create(
+                task_id,
                 url.clone(),
-                self.metainfo.clone(),
-                self.client_id.clone(),
-                tracker_sender.clone(),
+                self.client.clone(),
+                Arc::clone(&self.metainfo),
+                Arc::clone(&self.stats),
+                self.pm_sender.clone(),
                 self.appstate_recv.clone(),
-            );
+            )
+            .await;
 
-            let tracker_task = tokio::spawn(async move {
-                let res = TrackerTask::start(tracker_task).await;
+            let completion_sender = completion_sender.clone();
 
-                if let Err(e) = res {
-                    tracing::debug!("Tracker task error: '{}'", e);
+            let web_seed_task = tokio::spawn(async move {
+                if let Err(e) = web_seed.start().await {
+                    tracing::debug!("Web seed task error: '{}'", e);
                 }
+
+                completion_sender.send(task_id).await.expect(
+                    "Internal error: a web seed task couldn't send a completion \
+                        notification to the tracker task",
+                );
             });
 
-            tasks.push(tracker_task);
+            self.web_seeds.insert(task_id, web_seed_task);
         }
-
-        tasks
     }
 
     const MAX_ACTIVE_TASKS: usize = 25;
+    /// Interval of the DHT lookups in seconds
+    const DHT_INTERVAL: u64 = 300;
 
     async fn queue_peer_tasks(
         &mut self,
         completion_sender: mpsc::Sender<TaskId>,
+        swarm_sender: mpsc::Sender<SwarmMsg>,
         handshake: Arc<Handshake>,
     ) -> TrResult<()> {
         if self.should_exit {
@@ -190,7 +356,7 @@ impl TrackerManager {
         let to_queue = Self::MAX_ACTIVE_TASKS.saturating_sub(active_peers);
 
         for _ in 0..to_queue {
-            if let Some(socket_addr) = self.queued_peers.pop_front() {
+            if let Some(peer_addr) = self.queued_peers.pop_front() {
                 let task_id = self.next_id;
                 self.next_id += 1;
 
@@ -198,11 +364,14 @@ impl TrackerManager {
 
                 let peer = Peer::create(
                     task_id,
-                    socket_addr,
+                    peer_addr,
                     Arc::clone(&handshake),
                     Arc::clone(&self.metainfo),
+                    Arc::clone(&self.stats),
                     self.pm_sender.clone(),
                     self.appstate_recv.clone(),
+                    swarm_sender.clone(),
+                    self.connected_recv.clone(),
                 )
                 .await;
 
@@ -226,7 +395,7 @@ impl TrackerManager {
         Ok(())
     }
 
-    fn gen_client_id() -> Arc<[u8; 20]> {
+    pub fn gen_client_id() -> Arc<[u8; 20]> {
         let mut id = [0u8; 20];
         id[..8].copy_from_slice("-LO0001-".as_bytes());
 
@@ -238,105 +407,433 @@ impl TrackerManager {
     }
 }
 
+/// Periodically announces to the first tracker that responds, in the order of the tiers
 struct TrackerTask {
-    url: String,
+    tiers: TrackerTiers,
+    /// Keyed by the URL, kept between the announces so that the UDP connection IDs
+    /// are reused and the HTTP tracker IDs are sent back
+    protocols: HashMap<String, TrackerProtocol>,
+    client: reqwest::Client,
     metainfo: Arc<Metainfo>,
     client_id: Arc<[u8; 20]>,
-    tracker_resp_sender: mpsc::Sender<Vec<SocketAddrV4>>,
+    stats: Arc<TransferStats>,
+    tracker_resp_sender: mpsc::Sender<Vec<PeerAddr>>,
+    /// The Tracker Manager ran out of peers, announce as soon as the tracker allows it
+    peers_wanted_recv: mpsc::Receiver<()>,
+    torrentstate_recv: watch::Receiver<TorrentState>,
     appstate_recv: watch::Receiver<AppState>,
 }
 
 impl TrackerTask {
+    /// Lower bound for the intervals, protects against misconfigured trackers
+    const MIN_INTERVAL: u64 = 60;
+    /// Used when the tracker doesn't send a 'min interval'
+    const DEFAULT_MIN_INTERVAL: u64 = 120;
+    /// Wait after the first failed announce, doubled after every consecutive failure
+    const BACKOFF_BASE: u64 = 30;
+    const MAX_BACKOFF: u64 = 1800;
+
+    /// The 'stopped' announce shouldn't hold up the exit for long
+    const STOPPED_TIMEOUT: u64 = 5;
+
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        url: String,
+        tiers: TrackerTiers,
+        client: reqwest::Client,
         metainfo: Arc<Metainfo>,
         client_id: Arc<[u8; 20]>,
-        tracker_resp_sender: mpsc::Sender<Vec<SocketAddrV4>>,
+        stats: Arc<TransferStats>,
+        tracker_resp_sender: mpsc::Sender<Vec<PeerAddr>>,
+        peers_wanted_recv: mpsc::Receiver<()>,
+        torrentstate_recv: watch::Receiver<TorrentState>,
         appstate_recv: watch::Receiver<AppState>,
     ) -> Self {
         Self {
-            url,
+            tiers,
+            protocols: HashMap::new(),
+            client,
             metainfo,
             client_id,
+            stats,
             tracker_resp_sender,
+            peers_wanted_recv,
+            torrentstate_recv,
             appstate_recv,
         }
     }
 
     async fn start(mut self) -> TrResult<()> {
-        // TODO: proper timeouts and reannounces
-        let mut protocol = TrackerProtocol::init(&self.url).await?;
+        let mut failures = 0;
+        // Repeated until the tracker accepts it
+        let mut event = ClientState::Started;
+        // The tracker that knows about us, it should receive the 'stopped' event
+        let mut announced_to: Option<String> = None;
 
-        let response_timeout = tokio::time::timeout(
-            Duration::from_secs(30),
-            protocol.announce(&self.metainfo, &self.client_id),
-        );
+        loop {
+            let announce = Self::announce_tiers(
+                &mut self.tiers,
+                &mut self.protocols,
+                &self.client,
+                &self.metainfo,
+                &self.client_id,
+                self.stats.snapshot(),
+                event,
+            );
+
+            let response = tokio::select! {
+                _ = self.appstate_recv.changed() => {
+                    // The borrow can't be held across the announce
+                    let exit = matches!(*self.appstate_recv.borrow(), AppState::Exit);
+                    if exit {
+                        self.announce_stopped(announced_to).await;
+                        return Ok(());
+                    }
+
+                    continue;
+                }
+                response = announce => response,
+            };
+
+            let announced_at = Instant::now();
+            let (interval, min_interval) = match response {
+                Ok((url, response)) => {
+                    failures = 0;
+                    event = ClientState::None;
+                    announced_to = Some(url);
+
+                    let intervals = Self::intervals(&response);
+                    // The channel is shared with the DHT task, a busy Tracker Manager can't block the exit
+                    let keep_running = tokio::select! {
+                        res = self.tracker_resp_sender.send(response.peers) => res.is_ok(),
+                        _ = self.appstate_recv.changed() => {
+                            !matches!(*self.appstate_recv.borrow(), AppState::Exit)
+                        }
+                    };
+                    if !keep_running {
+                        self.announce_stopped(announced_to).await;
+                        return Ok(());
+                    }
+
+                    intervals
+                }
+                Err(e) => {
+                    failures += 1;
+
+                    let backoff = Self::backoff(failures);
+                    tracing::debug!("{}, retrying in '{:?}'", e, backoff);
 
-        tokio::select! {
-            _ = self.appstate_recv.changed() => {
-                if let AppState::Exit = *self.appstate_recv.borrow() {
-                    return Ok(());
+                    (backoff, backoff)
+                }
+            };
+
+            let mut next_announce = announced_at + interval;
+
+            loop {
+                tokio::select! {
+                    _ = self.appstate_recv.changed() => {
+                        let exit = matches!(*self.appstate_recv.borrow(), AppState::Exit);
+                        if exit {
+                            self.announce_stopped(announced_to).await;
+                            return Ok(());
+                        }
+                    }
+                    Ok(_) = self.torrentstate_recv.changed() => {
+                        // If the 'started' event wasn't accepted yet, it is sent with 0 bytes left instead
+                        let complete = matches!(*self.torrentstate_recv.borrow(), TorrentState::Complete);
+                        if complete && event == ClientState::None {
+                            event = ClientState::Completed;
+                            break;
+                        }
+                    }
+                    Some(()) = self.peers_wanted_recv.recv() => {
+                        next_announce = next_announce.min(announced_at + min_interval);
+                    }
+                    _ = tokio::time::sleep_until(next_announce) => break,
                 }
             }
-            response_timeout = response_timeout => {
-                let response = match response_timeout {
-                    Ok(r) => r?,
-                    Err(_) => return Ok(()),
-                };
+        }
+    }
 
-                tracing::info!(
-                    "Received '{}' peers from a tracker at '{}'",
-                    response.peers.len(),
-                    &self.url
-                );
+    /// Tries the trackers in order until one of them responds, returns its URL and response
+    async fn announce_tiers(
+        tiers: &mut TrackerTiers,
+        protocols: &mut HashMap<String, TrackerProtocol>,
+        client: &reqwest::Client,
+        metainfo: &Metainfo,
+        client_id: &[u8; 20],
+        transfer: Transfer,
+        event: ClientState,
+    ) -> TrResult<(String, TrackerResponse)> {
+        for pos in tiers.positions() {
+            let url = tiers.url(pos).to_string();
+            // The protocols time out on their own
+            let announce = Self::announce(
+                &url, protocols, client, metainfo, client_id, transfer, event,
+            );
 
-                self.tracker_resp_sender.send(response.peers).await.expect(
-                    "Internal error: a tracker task couldn't send a TrackerResponse to the Tracker Manager",
-                );
+            match announce.await {
+                Ok(response) => {
+                    tracing::info!(
+                        "Received '{}' peers from a tracker at '{}'",
+                        response.peers.len(),
+                        url
+                    );
+
+                    tiers.promote(pos);
+                    return Ok((url, response));
+                }
+                Err(e) => tracing::debug!("Tracker at '{}' failed: '{}'", url, e),
             }
         }
 
-        Ok(())
+        Err(TrErr::NoResponse)
+    }
+
+    /// Tells the tracker that we are leaving the swarm, failures don't matter at this point
+    async fn announce_stopped(&mut self, url: Option<String>) {
+        let url = match url {
+            Some(url) => url,
+            None => return,
+        };
+
+        let announce = Self::announce(
+            &url,
+            &mut self.protocols,
+            &self.client,
+            &self.metainfo,
+            &self.client_id,
+            self.stats.snapshot(),
+            ClientState::Stopped,
+        );
+
+        match tokio::time::timeout(Duration::from_secs(Self::STOPPED_TIMEOUT), announce).await {
+            Ok(Ok(_)) => tracing::info!("Sent the 'stopped' event to '{}'", url),
+            Ok(Err(e)) => tracing::debug!("Tracker at '{}' failed: '{}'", url, e),
+            Err(_) => tracing::debug!("Tracker at '{}' failed: '{}'", url, TrErr::Timeout),
+        }
+    }
+
+    async fn announce(
+        url: &str,
+        protocols: &mut HashMap<String, TrackerProtocol>,
+        client: &reqwest::Client,
+        metainfo: &Metainfo,
+        client_id: &[u8; 20],
+        transfer: Transfer,
+        event: ClientState,
+    ) -> TrResult<TrackerResponse> {
+        let protocol = match protocols.entry(url.to_string()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(TrackerProtocol::init(url, client).await?),
+        };
+
+        protocol
+            .announce(&metainfo.info_hash, transfer, event, client_id)
+            .await
+    }
+
+    /// The regular interval and the interval of early announces
+    fn intervals(response: &TrackerResponse) -> (Duration, Duration) {
+        let interval = u64::from(response.interval).max(Self::MIN_INTERVAL);
+        let min_interval = response
+            .min_interval
+            .map_or(Self::DEFAULT_MIN_INTERVAL, u64::from)
+            .clamp(Self::MIN_INTERVAL, interval);
+
+        (
+            Duration::from_secs(interval),
+            Duration::from_secs(min_interval),
+        )
+    }
+
+    fn backoff(failures: u32) -> Duration {
+        let exp = failures.saturating_sub(1).min(16);
+        let backoff = (Self::BACKOFF_BASE << exp).min(Self::MAX_BACKOFF);
+
+        Duration::from_secs(backoff)
+    }
+}
+
+/// Announces to all of the trackers at once and collects the unique received peers.
+/// Used before the torrent metadata is known, so the amount of remaining bytes is only a guess.
+pub async fn find_peers(
+    trackers: &[String],
+    info_hash: &[u8; 20],
+    client_id: &[u8; 20],
+    client: &reqwest::Client,
+) -> Vec<SocketAddr> {
+    // Announcing 0 remaining bytes would make us look like a seed
+    const UNKNOWN_LEFT: u64 = 16384;
+
+    let announces = trackers.iter().map(|url| async move {
+        let transfer = Transfer {
+            left: UNKNOWN_LEFT,
+            ..Transfer::default()
+        };
+
+        let mut protocol = TrackerProtocol::init(url, client).await?;
+        // Don't wait for the whole retransmission schedule of dead UDP trackers
+        let response = tokio::time::timeout(
+            Duration::from_secs(30),
+            protocol.announce(info_hash, transfer, ClientState::Started, client_id),
+        )
+        .await
+        .map_err(|_| TrErr::Timeout)??;
+
+        tracing::info!(
+            "Received '{}' peers from a tracker at '{}'",
+            response.peers.len(),
+            url
+        );
+
+        TrResult::Ok(response.peers.into_iter().map(|p| p.socket_addr))
+    });
+
+    let mut unique = HashSet::new();
+    let mut peers = Vec::new();
+
+    for res in futures::future::join_all(announces).await {
+        match res {
+            Ok(new_peers) => peers.extend(new_peers.filter(|p| unique.insert(*p))),
+            Err(e) => tracing::debug!("Tracker error: '{}'", e),
+        }
+    }
+
+    peers
+}
+
+/// Asks the tracker about the swarms of the torrents.
+/// Torrents that the tracker doesn't know about are missing from the result.
+pub async fn scrape(
+    url: &str,
+    info_hashes: &[[u8; 20]],
+    client: &reqwest::Client,
+) -> TrResult<HashMap<[u8; 20], ScrapeInfo>> {
+    let mut protocol = TrackerProtocol::init(url, client).await?;
+
+    tokio::time::timeout(Duration::from_secs(30), protocol.scrape(info_hashes))
+        .await
+        .map_err(|_| TrErr::Timeout)?
+}
+
+/// Creates the HTTP client shared by the HTTP trackers and the web seeds.
+/// The proxy can be an HTTP, HTTPS or SOCKS5 URL, UDP trackers don't use it.
+pub fn http_client(proxy: Option<&str>) -> TrResult<reqwest::Client> {
+    const USER_AGENT: &str = concat!("learntorrent/", env!("CARGO_PKG_VERSION"));
+    const CONNECT_TIMEOUT: u64 = 15;
+
+    let mut builder = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(Duration::from_secs(CONNECT_TIMEOUT))
+        .gzip(true);
+
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
     }
+
+    Ok(builder.build()?)
 }
 
 const DEFAULT_PORT: u16 = 6881;
 
-enum TrackerProtocol<'u> {
-    Http(HttpTracker<'u>),
+enum TrackerProtocol {
+    Http(HttpTracker),
     Udp(UdpTracker),
 }
 
-impl<'u> TrackerProtocol<'u> {
-    async fn init(url: &'u str) -> TrResult<TrackerProtocol<'_>> {
+impl TrackerProtocol {
+    async fn init(url: &str, client: &reqwest::Client) -> TrResult<TrackerProtocol> {
         match url.split_once("://") {
-            Some(("http", _)) => Ok(Self::Http(HttpTracker::init(url))),
+            Some(("http" | "https", _)) => Ok(Self::Http(HttpTracker::init(url, client.clone()))),
             Some(("udp", _)) => Ok(Self::Udp(UdpTracker::init(url).await?)),
-            _ => return Err(TrErr::UnknownProtocol),
+            _ => Err(TrErr::UnknownProtocol),
         }
     }
 
     async fn announce(
         &mut self,
-        metainfo: &Metainfo,
+        info_hash: &[u8; 20],
+        transfer: Transfer,
+        event: ClientState,
         client_id: &[u8; 20],
     ) -> TrResult<TrackerResponse> {
         match self {
-            TrackerProtocol::Http(http_tracker) => http_tracker.announce(metainfo, client_id).await,
-            TrackerProtocol::Udp(udp_tracker) => udp_tracker.announce(metainfo, client_id).await,
+            TrackerProtocol::Http(http_tracker) => {
+                http_tracker
+                    .announce(info_hash, transfer, event, client_id)
+                    .await
+            }
+            TrackerProtocol::Udp(udp_tracker) => {
+                udp_tracker
+                    .announce(info_hash, transfer, event, client_id)
+                    .await
+            }
         }
     }
+
+    async fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> TrResult<HashMap<[u8; 20], ScrapeInfo>> {
+        match self {
+            TrackerProtocol::Http(http_tracker) => http_tracker.scrape(info_hashes).await,
+            TrackerProtocol::Udp(udp_tracker) => udp_tracker.scrape(info_hashes).await,
+        }
+    }
+}
+
+/// Length of a compact IPv4 peer address, the IP followed by the port (BEP 23)
+pub const COMPACT_V4_LEN: usize = 4 + 2;
+/// Length of a compact IPv6 peer address (BEP 7)
+pub const COMPACT_V6_LEN: usize = 16 + 2;
+
+/// Decodes a list of compact peer addresses of the given length, trailing bytes are ignored
+pub fn decode_compact_peers(src: &[u8], addr_len: usize) -> Vec<SocketAddr> {
+    src.chunks_exact(addr_len)
+        .filter_map(|c| {
+            let (ip, port) = c.split_at(addr_len - 2);
+            let ip = match ip.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+                16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+                _ => return None,
+            };
+
+            Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+        })
+        .collect()
+}
+
+/// Appends the compact form of the address, 6 bytes for IPv4 and 18 bytes for IPv6
+pub fn encode_compact_peer(addr: &SocketAddr, buf: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Swarm statistics of a single torrent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScrapeInfo {
+    /// Number of peers with the entire file
+    pub seeders: u32,
+    /// Number of times the download was completed
+    pub completed: u32,
+    /// Number of non-seeder peers
+    pub leechers: u32,
 }
 
 pub struct TrackerResponse {
     /// Interval in seconds that the client should wait between sending regular requests to the tracker
     interval: u32,
+    /// Clients must not reannounce more frequently than this
+    min_interval: Option<u32>,
     /// number of peers with the entire file
     seeds: Option<u32>,
     /// number of non-seeder peers
     leeches: Option<u32>,
-    /// IP addresses and ports of peers (currently only the binary model is supported)
-    pub peers: Vec<SocketAddrV4>,
+    /// IP addresses and ports of peers, IPv4 and IPv6, and their IDs if the tracker sent them
+    pub peers: Vec<PeerAddr>,
 }
 
 impl TrackerResponse {
@@ -344,10 +841,11 @@ impl TrackerResponse {
         interval: u32,
         seeds: Option<u32>,
         leeches: Option<u32>,
-        peers: Vec<SocketAddrV4>,
+        peers: Vec<PeerAddr>,
     ) -> Self {
         Self {
             interval,
+            min_interval: None,
             seeds,
             leeches,
             peers,
@@ -355,21 +853,47 @@ impl TrackerResponse {
     }
 }
 
+/// The event of an announce
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u32)]
-enum ClientState {
-    // Completed = 1
-    //Paused,
+pub enum ClientState {
+    /// Regular announce
+    None = 0,
+    Completed = 1,
     Started = 2,
-    //Stopped = 3,
+    Stopped = 3,
 }
 
 impl ClientState {
-    pub fn to_str(self) -> &'static str {
+    /// Value of the 'event' parameter of HTTP announces
+    pub fn to_str(self) -> Option<&'static str> {
         match self {
-            ClientState::Started => "started",
-            //ClientState::Stopped => "stopped",
-            //ClientState::Paused => "paused",
+            ClientState::None => None,
+            ClientState::Completed => Some("completed"),
+            ClientState::Started => Some("started"),
+            ClientState::Stopped => Some("stopped"),
+        }
+    }
+
+    /// Parses the 'event' parameter of HTTP announces, a missing or empty event is a regular announce
+    pub fn from_event(event: Option<&str>) -> Option<Self> {
+        match event {
+            None | Some("") => Some(ClientState::None),
+            Some("completed") => Some(ClientState::Completed),
+            Some("started") => Some(ClientState::Started),
+            Some("stopped") => Some(ClientState::Stopped),
+            Some(_) => None,
+        }
+    }
+
+    /// Value of the 'event' field of UDP announces
+    pub fn from_u32(event: u32) -> Option<Self> {
+        match event {
+            0 => Some(ClientState::None),
+            1 => Some(ClientState::Completed),
+            2 => Some(ClientState::Started),
+            3 => Some(ClientState::Stopped),
+            _ => None,
         }
     }
 }
@@ -378,6 +902,7 @@ impl std::fmt::Debug for TrackerResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("TrackerResponse")
             .field("interval", &self.interval)
+            .field("min_interval", &self.min_interval)
             .field("seeds", &self.seeds)
             .field("leeches", &self.leeches)
             .field("peers", &format_args!("<peer IP addresses>"))
@@ -389,12 +914,16 @@ type TrResult<T> = Result<T, TrErr>;
 
 #[derive(Error, Debug)]
 pub enum TrErr {
-    #[error("Bencoding response parse error: '{0}'")]
-    BeError(#[from] ResponseParseError),
     #[error("Bencode decoding error while parsing the tracker response: '{0}'")]
-    BeDecodeError(#[from] BeDecodeErr),
-    #[error("Peers string length '{0}' should be a multiple of 6")]
+    BeDeserializeError(#[from] BeDeserializeErr),
+    #[error("Error while receiving the tracker response: '{0}'")]
+    BeCodecError(#[from] BeCodecErr),
+    #[error("Invalid length of the compact peers string: '{0}'")]
     InvalidPeersLen(usize),
+    #[error("The tracker refused the request: '{0}'")]
+    TrackerFailure(String),
+    #[error("The tracker response doesn't contain the announce interval")]
+    MissingInterval,
     #[error("UDP Tracker message parse error: '{0}'")]
     TrackerMsgErr(#[from] TrackerMsgDecodeErr),
     #[error("Received an invalid message from a UDP tracker")]
@@ -409,7 +938,92 @@ pub enum TrErr {
     UnknownProtocol,
     #[error("Invalid tracker URL")]
     InvalidUrl,
+    #[error("The tracker didn't respond in time")]
+    Timeout,
+    #[error("None of the trackers responded")]
+    NoResponse,
+    #[error("The tracker doesn't support scraping")]
+    ScrapeUnsupported,
 
     #[error("Tokio join error: '{0}'")]
     JoinError(#[from] tokio::task::JoinError),
 }
+
+#[cfg(test)]
+mod test_tracker_manager {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_intervals() {
+        let mut response = TrackerResponse::new(1800, None, None, vec![]);
+        assert_eq!(
+            TrackerTask::intervals(&response),
+            (Duration::from_secs(1800), Duration::from_secs(120))
+        );
+
+        response.min_interval = Some(900);
+        assert_eq!(
+            TrackerTask::intervals(&response).1,
+            Duration::from_secs(900)
+        );
+
+        // Misconfigured trackers can't make us announce all the time
+        let mut response = TrackerResponse::new(0, None, None, vec![]);
+        response.min_interval = Some(0);
+        assert_eq!(
+            TrackerTask::intervals(&response),
+            (Duration::from_secs(60), Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(TrackerTask::backoff(1), Duration::from_secs(30));
+        assert_eq!(TrackerTask::backoff(2), Duration::from_secs(60));
+        assert_eq!(TrackerTask::backoff(3), Duration::from_secs(120));
+        assert_eq!(TrackerTask::backoff(10), Duration::from_secs(1800));
+        assert_eq!(TrackerTask::backoff(u32::MAX), Duration::from_secs(1800));
+    }
+
+    #[test]
+    fn test_queue_peers() {
+        let info = b"d6:lengthi16e4:name1:a12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let metainfo = Metainfo::from_info_src(info, vec![]).unwrap();
+        let (pm_sender, _) = flume::unbounded();
+        let (_, torrentstate_recv) = watch::channel(TorrentState::InProgress);
+        let (_, appstate_recv) = watch::channel(AppState::Running);
+
+        let mut tm = TrackerManager::new(
+            appstate_recv,
+            torrentstate_recv,
+            pm_sender,
+            Arc::new(metainfo),
+            Arc::new([0; 20]),
+            Arc::new(TransferStats::new(16)),
+            http_client(None).unwrap(),
+            None,
+        );
+
+        // Peers behind the same NAT share the IP address
+        let peer = |port| PeerAddr::from(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        tm.queue_peers(vec![peer(6881), peer(6882)]);
+        tm.queue_peers(vec![peer(6882), peer(6881), peer(6883)]);
+
+        let ports: Vec<u16> = tm
+            .queued_peers
+            .iter()
+            .map(|p| p.socket_addr.port())
+            .collect();
+        assert_eq!(ports, [6881, 6882, 6883]);
+    }
+
+    #[test]
+    fn test_http_client() {
+        http_client(None).unwrap();
+        http_client(Some("socks5://127.0.0.1:9050")).unwrap();
+        http_client(Some("http://proxy.example:3128")).unwrap();
+        http_client(Some("not a proxy")).unwrap_err();
+    }
+}
@@ -1,108 +1,524 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::{
+    collections::HashMap,
+    fmt,
+    marker::PhantomData,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use reqwest::Client;
+use serde::{
+    de::{Error, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use tokio_util::codec::Decoder;
 
-use super::{ClientState, TrErr, TrResult, TrackerResponse, DEFAULT_PORT};
+use super::{
+    decode_compact_peers, ClientState, ScrapeInfo, TrErr, TrResult, TrackerResponse,
+    COMPACT_V4_LEN, COMPACT_V6_LEN, DEFAULT_PORT,
+};
 use crate::{
-    bencoding::bevalue::{BeStr, BeValue},
-    metainfo::Metainfo,
+    bencoding::{bevalue::BeStr, de, stream::BeCodec},
+    p2p::PeerAddr,
+    stats::Transfer,
 };
 
-pub struct HttpTracker<'u> {
+pub struct HttpTracker {
     /// A string that the client should send back on its next announcements.
     /// If absent and a previous announce sent a tracker id, do not discard the old value; keep using it.
     tracker_id: Option<BeStr>,
     /// The tracker URL without the prefix and suffix
-    url: &'u str,
+    url: String,
+    /// Shared by all HTTP trackers and web seeds
+    client: Client,
 }
 
-impl<'u> HttpTracker<'u> {
-    pub fn init(url: &'u str) -> Self {
+impl HttpTracker {
+    pub fn init(url: &str, client: Client) -> Self {
         Self {
             tracker_id: None,
-            url,
+            url: url.to_string(),
+            client,
+        }
+    }
+
+    const TIMEOUT: u64 = 30;
+
+    /// The response is decoded while it arrives, which limits its size
+    async fn get(&self, url: String) -> TrResult<BytesMut> {
+        let mut response = self
+            .client
+            .get(url)
+            .timeout(Duration::from_secs(Self::TIMEOUT))
+            .send()
+            .await?;
+
+        let mut codec = BeCodec::default();
+        let mut buf = BytesMut::new();
+
+        loop {
+            // Bytes after the response value are ignored
+            if let Some(value) = codec.decode(&mut buf)? {
+                return Ok(value);
+            }
+
+            match response.chunk().await? {
+                Some(chunk) => buf.extend_from_slice(&chunk),
+                // Empty responses fail to deserialize
+                None => return Ok(codec.decode_eof(&mut buf)?.unwrap_or_default()),
+            }
         }
     }
 
     pub async fn announce(
         &mut self,
-        metainfo: &Metainfo,
+        info_hash: &[u8; 20],
+        transfer: Transfer,
+        event: ClientState,
         client_id: &[u8; 20],
     ) -> TrResult<TrackerResponse> {
-        let req_url = self.build_announce_url(
-            metainfo,
-            client_id,
-            0,
-            0,
-            metainfo.piece_count(),
-            ClientState::Started,
-        );
+        let req_url = self.build_announce_url(info_hash, client_id, transfer, event);
 
-        let response = reqwest::get(req_url).await?.bytes().await?;
-        let response = BeValue::from_bytes(&response)?;
-        let response = self.parse_bevalue(response)?;
+        let response = self.get(req_url).await?;
+        let response = self.parse_response(&response)?;
 
         Ok(response)
     }
 
+    /// Long URLs might be rejected by the tracker
+    const MAX_SCRAPE_HASHES: usize = 50;
+
+    pub async fn scrape(
+        &self,
+        info_hashes: &[[u8; 20]],
+    ) -> TrResult<HashMap<[u8; 20], ScrapeInfo>> {
+        let scrape_url = self.scrape_url()?;
+        let mut swarms = HashMap::new();
+
+        for batch in info_hashes.chunks(Self::MAX_SCRAPE_HASHES) {
+            let mut req_url = scrape_url.clone();
+            for info_hash in batch {
+                req_url.push(if req_url.contains('?') { '&' } else { '?' });
+                req_url.push_str("info_hash=");
+                req_url.push_str(&urlencoding::encode_binary(info_hash));
+            }
+
+            let response = self.get(req_url).await?;
+            swarms.extend(Self::parse_scrape_response(&response)?);
+        }
+
+        Ok(swarms)
+    }
+
+    /// The scrape URL is the announce URL with 'announce' at the start
+    /// of the last path component replaced by 'scrape'
+    fn scrape_url(&self) -> TrResult<String> {
+        let (base, last) = self.url.rsplit_once('/').ok_or(TrErr::ScrapeUnsupported)?;
+        let rest = last
+            .strip_prefix("announce")
+            .ok_or(TrErr::ScrapeUnsupported)?;
+
+        Ok(format!("{}/scrape{}", base, rest))
+    }
+
+    fn parse_scrape_response(src: &[u8]) -> TrResult<HashMap<[u8; 20], ScrapeInfo>> {
+        let resp = de::from_bytes::<ScrapeResponse>(src)?;
+
+        let swarms = resp
+            .files
+            .into_iter()
+            .filter_map(|(info_hash, file)| {
+                let info_hash = info_hash.try_into().ok()?;
+                let info = ScrapeInfo {
+                    seeders: file.complete,
+                    completed: file.downloaded,
+                    leechers: file.incomplete,
+                };
+
+                Some((info_hash, info))
+            })
+            .collect();
+
+        Ok(swarms)
+    }
+
     fn build_announce_url(
         &self,
-        metainfo: &Metainfo,
+        info_hash: &[u8; 20],
         client_id: &[u8],
-        uploaded: u32,
-        downloaded: u32,
-        left: u32,
+        transfer: Transfer,
         event: ClientState,
     ) -> String {
-        return format!(
-            "{announce}?info_hash={info_hash}&peer_id={peer_id}&port={port}&uploaded={uploaded}&downloaded={downloaded}&left={left}&event={event}&compact=1",
+        // Private trackers often put a passkey into the query of the announce URL
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let mut url = format!(
+            "{announce}{separator}info_hash={info_hash}&peer_id={peer_id}&port={port}&uploaded={uploaded}&downloaded={downloaded}&left={left}&compact=1",
             announce = self.url,
-            info_hash = urlencoding::encode_binary(&*metainfo.info_hash),
+            separator = separator,
+            info_hash = urlencoding::encode_binary(info_hash),
             peer_id = urlencoding::encode_binary(client_id),
             port = DEFAULT_PORT,
-            uploaded = uploaded,
-            downloaded = downloaded,
-            left = left,
-            event = event.to_str(),
+            uploaded = transfer.uploaded,
+            downloaded = transfer.downloaded,
+            left = transfer.left,
         );
+
+        // Regular announces don't have an event
+        if let Some(event) = event.to_str() {
+            url.push_str("&event=");
+            url.push_str(event);
+        }
+
+        if let Some(tracker_id) = &self.tracker_id {
+            url.push_str("&trackerid=");
+            url.push_str(&urlencoding::encode_binary(tracker_id));
+        }
+
+        url
     }
 
-    fn parse_bevalue(&mut self, mut be: BeValue) -> TrResult<TrackerResponse> {
-        let resp = be.get_dict()?;
+    fn parse_response(&mut self, src: &[u8]) -> TrResult<TrackerResponse> {
+        let resp = de::from_bytes::<AnnounceResponse>(src)?;
 
-        let interval = resp.expect("interval")?.get_u32()?;
+        // The other keys may be missing if the request failed
+        if let Some(reason) = resp.failure_reason {
+            return Err(TrErr::TrackerFailure(
+                String::from_utf8_lossy(reason).into_owned(),
+            ));
+        }
 
-        let seeds = resp.try_get("complete", BeValue::get_u32)?;
-        let leeches = resp.try_get("incomplete", BeValue::get_u32)?;
-        let peers = resp.expect("peers")?.get_str()?;
-        if peers.len() % 6 != 0 {
-            return Err(TrErr::InvalidPeersLen(peers.len()));
+        if let Some(warning) = resp.warning_message {
+            tracing::warn!(
+                "Tracker at '{}' sent a warning: '{}'",
+                self.url,
+                String::from_utf8_lossy(warning)
+            );
         }
 
-        let peers = peers
-            .chunks(6)
-            .map(|c| {
-                SocketAddrV4::new(
-                    Ipv4Addr::new(c[0], c[1], c[2], c[3]),
-                    u16::from_be_bytes([c[4], c[5]]),
-                )
-            })
-            .collect();
+        let interval = resp.interval.ok_or(TrErr::MissingInterval)?;
 
-        let tracker_id = resp.try_get("tracker id", BeValue::get_str)?;
-        if tracker_id.is_some() {
-            self.tracker_id = tracker_id;
+        if resp.peers6.len() % COMPACT_V6_LEN != 0 {
+            return Err(TrErr::InvalidPeersLen(resp.peers6.len()));
+        }
+
+        let mut peers: Vec<PeerAddr> = match resp.peers {
+            Peers::Compact(compact) => {
+                if compact.len() % COMPACT_V4_LEN != 0 {
+                    return Err(TrErr::InvalidPeersLen(compact.len()));
+                }
+
+                decode_compact_peers(compact, COMPACT_V4_LEN)
+                    .into_iter()
+                    .map(PeerAddr::from)
+                    .collect()
+            }
+            Peers::Dicts(dicts) => dicts.iter().filter_map(PeerDict::peer_addr).collect(),
+        };
+        peers.extend(
+            decode_compact_peers(resp.peers6, COMPACT_V6_LEN)
+                .into_iter()
+                .map(PeerAddr::from),
+        );
+
+        if let Some(tracker_id) = resp.tracker_id {
+            self.tracker_id = Some(tracker_id.to_vec());
         }
 
         Ok(TrackerResponse {
             interval,
-            seeds,
-            leeches,
+            min_interval: resp.min_interval,
+            seeds: resp.complete,
+            leeches: resp.incomplete,
             peers,
         })
     }
 }
 
+/// Bencoded response to an announce request
+#[derive(Deserialize)]
+struct AnnounceResponse<'a> {
+    /// If present, the request failed and no other keys are required
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<&'a [u8]>,
+    /// The request succeeded, but the tracker has something to say
+    #[serde(rename = "warning message")]
+    warning_message: Option<&'a [u8]>,
+    interval: Option<u32>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u32>,
+    /// Number of peers with the entire file
+    complete: Option<u32>,
+    /// Number of non-seeder peers
+    incomplete: Option<u32>,
+    #[serde(default, borrow)]
+    peers: Peers<'a>,
+    /// Compact IPv6 peer list, 18 bytes per peer (BEP 7)
+    #[serde(default)]
+    peers6: &'a [u8],
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<&'a [u8]>,
+}
+
+/// Some trackers ignore 'compact=1' and send the original list of dictionaries
+enum Peers<'a> {
+    /// Compact IPv4 peer list, 6 bytes per peer
+    Compact(&'a [u8]),
+    Dicts(Vec<PeerDict<'a>>),
+}
+
+impl Default for Peers<'_> {
+    fn default() -> Self {
+        Peers::Compact(&[])
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for Peers<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PeersVisitor<'a>(PhantomData<&'a ()>);
+
+        impl<'de: 'a, 'a> Visitor<'de> for PeersVisitor<'a> {
+            type Value = Peers<'a>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a compact peer string or a list of peer dictionaries")
+            }
+
+            fn visit_borrowed_bytes<E: Error>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+                Ok(Peers::Compact(v))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut dicts = Vec::new();
+                while let Some(dict) = seq.next_element()? {
+                    dicts.push(dict);
+                }
+
+                Ok(Peers::Dicts(dicts))
+            }
+        }
+
+        deserializer.deserialize_any(PeersVisitor(PhantomData))
+    }
+}
+
+#[derive(Deserialize)]
+struct PeerDict<'a> {
+    #[serde(rename = "peer id")]
+    peer_id: Option<&'a [u8]>,
+    /// IPv4 or IPv6 address, or a DNS name
+    ip: &'a [u8],
+    port: u16,
+}
+
+impl PeerDict<'_> {
+    /// DNS names aren't resolved, those peers are skipped
+    fn peer_addr(&self) -> Option<PeerAddr> {
+        let ip = match std::str::from_utf8(self.ip).ok()?.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => {
+                tracing::debug!(
+                    "Skipping a peer with the address '{}'",
+                    String::from_utf8_lossy(self.ip)
+                );
+                return None;
+            }
+        };
+
+        Some(PeerAddr {
+            socket_addr: SocketAddr::new(ip, self.port),
+            peer_id: self.peer_id.and_then(|id| id.try_into().ok()),
+        })
+    }
+}
+
+/// Bencoded response to a scrape request
+#[derive(Deserialize)]
+struct ScrapeResponse<'a> {
+    /// Keyed by the info hashes
+    #[serde(borrow)]
+    files: HashMap<&'a [u8], ScrapeFile>,
+}
+
+#[derive(Deserialize)]
+struct ScrapeFile {
+    /// Number of peers with the entire file
+    complete: u32,
+    /// Number of times the download was completed
+    downloaded: u32,
+    /// Number of non-seeder peers
+    incomplete: u32,
+}
+
 #[cfg(test)]
 mod test_super {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let mut tracker = HttpTracker::init("http://tracker.example/announce", Client::new());
+
+        let src = b"d8:completei5e10:incompletei3e8:intervali1800e5:peers12:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe210:tracker id3:abce";
+        let response = tracker.parse_response(src).unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.min_interval, None);
+        assert_eq!(response.seeds, Some(5));
+        assert_eq!(response.leeches, Some(3));
+        assert_eq!(
+            response.peers,
+            [
+                PeerAddr::from(SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 6881))),
+                PeerAddr::from(SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), 6882))),
+            ]
+        );
+        assert_eq!(tracker.tracker_id.as_deref(), Some(&b"abc"[..]));
+
+        let announce_url = |tracker: &HttpTracker| {
+            tracker.build_announce_url(&[0; 20], &[0; 20], Transfer::default(), ClientState::None)
+        };
+        assert!(announce_url(&tracker).ends_with("&compact=1&trackerid=abc"));
+
+        let response = tracker
+            .parse_response(b"d8:intervali1800e12:min intervali900e5:peers0:e")
+            .unwrap();
+        assert_eq!(response.min_interval, Some(900));
+        // The previous tracker id is kept
+        assert!(announce_url(&tracker).ends_with("&trackerid=abc"));
+
+        tracker
+            .parse_response(b"d8:intervali1800e5:peers0:10:tracker id3:a&\xFFe")
+            .unwrap();
+        assert!(announce_url(&tracker).ends_with("&trackerid=a%26%FF"));
+
+        let response = tracker
+            .parse_response(b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe16:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1e")
+            .unwrap();
+        assert_eq!(
+            response.peers,
+            [
+                PeerAddr::from(SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 6881))),
+                PeerAddr::from(SocketAddr::from((Ipv6Addr::LOCALHOST, 6881))),
+            ]
+        );
+        tracker
+            .parse_response(b"d8:intervali1800e6:peers63:\x00\x00\x00e")
+            .unwrap_err();
+
+        tracker
+            .parse_response(b"d8:intervali1800e5:peers5:\x00\x00\x00\x00\x00e")
+            .unwrap_err();
+        assert!(matches!(
+            tracker.parse_response(b"d5:peers0:e"),
+            Err(TrErr::MissingInterval)
+        ));
+    }
+
+    #[test]
+    fn test_parse_dict_peers() {
+        let mut tracker = HttpTracker::init("http://tracker.example/announce", Client::new());
+
+        let src = b"d8:intervali1800e5:peersl\
+            d2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee\
+            d2:ip3:::14:porti6882ee\
+            d2:ip16:peer.example.org4:porti6883ee\
+            ee";
+        let response = tracker.parse_response(src).unwrap();
+
+        assert_eq!(
+            response.peers,
+            [
+                PeerAddr {
+                    socket_addr: SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 6881)),
+                    peer_id: Some([b'a'; 20]),
+                },
+                PeerAddr::from(SocketAddr::from((Ipv6Addr::LOCALHOST, 6882))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_failure() {
+        let mut tracker = HttpTracker::init("http://tracker.example/announce", Client::new());
+
+        let res = tracker.parse_response(b"d14:failure reason17:torrent not founde");
+        assert!(matches!(res, Err(TrErr::TrackerFailure(r)) if r == "torrent not found"));
+
+        let response = tracker
+            .parse_response(b"d8:intervali1800e5:peers0:15:warning message4:slowe")
+            .unwrap();
+        assert_eq!(response.interval, 1800);
+    }
+
+    #[test]
+    fn test_announce_url() {
+        let tracker = HttpTracker::init("http://tracker.example/announce", Client::new());
+        let transfer = Transfer {
+            uploaded: 1,
+            downloaded: 20,
+            left: 300,
+        };
+
+        let url = tracker.build_announce_url(&[0; 20], &[1; 20], transfer, ClientState::Stopped);
+        assert!(url.starts_with("http://tracker.example/announce?info_hash=%00"));
+        assert!(url.contains("&uploaded=1&downloaded=20&left=300&"));
+        assert!(url.ends_with("&event=stopped"));
+
+        let url = tracker.build_announce_url(&[0; 20], &[1; 20], transfer, ClientState::None);
+        assert!(!url.contains("event"));
+
+        let tracker = HttpTracker::init(
+            "https://tracker.example/announce.php?passkey=abc",
+            Client::new(),
+        );
+        let url = tracker.build_announce_url(&[0; 20], &[1; 20], transfer, ClientState::None);
+        assert!(url.starts_with("https://tracker.example/announce.php?passkey=abc&info_hash=%00"));
+        assert_eq!(url.matches('?').count(), 1);
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let scrape_url = |url| HttpTracker::init(url, Client::new()).scrape_url().ok();
+
+        assert_eq!(
+            scrape_url("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php").as_deref(),
+            Some("http://example.com/x/scrape.php")
+        );
+        assert_eq!(
+            scrape_url("http://example.com/announce?x2%0644").as_deref(),
+            Some("http://example.com/scrape?x2%0644")
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce?x=2/4"), None);
+        assert_eq!(scrape_url("http://example.com/x%064announce"), None);
+    }
+
+    #[test]
+    fn test_parse_scrape_response() {
+        let src = b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e\
+            10:incompletei10ee3:bbbd8:completei0e10:downloadedi0e10:incompletei0eeee";
+        let swarms = HttpTracker::parse_scrape_response(src).unwrap();
+
+        assert_eq!(swarms.len(), 1);
+        assert_eq!(
+            swarms[b"aaaaaaaaaaaaaaaaaaaa"],
+            ScrapeInfo {
+                seeders: 5,
+                completed: 50,
+                leechers: 10
+            }
+        );
+
+        HttpTracker::parse_scrape_response(b"d5:filesdee").unwrap();
+        HttpTracker::parse_scrape_response(b"de").unwrap_err();
+    }
+
     #[test]
     fn test_urlencoding() {
         assert_eq!(
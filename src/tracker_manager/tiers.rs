@@ -0,0 +1,85 @@
+use rand::seq::SliceRandom;
+
+/// Position of a tracker, the index of its tier and the index inside of the tier
+pub type TrackerPos = (usize, usize);
+
+/// Trackers grouped into tiers (BEP 12).
+/// The tiers are tried in order, the trackers inside of a tier are tried in random order.
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    /// Shuffles the trackers inside of each tier and skips the empty tiers
+    pub fn new(tiers: &[Vec<String>]) -> Self {
+        let mut rng = rand::thread_rng();
+
+        let tiers = tiers
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| {
+                let mut tier = tier.clone();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+
+        Self { tiers }
+    }
+
+    /// Positions of all trackers in the order they should be tried
+    pub fn positions(&self) -> Vec<TrackerPos> {
+        self.tiers
+            .iter()
+            .enumerate()
+            .flat_map(|(t, tier)| (0..tier.len()).map(move |i| (t, i)))
+            .collect()
+    }
+
+    pub fn url(&self, (tier, index): TrackerPos) -> &str {
+        &self.tiers[tier][index]
+    }
+
+    /// Moves a tracker that responded to the front of its tier
+    pub fn promote(&mut self, (tier, index): TrackerPos) {
+        let url = self.tiers[tier].remove(index);
+        self.tiers[tier].insert(0, url);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test_tiers {
+    use super::*;
+
+    fn tiers(tiers: &[&[&str]]) -> Vec<Vec<String>> {
+        tiers
+            .iter()
+            .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_order() {
+        let mut tt = TrackerTiers::new(&tiers(&[&["a", "b", "c"], &[], &["d"]]));
+
+        let positions = tt.positions();
+        assert_eq!(positions, [(0, 0), (0, 1), (0, 2), (1, 0)]);
+
+        let mut first_tier: Vec<&str> = positions[..3].iter().map(|p| tt.url(*p)).collect();
+        first_tier.sort_unstable();
+        assert_eq!(first_tier, ["a", "b", "c"]);
+        assert_eq!(tt.url((1, 0)), "d");
+
+        let last = tt.url((0, 2)).to_string();
+        let first = tt.url((0, 0)).to_string();
+        tt.promote((0, 2));
+        assert_eq!(tt.url((0, 0)), last);
+        assert_eq!(tt.url((0, 1)), first);
+
+        assert!(TrackerTiers::new(&tiers(&[&[]])).is_empty());
+    }
+}
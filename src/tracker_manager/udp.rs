@@ -1,87 +1,239 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 
 use bytes::Buf;
 use thiserror::Error;
-use tokio::net::UdpSocket;
+use tokio::{net::UdpSocket, time::Instant};
+use url::{Host, Position, Url};
 
-use crate::metainfo::Metainfo;
-
-use super::{ClientState, TrErr, TrResult, TrackerResponse};
+use super::{
+    decode_compact_peers, encode_compact_peer, ClientState, ScrapeInfo, TrErr, TrResult,
+    TrackerResponse, COMPACT_V4_LEN, COMPACT_V6_LEN,
+};
+use crate::{p2p::PeerAddr, stats::Transfer};
 
 pub struct UdpTracker {
-    /// Connection ID received in the first response
-    conn_id: Option<u64>,
+    /// Connection ID and the time it was received
+    conn_id: Option<(u64, Instant)>,
     /// Socket for communicating with the tracker
     socket: UdpSocket,
+    /// The path and the query of the URL, sent as the URLData option (BEP 41)
+    url_data: Vec<u8>,
+    /// Trackers contacted over IPv6 respond with IPv6 peers
+    peer_addr_len: usize,
 }
 
 impl UdpTracker {
     pub async fn init(url: &str) -> TrResult<Self> {
-        let url = url
-            .trim_start_matches("udp://")
-            .split_once("/announce")
-            .ok_or(TrErr::InvalidUrl)?
-            .0;
+        let url = Url::parse(url).map_err(|_| TrErr::InvalidUrl)?;
+        if url.scheme() != "udp" {
+            return Err(TrErr::InvalidUrl);
+        }
+
+        // UDP trackers don't have a default port
+        let port = url.port().ok_or(TrErr::InvalidUrl)?;
+        let addr = match url.host().ok_or(TrErr::InvalidUrl)? {
+            Host::Ipv4(ip) => SocketAddr::from((ip, port)),
+            Host::Ipv6(ip) => SocketAddr::from((ip, port)),
+            Host::Domain(domain) => tokio::net::lookup_host((domain, port))
+                .await?
+                .next()
+                .ok_or(TrErr::InvalidUrl)?,
+        };
 
         // "It's not obvious, but if you bind the socket to (UNSPECIFIED, 0),
         // then when you connect to a remote address the local address is set to
         // the appropriate interface address. (Port 0 means to auto-allocate a port,
         // just like an unbound socket in C.)"
-        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
-        socket.connect(&url).await?;
+        let (socket, peer_addr_len) = match addr {
+            SocketAddr::V4(_) => (
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+                COMPACT_V4_LEN,
+            ),
+            SocketAddr::V6(_) => (
+                UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+                COMPACT_V6_LEN,
+            ),
+        };
+        socket.connect(addr).await?;
 
         let sel = Self {
             conn_id: None,
             socket,
+            url_data: Self::url_data(&url),
+            peer_addr_len,
         };
 
         Ok(sel)
     }
 
+    /// Everything after the port, except the fragment. Empty if there's nothing to send.
+    fn url_data(url: &Url) -> Vec<u8> {
+        let url_data = &url[Position::BeforePath..Position::AfterQuery];
+
+        if url_data == "/" {
+            Vec::new()
+        } else {
+            url_data.as_bytes().to_vec()
+        }
+    }
+
     /// "2 kilobytes should be enough for everyone"
     /// (enough for exactly 338 peers)
     const MAXIMUM_PACKET_SIZE: usize = 2048;
+    /// The n-th request waits for a response for 15 * 2^n seconds (BEP 15)
+    const BASE_TIMEOUT: u64 = 15;
+    /// BEP 15 allows up to 8 retransmissions, which takes over an hour.
+    /// We give up sooner, so that the other trackers get a chance.
+    const MAX_RETRANSMISSIONS: u32 = 3;
+    /// A connection ID can be used for a minute after it was received
+    const CONN_ID_LIFETIME: u64 = 60;
 
     pub async fn announce(
         &mut self,
-        metainfo: &Metainfo,
+        info_hash: &[u8; 20],
+        transfer: Transfer,
+        event: ClientState,
         client_id: &[u8; 20],
     ) -> TrResult<TrackerResponse> {
         let mut read_buffer = vec![0; Self::MAXIMUM_PACKET_SIZE];
 
-        let (connect_msg, trans_id) = TrackerRequestMsg::new_connect();
-
-        // TODO: retries and timeouts
-        self.send_msg(connect_msg).await?;
-        let connect_resp = self.recv_msg(&mut read_buffer).await?;
-        let connect_resp = connect_resp.expect_conncet()?;
-        self.check_trans_id(trans_id, connect_resp.trans_id)?;
-
-        self.conn_id = Some(connect_resp.conn_id);
-
         let port = self.socket.local_addr()?.port();
-        let (announce_msg, trans_id) = TrackerRequestMsg::new_announce(
-            self.conn_id.unwrap(),
-            &*metainfo.info_hash,
-            client_id,
-            0,
-            metainfo.total_length,
-            0,
-            ClientState::Started,
-            port,
-        );
-
-        self.send_msg(announce_msg).await?;
-        let announce_resp = self.recv_msg(&mut read_buffer).await?;
+        let url_data = self.url_data.clone();
+        let announce_resp = self
+            .request(&mut read_buffer, |conn_id| {
+                TrackerRequestMsg::new_announce(
+                    conn_id,
+                    info_hash,
+                    client_id,
+                    transfer.downloaded,
+                    transfer.left,
+                    transfer.uploaded,
+                    event,
+                    port,
+                    &url_data,
+                )
+            })
+            .await?;
         let ar = announce_resp.expect_announce()?;
-        self.check_trans_id(trans_id, ar.trans_id)?;
 
+        let peers = ar.peers.into_iter().map(PeerAddr::from).collect();
         let tracker_response =
-            TrackerResponse::new(ar.interval, Some(ar.seeders), Some(ar.leechers), ar.peers);
+            TrackerResponse::new(ar.interval, Some(ar.seeders), Some(ar.leechers), peers);
 
         Ok(tracker_response)
     }
 
+    /// Up to about 74 torrents can be scraped at once (BEP 15)
+    const MAX_SCRAPE_HASHES: usize = 74;
+
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> TrResult<HashMap<[u8; 20], ScrapeInfo>> {
+        let mut read_buffer = vec![0; Self::MAXIMUM_PACKET_SIZE];
+        let mut swarms = HashMap::new();
+
+        for batch in info_hashes.chunks(Self::MAX_SCRAPE_HASHES) {
+            let scrape_resp = self
+                .request(&mut read_buffer, |conn_id| {
+                    TrackerRequestMsg::new_scrape(conn_id, batch)
+                })
+                .await?;
+            let sr = scrape_resp.expect_scrape()?;
+
+            // The statistics are in the same order as the requested hashes
+            if sr.swarms.len() != batch.len() {
+                return Err(TrErr::TrackerProtocolErr);
+            }
+
+            swarms.extend(batch.iter().copied().zip(sr.swarms));
+        }
+
+        Ok(swarms)
+    }
+
+    /// Retransmits the request until a response arrives. The requests are built with
+    /// the current connection ID, which is requested again when it expires.
+    async fn request<'h, F>(&mut self, buf: &mut [u8], new_msg: F) -> TrResult<TrackerResponseMsg>
+    where
+        F: Fn(u64) -> (TrackerRequestMsg<'h>, u32),
+    {
+        for n in 0..=Self::MAX_RETRANSMISSIONS {
+            let timeout = Duration::from_secs(Self::BASE_TIMEOUT << n);
+
+            let conn_id = match self.conn_id {
+                Some((conn_id, received))
+                    if received.elapsed() < Duration::from_secs(Self::CONN_ID_LIFETIME) =>
+                {
+                    conn_id
+                }
+                _ => {
+                    let (connect_msg, trans_id) = TrackerRequestMsg::new_connect();
+
+                    match self.transact(connect_msg, trans_id, buf, timeout).await? {
+                        Some(connect_resp) => {
+                            let conn_id = connect_resp.expect_conncet()?.conn_id;
+                            self.conn_id = Some((conn_id, Instant::now()));
+                            conn_id
+                        }
+                        None => continue,
+                    }
+                }
+            };
+
+            let (msg, trans_id) = new_msg(conn_id);
+            if let Some(resp) = self.transact(msg, trans_id, buf, timeout).await? {
+                return Ok(resp);
+            }
+        }
+
+        Err(TrErr::Timeout)
+    }
+
+    /// Sends the request and waits for the response with the same transaction ID.
+    /// Returns None if it doesn't arrive in time or if the tracker responds with an error.
+    async fn transact(
+        &mut self,
+        msg: TrackerRequestMsg<'_>,
+        trans_id: u32,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> TrResult<Option<TrackerResponseMsg>> {
+        self.send_msg(msg).await?;
+
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let resp = match tokio::time::timeout_at(deadline, self.recv_msg(buf)).await {
+                Ok(resp) => resp,
+                Err(_) => return Ok(None),
+            };
+
+            match resp {
+                Ok(TrackerResponseMsg::Error(e)) if e.trans_id == trans_id => {
+                    tracing::debug!("UDP tracker error: '{}'", e.error);
+
+                    // The error might be caused by an expired connection ID
+                    self.conn_id = None;
+                    return Ok(None);
+                }
+                Ok(resp) if resp.trans_id() == trans_id => return Ok(Some(resp)),
+                // Most likely a late response to a previous request
+                Ok(_) => tracing::debug!(
+                    "Ignoring a UDP tracker response with a different transaction ID"
+                ),
+                Err(TrErr::TrackerMsgErr(e)) => {
+                    tracing::debug!("Ignoring a malformed UDP tracker response: '{}'", e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     async fn recv_msg(&self, buf: &mut [u8]) -> TrResult<TrackerResponseMsg> {
         let bytes_read = self.socket.recv(buf).await?;
 
@@ -89,7 +241,10 @@ impl UdpTracker {
             tracing::warn!("Received UDP packet might have been bigger than the max size");
         }
 
-        Ok(TrackerResponseMsg::try_from(&buf[..bytes_read])?)
+        Ok(TrackerResponseMsg::decode(
+            &buf[..bytes_read],
+            self.peer_addr_len,
+        )?)
     }
 
     async fn send_msg(&self, msg: TrackerRequestMsg<'_>) -> TrResult<()> {
@@ -100,19 +255,15 @@ impl UdpTracker {
 
         Ok(())
     }
-
-    fn check_trans_id(&self, trans_id_1: u32, trans_id_2: u32) -> Result<(), TrErr> {
-        if trans_id_1 != trans_id_2 {
-            Err(TrErr::TrackerProtocolErr)
-        } else {
-            Ok(())
-        }
-    }
 }
 
+/// Magic constant identifying the UDP tracker protocol, sent in place of the connection ID
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+/// Requests are encoded by the client and decoded by the tracker server
 #[derive(Debug)]
 #[cfg_attr(test, derive(PartialEq))]
-enum TrackerRequestMsg<'h> {
+pub enum TrackerRequestMsg<'h> {
     Connect {
         // Connection ID: 0x41727101980 - u64
         // action: i32
@@ -130,8 +281,16 @@ enum TrackerRequestMsg<'h> {
         event: ClientState,
         // ip address: default = 0 (use our address)
         // key: u32, random value
-        // num_want: u32, default = -1
+        /// Number of peers the client wants, -1 for the tracker's default
+        num_want: i32,
         port: u16,
+        url_data: &'h [u8],
+    },
+    Scrape {
+        conn_id: u64,
+        // action: i32
+        trans_id: u32,
+        info_hashes: &'h [[u8; 20]],
     },
 }
 
@@ -142,6 +301,7 @@ impl<'h> TrackerRequestMsg<'h> {
         (Self::Connect { trans_id }, trans_id)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn new_announce(
         conn_id: u64,
         info_hash: &'h [u8; 20],
@@ -151,6 +311,7 @@ impl<'h> TrackerRequestMsg<'h> {
         uploaded: u64,
         event: ClientState,
         port: u16,
+        url_data: &'h [u8],
     ) -> (Self, u32) {
         let trans_id = rand::random();
 
@@ -163,12 +324,41 @@ impl<'h> TrackerRequestMsg<'h> {
             left,
             uploaded,
             event,
+            num_want: -1,
             port,
+            url_data,
         };
 
         (announce, trans_id)
     }
 
+    fn new_scrape(conn_id: u64, info_hashes: &'h [[u8; 20]]) -> (Self, u32) {
+        let trans_id = rand::random();
+
+        let scrape = Self::Scrape {
+            conn_id,
+            trans_id,
+            info_hashes,
+        };
+
+        (scrape, trans_id)
+    }
+
+    /// The URLData option can carry at most 255 bytes, longer data is split
+    /// into multiple consecutive options. The options end with EndOfOptions (BEP 41).
+    fn put_url_data(url_data: &[u8], buf: &mut Vec<u8>) {
+        const END_OF_OPTIONS: u8 = 0x0;
+        const URL_DATA: u8 = 0x2;
+
+        for chunk in url_data.chunks(u8::MAX as usize) {
+            buf.push(URL_DATA);
+            buf.push(chunk.len() as u8);
+            buf.extend_from_slice(chunk);
+        }
+
+        buf.push(END_OF_OPTIONS);
+    }
+
     fn encode(&self) -> Vec<u8> {
         let put_u16 = |num, buf: &mut Vec<u8>| buf.extend_from_slice(&u16::to_be_bytes(num));
         let put_u32 = |num, buf: &mut Vec<u8>| buf.extend_from_slice(&u32::to_be_bytes(num));
@@ -178,8 +368,7 @@ impl<'h> TrackerRequestMsg<'h> {
             TrackerRequestMsg::Connect { trans_id } => {
                 let mut buf = Vec::with_capacity(16);
 
-                // Magic constant identifying the UDP tracker protocol
-                put_u64(0x41727101980, &mut buf);
+                put_u64(PROTOCOL_ID, &mut buf);
                 put_u32(Action::Connect as u32, &mut buf);
                 put_u32(trans_id, &mut buf);
 
@@ -194,7 +383,9 @@ impl<'h> TrackerRequestMsg<'h> {
                 left,
                 uploaded,
                 event,
+                num_want,
                 port,
+                url_data,
             } => {
                 let mut buf = Vec::with_capacity(98);
 
@@ -211,57 +402,156 @@ impl<'h> TrackerRequestMsg<'h> {
                 put_u32(event as u32, &mut buf);
                 put_u32(0, &mut buf); // ip
                 put_u32(rand::random(), &mut buf); // key
-                put_u32(u32::MAX, &mut buf); // numwant
+                put_u32(num_want as u32, &mut buf);
                 put_u16(port, &mut buf);
 
+                if !url_data.is_empty() {
+                    Self::put_url_data(url_data, &mut buf);
+                }
+
+                buf
+            }
+            TrackerRequestMsg::Scrape {
+                conn_id,
+                trans_id,
+                info_hashes,
+            } => {
+                let mut buf = Vec::with_capacity(16 + 20 * info_hashes.len());
+
+                put_u64(conn_id, &mut buf);
+                put_u32(Action::Scrape as u32, &mut buf);
+                put_u32(trans_id, &mut buf);
+
+                for info_hash in info_hashes {
+                    buf.extend_from_slice(info_hash);
+                }
+
                 buf
             }
         }
     }
-}
 
-#[derive(Error, Debug)]
-enum TrackerMsgEncodeErr {
-    #[error("IO error: '{0}'")]
-    Io(#[from] std::io::Error),
+    /// Decodes a request received by the tracker server.
+    /// The announced IP address, the key, the number of wanted peers and the options are ignored.
+    pub fn decode(mut src: &'h [u8]) -> Result<Self, TrackerMsgDecodeErr> {
+        const MIN_PACKET_LEN: usize = 8 + 4 + 4;
+        const ANNOUNCE_LEN: usize = 98;
+
+        if src.len() < MIN_PACKET_LEN {
+            return Err(TrackerMsgDecodeErr::MalformedPacket);
+        }
+
+        let packet_len = src.len();
+        let conn_id = src.get_u64();
+        let action = Action::try_from(src.get_u32())?;
+        let trans_id = src.get_u32();
+
+        match action {
+            Action::Connect if conn_id == PROTOCOL_ID => Ok(Self::Connect { trans_id }),
+            Action::Announce if packet_len >= ANNOUNCE_LEN => {
+                // UNWRAP: the length was checked
+                let info_hash = src[..20].try_into().unwrap();
+                let peer_id = src[20..40].try_into().unwrap();
+                src = &src[40..];
+
+                let downloaded = src.get_u64();
+                let left = src.get_u64();
+                let uploaded = src.get_u64();
+                let event = ClientState::from_u32(src.get_u32())
+                    .ok_or(TrackerMsgDecodeErr::MalformedPacket)?;
+                // ip, key
+                src.advance(4 + 4);
+                let num_want = src.get_i32();
+                let port = src.get_u16();
+
+                Ok(Self::Announce {
+                    conn_id,
+                    trans_id,
+                    info_hash,
+                    peer_id,
+                    downloaded,
+                    left,
+                    uploaded,
+                    event,
+                    num_want,
+                    port,
+                    url_data: &[],
+                })
+            }
+            Action::Scrape => {
+                let (info_hashes, rest) = src.as_chunks::<20>();
+                if !rest.is_empty() {
+                    return Err(TrackerMsgDecodeErr::MalformedPacket);
+                }
+
+                Ok(Self::Scrape {
+                    conn_id,
+                    trans_id,
+                    info_hashes,
+                })
+            }
+            _ => Err(TrackerMsgDecodeErr::MalformedPacket),
+        }
+    }
 }
 
+/// Responses are encoded by the tracker server and decoded by the client
 #[derive(Debug)]
 #[cfg_attr(test, derive(PartialEq))]
-enum TrackerResponseMsg {
+pub enum TrackerResponseMsg {
     Connect(ConnectResponseMsg),
     Announce(AnnounceResponseMsg),
+    Scrape(ScrapeResponseMsg),
     Error(ErrorResponseMsg),
 }
 
 #[derive(Debug)]
 #[cfg_attr(test, derive(PartialEq))]
-struct ConnectResponseMsg {
+pub struct ConnectResponseMsg {
     // action id: u32
-    trans_id: u32,
-    conn_id: u64,
+    pub trans_id: u32,
+    pub conn_id: u64,
 }
 
 #[derive(Debug)]
 #[cfg_attr(test, derive(PartialEq))]
-struct AnnounceResponseMsg {
+pub struct AnnounceResponseMsg {
     // ation id: u32
-    trans_id: u32,
-    interval: u32,
-    leechers: u32,
-    seeders: u32,
-    peers: Vec<SocketAddrV4>,
+    pub trans_id: u32,
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    /// All of the same IP version as the tracker address
+    pub peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct ScrapeResponseMsg {
+    // ation id: u32
+    pub trans_id: u32,
+    /// One entry for every requested info hash
+    pub swarms: Vec<ScrapeInfo>,
 }
 
 #[derive(Debug, PartialEq)]
-struct ErrorResponseMsg {
+pub struct ErrorResponseMsg {
     // ation id: u32
-    trans_id: u32,
+    pub trans_id: u32,
     // Seems that this is not a null-terminated string
-    error: String,
+    pub error: String,
 }
 
 impl TrackerResponseMsg {
+    fn trans_id(&self) -> u32 {
+        match self {
+            TrackerResponseMsg::Connect(c) => c.trans_id,
+            TrackerResponseMsg::Announce(a) => a.trans_id,
+            TrackerResponseMsg::Scrape(s) => s.trans_id,
+            TrackerResponseMsg::Error(e) => e.trans_id,
+        }
+    }
+
     fn expect_conncet(self) -> Result<ConnectResponseMsg, TrErr> {
         match self {
             TrackerResponseMsg::Connect(c) => Ok(c),
@@ -281,16 +571,25 @@ impl TrackerResponseMsg {
             }
         }
     }
-}
 
-impl TryFrom<&[u8]> for TrackerResponseMsg {
-    type Error = TrackerMsgDecodeErr;
+    fn expect_scrape(self) -> Result<ScrapeResponseMsg, TrErr> {
+        match self {
+            TrackerResponseMsg::Scrape(s) => Ok(s),
+            m => {
+                tracing::debug!("Unexpected message from UDP tracked: '{:?}'", m);
+                Err(TrErr::TrackerProtocolErr)
+            }
+        }
+    }
+}
 
-    fn try_from(mut src: &[u8]) -> Result<Self, TrackerMsgDecodeErr> {
+impl TrackerResponseMsg {
+    /// The length of the peer addresses depends on the IP version used to contact the tracker
+    fn decode(mut src: &[u8], peer_addr_len: usize) -> Result<Self, TrackerMsgDecodeErr> {
         const MIN_PACKET_LEN: usize = 4 + 4;
         const MIN_CONNECT_LEN: usize = 4 + 4 + 8;
         const MIN_ANNOUNCE_LEN: usize = 4 + 4 + 4 + 4 + 4;
-        const SOCKET_ADDR_LEN: usize = 6;
+        const SCRAPE_INFO_LEN: usize = 4 + 4 + 4;
 
         // src size shrinks with every get_x() !
         let packet_len = src.len();
@@ -310,10 +609,10 @@ impl TryFrom<&[u8]> for TrackerResponseMsg {
                 let trans_id = src.get_u32();
                 let conn_id = src.get_u64();
 
-                return Ok(TrackerResponseMsg::Connect(ConnectResponseMsg {
+                Ok(TrackerResponseMsg::Connect(ConnectResponseMsg {
                     trans_id,
                     conn_id,
-                }));
+                }))
             }
             Action::Announce => {
                 if packet_len < MIN_ANNOUNCE_LEN {
@@ -325,25 +624,33 @@ impl TryFrom<&[u8]> for TrackerResponseMsg {
                 let leechers = src.get_u32();
                 let seeders = src.get_u32();
 
-                let peers: Vec<SocketAddrV4> = src
-                    .chunks_exact(SOCKET_ADDR_LEN)
-                    .map(|c| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(c[0], c[1], c[2], c[3]),
-                            u16::from_be_bytes([c[4], c[5]]),
-                        )
-                    })
-                    .collect();
+                let peers = decode_compact_peers(src, peer_addr_len);
 
-                return Ok(TrackerResponseMsg::Announce(AnnounceResponseMsg {
+                Ok(TrackerResponseMsg::Announce(AnnounceResponseMsg {
                     trans_id,
                     interval,
                     leechers,
                     seeders,
                     peers,
-                }));
+                }))
+            }
+            Action::Scrape => {
+                let trans_id = src.get_u32();
+
+                let swarms = src
+                    .chunks_exact(SCRAPE_INFO_LEN)
+                    .map(|mut c| ScrapeInfo {
+                        seeders: c.get_u32(),
+                        completed: c.get_u32(),
+                        leechers: c.get_u32(),
+                    })
+                    .collect();
+
+                Ok(TrackerResponseMsg::Scrape(ScrapeResponseMsg {
+                    trans_id,
+                    swarms,
+                }))
             }
-            Action::Scrape => unimplemented!(),
             Action::Error => {
                 // We know that the packet has enough data to hold the error message
                 let trans_id = src.get_u32();
@@ -356,6 +663,48 @@ impl TryFrom<&[u8]> for TrackerResponseMsg {
             }
         }
     }
+
+    /// Encodes a response of the tracker server, the peers have to be of the same IP version
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let put_u32 = |num, buf: &mut Vec<u8>| buf.extend_from_slice(&u32::to_be_bytes(num));
+
+        match self {
+            TrackerResponseMsg::Connect(c) => {
+                put_u32(Action::Connect as u32, &mut buf);
+                put_u32(c.trans_id, &mut buf);
+                buf.extend_from_slice(&c.conn_id.to_be_bytes());
+            }
+            TrackerResponseMsg::Announce(a) => {
+                put_u32(Action::Announce as u32, &mut buf);
+                put_u32(a.trans_id, &mut buf);
+                put_u32(a.interval, &mut buf);
+                put_u32(a.leechers, &mut buf);
+                put_u32(a.seeders, &mut buf);
+
+                for peer in &a.peers {
+                    encode_compact_peer(peer, &mut buf);
+                }
+            }
+            TrackerResponseMsg::Scrape(s) => {
+                put_u32(Action::Scrape as u32, &mut buf);
+                put_u32(s.trans_id, &mut buf);
+
+                for swarm in &s.swarms {
+                    put_u32(swarm.seeders, &mut buf);
+                    put_u32(swarm.completed, &mut buf);
+                    put_u32(swarm.leechers, &mut buf);
+                }
+            }
+            TrackerResponseMsg::Error(e) => {
+                put_u32(Action::Error as u32, &mut buf);
+                put_u32(e.trans_id, &mut buf);
+                buf.extend_from_slice(e.error.as_bytes());
+            }
+        }
+
+        buf
+    }
 }
 
 #[derive(Error, Debug)]
@@ -391,6 +740,11 @@ impl TryFrom<u32> for Action {
 
 #[cfg(test)]
 mod test_udp_messages {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
     use super::*;
 
     #[test]
@@ -425,6 +779,7 @@ mod test_udp_messages {
             0x00_00_00_00_99_88_77_66,
             ClientState::Started,
             0xAB_CD,
+            &[],
         );
         let buf = announce_msg.encode();
 
@@ -452,7 +807,7 @@ mod test_udp_messages {
             0x55, 0x66, 0x77, 0x88, 0, 0, 0, 0, // conn_id
         ];
 
-        let got_msg = TrackerResponseMsg::try_from(buf.as_slice()).unwrap();
+        let got_msg = TrackerResponseMsg::decode(buf.as_slice(), COMPACT_V4_LEN).unwrap();
 
         let expected_msg = TrackerResponseMsg::Connect(ConnectResponseMsg {
             trans_id: 0x11_22_33_44,
@@ -473,7 +828,7 @@ mod test_udp_messages {
                   // no peers for now
         ];
 
-        let got_msg = TrackerResponseMsg::try_from(buf.as_slice()).unwrap();
+        let got_msg = TrackerResponseMsg::decode(buf.as_slice(), COMPACT_V4_LEN).unwrap();
 
         let expected_msg = TrackerResponseMsg::Announce(AnnounceResponseMsg {
             trans_id: 0x11_22_33_44,
@@ -489,7 +844,7 @@ mod test_udp_messages {
         buf.extend_from_slice(&[0, 0x11, 0, 0x22, 0x33, 0x44]);
         buf.extend_from_slice(&[0, 0x55, 0, 0x66, 0x77, 0x88]);
 
-        let got_msg = TrackerResponseMsg::try_from(buf.as_slice()).unwrap();
+        let got_msg = TrackerResponseMsg::decode(buf.as_slice(), COMPACT_V4_LEN).unwrap();
 
         let expected_msg = TrackerResponseMsg::Announce(AnnounceResponseMsg {
             trans_id: 0x11_22_33_44,
@@ -497,8 +852,8 @@ mod test_udp_messages {
             leechers: 0x99_AA_BB_CC,
             seeders: 0xDD_EE_FF_00,
             peers: vec![
-                SocketAddrV4::new(Ipv4Addr::new(0, 0x11, 0, 0x22), 0x33_44),
-                SocketAddrV4::new(Ipv4Addr::new(0, 0x55, 0, 0x66), 0x77_88),
+                SocketAddr::from((Ipv4Addr::new(0, 0x11, 0, 0x22), 0x33_44)),
+                SocketAddr::from((Ipv4Addr::new(0, 0x55, 0, 0x66), 0x77_88)),
             ],
         });
 
@@ -507,7 +862,7 @@ mod test_udp_messages {
         // 2 peers, potentially extension bytes at the end
         buf.extend_from_slice(&[23, 42]);
 
-        let got_msg = TrackerResponseMsg::try_from(buf.as_slice()).unwrap();
+        let got_msg = TrackerResponseMsg::decode(buf.as_slice(), COMPACT_V4_LEN).unwrap();
 
         let expected_msg = TrackerResponseMsg::Announce(AnnounceResponseMsg {
             trans_id: 0x11_22_33_44,
@@ -515,8 +870,66 @@ mod test_udp_messages {
             leechers: 0x99_AA_BB_CC,
             seeders: 0xDD_EE_FF_00,
             peers: vec![
-                SocketAddrV4::new(Ipv4Addr::new(0, 0x11, 0, 0x22), 0x33_44),
-                SocketAddrV4::new(Ipv4Addr::new(0, 0x55, 0, 0x66), 0x77_88),
+                SocketAddr::from((Ipv4Addr::new(0, 0x11, 0, 0x22), 0x33_44)),
+                SocketAddr::from((Ipv4Addr::new(0, 0x55, 0, 0x66), 0x77_88)),
+            ],
+        });
+
+        assert_eq!(got_msg, expected_msg);
+
+        // 1 IPv6 peer from a tracker contacted over IPv6
+        buf.truncate(20);
+        buf.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        buf.extend_from_slice(&[0x1a, 0xe1]);
+
+        let got_msg = TrackerResponseMsg::decode(buf.as_slice(), COMPACT_V6_LEN).unwrap();
+
+        let expected_msg = TrackerResponseMsg::Announce(AnnounceResponseMsg {
+            trans_id: 0x11_22_33_44,
+            interval: 0x55_66_77_88,
+            leechers: 0x99_AA_BB_CC,
+            seeders: 0xDD_EE_FF_00,
+            peers: vec![SocketAddr::from((Ipv6Addr::LOCALHOST, 6881))],
+        });
+
+        assert_eq!(got_msg, expected_msg);
+    }
+
+    #[test]
+    fn test_scrape() {
+        let info_hashes = [[1; 20], [2; 20]];
+        let (scrape_msg, trans_id) = TrackerRequestMsg::new_scrape(0x11, &info_hashes);
+        let buf = scrape_msg.encode();
+
+        assert_eq!(buf.len(), 56);
+        assert_eq!(&buf[..8], [0, 0, 0, 0, 0, 0, 0, 0x11]);
+        assert_eq!(&buf[8..12], [0, 0, 0, 2]); // scrape request = 2
+        assert_eq!(&buf[12..16], u32::to_be_bytes(trans_id));
+        assert_eq!(&buf[16..36], [1; 20]);
+        assert_eq!(&buf[36..56], [2; 20]);
+
+        let buf = vec![
+            0, 0, 0, 2, // action
+            0x11, 0x22, 0x33, 0x44, // trans_id
+            0, 0, 0, 5, 0, 0, 0, 50, 0, 0, 0, 10, // first torrent
+            0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, // second torrent
+        ];
+
+        let got_msg = TrackerResponseMsg::decode(buf.as_slice(), COMPACT_V4_LEN).unwrap();
+
+        let expected_msg = TrackerResponseMsg::Scrape(ScrapeResponseMsg {
+            trans_id: 0x11_22_33_44,
+            swarms: vec![
+                ScrapeInfo {
+                    seeders: 5,
+                    completed: 50,
+                    leechers: 10,
+                },
+                ScrapeInfo {
+                    seeders: 0,
+                    completed: 1,
+                    leechers: 2,
+                },
             ],
         });
 
@@ -532,7 +945,7 @@ mod test_udp_messages {
             115, 105, 116, 32, 97, 109, 101, 116, // message
         ];
 
-        let got_msg = TrackerResponseMsg::try_from(buf.as_slice()).unwrap();
+        let got_msg = TrackerResponseMsg::decode(buf.as_slice(), COMPACT_V4_LEN).unwrap();
 
         let expected_msg = TrackerResponseMsg::Error(ErrorResponseMsg {
             trans_id: 0x11_22_33_44,
@@ -541,4 +954,190 @@ mod test_udp_messages {
 
         assert_eq!(got_msg, expected_msg);
     }
+
+    #[test]
+    fn test_url_data_encode() {
+        let url_data = [b'a'; 300];
+        let (announce_msg, _) = TrackerRequestMsg::new_announce(
+            0,
+            &[0; 20],
+            &[0; 20],
+            0,
+            0,
+            0,
+            ClientState::None,
+            0,
+            &url_data,
+        );
+        let buf = announce_msg.encode();
+
+        assert_eq!(buf.len(), 98 + 2 + 255 + 2 + 45 + 1);
+        assert_eq!(&buf[98..100], [0x2, 255]);
+        assert_eq!(&buf[355..357], [0x2, 45]);
+        assert_eq!(buf[402], 0x0);
+    }
+
+    #[test]
+    fn test_server_roundtrip() {
+        let info_hashes = [[1; 20], [2; 20]];
+        let (connect_msg, _) = TrackerRequestMsg::new_connect();
+        let (announce_msg, _) = TrackerRequestMsg::new_announce(
+            0x11,
+            &info_hashes[0],
+            &info_hashes[1],
+            1,
+            2,
+            3,
+            ClientState::Completed,
+            6881,
+            &[],
+        );
+        let (scrape_msg, _) = TrackerRequestMsg::new_scrape(0x11, &info_hashes);
+
+        for msg in [connect_msg, announce_msg, scrape_msg] {
+            let buf = msg.encode();
+            assert_eq!(TrackerRequestMsg::decode(&buf).unwrap(), msg);
+        }
+
+        // Connect requests without the magic constant are rejected
+        let mut buf = TrackerRequestMsg::new_connect().0.encode();
+        buf[0] = 1;
+        assert!(TrackerRequestMsg::decode(&buf).is_err());
+
+        let responses = [
+            TrackerResponseMsg::Connect(ConnectResponseMsg {
+                trans_id: 1,
+                conn_id: 2,
+            }),
+            TrackerResponseMsg::Announce(AnnounceResponseMsg {
+                trans_id: 1,
+                interval: 300,
+                leechers: 2,
+                seeders: 3,
+                peers: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 6881))],
+            }),
+            TrackerResponseMsg::Scrape(ScrapeResponseMsg {
+                trans_id: 1,
+                swarms: vec![ScrapeInfo {
+                    seeders: 1,
+                    completed: 2,
+                    leechers: 3,
+                }],
+            }),
+            TrackerResponseMsg::Error(ErrorResponseMsg {
+                trans_id: 1,
+                error: String::from("invalid connection ID"),
+            }),
+        ];
+
+        for msg in responses {
+            let buf = msg.encode();
+            assert_eq!(
+                TrackerResponseMsg::decode(&buf, COMPACT_V4_LEN).unwrap(),
+                msg
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_init() {
+        let url_data = |url| UdpTracker::url_data(&Url::parse(url).unwrap());
+
+        assert_eq!(url_data("udp://tracker.example:1337"), b"");
+        assert_eq!(url_data("udp://tracker.example:1337/"), b"");
+        assert_eq!(url_data("udp://[::1]:1337/announce"), b"/announce");
+        assert_eq!(
+            url_data("udp://tracker.example:1337/dir/announce?passkey=abc#fragment"),
+            b"/dir/announce?passkey=abc"
+        );
+
+        let tracker = UdpTracker::init("udp://127.0.0.1:1337/announce?a=b")
+            .await
+            .unwrap();
+        assert_eq!(tracker.url_data, b"/announce?a=b");
+
+        for url in [
+            "udp://127.0.0.1/announce",
+            "http://127.0.0.1:1337/announce",
+            "udp://:1337",
+            "127.0.0.1:1337",
+        ] {
+            assert!(matches!(
+                UdpTracker::init(url).await,
+                Err(TrErr::InvalidUrl)
+            ));
+        }
+    }
+
+    fn packet(action: u32, trans_id: u32, body: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&trans_id.to_be_bytes());
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    /// Sends an error and a response with stale transaction IDs before every response,
+    /// counts the connect requests
+    async fn mock_tracker(socket: tokio::net::UdpSocket, connects: Arc<AtomicU32>) {
+        let mut buf = [0; 2048];
+
+        loop {
+            let (_, addr) = socket.recv_from(&mut buf).await.unwrap();
+            let action = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+            let trans_id = u32::from_be_bytes(buf[12..16].try_into().unwrap());
+            let stale_id = trans_id.wrapping_add(1);
+
+            let resp = if action == 0 {
+                connects.fetch_add(1, Ordering::SeqCst);
+                packet(0, trans_id, &0x1122u64.to_be_bytes())
+            } else {
+                assert_eq!(buf[..8], 0x1122u64.to_be_bytes());
+
+                let mut body = Vec::new();
+                body.extend_from_slice(&1800u32.to_be_bytes());
+                body.extend_from_slice(&2u32.to_be_bytes());
+                body.extend_from_slice(&3u32.to_be_bytes());
+                body.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+                packet(1, trans_id, &body)
+            };
+
+            let stale_resp = packet(action, stale_id, &resp[8..]);
+            for p in [packet(3, stale_id, b"stale"), stale_resp, resp] {
+                socket.send_to(&p, addr).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transactions() {
+        let server = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let url = format!("udp://{}/announce", server.local_addr().unwrap());
+
+        let connects = Arc::new(AtomicU32::new(0));
+        tokio::spawn(mock_tracker(server, connects.clone()));
+
+        let mut tracker = UdpTracker::init(&url).await.unwrap();
+
+        for _ in 0..2 {
+            let resp = tracker
+                .announce(&[1; 20], Transfer::default(), ClientState::None, &[2; 20])
+                .await
+                .unwrap();
+
+            assert_eq!(resp.interval, 1800);
+            assert_eq!(
+                resp.peers,
+                [PeerAddr::from(SocketAddr::from((
+                    Ipv4Addr::LOCALHOST,
+                    6881
+                )))]
+            );
+        }
+
+        // The stale responses were ignored and the connection ID was reused
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
 }
@@ -0,0 +1,169 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    time::Instant,
+};
+
+use self::swarms::Swarms;
+
+mod http;
+mod swarms;
+mod udp;
+
+/// Interval of the regular announces in seconds, short enough to keep local swarms connected
+const ANNOUNCE_INTERVAL: u32 = 300;
+const MIN_ANNOUNCE_INTERVAL: u32 = 60;
+
+/// A tracker for running swarms without the internet, e.g. in tests.
+/// Serves HTTP announces and scrapes and the UDP tracker protocol (BEP 15),
+/// the swarms are only kept in memory.
+pub struct TrackerServer {
+    swarms: Arc<Mutex<Swarms>>,
+    http_listener: Option<TcpListener>,
+    udp_socket: Option<UdpSocket>,
+}
+
+impl TrackerServer {
+    /// Binds the sockets, port 0 picks a free port
+    pub async fn bind(
+        http_addr: Option<SocketAddr>,
+        udp_addr: Option<SocketAddr>,
+    ) -> ServerResult<Self> {
+        let http_listener = match http_addr {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        let udp_socket = match udp_addr {
+            Some(addr) => Some(UdpSocket::bind(addr).await?),
+            None => None,
+        };
+
+        Ok(Self {
+            swarms: Arc::new(Mutex::new(Swarms::default())),
+            http_listener,
+            udp_socket,
+        })
+    }
+
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_listener.as_ref()?.local_addr().ok()
+    }
+
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.udp_socket.as_ref()?.local_addr().ok()
+    }
+
+    /// Serves the requests until an IO error occurs
+    pub async fn start(self) -> ServerResult<()> {
+        let http = async {
+            match self.http_listener {
+                Some(listener) => http::serve(listener, Arc::clone(&self.swarms)).await,
+                None => Ok(()),
+            }
+        };
+        let udp = async {
+            match self.udp_socket {
+                Some(socket) => udp::serve(socket, Arc::clone(&self.swarms)).await,
+                None => Ok(()),
+            }
+        };
+
+        tokio::try_join!(http, udp, expire_peers(Arc::clone(&self.swarms)))?;
+
+        Ok(())
+    }
+}
+
+/// Periodically removes the peers that stopped announcing
+async fn expire_peers(swarms: Arc<Mutex<Swarms>>) -> ServerResult<()> {
+    const EXPIRY_INTERVAL: u64 = 60;
+
+    let mut interval = tokio::time::interval(Duration::from_secs(EXPIRY_INTERVAL));
+
+    loop {
+        interval.tick().await;
+        lock(&swarms).expire(Instant::now());
+    }
+}
+
+fn lock(swarms: &Mutex<Swarms>) -> MutexGuard<'_, Swarms> {
+    swarms
+        .lock()
+        .expect("Internal error: a tracker server task panicked while holding the swarms")
+}
+
+pub type ServerResult<T> = Result<T, ServerErr>;
+
+#[derive(Error, Debug)]
+pub enum ServerErr {
+    #[error("General IO error: '{0}'")]
+    Io(#[from] std::io::Error),
+    #[error("Received an invalid HTTP request")]
+    InvalidRequest,
+    #[error("The client didn't send the request in time")]
+    Timeout,
+}
+
+#[cfg(test)]
+mod test_tracker_server {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::tracker_manager::{self, ScrapeInfo};
+
+    #[tokio::test]
+    async fn test_announce_and_scrape() {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let server = TrackerServer::bind(Some(localhost), Some(localhost))
+            .await
+            .unwrap();
+
+        let http_url = format!("http://{}/announce", server.http_addr().unwrap());
+        let udp_url = format!("udp://{}/announce", server.udp_addr().unwrap());
+        tokio::spawn(server.start());
+
+        let client = tracker_manager::http_client(None).unwrap();
+        let info_hash = [1; 20];
+
+        // The UDP client announces the port of its socket, the HTTP client the default port
+        let peers = tracker_manager::find_peers(
+            std::slice::from_ref(&udp_url),
+            &info_hash,
+            &[2; 20],
+            &client,
+        )
+        .await;
+        assert!(peers.is_empty());
+
+        let peers = tracker_manager::find_peers(
+            std::slice::from_ref(&http_url),
+            &info_hash,
+            &[3; 20],
+            &client,
+        )
+        .await;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+
+        for url in [&http_url, &udp_url] {
+            let swarms = tracker_manager::scrape(url, &[info_hash, [9; 20]], &client)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                swarms.get(&info_hash),
+                Some(&ScrapeInfo {
+                    seeders: 0,
+                    completed: 0,
+                    leechers: 2
+                })
+            );
+        }
+    }
+}
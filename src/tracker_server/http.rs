@@ -0,0 +1,379 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::Instant,
+};
+
+use super::{
+    lock,
+    swarms::{Announce, Swarms, DEFAULT_NUM_WANT},
+    ServerErr, ServerResult, ANNOUNCE_INTERVAL, MIN_ANNOUNCE_INTERVAL,
+};
+use crate::{
+    bencoding::ser,
+    tracker_manager::{encode_compact_peer, ClientState},
+};
+
+/// Trackers only receive GET requests, so the header is the whole request
+const MAX_REQUEST_LEN: usize = 8192;
+const REQUEST_TIMEOUT: u64 = 10;
+
+pub async fn serve(listener: TcpListener, swarms: Arc<Mutex<Swarms>>) -> ServerResult<()> {
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            // E.g. too many open files, the next connection might succeed
+            Err(e) => {
+                tracing::warn!("Couldn't accept an HTTP tracker connection: '{}'", e);
+                continue;
+            }
+        };
+
+        let swarms = Arc::clone(&swarms);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, remote, &swarms).await {
+                tracing::debug!("HTTP tracker connection error: '{}'", e);
+            }
+        });
+    }
+}
+
+/// Answers a single request and closes the connection
+async fn handle_connection(
+    mut stream: TcpStream,
+    remote: SocketAddr,
+    swarms: &Mutex<Swarms>,
+) -> ServerResult<()> {
+    let request = tokio::time::timeout(
+        Duration::from_secs(REQUEST_TIMEOUT),
+        read_request(&mut stream),
+    )
+    .await
+    .map_err(|_| ServerErr::Timeout)??;
+
+    let (status, body) = match request_target(&request) {
+        Some(target) => respond(target, remote, swarms),
+        None => ("400 Bad Request", Vec::new()),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Reads until the end of the header
+async fn read_request(stream: &mut TcpStream) -> ServerResult<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_LEN {
+            return Err(ServerErr::InvalidRequest);
+        }
+
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    Ok(request)
+}
+
+/// The target of a GET request, e.g. '/announce?info_hash=...'
+fn request_target(request: &[u8]) -> Option<&str> {
+    let line = request.split(|b| *b == b'\r').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+
+    match line.split(' ').collect::<Vec<_>>()[..] {
+        ["GET", target, version] if version.starts_with("HTTP/1.") => Some(target),
+        _ => None,
+    }
+}
+
+/// Returns the status and the body of the response
+fn respond(target: &str, remote: SocketAddr, swarms: &Mutex<Swarms>) -> (&'static str, Vec<u8>) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = query_params(query);
+
+    // The last path component decides, so that the URLs can contain passkeys and such
+    match path.rsplit('/').next() {
+        Some("announce") => ("200 OK", announce(&params, remote, swarms)),
+        Some("scrape") => ("200 OK", scrape(&params, swarms)),
+        _ => ("404 Not Found", Vec::new()),
+    }
+}
+
+type QueryParams<'q> = Vec<(&'q str, Cow<'q, [u8]>)>;
+
+fn query_params(query: &str) -> QueryParams<'_> {
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (key, val) = p.split_once('=').unwrap_or((p, ""));
+            (key, urlencoding::decode_binary(val.as_bytes()))
+        })
+        .collect()
+}
+
+fn param<'p>(params: &'p QueryParams, key: &str) -> Option<&'p [u8]> {
+    params.iter().find(|(k, _)| *k == key).map(|(_, v)| &v[..])
+}
+
+fn param_str<'p>(params: &'p QueryParams, key: &str) -> Option<&'p str> {
+    param(params, key).and_then(|v| std::str::from_utf8(v).ok())
+}
+
+fn announce(params: &QueryParams, remote: SocketAddr, swarms: &Mutex<Swarms>) -> Vec<u8> {
+    let (announce, compact) = match parse_announce(params, remote) {
+        Ok(announce) => announce,
+        Err(reason) => {
+            // UNWRAP: the response only contains serializable types
+            return ser::to_bytes(&FailureResponse {
+                failure_reason: reason,
+            })
+            .unwrap();
+        }
+    };
+
+    let reply = lock(swarms).announce(&announce, Instant::now());
+
+    let mut peers_v4 = Vec::new();
+    let mut peers_v6 = Vec::new();
+    for peer in &reply.peers {
+        match peer.socket_addr {
+            SocketAddr::V4(_) => encode_compact_peer(&peer.socket_addr, &mut peers_v4),
+            SocketAddr::V6(_) => encode_compact_peer(&peer.socket_addr, &mut peers_v6),
+        }
+    }
+
+    let peers = match compact {
+        true => Peers::Compact(ByteBuf::from(peers_v4)),
+        false => Peers::Dicts(
+            reply
+                .peers
+                .iter()
+                .map(|p| PeerDict {
+                    // UNWRAP: the tracker knows the IDs of all peers
+                    peer_id: ByteBuf::from(p.peer_id.unwrap().to_vec()),
+                    ip: p.socket_addr.ip().to_string(),
+                    port: p.socket_addr.port(),
+                })
+                .collect(),
+        ),
+    };
+
+    let response = AnnounceResponse {
+        interval: ANNOUNCE_INTERVAL,
+        min_interval: MIN_ANNOUNCE_INTERVAL,
+        complete: reply.seeders,
+        incomplete: reply.leechers,
+        peers,
+        // The dictionaries contain the IPv6 peers as well
+        peers6: (compact && !peers_v6.is_empty()).then(|| ByteBuf::from(peers_v6)),
+    };
+
+    // UNWRAP: the response only contains serializable types
+    ser::to_bytes(&response).unwrap()
+}
+
+/// Returns the announce and whether the peers should be compact, or the failure reason
+fn parse_announce(
+    params: &QueryParams,
+    remote: SocketAddr,
+) -> Result<(Announce, bool), &'static str> {
+    let info_hash = param(params, "info_hash")
+        .and_then(|v| v.try_into().ok())
+        .ok_or("invalid info_hash")?;
+    let peer_id = param(params, "peer_id")
+        .and_then(|v| v.try_into().ok())
+        .ok_or("invalid peer_id")?;
+    let port = param_str(params, "port")
+        .and_then(|v| v.parse().ok())
+        .ok_or("invalid port")?;
+    let left = param_str(params, "left")
+        .and_then(|v| v.parse().ok())
+        .ok_or("invalid left")?;
+    let event = ClientState::from_event(param_str(params, "event")).ok_or("invalid event")?;
+    let num_want = match param_str(params, "numwant") {
+        Some(v) => v.parse().map_err(|_| "invalid numwant")?,
+        None => DEFAULT_NUM_WANT,
+    };
+    // Compact responses are the default, the dictionaries are much larger
+    let compact = param_str(params, "compact") != Some("0");
+
+    let announce = Announce {
+        info_hash,
+        peer_id,
+        // IPv4 clients of dual-stack sockets have mapped addresses
+        addr: SocketAddr::new(remote.ip().to_canonical(), port),
+        left,
+        event,
+        num_want,
+        same_ip_version: false,
+    };
+
+    Ok((announce, compact))
+}
+
+/// Unknown torrents are left out of the response
+fn scrape(params: &QueryParams, swarms: &Mutex<Swarms>) -> Vec<u8> {
+    let swarms = lock(swarms);
+
+    let files = params
+        .iter()
+        .filter(|(key, _)| *key == "info_hash")
+        .filter_map(|(_, info_hash)| {
+            let info = swarms.scrape(&info_hash[..].try_into().ok()?)?;
+            let file = ScrapeFile {
+                complete: info.seeders,
+                downloaded: info.completed,
+                incomplete: info.leechers,
+            };
+
+            Some((ByteBuf::from(info_hash.to_vec()), file))
+        })
+        .collect();
+
+    // UNWRAP: the response only contains serializable types
+    ser::to_bytes(&ScrapeResponse { files }).unwrap()
+}
+
+#[derive(Serialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: &'static str,
+}
+
+#[derive(Serialize)]
+struct AnnounceResponse {
+    interval: u32,
+    #[serde(rename = "min interval")]
+    min_interval: u32,
+    /// Number of peers with the entire file
+    complete: u32,
+    /// Number of non-seeder peers
+    incomplete: u32,
+    peers: Peers,
+    /// Compact IPv6 peer list (BEP 7)
+    peers6: Option<ByteBuf>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Peers {
+    /// Compact IPv4 peer list (BEP 23)
+    Compact(ByteBuf),
+    Dicts(Vec<PeerDict>),
+}
+
+#[derive(Serialize)]
+struct PeerDict {
+    #[serde(rename = "peer id")]
+    peer_id: ByteBuf,
+    ip: String,
+    port: u16,
+}
+
+#[derive(Serialize)]
+struct ScrapeResponse {
+    /// Keyed by the info hashes
+    files: BTreeMap<ByteBuf, ScrapeFile>,
+}
+
+#[derive(Serialize)]
+struct ScrapeFile {
+    complete: u32,
+    downloaded: u32,
+    incomplete: u32,
+}
+
+#[cfg(test)]
+mod test_http {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const REMOTE: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 50000);
+
+    fn announce_target(peer_id: u8, port: u16, extra: &str) -> String {
+        format!(
+            "/announce?info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&left=10{}",
+            urlencoding::encode_binary(&[1; 20]),
+            urlencoding::encode_binary(&[peer_id; 20]),
+            port,
+            extra
+        )
+    }
+
+    #[test]
+    fn test_request_target() {
+        assert_eq!(
+            request_target(b"GET /announce?a=b HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Some("/announce?a=b")
+        );
+        assert_eq!(request_target(b"POST /announce HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(request_target(b"GET /announce\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_announce() {
+        let swarms = Mutex::new(Swarms::default());
+
+        let (status, body) = respond(&announce_target(1, 6881, ""), REMOTE, &swarms);
+        assert_eq!(status, "200 OK");
+        assert_eq!(
+            body,
+            b"d8:completei0e10:incompletei1e8:intervali300e12:min intervali60e5:peers0:e"
+        );
+
+        let (_, body) = respond(&announce_target(2, 6882, ""), REMOTE, &swarms);
+        assert!(body.ends_with(b"5:peers6:\x7f\x00\x00\x01\x1a\xe1e"));
+
+        let (_, body) = respond(&announce_target(3, 6883, "&compact=0"), REMOTE, &swarms);
+        assert!(body
+            .windows(b"2:ip9:127.0.0.1".len())
+            .any(|w| w == b"2:ip9:127.0.0.1"));
+
+        let (_, body) = respond("/tracker/announce?port=1", REMOTE, &swarms);
+        assert_eq!(body, b"d14:failure reason17:invalid info_hashe");
+
+        let (status, _) = respond("/index.html", REMOTE, &swarms);
+        assert_eq!(status, "404 Not Found");
+    }
+
+    #[test]
+    fn test_scrape() {
+        let swarms = Mutex::new(Swarms::default());
+        respond(&announce_target(1, 6881, "&event=started"), REMOTE, &swarms);
+
+        let target = format!(
+            "/scrape?info_hash={}&info_hash={}",
+            urlencoding::encode_binary(&[1; 20]),
+            urlencoding::encode_binary(&[2; 20])
+        );
+        let (_, body) = respond(&target, REMOTE, &swarms);
+
+        let mut expected = b"d5:filesd20:".to_vec();
+        expected.extend_from_slice(&[1; 20]);
+        expected.extend_from_slice(b"d8:completei0e10:downloadedi0e10:incompletei1eeee");
+        assert_eq!(body, expected);
+    }
+}
@@ -0,0 +1,240 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use rand::seq::IteratorRandom;
+use tokio::time::Instant;
+
+use crate::{
+    p2p::PeerAddr,
+    tracker_manager::{ClientState, ScrapeInfo},
+};
+
+/// Peers that didn't announce for this long are removed from the swarm
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(2 * super::ANNOUNCE_INTERVAL as u64);
+/// Used when the peer doesn't say how many peers it wants
+pub const DEFAULT_NUM_WANT: usize = 50;
+const MAX_NUM_WANT: usize = 200;
+
+/// Peers of all torrents that were announced to the tracker, kept in memory
+#[derive(Default)]
+pub struct Swarms {
+    swarms: HashMap<[u8; 20], Swarm>,
+}
+
+#[derive(Default)]
+struct Swarm {
+    /// Peers are identified by the address they accept connections on
+    peers: HashMap<SocketAddr, SwarmPeer>,
+    /// Number of 'completed' events
+    completed: u32,
+}
+
+struct SwarmPeer {
+    peer_id: [u8; 20],
+    /// The peer has the whole torrent
+    seed: bool,
+    last_seen: Instant,
+}
+
+/// An announce received over HTTP or UDP
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    /// IP address of the connection and the announced port
+    pub addr: SocketAddr,
+    pub left: u64,
+    pub event: ClientState,
+    pub num_want: usize,
+    /// Only return peers of the same IP version, UDP responses can't mix them
+    pub same_ip_version: bool,
+}
+
+/// The state of the swarm after the announce
+pub struct AnnounceReply {
+    pub seeders: u32,
+    pub leechers: u32,
+    /// Random peers of the swarm, without the announcing peer
+    pub peers: Vec<PeerAddr>,
+}
+
+impl Swarms {
+    pub fn announce(&mut self, announce: &Announce, now: Instant) -> AnnounceReply {
+        let swarm = match announce.event {
+            // Stopped peers of unknown torrents don't create a swarm
+            ClientState::Stopped => match self.swarms.get_mut(&announce.info_hash) {
+                Some(swarm) => {
+                    swarm.peers.remove(&announce.addr);
+                    swarm
+                }
+                None => {
+                    return AnnounceReply {
+                        seeders: 0,
+                        leechers: 0,
+                        peers: Vec::new(),
+                    }
+                }
+            },
+            event => {
+                let swarm = self.swarms.entry(announce.info_hash).or_default();
+                if event == ClientState::Completed {
+                    swarm.completed += 1;
+                }
+
+                swarm.peers.insert(
+                    announce.addr,
+                    SwarmPeer {
+                        peer_id: announce.peer_id,
+                        seed: announce.left == 0,
+                        last_seen: now,
+                    },
+                );
+                swarm
+            }
+        };
+
+        let peers = match announce.event {
+            ClientState::Stopped => Vec::new(),
+            _ => swarm
+                .peers
+                .iter()
+                .filter(|(addr, _)| **addr != announce.addr)
+                .filter(|(addr, _)| {
+                    !announce.same_ip_version || addr.is_ipv4() == announce.addr.is_ipv4()
+                })
+                .map(|(addr, peer)| PeerAddr {
+                    socket_addr: *addr,
+                    peer_id: Some(peer.peer_id),
+                })
+                .choose_multiple(&mut rand::thread_rng(), announce.num_want.min(MAX_NUM_WANT)),
+        };
+
+        let info = swarm.info();
+        AnnounceReply {
+            seeders: info.seeders,
+            leechers: info.leechers,
+            peers,
+        }
+    }
+
+    /// Torrents that the tracker doesn't know about are missing from the result
+    pub fn scrape(&self, info_hash: &[u8; 20]) -> Option<ScrapeInfo> {
+        self.swarms.get(info_hash).map(Swarm::info)
+    }
+
+    /// Removes the peers that stopped announcing and the empty swarms
+    pub fn expire(&mut self, now: Instant) {
+        for swarm in self.swarms.values_mut() {
+            swarm
+                .peers
+                .retain(|_, peer| now.duration_since(peer.last_seen) < PEER_TIMEOUT);
+        }
+
+        self.swarms.retain(|_, swarm| !swarm.peers.is_empty());
+    }
+}
+
+impl Swarm {
+    fn info(&self) -> ScrapeInfo {
+        let seeders = self.peers.values().filter(|p| p.seed).count() as u32;
+
+        ScrapeInfo {
+            seeders,
+            completed: self.completed,
+            leechers: self.peers.len() as u32 - seeders,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_swarms {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn announce(port: u16, left: u64, event: ClientState) -> Announce {
+        Announce {
+            info_hash: [1; 20],
+            peer_id: [port as u8; 20],
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            left,
+            event,
+            num_want: DEFAULT_NUM_WANT,
+            same_ip_version: false,
+        }
+    }
+
+    #[test]
+    fn test_announce() {
+        let mut swarms = Swarms::default();
+        let now = Instant::now();
+
+        let reply = swarms.announce(&announce(1, 100, ClientState::Started), now);
+        assert!(reply.peers.is_empty());
+        assert_eq!((reply.seeders, reply.leechers), (0, 1));
+
+        let reply = swarms.announce(&announce(2, 0, ClientState::Started), now);
+        assert_eq!(
+            reply.peers,
+            [PeerAddr {
+                socket_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 1)),
+                peer_id: Some([1; 20]),
+            }]
+        );
+        assert_eq!((reply.seeders, reply.leechers), (1, 1));
+
+        swarms.announce(&announce(1, 0, ClientState::Completed), now);
+        let reply = swarms.announce(&announce(2, 0, ClientState::Stopped), now);
+        assert!(reply.peers.is_empty());
+
+        let info = swarms.scrape(&[1; 20]).unwrap();
+        assert_eq!(
+            info,
+            ScrapeInfo {
+                seeders: 1,
+                completed: 1,
+                leechers: 0
+            }
+        );
+        assert_eq!(swarms.scrape(&[2; 20]), None);
+
+        // Stopping an unknown torrent doesn't create its swarm
+        let mut stopped = announce(3, 0, ClientState::Stopped);
+        stopped.info_hash = [2; 20];
+        let reply = swarms.announce(&stopped, now);
+        assert_eq!((reply.seeders, reply.leechers), (0, 0));
+        assert_eq!(swarms.scrape(&[2; 20]), None);
+    }
+
+    #[test]
+    fn test_ip_versions() {
+        let mut swarms = Swarms::default();
+        let now = Instant::now();
+
+        swarms.announce(&announce(1, 100, ClientState::Started), now);
+
+        let mut v6 = announce(2, 100, ClientState::Started);
+        v6.addr = SocketAddr::from((Ipv6Addr::LOCALHOST, 2));
+        v6.same_ip_version = true;
+        assert!(swarms.announce(&v6, now).peers.is_empty());
+
+        v6.same_ip_version = false;
+        assert_eq!(swarms.announce(&v6, now).peers.len(), 1);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut swarms = Swarms::default();
+        let now = Instant::now();
+
+        swarms.announce(&announce(1, 100, ClientState::Started), now);
+        swarms.announce(
+            &announce(2, 100, ClientState::Started),
+            now + PEER_TIMEOUT / 2,
+        );
+
+        swarms.expire(now + PEER_TIMEOUT);
+        assert_eq!(swarms.scrape(&[1; 20]).unwrap().leechers, 1);
+
+        swarms.expire(now + PEER_TIMEOUT * 2);
+        assert_eq!(swarms.scrape(&[1; 20]), None);
+    }
+}
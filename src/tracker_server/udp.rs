@@ -0,0 +1,251 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{net::UdpSocket, time::Instant};
+
+use super::{
+    lock,
+    swarms::{Announce, Swarms, DEFAULT_NUM_WANT},
+    ServerResult, ANNOUNCE_INTERVAL,
+};
+use crate::tracker_manager::{
+    udp::{
+        AnnounceResponseMsg, ConnectResponseMsg, ErrorResponseMsg, ScrapeResponseMsg,
+        TrackerRequestMsg, TrackerResponseMsg,
+    },
+    ScrapeInfo,
+};
+
+const MAXIMUM_PACKET_SIZE: usize = 2048;
+/// Connection IDs are valid for at least this many seconds and at most twice as long
+const CONN_ID_WINDOW: u64 = 60;
+
+pub async fn serve(socket: UdpSocket, swarms: Arc<Mutex<Swarms>>) -> ServerResult<()> {
+    let conn_ids = ConnectionIds::new();
+    let mut buf = vec![0; MAXIMUM_PACKET_SIZE];
+
+    loop {
+        let (read, remote) = socket.recv_from(&mut buf).await?;
+
+        let request = match TrackerRequestMsg::decode(&buf[..read]) {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::debug!("Ignoring a UDP tracker request from {}: '{}'", remote, e);
+                continue;
+            }
+        };
+
+        let response = respond(request, remote, &conn_ids, &swarms);
+
+        // A failed response only affects a single client
+        if let Err(e) = socket.send_to(&response.encode(), remote).await {
+            tracing::debug!(
+                "Couldn't send a UDP tracker response to {}: '{}'",
+                remote,
+                e
+            );
+        }
+    }
+}
+
+fn respond(
+    request: TrackerRequestMsg,
+    remote: SocketAddr,
+    conn_ids: &ConnectionIds,
+    swarms: &Mutex<Swarms>,
+) -> TrackerResponseMsg {
+    match request {
+        TrackerRequestMsg::Connect { trans_id } => {
+            TrackerResponseMsg::Connect(ConnectResponseMsg {
+                trans_id,
+                conn_id: conn_ids.generate(&remote, Instant::now()),
+            })
+        }
+        TrackerRequestMsg::Announce {
+            conn_id, trans_id, ..
+        }
+        | TrackerRequestMsg::Scrape {
+            conn_id, trans_id, ..
+        } if !conn_ids.is_valid(conn_id, &remote, Instant::now()) => {
+            TrackerResponseMsg::Error(ErrorResponseMsg {
+                trans_id,
+                error: String::from("invalid connection ID"),
+            })
+        }
+        TrackerRequestMsg::Announce {
+            trans_id,
+            info_hash,
+            peer_id,
+            left,
+            event,
+            num_want,
+            port,
+            ..
+        } => {
+            let announce = Announce {
+                info_hash: *info_hash,
+                peer_id: *peer_id,
+                // IPv4 clients of dual-stack sockets have mapped addresses
+                addr: SocketAddr::new(remote.ip().to_canonical(), port),
+                left,
+                event,
+                // Negative values ask for the default
+                num_want: usize::try_from(num_want).unwrap_or(DEFAULT_NUM_WANT),
+                // The peer addresses have the length of the IP version used by the client
+                same_ip_version: true,
+            };
+
+            let reply = lock(swarms).announce(&announce, Instant::now());
+
+            TrackerResponseMsg::Announce(AnnounceResponseMsg {
+                trans_id,
+                interval: ANNOUNCE_INTERVAL,
+                leechers: reply.leechers,
+                seeders: reply.seeders,
+                peers: reply.peers.iter().map(|p| p.socket_addr).collect(),
+            })
+        }
+        TrackerRequestMsg::Scrape {
+            trans_id,
+            info_hashes,
+            ..
+        } => {
+            let swarms = lock(swarms);
+
+            // The response has an entry for every requested hash, even the unknown ones
+            let swarms = info_hashes
+                .iter()
+                .map(|info_hash| {
+                    swarms.scrape(info_hash).unwrap_or(ScrapeInfo {
+                        seeders: 0,
+                        completed: 0,
+                        leechers: 0,
+                    })
+                })
+                .collect();
+
+            TrackerResponseMsg::Scrape(ScrapeResponseMsg { trans_id, swarms })
+        }
+    }
+}
+
+/// Connection IDs are derived from the client address and the time,
+/// so the server doesn't have to remember them (BEP 15)
+struct ConnectionIds {
+    secret: u64,
+    start: Instant,
+}
+
+impl ConnectionIds {
+    fn new() -> Self {
+        Self {
+            secret: rand::random(),
+            start: Instant::now(),
+        }
+    }
+
+    fn generate(&self, remote: &SocketAddr, now: Instant) -> u64 {
+        self.hash(remote, self.window(now))
+    }
+
+    /// IDs from the previous window are accepted as well, so that they don't expire too soon
+    fn is_valid(&self, conn_id: u64, remote: &SocketAddr, now: Instant) -> bool {
+        let window = self.window(now);
+
+        conn_id == self.hash(remote, window)
+            || (window > 0 && conn_id == self.hash(remote, window - 1))
+    }
+
+    fn window(&self, now: Instant) -> u64 {
+        now.duration_since(self.start).as_secs() / CONN_ID_WINDOW
+    }
+
+    fn hash(&self, remote: &SocketAddr, window: u64) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (self.secret, remote, window).hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod test_udp {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+    use crate::tracker_manager::ClientState;
+
+    #[test]
+    fn test_connection_ids() {
+        let conn_ids = ConnectionIds::new();
+        let remote = SocketAddr::from((Ipv4Addr::LOCALHOST, 6881));
+        let other = SocketAddr::from((Ipv4Addr::LOCALHOST, 6882));
+        let now = Instant::now();
+
+        let conn_id = conn_ids.generate(&remote, now);
+        assert!(conn_ids.is_valid(conn_id, &remote, now));
+        assert!(!conn_ids.is_valid(conn_id, &other, now));
+
+        let window = Duration::from_secs(CONN_ID_WINDOW);
+        assert!(conn_ids.is_valid(conn_id, &remote, now + window));
+        assert!(!conn_ids.is_valid(conn_id, &remote, now + window * 2));
+    }
+
+    #[test]
+    fn test_num_want() {
+        let conn_ids = ConnectionIds::new();
+        let swarms = Mutex::new(Swarms::default());
+        let remote = SocketAddr::from((Ipv4Addr::LOCALHOST, 6881));
+
+        let announce = |port: u16, num_want: i32| {
+            let request = TrackerRequestMsg::Announce {
+                conn_id: conn_ids.generate(&remote, Instant::now()),
+                trans_id: 7,
+                info_hash: &[1; 20],
+                peer_id: &[2; 20],
+                downloaded: 0,
+                left: 100,
+                uploaded: 0,
+                event: ClientState::Started,
+                num_want,
+                port,
+                url_data: &[],
+            };
+
+            match respond(request, remote, &conn_ids, &swarms) {
+                TrackerResponseMsg::Announce(r) => r.peers.len(),
+                r => panic!("Expected an announce response, got {:?}", r),
+            }
+        };
+
+        for port in 1..=3 {
+            announce(port, 0);
+        }
+
+        assert_eq!(announce(4, 1), 1);
+        assert_eq!(announce(4, 0), 0);
+        // The default is larger than the swarm
+        assert_eq!(announce(4, -1), 3);
+    }
+
+    #[test]
+    fn test_invalid_conn_id() {
+        let conn_ids = ConnectionIds::new();
+        let swarms = Mutex::new(Swarms::default());
+        let remote = SocketAddr::from((Ipv4Addr::LOCALHOST, 6881));
+
+        let request = TrackerRequestMsg::Scrape {
+            conn_id: 0x41727101980,
+            trans_id: 7,
+            info_hashes: &[[1; 20]],
+        };
+
+        assert!(matches!(
+            respond(request, remote, &conn_ids, &swarms),
+            TrackerResponseMsg::Error(ErrorResponseMsg { trans_id: 7, .. })
+        ));
+    }
+}